@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// 署名付きのクエリでキーの名前を指定するパラメータ
+const KEY_PARAM: &str = "key";
+/// 署名に使ったシークレットのIDを指定するパラメータ
+const KID_PARAM: &str = "kid";
+/// 署名を指定するパラメータ
+const SIG_PARAM: &str = "sig";
+const DAY: u64 = 24 * 60 * 60;
+
+/// 設定ファイルに書くAPIキーごとの設定
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKeyConfig {
+    /// `kid`を指定しない署名とヘッダーで使うシークレット
+    #[serde(default)]
+    secret: Option<String>,
+    /// IDごとのシークレット。ローテーション中は新旧のシークレットを並べて、どちらでも認証できるようにする
+    #[serde(default)]
+    secrets: HashMap<String, String>,
+    /// 1日(UTC)あたりのリクエスト数の上限
+    daily_requests: Option<u64>,
+    /// 1日(UTC)あたりに返すバイト数の上限
+    daily_bytes: Option<u64>,
+}
+
+/// シークレットはログに出さない。ローテーション中かわかるようにIDだけ出力する
+impl std::fmt::Debug for ApiKeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKeyConfig")
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .field("secrets", &self.secrets.keys().collect::<Vec<_>>())
+            .field("daily_requests", &self.daily_requests)
+            .field("daily_bytes", &self.daily_bytes)
+            .finish()
+    }
+}
+
+impl ApiKeyConfig {
+    /// 有効なすべてのシークレット
+    fn secrets(&self) -> impl Iterator<Item = &str> {
+        self.secret
+            .iter()
+            .chain(self.secrets.values())
+            .map(String::as_str)
+    }
+
+    /// 署名の検証に使うシークレット
+    fn signing_secret(&self, kid: Option<&str>) -> Option<&str> {
+        match kid {
+            Some(kid) => self.secrets.get(kid).map(String::as_str),
+            None => self.secret.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// UNIX時間での日数
+    day: u64,
+    requests: u64,
+    bytes: u64,
+}
+
+/// 認証に失敗した理由
+#[derive(Debug, PartialEq)]
+pub(crate) enum Rejection {
+    /// キーがないか正しくない
+    Unauthorized,
+    /// その日の上限に達した。次の日までの時間を持つ
+    QuotaExceeded(Duration),
+}
+
+/// APIキーとその日ごとの使用量
+#[derive(Debug)]
+pub(crate) struct ApiKeys {
+    keys: HashMap<String, ApiKeyConfig>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl ApiKeys {
+    /// `{"<name>": {"secret": "...", "daily_requests": 10000, "daily_bytes": 1000000000}}`の形のJSONファイルを読み込む。
+    /// `"secrets": {"<kid>": "..."}`でIDの付いたシークレットを複数指定できる
+    pub(crate) fn load(path: PathBuf) -> Result<Self> {
+        let txt = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&txt)
+    }
+
+    fn parse(txt: &str) -> Result<Self> {
+        let keys: HashMap<String, ApiKeyConfig> = serde_json::from_str(txt)?;
+        for (name, key) in &keys {
+            if key.secrets().next().is_none() {
+                return Err(anyhow::anyhow!("{} has no secret", name));
+            }
+            if key.secrets().any(str::is_empty) {
+                return Err(anyhow::anyhow!("secret of {} is empty", name));
+            }
+        }
+        Ok(Self {
+            keys,
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// `X-Api-Key`ヘッダーのシークレットか、`key`と`sig`パラメータの署名で認証してキーの名前を返す。
+    /// 署名は`sig`を除いたパスとクエリに対するシークレットを鍵にしたHMAC-SHA1の16進数。
+    /// `kid`があればそのIDのシークレットで検証する
+    pub(crate) fn authenticate(
+        &self,
+        header: Option<&str>,
+        path: &str,
+        query: Option<&str>,
+    ) -> Option<&str> {
+        if let Some(secret) = header {
+            return self
+                .keys
+                .iter()
+                .find(|(_, key)| {
+                    key.secrets()
+                        .any(|s| constant_time_eq(s.as_bytes(), secret.as_bytes()))
+                })
+                .map(|(name, _)| name.as_str());
+        }
+
+        let query = query?;
+        let mut name = None;
+        let mut kid = None;
+        let mut sig = None;
+        for (k, v) in url::form_urlencoded::parse(query.as_bytes()) {
+            match k.as_ref() {
+                KEY_PARAM => name = Some(v),
+                KID_PARAM => kid = Some(v),
+                SIG_PARAM => sig = Some(v),
+                _ => {}
+            }
+        }
+        let (name, key) = self.keys.get_key_value(name?.as_ref())?;
+        let secret = key.signing_secret(kid.as_deref())?;
+        let unsigned = query
+            .split('&')
+            .filter(|pair| !pair.starts_with("sig="))
+            .collect::<Vec<_>>()
+            .join("&");
+        let expected = hex(&hmac_sha1(
+            secret.as_bytes(),
+            format!("{}?{}", path, unsigned).as_bytes(),
+        ));
+        constant_time_eq(expected.as_bytes(), sig?.to_ascii_lowercase().as_bytes())
+            .then_some(name.as_str())
+    }
+
+    /// 上限に達していなければリクエストを1回数える
+    pub(crate) fn acquire(&self, name: &str, now: SystemTime) -> Result<(), Rejection> {
+        let key = self.keys.get(name).ok_or(Rejection::Unauthorized)?;
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let day = secs / DAY;
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(name.to_string()).or_default();
+        if usage.day != day {
+            *usage = Usage {
+                day,
+                ..Default::default()
+            };
+        }
+        let exceeded = key.daily_requests.is_some_and(|max| usage.requests >= max)
+            || key.daily_bytes.is_some_and(|max| usage.bytes >= max);
+        if exceeded {
+            return Err(Rejection::QuotaExceeded(Duration::from_secs(
+                (day + 1) * DAY - secs,
+            )));
+        }
+        usage.requests += 1;
+        Ok(())
+    }
+
+    /// 返したバイト数を数える
+    pub(crate) fn record_bytes(&self, name: &str, bytes: u64) {
+        if let Some(usage) = self.usage.lock().unwrap().get_mut(name) {
+            usage.bytes += bytes;
+        }
+    }
+}
+
+pub(crate) fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..20].copy_from_slice(&sha1_smol::Sha1::from(key).digest().bytes());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = sha1_smol::Sha1::new();
+    inner.update(&block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = sha1_smol::Sha1::new();
+    outer.update(&block.map(|b| b ^ 0x5c));
+    outer.update(&inner.digest().bytes());
+    outer.digest().bytes()
+}
+
+pub(crate) fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 一致するまでの時間からシークレットを推測されないように、常に全体を比較する
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    fn api_keys() -> ApiKeys {
+        ApiKeys::parse(
+            r#"{
+                "community-a": {"secret": "secret-a", "daily_requests": 2},
+                "community-b": {"secret": "secret-b", "daily_bytes": 100},
+                "community-c": {"secrets": {"2025": "secret-old", "2026": "secret-new"}}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn debug_redacts_secrets_test() {
+        let debug = format!("{:?}", api_keys());
+        assert!(!debug.contains("secret-"));
+        assert!(debug.contains("community-a"));
+    }
+
+    #[test]
+    fn hmac_sha1_test() {
+        // RFC 2202 test case 2
+        assert_eq!(
+            hex(&hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+    }
+
+    #[rstest]
+    #[case(Some("secret-a"), None, Some("community-a"))]
+    #[case(Some("secret-c"), None, None)]
+    #[case(
+        None,
+        Some("url=https%3A%2F%2Fexample.com%2Fa.png&key=community-a&sig=SIG"),
+        Some("community-a")
+    )]
+    #[case(
+        None,
+        Some("url=https%3A%2F%2Fexample.com%2Fb.png&key=community-a&sig=SIG"),
+        None
+    )]
+    #[case(
+        None,
+        Some("url=https%3A%2F%2Fexample.com%2Fa.png&key=community-b&sig=SIG"),
+        None
+    )]
+    #[case(Some("secret-old"), None, Some("community-c"))]
+    #[case(Some("secret-new"), None, Some("community-c"))]
+    #[case(
+        None,
+        Some("url=https%3A%2F%2Fexample.com%2Fa.png&key=community-c&kid=2025&sig=OLD"),
+        Some("community-c")
+    )]
+    #[case(
+        None,
+        Some("url=https%3A%2F%2Fexample.com%2Fa.png&key=community-c&kid=2026&sig=NEW"),
+        Some("community-c")
+    )]
+    // IDと違うシークレットで署名した
+    #[case(
+        None,
+        Some("url=https%3A%2F%2Fexample.com%2Fa.png&key=community-c&kid=2026&sig=OLD"),
+        None
+    )]
+    #[case(
+        None,
+        Some("url=https%3A%2F%2Fexample.com%2Fa.png&key=community-c&kid=2024&sig=OLD"),
+        None
+    )]
+    // `kid`がなければ`secret`で検証する
+    #[case(
+        None,
+        Some("url=https%3A%2F%2Fexample.com%2Fa.png&key=community-a&kid=2025&sig=SIG"),
+        None
+    )]
+    #[case(None, Some("url=https%3A%2F%2Fexample.com%2Fa.png"), None)]
+    #[case(None, None, None)]
+    fn authenticate_test(
+        #[case] header: Option<&str>,
+        #[case] query: Option<&str>,
+        #[case] expected: Option<&str>,
+    ) {
+        let sig = hex(&hmac_sha1(
+            b"secret-a",
+            b"/proxy/emoji.webp?url=https%3A%2F%2Fexample.com%2Fa.png&key=community-a",
+        ));
+        let query = query.map(|q| {
+            let unsigned = q.split("&sig=").next().unwrap();
+            let sign = |secret: &[u8]| {
+                hex(&hmac_sha1(
+                    secret,
+                    format!("/proxy/emoji.webp?{}", unsigned).as_bytes(),
+                ))
+            };
+            q.replace("SIG", &sig)
+                .replace("OLD", &sign(b"secret-old"))
+                .replace("NEW", &sign(b"secret-new"))
+        });
+        assert_eq!(
+            api_keys().authenticate(header, "/proxy/emoji.webp", query.as_deref()),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case(r#"{"a": {"daily_requests": 1}}"#)]
+    #[case(r#"{"a": {"secret": ""}}"#)]
+    #[case(r#"{"a": {"secrets": {"1": ""}}}"#)]
+    fn parse_error_test(#[case] txt: &str) {
+        assert!(ApiKeys::parse(txt).is_err());
+    }
+
+    #[test]
+    fn quota_test() {
+        let api_keys = api_keys();
+        let now = UNIX_EPOCH + Duration::from_secs(10 * DAY + 100);
+        assert_eq!(api_keys.acquire("community-a", now), Ok(()));
+        assert_eq!(api_keys.acquire("community-a", now), Ok(()));
+        assert_eq!(
+            api_keys.acquire("community-a", now),
+            Err(Rejection::QuotaExceeded(Duration::from_secs(DAY - 100)))
+        );
+        // 次の日になると使用量が戻る
+        let tomorrow = now + Duration::from_secs(DAY);
+        assert_eq!(api_keys.acquire("community-a", tomorrow), Ok(()));
+
+        assert_eq!(api_keys.acquire("community-b", now), Ok(()));
+        api_keys.record_bytes("community-b", 100);
+        assert!(api_keys.acquire("community-b", now).is_err());
+
+        assert_eq!(
+            api_keys.acquire("unknown", now),
+            Err(Rejection::Unauthorized)
+        );
+    }
+}
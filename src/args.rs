@@ -1,5 +1,9 @@
+use std::str::FromStr;
+
 use clap::Parser;
 
+use crate::{client::ImageExt, handler::ConvertType, processor::ResizeFilter, webp::Preset};
+
 #[derive(Parser, Debug)]
 #[command(
     version,
@@ -14,6 +18,52 @@ pub(crate) struct Args {
         help = "Media Proxyが利用するhttp proxyです。設定しない場合http proxyを利用しません"
     )]
     pub(crate) http_proxy: Option<String>,
+    #[arg(
+        long,
+        env,
+        help = "取得先のホストごとに保持するアイドル接続数の上限です。未設定の場合無制限です"
+    )]
+    pub(crate) upstream_pool_idle_per_host: Option<usize>,
+    #[arg(
+        long,
+        env,
+        help = "取得先とのアイドル接続を保持する秒数です。未設定の場合90秒です"
+    )]
+    pub(crate) upstream_pool_idle_timeout: Option<u64>,
+    #[arg(
+        long,
+        env,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        help = "取得先との接続でTCP_NODELAYを有効にするかです"
+    )]
+    pub(crate) upstream_tcp_nodelay: bool,
+    #[arg(
+        long,
+        env,
+        help = "取得先との接続でTCP keepaliveを送る間隔(秒)です。未設定の場合送りません"
+    )]
+    pub(crate) upstream_tcp_keepalive: Option<u64>,
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "よく取得するホストです。起動時に名前解決し、結果を`--warm-dns-ttl`の間使い続けます。問い合わせに失敗した場合は前回の結果を使います\nExample: `--warm-hosts=media.misskeyusercontent.jp,s3.arkjp.net`"
+    )]
+    pub(crate) warm_hosts: Vec<String>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 300,
+        help = "`--warm-hosts`の名前解決の結果を使い続ける時間(秒)です"
+    )]
+    pub(crate) warm_dns_ttl: u64,
+    #[arg(
+        long,
+        env,
+        help = "起動時に`--warm-hosts`へHTTPSで接続し、TLSのハンドシェイクを済ませた接続をコネクションプールに残します"
+    )]
+    pub(crate) warm_connections: bool,
     #[arg(
         long,
         env,
@@ -34,9 +84,641 @@ pub(crate) struct Args {
         help = "Webpの圧縮率です。0-100の範囲で指定でき、0が最も高い圧縮率ですが画質が低くなります"
     )]
     pub(crate) quality_factor: u8,
+    #[arg(
+        long,
+        env,
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "`quality`パラメータで指定できる圧縮率の上限です"
+    )]
+    pub(crate) max_quality: u8,
+    #[arg(
+        long,
+        env,
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help = "透明度の圧縮率です。0-100の範囲で指定でき、小さいほどサイズが小さくなりますが透明度の階調が粗くなります"
+    )]
+    pub(crate) alpha_quality: u8,
+    #[arg(
+        long,
+        env,
+        default_value = "picture",
+        help = "WebPのプリセットです。default, picture, photo, drawing, icon, textのいずれかを指定します"
+    )]
+    pub(crate) webp_preset: Preset,
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "変換の種類ごとのWebPのプリセットです。`<type>=<preset>`の形式で指定します\nExample: `--preset-profile=emoji=icon,preview=photo`"
+    )]
+    pub(crate) preset_profile: Vec<PresetProfile>,
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "変換後にバックグラウンドで作ってキャッシュする同じ画像の別の変換です。`<type>=<type>`の形式で指定し、右辺を`static`にすると同じ変換の静止画を作ります\nExample: `--eager-variant=avatar=static,avatar=preview`"
+    )]
+    pub(crate) eager_variant: Vec<EagerVariant>,
+    #[arg(
+        long,
+        env,
+        default_value = "triangle",
+        help = "拡大縮小に使うフィルタです。nearest, triangle, catmullrom, lanczos3のいずれかを指定します"
+    )]
+    pub(crate) resize_filter: ResizeFilter,
+    #[arg(
+        long,
+        env,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(0..=2),
+        help = "透明度の予測フィルタです。0がなし、1が高速、2が最良です"
+    )]
+    pub(crate) alpha_filtering: u8,
+    #[arg(
+        long,
+        env,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        help = "透明度を可逆圧縮するかです。無効にすると透明度を無圧縮で保存します"
+    )]
+    pub(crate) alpha_compression: bool,
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "変換の種類ごとの透明度の設定です。`<type>=<quality>:<filtering>:<compression>`の形式で指定します\nExample: `--alpha-profile=emoji=100:2:true,preview=50:1:true`"
+    )]
+    pub(crate) alpha_profile: Vec<AlphaProfile>,
+    #[arg(
+        long,
+        env,
+        help = "アニメーションの画質を下げ始めるピクセル数です。エンコードしたフレームのピクセル数の合計がこれを超えると、超えた分に反比例して画質を下げます。指定しない場合はすべてのフレームを同じ画質にします"
+    )]
+    pub(crate) anim_quality_threshold: Option<u64>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help = "`--anim-quality-threshold`で下げる画質の下限です"
+    )]
+    pub(crate) anim_min_quality: u8,
+    #[arg(
+        long,
+        env,
+        help = "出力するWebPに取得元のURLと変換の設定をXMPで埋め込みます。どこから取得した画像かを後から辿れますが、サイズが少し増えます"
+    )]
+    pub(crate) embed_provenance: bool,
+    #[arg(
+        long,
+        default_value_t = 2048,
+        help = "`w`パラメータで指定できる幅の上限です"
+    )]
+    pub(crate) max_width: u32,
+    #[arg(
+        long,
+        default_value_t = 2048,
+        help = "`h`パラメータで指定できる高さの上限です"
+    )]
+    pub(crate) max_height: u32,
+    #[arg(
+        long,
+        env,
+        help = "アニメーションのフレームの切り替わりをこのフレームレートの間隔に揃えます。間隔より短く表示されるフレームは捨てます。未設定の場合は元のタイミングを維持します"
+    )]
+    pub(crate) max_fps: Option<u32>,
+    #[arg(
+        long,
+        env,
+        help = "変換の指定がない場合(original)の長辺の上限です。超える場合はアスペクト比を維持したまま縮小します。未設定の場合は元の大きさのままです"
+    )]
+    pub(crate) original_max_size: Option<u32>,
+    #[arg(
+        long,
+        env,
+        help = "previewでアニメーションを先頭から何フレームまで残すかです。未設定の場合はすべてのフレームを残します"
+    )]
+    pub(crate) preview_max_frames: Option<u32>,
+    #[arg(
+        long,
+        env,
+        help = "previewでアニメーションを先頭から何ミリ秒まで残すかです。未設定の場合はすべてのフレームを残します"
+    )]
+    pub(crate) preview_max_duration: Option<u32>,
+    #[arg(
+        long,
+        env,
+        help = "アニメーションを先頭から何秒まで残すかです。超える部分は変換せずに切り詰めます。未設定もしくは0の場合はすべてのフレームを残します"
+    )]
+    pub(crate) max_animation_duration: Option<u32>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 60,
+        help = "`ttl`でブラウザにキャッシュさせる秒数を指定された場合の下限です"
+    )]
+    pub(crate) min_ttl: u32,
+    #[arg(
+        long,
+        env,
+        default_value_t = 31_536_000,
+        help = "`ttl`でブラウザにキャッシュさせる秒数を指定された場合の上限です。`ttl`がない場合は常に1年間キャッシュさせます"
+    )]
+    pub(crate) max_ttl: u32,
+    #[arg(
+        long,
+        env,
+        help = "アニメーションの出力の幅の上限です。静止画より小さくすることで変換の負荷を抑えられます。超える場合はアスペクト比を維持したまま縮小します。未設定の場合は静止画と同じです"
+    )]
+    pub(crate) animated_max_width: Option<u32>,
+    #[arg(
+        long,
+        env,
+        help = "アニメーションの出力の高さの上限です。超える場合はアスペクト比を維持したまま縮小します。未設定の場合は静止画と同じです"
+    )]
+    pub(crate) animated_max_height: Option<u32>,
+    #[arg(
+        long,
+        default_value_t = 100_000_000,
+        help = "デコードを許可する画像の最大ピクセル数(幅x高さ)です。ヘッダーから判断し、超える場合はデコードせずにエラーを返します"
+    )]
+    pub(crate) max_pixels: u64,
+    #[arg(
+        long,
+        env,
+        default_value_t = 65_535,
+        help = "デコードを許可する画像の幅と高さの上限です。デコーダの中で判断します"
+    )]
+    pub(crate) max_dimension: u32,
+    #[arg(
+        long,
+        env,
+        default_value_t = 262_144_000,
+        help = "取得する画像の最大バイト数です。超えた時点でダウンロードを中断します"
+    )]
+    pub(crate) max_download_size: usize,
+    #[arg(
+        long,
+        env,
+        help = "画像のデコードを子プロセスで行います。子プロセスにはメモリとCPU時間の上限を設定し、デコーダの不具合がサーバーに及ばないようにします"
+    )]
+    pub(crate) decoder_sandbox: bool,
+    #[arg(
+        long,
+        env,
+        default_value_t = 1024,
+        help = "デコード用の子プロセスが使えるメモリの上限(MiB)です"
+    )]
+    pub(crate) decoder_sandbox_memory: u64,
+    #[arg(
+        long,
+        env,
+        default_value_t = 10,
+        help = "デコード用の子プロセスが使えるCPU時間の上限(秒)です"
+    )]
+    pub(crate) decoder_sandbox_cpu: u64,
+    #[arg(
+        long,
+        env,
+        default_value_t = 512,
+        help = "デコード用の子プロセスから受け取るデコード結果の上限(MiB)です。アニメーションは全フレームを展開して受け取るため、これを超える画像は変換しません"
+    )]
+    pub(crate) decoder_sandbox_output: u64,
+    #[arg(
+        long,
+        env,
+        default_value_t = 30,
+        help = "デコード用の子プロセスの実行時間の上限(秒)です。入出力で止まっている場合も含みます"
+    )]
+    pub(crate) decoder_sandbox_timeout: u64,
+    #[arg(
+        long,
+        env,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        help = "起動時にフォントを読み込み、小さな画像を一通り変換します。最初のリクエストが遅くならず、設定の誤りがあれば起動に失敗します"
+    )]
+    pub(crate) warmup: bool,
+    #[arg(
+        long,
+        env,
+        help = "Linuxで起動時にLandlockとseccompによる制限をかけます。フォントや証明書、設定したファイル以外は読み込めず、監査ログ以外には書き込めなくなります"
+    )]
+    pub(crate) harden: bool,
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "デコードしない画像の形式です。png, jpeg, gif, svg, webp, icoから選びます。該当する画像はヘッダーを読む前に415を返します\nExample: `--disable-format=svg,ico`"
+    )]
+    pub(crate) disable_format: Vec<ImageExt>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 8192,
+        help = "変換のリクエストのパスとクエリの合計の長さの上限です。超えた場合は414を返します"
+    )]
+    pub(crate) max_uri_length: usize,
+    #[arg(
+        long,
+        env,
+        default_value_t = 32,
+        help = "変換のリクエストのクエリパラメータの数の上限です。超えた場合は400を返します"
+    )]
+    pub(crate) max_query_params: usize,
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "`file://`で画像を読み込むことを許可するディレクトリです。未設定の場合`file://`は使えません\nExample: `--file-root=/var/lib/misskey/files`"
+    )]
+    pub(crate) file_root: Vec<std::path::PathBuf>,
+    #[arg(
+        long,
+        env,
+        help = "`ipfs://CID/path`の画像を取得するゲートウェイです。`<ゲートウェイ>/ipfs/CID/path`から取得します。未設定の場合`ipfs://`は使えません\nExample: `--ipfs-gateway=https://ipfs.io`"
+    )]
+    pub(crate) ipfs_gateway: Option<reqwest::Url>,
+    #[arg(
+        long,
+        env,
+        help = "画像を取得する前にHEADで`Content-Length`と`Content-Type`を確かめ、上限を超えるものや画像ではないものはダウンロードせずに拒否します。HEADに失敗した場合はそのまま取得します"
+    )]
+    pub(crate) preflight_head: bool,
+    #[arg(
+        long,
+        env,
+        help = "非同期ランタイムのワーカースレッド数です。未設定の場合CPUのコア数になります"
+    )]
+    pub(crate) worker_threads: Option<usize>,
+    #[arg(
+        long,
+        env,
+        help = "画像の変換などに使うブロッキングスレッドの上限です。未設定の場合tokioのデフォルト(512)になります"
+    )]
+    pub(crate) max_blocking_threads: Option<usize>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 512,
+        help = "エンコード待ちがこの数を超えると`/readyz`が503を返します"
+    )]
+    pub(crate) ready_max_queued: usize,
+    #[arg(
+        long,
+        env,
+        help = "エンコード待ちがこの数を超えると変換のリクエストにすぐ503を返します。未設定の場合は制限しません"
+    )]
+    pub(crate) shed_max_queued: Option<usize>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 1,
+        help = "負荷を理由に503を返す際の`Retry-After`の秒数です"
+    )]
+    pub(crate) shed_retry_after: u64,
+    #[arg(
+        long,
+        env,
+        default_value_t = 0,
+        help = "変換結果をメモリ上にキャッシュする最大バイト数です。0の場合キャッシュしません"
+    )]
+    pub(crate) cache_size: usize,
+    #[arg(
+        long,
+        env,
+        help = "キャッシュした変換結果を使う期間(秒)です。未設定の場合は期限切れにしません"
+    )]
+    pub(crate) cache_ttl: Option<u64>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 86_400,
+        help = "取得元の障害で変換できない場合に、期限切れから何秒までのキャッシュを代わりに返すかです。`Warning`と`Age`を付け、短い`max-age`で返します。0の場合は返しません"
+    )]
+    pub(crate) stale_if_error: u64,
+    #[arg(
+        long,
+        env,
+        help = "変換の指定がなく、取得元が上限以内のWebPの場合は取得元へリダイレクトします。転送量を減らせますが、WebPかどうかを確かめるために取得元から先頭だけを取得します"
+    )]
+    pub(crate) redirect_origin_webp: bool,
+    #[arg(
+        long,
+        env,
+        help = "変換の指定がなく、取得元が上限以内のWebPか動画や音声の場合は、全体を読み込まずにそのまま転送します。大きなファイルでもメモリを使いませんが、キャッシュしません"
+    )]
+    pub(crate) stream_passthrough: bool,
+    #[arg(
+        long,
+        env,
+        help = "avatarとpreviewで`Sec-CH-DPR`と`Sec-CH-Width`に合わせて出力の大きさを変えます。`Accept-CH`で要求し、`Vary`を付けます"
+    )]
+    pub(crate) client_hints: bool,
+    #[cfg(feature = "avif")]
+    #[arg(
+        long,
+        env,
+        help = "出力形式の指定がなく`Accept`に`image/avif`を含むリクエストには、アニメーションをアニメーションAVIFで返します。`Vary: Accept`を付けます"
+    )]
+    pub(crate) animated_avif: bool,
+    #[arg(
+        long,
+        env,
+        help = "管理用API(/admin/*とgRPCのPurge)のトークンです。`Authorization: Bearer <token>`で指定します。未設定の場合管理用APIは無効です"
+    )]
+    pub(crate) admin_token: Option<String>,
+    #[cfg(feature = "grpc")]
+    #[arg(
+        long,
+        env,
+        help = "gRPCサービスを待ち受けるポート番号です。未設定の場合gRPCサービスは起動しません"
+    )]
+    pub(crate) grpc_port: Option<u16>,
+    #[cfg(feature = "http3")]
+    #[arg(
+        long,
+        env,
+        requires_all = ["http3_cert", "http3_key"],
+        help = "HTTP/3(QUIC)で待ち受けるUDPのポート番号です。TCPと同じAPIを提供し、TCPのレスポンスに`Alt-Svc`を付けます。未設定の場合HTTP/3は使いません"
+    )]
+    pub(crate) http3_port: Option<u16>,
+    #[cfg(feature = "http3")]
+    #[arg(long, env, help = "HTTP/3で使う証明書チェーンのPEMファイルです")]
+    pub(crate) http3_cert: Option<std::path::PathBuf>,
+    #[cfg(feature = "http3")]
+    #[arg(long, env, help = "HTTP/3で使う秘密鍵のPEMファイルです")]
+    pub(crate) http3_key: Option<std::path::PathBuf>,
+    #[cfg(feature = "redis")]
+    #[arg(
+        long,
+        env,
+        help = "複数のレプリカで同じ画像の変換をまとめるためのRedisのURLです。未設定の場合はまとめません\nExample: `--redis-url=redis://127.0.0.1:6379`"
+    )]
+    pub(crate) redis_url: Option<String>,
+    #[cfg(feature = "redis")]
+    #[arg(
+        long,
+        env,
+        default_value_t = 30,
+        help = "Redisで取得するリースの有効期間(秒)です。他のレプリカはこの期間だけ変換結果を待ちます"
+    )]
+    pub(crate) redis_lease: u64,
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "クラスタを構成するノードのURLです。キャッシュのキーを担当するノードに変換を転送します\nExample: `--cluster-peers=http://10.0.0.1:12766,http://10.0.0.2:12766`"
+    )]
+    pub(crate) cluster_peers: Vec<reqwest::Url>,
+    #[arg(
+        long,
+        env,
+        help = "クラスタ内での自分自身のURLです。未設定の場合はクラスタモードを無効にします"
+    )]
+    pub(crate) cluster_self: Option<reqwest::Url>,
+    #[arg(
+        long,
+        env,
+        help = "クラスタのノード間で共有するシークレットです。転送したリクエストに署名し、署名のない転送は通常のリクエストとして扱います。クラスタモードでは必須です"
+    )]
+    pub(crate) cluster_secret: Option<String>,
+    #[arg(
+        long,
+        env,
+        help = "取得を拒否するURLのパターンを1行ずつ書いたファイルです。`/`を含まない行はホスト名、含む行はURL全体に対するglob、`regex:`で始まる行は正規表現として扱います"
+    )]
+    pub(crate) blocklist: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 10,
+        help = "ブロックリストのファイルの更新を確認する間隔(秒)です"
+    )]
+    pub(crate) blocklist_reload_interval: u64,
+    #[arg(
+        long,
+        env,
+        help = "`Host`ヘッダーごとの設定を書いたJSONファイルです。`quality`、`max_width`、`max_height`、`allow_hosts`、`fallback`を指定できます\nExample: `{\"misskey.example\": {\"quality\": 70, \"allow_hosts\": [\"*.misskey.example\"], \"fallback\": \"/etc/misskey-webp-proxy/dummy.png\"}}`"
+    )]
+    pub(crate) tenants: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        env,
+        help = "APIキーを書いたJSONファイルです。設定した場合、変換には`X-Api-Key`ヘッダーか`key`と`sig`パラメータによる署名が必要になります。`secrets`にIDごとのシークレットを並べると、署名の`kid`パラメータで使うシークレットを選べるので、URLを無効にせずにシークレットをローテーションできます。クラスタモードでは`--cluster-secret`の署名を検証できたノード間の転送だけを認証済みとして扱います\nExample: `{\"community-a\": {\"secret\": \"...\", \"daily_requests\": 100000, \"daily_bytes\": 10000000000}}`"
+    )]
+    pub(crate) api_keys: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        env,
+        help = "取得元へのリクエストにAWS SigV4で署名するための認証情報を書いたJSONファイルです。非公開のS3やR2のバケットから直接取得できます。キーはホスト名か`*.`で始まるパターンで、`service`の既定は`s3`です\nExample: `{\"*.r2.cloudflarestorage.com\": {\"region\": \"auto\", \"access_key_id\": \"...\", \"secret_access_key\": \"...\"}}`"
+    )]
+    pub(crate) origin_credentials: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        env,
+        help = "取得元のホストごとに1秒あたりに取得する回数の上限です。超えた場合は待ってから取得します。未設定の場合は制限しません"
+    )]
+    pub(crate) origin_rate_limit: Option<f64>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 10,
+        help = "取得元のホストごとに連続して取得できる回数です"
+    )]
+    pub(crate) origin_rate_burst: u32,
+    #[arg(
+        long,
+        env,
+        help = "画像を取得する前に問い合わせるwebhookのURLです。`{\"url\":...,\"type\":...}`をPOSTし、2xxなら許可、4xxなら拒否します"
+    )]
+    pub(crate) prefetch_webhook: Option<reqwest::Url>,
+    #[arg(
+        long,
+        env,
+        default_value_t = 1000,
+        help = "webhookのタイムアウト(ミリ秒)です。応答がない場合は取得しません"
+    )]
+    pub(crate) prefetch_webhook_timeout: u64,
+    #[arg(
+        long,
+        env,
+        help = "取得元へのアクセスを記録する監査ログのファイルです。JSON Linesで追記します。未設定の場合は記録しません"
+    )]
+    pub(crate) audit_log: Option<std::path::PathBuf>,
     #[arg(
         long,
         help = "CORSの設定です。未設定の場合、すべてのオリジンからのリクエストを受け付けます\nExample: `--allow_origin=https://misskey1.example.com --allow_origin=https://misskey2.example.com`"
     )]
     pub(crate) allow_origin: Vec<http::HeaderValue>,
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone, PartialEq)]
+pub(crate) enum Command {
+    /// 設定を検証して終了します。問題があれば終了コード1で終了します
+    Check,
+    /// ライブラリのバージョンやフォント、外部への疎通、キャッシュ、実際に使われる上限を表示します
+    Doctor(crate::doctor::DoctorArgs),
+    /// 子プロセスとして画像をデコードします。`--decoder-sandbox`から内部的に使います
+    #[command(hide = true)]
+    DecodeWorker(crate::sandbox::WorkerArgs),
+}
+
+/// 変換の種類ごとの透明度の設定
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AlphaProfile {
+    pub(crate) convert_type: ConvertType,
+    pub(crate) quality: u8,
+    pub(crate) filtering: u8,
+    pub(crate) compression: bool,
+}
+
+impl FromStr for AlphaProfile {
+    type Err = anyhow::Error;
+
+    /// `<type>=<quality>:<filtering>:<compression>`をパースする
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (convert_type, rest) = s.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("expected <type>=<quality>:<filtering>:<compression>")
+        })?;
+        let convert_type = convert_type.parse()?;
+        let [quality, filtering, compression] = rest.split(':').collect::<Vec<_>>()[..] else {
+            return Err(anyhow::anyhow!(
+                "expected <quality>:<filtering>:<compression>"
+            ));
+        };
+        let quality: u8 = quality.parse()?;
+        let filtering: u8 = filtering.parse()?;
+        if quality > 100 || filtering > 2 {
+            return Err(anyhow::anyhow!(
+                "quality must be 0-100 and filtering must be 0-2"
+            ));
+        }
+
+        Ok(Self {
+            convert_type,
+            quality,
+            filtering,
+            compression: compression.parse()?,
+        })
+    }
+}
+
+/// 変換の種類ごとのWebPのプリセット
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PresetProfile {
+    pub(crate) convert_type: ConvertType,
+    pub(crate) preset: Preset,
+}
+
+impl FromStr for PresetProfile {
+    type Err = anyhow::Error;
+
+    /// `<type>=<preset>`をパースする
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (convert_type, preset) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected <type>=<preset>"))?;
+        Ok(Self {
+            convert_type: convert_type.parse()?,
+            preset: preset.parse()?,
+        })
+    }
+}
+
+/// `from`の変換の後にバックグラウンドで作る変換
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct EagerVariant {
+    pub(crate) from: ConvertType,
+    pub(crate) to: ConvertType,
+    pub(crate) is_static: bool,
+}
+
+impl FromStr for EagerVariant {
+    type Err = anyhow::Error;
+
+    /// `<type>=<type>`もしくは`<type>=static`をパースする
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected <type>=<type>"))?;
+        let from = from.parse()?;
+        Ok(match to {
+            "static" => Self {
+                from,
+                to: from,
+                is_static: true,
+            },
+            to => Self {
+                from,
+                to: to.parse()?,
+                is_static: false,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[test]
+    fn alpha_profile_test() {
+        assert_eq!(
+            "emoji=80:2:false".parse::<AlphaProfile>().unwrap(),
+            AlphaProfile {
+                convert_type: ConvertType::Emoji,
+                quality: 80,
+                filtering: 2,
+                compression: false,
+            }
+        );
+    }
+
+    #[rstest]
+    #[case("emoji=icon", Some((ConvertType::Emoji, Preset::Icon)))]
+    #[case("preview=photo", Some((ConvertType::Preview, Preset::Photo)))]
+    #[case("emoji=vector", None)]
+    #[case("icon", None)]
+    fn preset_profile_test(#[case] s: &str, #[case] expected: Option<(ConvertType, Preset)>) {
+        let parsed = s
+            .parse::<PresetProfile>()
+            .ok()
+            .map(|p| (p.convert_type, p.preset));
+        assert_eq!(parsed, expected);
+    }
+
+    #[rstest]
+    #[case("avatar=static", Some((ConvertType::Avatar, ConvertType::Avatar, true)))]
+    #[case("avatar=preview", Some((ConvertType::Avatar, ConvertType::Preview, false)))]
+    #[case("avatar=sticker", None)]
+    #[case("avatar", None)]
+    fn eager_variant_test(
+        #[case] s: &str,
+        #[case] expected: Option<(ConvertType, ConvertType, bool)>,
+    ) {
+        let parsed = s
+            .parse::<EagerVariant>()
+            .ok()
+            .map(|v| (v.from, v.to, v.is_static));
+        assert_eq!(parsed, expected);
+    }
+
+    #[rstest]
+    #[case("emoji")]
+    #[case("emoji=80:2")]
+    #[case("sticker=80:2:true")]
+    #[case("emoji=101:2:true")]
+    #[case("emoji=80:3:true")]
+    #[case("emoji=80:2:yes")]
+    fn invalid_alpha_profile(#[case] s: &str) {
+        assert!(s.parse::<AlphaProfile>().is_err());
+    }
 }
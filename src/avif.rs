@@ -0,0 +1,527 @@
+use anyhow::{Context as _, Result};
+use image::Frame;
+use rav1e::prelude::{
+    ChromaSampling, ColorDescription, ColorPrimaries, Config, Context, EncoderConfig,
+    EncoderStatus, FrameType, MatrixCoefficients, Packet, PixelRange, TransferCharacteristics,
+};
+
+use crate::processor::{place_on_canvas, InvalidImage};
+
+/// rav1eの速度のプリセット。0が最も遅く高品質で、10が最も速い
+const SPEED: u8 = 8;
+/// トラックとムービーの時間の単位。ミリ秒にする
+const TIMESCALE: u32 = 1000;
+/// 透明度のトラックとアイテムであることを示すURN
+const ALPHA_URN: &[u8] = b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0";
+/// 大きさが0のTemporal Delimiter OBU
+const TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
+/// YCbCrへの変換に使うBT.601の係数
+const BT601: [f32; 3] = [0.299, 0.587, 0.114];
+
+/// 1つのAV1のビットストリームとしてエンコードしたサンプル
+struct Track {
+    /// `av1C`ボックスの中身
+    config: Vec<u8>,
+    samples: Vec<Vec<u8>>,
+    /// キーフレームのサンプルの番号。1から始まる
+    sync: Vec<u32>,
+}
+
+/// rav1eでフレームを1枚ずつエンコードする
+struct Encoder {
+    ctx: Context<u8>,
+    samples: Vec<Vec<u8>>,
+    sync: Vec<u32>,
+}
+
+impl Encoder {
+    fn new(width: u32, height: u32, quantizer: usize, alpha: bool) -> Result<Self> {
+        let (chroma_sampling, color_description) = match alpha {
+            true => (ChromaSampling::Cs400, None),
+            false => (
+                ChromaSampling::Cs444,
+                Some(ColorDescription {
+                    color_primaries: ColorPrimaries::BT709,
+                    transfer_characteristics: TransferCharacteristics::SRGB,
+                    matrix_coefficients: MatrixCoefficients::BT601,
+                }),
+            ),
+        };
+        let config = Config::new().with_encoder_config(EncoderConfig {
+            width: width as usize,
+            height: height as usize,
+            time_base: rav1e::prelude::Rational::new(1, TIMESCALE as u64),
+            chroma_sampling,
+            pixel_range: PixelRange::Full,
+            color_description,
+            quantizer,
+            min_quantizer: quantizer as u8,
+            ..EncoderConfig::with_speed_preset(SPEED)
+        });
+        Ok(Self {
+            ctx: config
+                .new_context()
+                .map_err(|e| anyhow::anyhow!("rav1e config error: {}", e))?,
+            samples: vec![],
+            sync: vec![],
+        })
+    }
+
+    /// `planes`を1フレームとして送り、出来上がったサンプルを受け取る
+    fn send(&mut self, width: u32, planes: &[Vec<u8>]) -> Result<()> {
+        let mut frame = self.ctx.new_frame();
+        for (plane, data) in frame.planes.iter_mut().zip(planes) {
+            plane.copy_from_raw_u8(data, width as usize, 1);
+        }
+        self.ctx.send_frame(frame)?;
+        self.receive()
+    }
+
+    fn receive(&mut self) -> Result<()> {
+        loop {
+            match self.ctx.receive_packet() {
+                Ok(packet) => self.push(packet),
+                Err(EncoderStatus::Encoded) => {}
+                Err(EncoderStatus::NeedMoreData | EncoderStatus::LimitReached) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn push(&mut self, mut packet: Packet<u8>) {
+        // ISOBMFFのサンプルには先頭のTemporal Delimiter OBUを含めない
+        if packet.data.starts_with(&TEMPORAL_DELIMITER) {
+            packet.data.drain(..TEMPORAL_DELIMITER.len());
+        }
+        self.samples.push(packet.data);
+        if packet.frame_type == FrameType::KEY {
+            self.sync.push(self.samples.len() as u32);
+        }
+    }
+
+    fn finish(mut self) -> Result<Track> {
+        self.ctx.flush();
+        self.receive()?;
+        Ok(Track {
+            config: self.ctx.container_sequence_header(),
+            samples: self.samples,
+            sync: self.sync,
+        })
+    }
+}
+
+/// RGBAのフレームをYCbCr 4:4:4と透明度の平面に分ける
+fn split_planes(frame: &Frame) -> ([Vec<u8>; 3], Vec<u8>) {
+    let len = frame.buffer().pixels().len();
+    let mut planes = [
+        Vec::with_capacity(len),
+        Vec::with_capacity(len),
+        Vec::with_capacity(len),
+    ];
+    let mut alpha = Vec::with_capacity(len);
+    for px in frame.buffer().pixels() {
+        let [r, g, b, a] = px.0.map(f32::from);
+        let y = BT601[0] * r + BT601[1] * g + BT601[2] * b;
+        let cb = (b - y) * 0.5 / (1.0 - BT601[2]) + 128.0;
+        let cr = (r - y) * 0.5 / (1.0 - BT601[0]) + 128.0;
+        planes[0].push(y.round() as u8);
+        planes[1].push(cb.round().clamp(0.0, 255.0) as u8);
+        planes[2].push(cr.round().clamp(0.0, 255.0) as u8);
+        alpha.push(a as u8);
+    }
+    (planes, alpha)
+}
+
+/// WebPと同じ0-100の圧縮率をrav1eの量子化パラメータ(0-255)にする。ravifと同じ対応
+fn quality_to_quantizer(quality: f32) -> usize {
+    let q = quality.clamp(0.0, 100.0) / 100.0;
+    let x = if q >= 0.85 {
+        (1.0 - q) * 3.0
+    } else if q > 0.25 {
+        1.0 - 0.125 - q * 0.5
+    } else {
+        1.0 - q
+    };
+    (x * 255.0).round() as usize
+}
+
+/// フレームを1枚ずつ受け取りながらアニメーションAVIFにエンコードする。
+/// 透明なピクセルがあるフレームを含む場合のみ透明度のトラックを付ける
+pub fn encode_avif_anim<I>(width: u32, height: u32, frames: I, quality: f32) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = Result<Frame>>,
+{
+    let quantizer = quality_to_quantizer(quality);
+    let mut color = Encoder::new(width, height, quantizer, false)?;
+    let mut alpha = Encoder::new(width, height, quantizer, true)?;
+    let mut opaque = true;
+    let mut durations = vec![];
+    for frame in frames {
+        let frame = place_on_canvas(frame?, width, height);
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        durations.push((numer / denom.max(1)).max(1));
+        let (planes, alpha_plane) = split_planes(&frame);
+        opaque &= alpha_plane.iter().all(|&a| a == u8::MAX);
+        color.send(width, &planes)?;
+        alpha.send(width, &[alpha_plane])?;
+    }
+    if durations.is_empty() {
+        return Err(InvalidImage::NoFrames.into());
+    }
+
+    let color = color.finish()?;
+    let alpha = match opaque {
+        true => None,
+        false => Some(alpha.finish()?),
+    };
+    if color.samples.len() != durations.len()
+        || alpha
+            .as_ref()
+            .is_some_and(|a| a.samples.len() != durations.len())
+    {
+        return Err(anyhow::anyhow!(
+            "rav1e returned unexpected number of frames"
+        ));
+    }
+    Muxer {
+        width,
+        height,
+        durations: &durations,
+        color: &color,
+        alpha: alpha.as_ref(),
+    }
+    .write()
+    .context("failed to write AVIF")
+}
+
+/// ISOBMFFのボックス
+fn bx(kind: &[u8; 4], parts: &[&[u8]]) -> Vec<u8> {
+    let size = 8 + parts.iter().map(|p| p.len()).sum::<usize>();
+    let mut buf = Vec::with_capacity(size);
+    buf.extend_from_slice(&(size as u32).to_be_bytes());
+    buf.extend_from_slice(kind);
+    for part in parts {
+        buf.extend_from_slice(part);
+    }
+    buf
+}
+
+/// バージョンとフラグを持つISOBMFFのボックス
+fn full(kind: &[u8; 4], version: u8, flags: u32, parts: &[&[u8]]) -> Vec<u8> {
+    let header = ((version as u32) << 24 | flags).to_be_bytes();
+    let mut all = vec![header.as_slice()];
+    all.extend_from_slice(parts);
+    bx(kind, &all)
+}
+
+/// 数値をビッグエンディアンで並べる
+macro_rules! be {
+    ($($v:expr),* $(,)?) => {{
+        let mut buf: Vec<u8> = vec![];
+        $(buf.extend_from_slice(&$v.to_be_bytes());)*
+        buf
+    }};
+}
+
+/// 恒等変換の行列
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+/// 最初のフレームを静止画のアイテムとし、すべてのフレームをトラックにしたAVIF
+struct Muxer<'a> {
+    width: u32,
+    height: u32,
+    /// フレームの表示時間(ミリ秒)
+    durations: &'a [u32],
+    color: &'a Track,
+    alpha: Option<&'a Track>,
+}
+
+impl Muxer<'_> {
+    fn write(&self) -> Result<Vec<u8>> {
+        let ftyp = bx(b"ftyp", &[b"avis", &be!(0u32), b"avifavismsf1iso8mif1miaf"]);
+        // オフセットの値によってボックスの大きさは変わらないので、一度組み立てて`mdat`の位置を求める
+        let header_len = |mdat: u32| ftyp.len() + self.meta(mdat).len() + self.moov(mdat).len();
+        let mdat_data = u32::try_from(header_len(0) + 8)?;
+        let mut buf = ftyp.clone();
+        buf.extend(self.meta(mdat_data));
+        buf.extend(self.moov(mdat_data));
+        let tracks = std::iter::once(self.color).chain(self.alpha);
+        let samples: Vec<&[u8]> = tracks
+            .flat_map(|t| t.samples.iter().map(Vec::as_slice))
+            .collect();
+        buf.extend(bx(b"mdat", &samples));
+        Ok(buf)
+    }
+
+    fn total_duration(&self) -> u32 {
+        self.durations.iter().sum()
+    }
+
+    fn track_len(track: &Track) -> u32 {
+        track.samples.iter().map(|s| s.len() as u32).sum()
+    }
+
+    /// 最初のフレームを指す静止画のアイテム。トラックを読まないデコーダはこれを表示する
+    fn meta(&self, mdat: u32) -> Vec<u8> {
+        let alpha_offset = mdat + Self::track_len(self.color);
+        let hdlr = hdlr(b"pict");
+        let pitm = full(b"pitm", 0, 0, &[&be!(1u16)]);
+        let mut iloc = be!(0x4400u16, 1u16 + self.alpha.is_some() as u16);
+        iloc.extend(be!(
+            1u16,
+            0u16,
+            1u16,
+            mdat,
+            self.color.samples[0].len() as u32
+        ));
+        let mut iinf = be!(1u16 + self.alpha.is_some() as u16);
+        iinf.extend(full(b"infe", 2, 0, &[&be!(1u16, 0u16), b"av01\0"]));
+        let ispe = full(b"ispe", 0, 0, &[&be!(self.width, self.height)]);
+        let pixi = full(b"pixi", 0, 0, &[&[3, 8, 8, 8]]);
+        let mut ipco = [ispe, pixi, bx(b"av1C", &[&self.color.config])].concat();
+        // ispe, pixi, av1Cの順。av1Cのみ必須
+        let mut ipma = be!(
+            1u32 + self.alpha.is_some() as u32,
+            1u16,
+            3u8,
+            1u8,
+            2u8,
+            0x83u8
+        );
+        let mut iref = vec![];
+        if let Some(alpha) = self.alpha {
+            iloc.extend(be!(
+                2u16,
+                0u16,
+                1u16,
+                alpha_offset,
+                alpha.samples[0].len() as u32
+            ));
+            iinf.extend(full(b"infe", 2, 0, &[&be!(2u16, 0u16), b"av01\0"]));
+            iref = full(b"iref", 0, 0, &[&bx(b"auxl", &[&be!(2u16, 1u16, 1u16)])]);
+            ipco.extend(full(b"pixi", 0, 0, &[&[1, 8]]));
+            ipco.extend(bx(b"av1C", &[&alpha.config]));
+            ipco.extend(full(b"auxC", 0, 0, &[ALPHA_URN]));
+            ipma.extend(be!(2u16, 4u8, 1u8, 4u8, 0x85u8, 6u8));
+        }
+        full(
+            b"meta",
+            0,
+            0,
+            &[
+                &hdlr,
+                &pitm,
+                &full(b"iloc", 0, 0, &[&iloc]),
+                &full(b"iinf", 0, 0, &[&iinf]),
+                &iref,
+                &bx(
+                    b"iprp",
+                    &[&bx(b"ipco", &[&ipco]), &full(b"ipma", 0, 0, &[&ipma])],
+                ),
+            ],
+        )
+    }
+
+    fn moov(&self, mdat: u32) -> Vec<u8> {
+        let duration = self.total_duration();
+        let mut mvhd = be!(0u32, 0u32, TIMESCALE, duration, 0x10000u32, 0x100u16, 0u16);
+        mvhd.extend([0; 8]);
+        MATRIX.iter().for_each(|v| mvhd.extend(v.to_be_bytes()));
+        mvhd.extend([0; 24]);
+        mvhd.extend(be!(1u32 + self.alpha.is_some() as u32 + 1));
+        let mut traks = self.trak(1, self.color, mdat, None);
+        if let Some(alpha) = self.alpha {
+            let offset = mdat + Self::track_len(self.color);
+            traks.extend(self.trak(2, alpha, offset, Some(1)));
+        }
+        bx(b"moov", &[&full(b"mvhd", 0, 0, &[&mvhd]), &traks])
+    }
+
+    /// `aux_of`がある場合はそのトラックの透明度のトラックにする
+    fn trak(&self, id: u32, track: &Track, offset: u32, aux_of: Option<u32>) -> Vec<u8> {
+        let duration = self.total_duration();
+        let mut tkhd = be!(0u32, 0u32, id, 0u32, duration, 0u64, 0u16, 0u16, 0u16, 0u16);
+        MATRIX.iter().for_each(|v| tkhd.extend(v.to_be_bytes()));
+        tkhd.extend(be!(self.width << 16, self.height << 16));
+        let tref = match aux_of {
+            Some(color) => bx(b"tref", &[&bx(b"auxl", &[&be!(color)])]),
+            None => vec![],
+        };
+        // 最後まで再生したら繰り返す
+        let elst = full(b"elst", 0, 1, &[&be!(1u32, duration, 0u32, 1u16, 0u16)]);
+        let mdhd = full(
+            b"mdhd",
+            0,
+            0,
+            &[&be!(0u32, 0u32, TIMESCALE, duration, 0x55c4u16, 0u16)],
+        );
+        let handler = match aux_of {
+            Some(_) => b"auxv",
+            None => b"pict",
+        };
+        let vmhd = full(b"vmhd", 0, 1, &[&[0; 8]]);
+        let dref = full(b"dref", 0, 0, &[&be!(1u32), &full(b"url ", 0, 1, &[])]);
+        let minf = bx(
+            b"minf",
+            &[
+                &vmhd,
+                &bx(b"dinf", &[&dref]),
+                &self.stbl(track, offset, aux_of.is_some()),
+            ],
+        );
+        let mdia = bx(b"mdia", &[&mdhd, &hdlr(handler), &minf]);
+        bx(
+            b"trak",
+            &[
+                &full(b"tkhd", 0, 1, &[&tkhd]),
+                &tref,
+                &bx(b"edts", &[&elst]),
+                &mdia,
+            ],
+        )
+    }
+
+    fn stbl(&self, track: &Track, offset: u32, alpha: bool) -> Vec<u8> {
+        let mut entry = vec![0; 6];
+        entry.extend(be!(1u16, 0u16, 0u16, 0u32, 0u32, 0u32));
+        entry.extend(be!(
+            self.width as u16,
+            self.height as u16,
+            0x480000u32,
+            0x480000u32
+        ));
+        entry.extend(be!(0u32, 1u16));
+        let mut compressor = [0u8; 32];
+        compressor[0] = 10;
+        compressor[1..11].copy_from_slice(b"AOM Coding");
+        entry.extend(compressor);
+        entry.extend(be!(0x18u16, -1i16));
+        entry.extend(bx(b"av1C", &[&track.config]));
+        // インター予測を使い、参照するフレームの数は制限しない
+        entry.extend(full(b"ccst", 0, 0, &[&be!(0x7c000000u32)]));
+        if alpha {
+            entry.extend(full(b"auxi", 0, 0, &[ALPHA_URN]));
+        }
+        let stsd = full(b"stsd", 0, 0, &[&be!(1u32), &bx(b"av01", &[&entry])]);
+
+        let mut runs: Vec<(u32, u32)> = vec![];
+        for &d in self.durations {
+            match runs.last_mut() {
+                Some((count, delta)) if *delta == d => *count += 1,
+                _ => runs.push((1, d)),
+            }
+        }
+        let mut stts = be!(runs.len() as u32);
+        runs.iter()
+            .for_each(|(count, delta)| stts.extend(be!(count, delta)));
+        let mut stss = be!(track.sync.len() as u32);
+        track.sync.iter().for_each(|n| stss.extend(be!(n)));
+        let mut stsz = be!(0u32, track.samples.len() as u32);
+        track
+            .samples
+            .iter()
+            .for_each(|s| stsz.extend(be!(s.len() as u32)));
+        // すべてのサンプルを1つのチャンクにまとめる
+        let stsc = be!(1u32, 1u32, track.samples.len() as u32, 1u32);
+        bx(
+            b"stbl",
+            &[
+                &stsd,
+                &full(b"stts", 0, 0, &[&stts]),
+                &full(b"stss", 0, 0, &[&stss]),
+                &full(b"stsc", 0, 0, &[&stsc]),
+                &full(b"stsz", 0, 0, &[&stsz]),
+                &full(b"stco", 0, 0, &[&be!(1u32, offset)]),
+            ],
+        )
+    }
+}
+
+fn hdlr(handler: &[u8; 4]) -> Vec<u8> {
+    full(b"hdlr", 0, 0, &[&be!(0u32), handler, &[0; 12], b"\0"])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Delay, Rgba, RgbaImage};
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    /// `kind`のボックスを子孫まで探す
+    fn find<'a>(buf: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+        let (kind, rest) = path.split_first()?;
+        let mut pos = 0;
+        while pos + 8 <= buf.len() {
+            let size = u32::from_be_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+            if &buf[pos + 4..pos + 8] == *kind {
+                let body = &buf[pos + 8..pos + size];
+                if rest.is_empty() {
+                    return Some(body);
+                }
+                // 子を持つFullBoxはバージョンとフラグを飛ばす
+                let skip = if *kind == b"meta" { 4 } else { 0 };
+                return find(&body[skip..], rest);
+            }
+            pos += size;
+        }
+        None
+    }
+
+    fn frames(count: u32, transparent: bool) -> Vec<Result<Frame>> {
+        (0..count)
+            .map(|i| {
+                let alpha = if transparent && i % 2 == 0 { 0 } else { 255 };
+                let img = RgbaImage::from_pixel(32, 24, Rgba([(i * 40) as u8, 100, 200, alpha]));
+                Ok(Frame::from_parts(
+                    img,
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                ))
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case(false, 1)]
+    #[case(true, 2)]
+    fn encode_avif_anim_test(#[case] transparent: bool, #[case] tracks: usize) -> Result<()> {
+        let avif = encode_avif_anim(32, 24, frames(5, transparent), 60.0)?;
+        assert_eq!(&avif[4..12], b"ftypavis");
+
+        let moov = find(&avif, &[b"moov"]).unwrap();
+        let mvhd = find(moov, &[b"mvhd"]).unwrap();
+        // timescaleとduration
+        assert_eq!(&mvhd[12..20], &be!(1000u32, 500u32));
+        let stsz = find(moov, &[b"trak", b"mdia", b"minf", b"stbl", b"stsz"]).unwrap();
+        assert_eq!(&stsz[8..12], &be!(5u32));
+        let ispe = find(&avif, &[b"meta", b"iprp", b"ipco", b"ispe"]).unwrap();
+        assert_eq!(&ispe[4..], &be!(32u32, 24u32));
+        assert_eq!(find(&avif, &[b"meta", b"iref"]).is_some(), tracks == 2);
+
+        // stcoとilocの位置がmdatの中のサンプルを指す
+        let stco = find(moov, &[b"trak", b"mdia", b"minf", b"stbl", b"stco"]).unwrap();
+        let offset = u32::from_be_bytes(stco[8..12].try_into().unwrap()) as usize;
+        let mdat = find(&avif, &[b"mdat"]).unwrap();
+        // 最初のサンプルはSequence Header OBUから始まる
+        assert_eq!(mdat[0], 0x0a);
+        assert_eq!(offset, mdat.as_ptr() as usize - avif.as_ptr() as usize);
+        let iloc = find(&avif, &[b"meta", b"iloc"]).unwrap();
+        assert_eq!(&iloc[14..18], &stco[8..12]);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_avif_anim_empty_test() {
+        assert!(encode_avif_anim(32, 24, vec![], 60.0).is_err());
+    }
+
+    #[rstest]
+    #[case(100.0, 0)]
+    #[case(0.0, 255)]
+    fn quality_to_quantizer_test(#[case] quality: f32, #[case] expected: usize) {
+        assert_eq!(quality_to_quantizer(quality), expected);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use reqwest::Url;
+
+use crate::client::UrlFilter;
+
+/// 取得を拒否するURLのパターン
+#[derive(Debug)]
+enum Pattern {
+    /// ホスト名に対するglob
+    Host(String),
+    /// URL全体に対するglob
+    Url(String),
+    /// URL全体に対する正規表現
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// 1行をパースする。`regex:`で始まる場合は正規表現、`/`を含む場合はURL、それ以外はホスト名のglobとして扱う
+    fn parse(line: &str) -> Result<Self> {
+        if let Some(re) = line.strip_prefix("regex:") {
+            return Ok(Pattern::Regex(Regex::new(re.trim())?));
+        }
+        if line.contains('/') {
+            Ok(Pattern::Url(line.to_string()))
+        } else {
+            Ok(Pattern::Host(line.to_lowercase()))
+        }
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        match self {
+            Pattern::Host(pattern) => url
+                .host_str()
+                .is_some_and(|host| glob_match(pattern, &host.to_lowercase())),
+            Pattern::Url(pattern) => glob_match(pattern, url.as_str()),
+            Pattern::Regex(re) => re.is_match(url.as_str()),
+        }
+    }
+}
+
+/// `*`は任意の文字列、`?`は任意の1文字に一致する
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // 直前の`*`の位置と、そこから一致させ始めたtextの位置
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn parse_patterns(txt: &str) -> Result<Vec<Pattern>> {
+    txt.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| Pattern::parse(line).with_context(|| format!("invalid pattern: {}", line)))
+        .collect()
+}
+
+/// ファイルから読み込んだブロックリスト。ファイルが更新されると読み込み直す
+#[derive(Debug)]
+pub(crate) struct Blocklist {
+    path: PathBuf,
+    patterns: RwLock<Vec<Pattern>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Blocklist {
+    pub(crate) fn load(path: PathBuf) -> Result<Self> {
+        let blocklist = Self {
+            path,
+            patterns: RwLock::new(vec![]),
+            modified: Mutex::new(None),
+        };
+        blocklist.reload_if_changed()?;
+        Ok(blocklist)
+    }
+
+    pub(crate) fn is_blocked(&self, url: &Url) -> bool {
+        let patterns = self.patterns.read().unwrap();
+        patterns.iter().any(|p| p.matches(url))
+    }
+
+    /// 更新日時が変わっていれば読み込み直す。読み込み直した場合は`true`を返す
+    /// ## Note
+    /// パースに失敗した場合は以前のパターンを使い続ける
+    fn reload_if_changed(&self) -> Result<bool> {
+        let modified = std::fs::metadata(&self.path)?.modified()?;
+        let mut last = self.modified.lock().unwrap();
+        if *last == Some(modified) {
+            return Ok(false);
+        }
+
+        let patterns = parse_patterns(&std::fs::read_to_string(&self.path)?)?;
+        tracing::info!(count = patterns.len(), path = ?self.path, "blocklist loaded");
+        *self.patterns.write().unwrap() = patterns;
+        *last = Some(modified);
+        Ok(true)
+    }
+
+    /// `interval`ごとにファイルの更新を確認する
+    pub(crate) fn watch(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.reload_if_changed() {
+                    tracing::warn!(path = ?self.path, "failed to reload blocklist: {:#}", e);
+                }
+            }
+        });
+    }
+}
+
+impl UrlFilter for Blocklist {
+    fn is_blocked(&self, url: &Url) -> bool {
+        Blocklist::is_blocked(self, url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("example.com", "example.com", true)]
+    #[case("*.example.com", "media.example.com", true)]
+    #[case("*.example.com", "example.com", false)]
+    #[case("exa?ple.com", "example.com", true)]
+    #[case("*bad*", "very-bad-host.net", true)]
+    #[case("example.com", "example.co", false)]
+    fn glob_match_test(#[case] pattern: &str, #[case] text: &str, #[case] expected: bool) {
+        assert_eq!(glob_match(pattern, text), expected);
+    }
+
+    #[rstest]
+    #[case("https://EVIL.example/a.png", true)]
+    #[case("https://cdn.evil.example/a.png", true)]
+    #[case("https://good.example/illegal/a.png", true)]
+    #[case("https://good.example/a.gif", true)]
+    #[case("https://good.example/a.png", false)]
+    fn patterns_test(#[case] url: &str, #[case] expected: bool) {
+        let patterns = parse_patterns(
+            "# comment\nevil.example\n*.evil.example\nhttps://good.example/illegal/*\n\nregex:\\.gif$\n",
+        )
+        .unwrap();
+        let url = Url::parse(url).unwrap();
+        assert_eq!(patterns.iter().any(|p| p.matches(&url)), expected);
+    }
+
+    #[test]
+    fn invalid_regex() {
+        assert!(parse_patterns("regex:(").is_err());
+    }
+
+    #[test]
+    fn reload_on_change() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.txt", std::process::id()));
+        std::fs::write(&path, "a.example\n")?;
+        let blocklist = Blocklist::load(path.clone())?;
+        let url = Url::parse("https://b.example/")?;
+        assert!(!blocklist.is_blocked(&url));
+
+        std::fs::write(&path, "a.example\nb.example\n")?;
+        // 更新日時の精度が粗いファイルシステムでも変わるようにする
+        let later = SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(later)?;
+        assert!(blocklist.reload_if_changed()?);
+        assert!(blocklist.is_blocked(&url));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
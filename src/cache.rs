@@ -0,0 +1,319 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{cluster::fnv1a, convert::Converted};
+
+/// 変換済みの画像をメモリ上に保持するキャッシュ。容量を超えた場合は古いものから削除する
+#[derive(Debug)]
+pub(crate) struct MemoryCache {
+    /// 保持する画像の合計バイト数の上限。0の場合はキャッシュしない
+    capacity: usize,
+    /// 追加してから期限切れになるまでの時間。`None`の場合は期限切れにしない
+    ttl: Option<Duration>,
+    inner: Mutex<CacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// キャッシュの統計情報
+#[derive(Debug, Serialize)]
+pub(crate) struct CacheStats {
+    entries: usize,
+    size: usize,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+    /// `get`のうちキャッシュにあった割合。まだ`get`していない場合は`None`
+    hit_rate: Option<f64>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    converted: Converted,
+    inserted: Instant,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<String, Entry>,
+    /// 追加された順番
+    order: VecDeque<String>,
+    size: usize,
+}
+
+impl MemoryCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ttl: None,
+            inner: Mutex::new(CacheInner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// `ttl`を過ぎたものは`get`で返さないようにする。期限切れのものも容量を超えるまでは保持する
+    pub(crate) fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<Converted> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &str, now: Instant) -> Option<Converted> {
+        let inner = self.inner.lock().unwrap();
+        let converted = inner
+            .entries
+            .get(key)
+            .filter(|entry| self.is_fresh(entry, now))
+            .map(|entry| entry.converted.clone());
+        match converted {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        converted
+    }
+
+    /// 期限切れでないものがあるか。ヒット率の統計には数えない
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .get(key)
+            .is_some_and(|entry| self.is_fresh(entry, Instant::now()))
+    }
+
+    fn is_fresh(&self, entry: &Entry, now: Instant) -> bool {
+        self.ttl
+            .is_none_or(|ttl| now.saturating_duration_since(entry.inserted) <= ttl)
+    }
+
+    /// 期限切れから`max_stale`以内であれば期限切れのものも返す。追加してからの時間も返す
+    pub(crate) fn get_stale(
+        &self,
+        key: &str,
+        max_stale: Duration,
+    ) -> Option<(Converted, Duration)> {
+        self.get_stale_at(key, max_stale, Instant::now())
+    }
+
+    fn get_stale_at(
+        &self,
+        key: &str,
+        max_stale: Duration,
+        now: Instant,
+    ) -> Option<(Converted, Duration)> {
+        let inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        let age = now.saturating_duration_since(entry.inserted);
+        if self.ttl.is_some_and(|ttl| age > ttl + max_stale) {
+            return None;
+        }
+        Some((entry.converted.clone(), age))
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheStats {
+            entries: inner.entries.len(),
+            size: inner.size,
+            capacity: self.capacity,
+            hits,
+            misses,
+            hit_rate: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+        }
+    }
+
+    /// キーが`prefix`で始まるものをすべて削除し、削除した数を返す
+    #[cfg(feature = "grpc")]
+    pub(crate) fn purge(&self, prefix: &str) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<String> = inner
+            .entries
+            .keys()
+            .filter(|k| k.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in keys.iter() {
+            if let Some(old) = inner.entries.remove(key) {
+                inner.size -= old.converted.body.len();
+            }
+        }
+        inner.order.retain(|k| !k.starts_with(prefix));
+        keys.len()
+    }
+
+    /// キャッシュに追加する。容量より大きい画像は追加しない
+    pub(crate) fn insert(&self, key: String, value: Converted) {
+        self.insert_at(key, value, Instant::now());
+    }
+
+    fn insert_at(&self, key: String, value: Converted, now: Instant) {
+        let size = value.body.len();
+        if size > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old) = inner.entries.remove(&key) {
+            inner.size -= old.converted.body.len();
+            inner.order.retain(|k| k != &key);
+        }
+        while inner.size + size > self.capacity {
+            let Some(oldest) = inner.order.pop_front() else {
+                break;
+            };
+            if let Some(old) = inner.entries.remove(&oldest) {
+                inner.size -= old.converted.body.len();
+            }
+        }
+
+        inner.size += size;
+        inner.order.push_back(key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                converted: value,
+                inserted: now,
+            },
+        );
+    }
+}
+
+/// CDNで取得元のホストごと、もしくは画像ごとにパージするためのキー。
+/// 画像のキーは取得元URLのFNV-1a(64bit)を16進数にしたもの
+pub(crate) fn surrogate_keys(url: &Url) -> Vec<String> {
+    let mut keys = vec![];
+    if let Some(host) = url.host_str() {
+        keys.push(format!("host-{}", host));
+    }
+    keys.push(format!("url-{:016x}", fnv1a(url.as_str().as_bytes())));
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Bytes;
+    use pretty_assertions::assert_eq;
+
+    fn converted(size: usize) -> Converted {
+        Converted {
+            content_type: "image/webp",
+            body: Bytes::from(vec![0; size]),
+            degraded: vec![],
+            input_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn evict_oldest_entry() {
+        let cache = MemoryCache::new(10);
+        cache.insert("a".to_string(), converted(4));
+        cache.insert("b".to_string(), converted(4));
+        cache.insert("c".to_string(), converted(4));
+
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(converted(4)));
+        assert_eq!(cache.get("c"), Some(converted(4)));
+    }
+
+    #[test]
+    fn skip_entry_larger_than_capacity() {
+        let cache = MemoryCache::new(10);
+        cache.insert("a".to_string(), converted(4));
+        cache.insert("b".to_string(), converted(11));
+
+        assert_eq!(cache.get("a"), Some(converted(4)));
+        assert_eq!(cache.get("b"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "grpc")]
+    fn purge_by_prefix() {
+        let cache = MemoryCache::new(10);
+        cache.insert("https://a.example/1|Emoji".to_string(), converted(1));
+        cache.insert("https://a.example/1|Avatar".to_string(), converted(1));
+        cache.insert("https://a.example/2|Emoji".to_string(), converted(1));
+
+        assert_eq!(cache.purge("https://a.example/1|"), 2);
+        assert_eq!(cache.get("https://a.example/1|Emoji"), None);
+        assert_eq!(cache.get("https://a.example/2|Emoji"), Some(converted(1)));
+    }
+
+    #[test]
+    fn surrogate_keys_test() {
+        let url = Url::parse("https://a.example/emoji.png").unwrap();
+        let keys = surrogate_keys(&url);
+        assert_eq!(keys[0], "host-a.example");
+        assert!(keys[1].starts_with("url-"));
+        assert_eq!(keys[1].len(), 4 + 16);
+
+        let other = Url::parse("https://a.example/other.png").unwrap();
+        assert_ne!(surrogate_keys(&other)[1], keys[1]);
+    }
+
+    #[test]
+    fn stats_hit_rate() {
+        let cache = MemoryCache::new(10);
+        assert_eq!(cache.stats().hit_rate, None);
+
+        cache.insert("a".to_string(), converted(4));
+        cache.get("a");
+        cache.get("a");
+        cache.get("b");
+        cache.get("c");
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.size), (1, 4));
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!(stats.hit_rate, Some(0.5));
+
+        assert!(cache.contains("a"));
+        assert!(!cache.contains("b"));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+    }
+
+    #[test]
+    fn expire_after_ttl() {
+        let cache = MemoryCache::new(10).with_ttl(Some(Duration::from_secs(60)));
+        let now = Instant::now();
+        cache.insert_at("a".to_string(), converted(4), now);
+
+        let at = |secs| now + Duration::from_secs(secs);
+        assert_eq!(cache.get_at("a", at(60)), Some(converted(4)));
+        assert_eq!(cache.get_at("a", at(61)), None);
+
+        let stale = |secs| cache.get_stale_at("a", Duration::from_secs(30), at(secs));
+        assert_eq!(stale(61), Some((converted(4), Duration::from_secs(61))));
+        assert_eq!(stale(90), Some((converted(4), Duration::from_secs(90))));
+        assert_eq!(stale(91), None);
+    }
+
+    #[test]
+    fn disabled_cache() {
+        let cache = MemoryCache::new(0);
+        cache.insert("a".to_string(), converted(1));
+
+        assert!(!cache.is_enabled());
+        assert_eq!(cache.get("a"), None);
+    }
+}
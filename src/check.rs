@@ -0,0 +1,210 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    apikey::ApiKeys,
+    args::Args,
+    blocklist::Blocklist,
+    client::{get_client, ClientConfig},
+    cluster::Cluster,
+    sigv4::OriginCredentials,
+    tenant::Tenants,
+    webhook::PrefetchWebhook,
+    webp_options,
+};
+
+/// 疎通を確かめる際のタイムアウト
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 設定を検証して結果を標準出力に書く。すべて問題なければ`true`を返す
+pub(crate) async fn run(args: &Args) -> bool {
+    let mut ok = true;
+
+    let (_, webp_by_type) = webp_options(args);
+    for (convert_type, options) in webp_by_type {
+        ok &= report(
+            &format!("webp config ({})", convert_type.name()),
+            options.config().map(|_| ()),
+        );
+    }
+
+    ok &= report(
+        "http client",
+        get_client(&ClientConfig {
+            proxy_url: args.http_proxy.clone(),
+            pool_idle_per_host: args.upstream_pool_idle_per_host,
+            pool_idle_timeout: args.upstream_pool_idle_timeout.map(Duration::from_secs),
+            tcp_nodelay: args.upstream_tcp_nodelay,
+            tcp_keepalive: args.upstream_tcp_keepalive.map(Duration::from_secs),
+            resolver: None,
+            redirect_filter: None,
+        })
+        .map(|_| ()),
+    );
+
+    if let Some(path) = &args.audit_log {
+        ok &= report("audit log", check_writable_dir(path));
+    }
+
+    for root in &args.file_root {
+        ok &= report(&format!("file root {}", root.display()), check_dir(root));
+    }
+
+    if let Some(path) = &args.blocklist {
+        ok &= report("blocklist", Blocklist::load(path.clone()).map(|_| ()));
+    }
+
+    if let Some(path) = &args.tenants {
+        ok &= report("tenants", Tenants::load(path.clone()).map(|_| ()));
+    }
+
+    if let Some(path) = &args.api_keys {
+        ok &= report("api keys", ApiKeys::load(path.clone()).map(|_| ()));
+    }
+
+    if let Some(path) = &args.origin_credentials {
+        ok &= report(
+            "origin credentials",
+            OriginCredentials::load(path.clone()).map(|_| ()),
+        );
+    }
+
+    if let Some(endpoint) = &args.prefetch_webhook {
+        ok &= report(
+            "prefetch webhook",
+            PrefetchWebhook::new(
+                endpoint.clone(),
+                Duration::from_millis(args.prefetch_webhook_timeout),
+            )
+            .map(|_| ()),
+        );
+    }
+
+    if let Some(this) = &args.cluster_self {
+        ok &= report(
+            "cluster",
+            args.cluster_secret
+                .clone()
+                .context("--cluster-secret is required in cluster mode")
+                .and_then(|secret| Cluster::new(args.cluster_peers.clone(), this.clone(), secret))
+                .map(|_| ()),
+        );
+        for peer in args.cluster_peers.iter().filter(|peer| *peer != this) {
+            ok &= report(&format!("cluster peer {}", peer), check_peer(peer).await);
+        }
+    }
+
+    for host in &args.warm_hosts {
+        ok &= report(&format!("warm host {}", host), check_resolve(host).await);
+    }
+
+    #[cfg(feature = "redis")]
+    if let Some(url) = &args.redis_url {
+        ok &= report("redis", check_redis(url).await);
+    }
+
+    #[cfg(feature = "http3")]
+    if args.http3_port.is_some() {
+        ok &= report(
+            "http3 certificate",
+            match (&args.http3_cert, &args.http3_key) {
+                (Some(cert), Some(key)) => crate::http3::server_config(cert, key).map(|_| ()),
+                _ => Err(anyhow::anyhow!(
+                    "--http3-cert and --http3-key are required for HTTP/3"
+                )),
+            },
+        );
+    }
+
+    ok &= report("fonts", check_fonts());
+    ok &= report("encoder", crate::webp::self_test());
+
+    ok
+}
+
+fn report(name: &str, result: Result<()>) -> bool {
+    match result {
+        Ok(()) => {
+            println!("ok  {}", name);
+            true
+        }
+        Err(e) => {
+            println!("NG  {}: {:#}", name, e);
+            false
+        }
+    }
+}
+
+async fn check_resolve(host: &str) -> Result<()> {
+    let addrs = tokio::time::timeout(TIMEOUT, tokio::net::lookup_host((host, 0)))
+        .await
+        .context("timed out")??;
+    match addrs.count() {
+        0 => Err(anyhow::anyhow!("no address")),
+        _ => Ok(()),
+    }
+}
+
+fn check_dir(dir: &Path) -> Result<()> {
+    let metadata = std::fs::metadata(dir).with_context(|| format!("{}", dir.display()))?;
+    if !metadata.is_dir() {
+        return Err(anyhow::anyhow!("{} is not a directory", dir.display()));
+    }
+    Ok(())
+}
+
+/// ファイルを書き込むディレクトリが存在するか
+fn check_writable_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    check_dir(dir)?;
+    if std::fs::metadata(dir)?.permissions().readonly() {
+        return Err(anyhow::anyhow!("{} is read-only", dir.display()));
+    }
+    Ok(())
+}
+
+async fn check_peer(peer: &reqwest::Url) -> Result<()> {
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()?
+        .get(peer.join("health")?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(feature = "redis")]
+pub(crate) async fn check_redis(url: &str) -> Result<()> {
+    let client = redis::Client::open(url)?;
+    let mut conn = tokio::time::timeout(TIMEOUT, client.get_multiplexed_async_connection())
+        .await
+        .context("timed out")??;
+    redis::cmd("PING").query_async::<()>(&mut conn).await?;
+    Ok(())
+}
+
+/// SVG内のテキストを描画するためのフォントがあるか
+pub(crate) fn check_fonts() -> Result<()> {
+    if crate::processor::fontdb().is_empty() {
+        return Err(anyhow::anyhow!("no system fonts found"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_writable_dir_test() {
+        let dir = std::env::temp_dir();
+        assert!(check_writable_dir(&dir.join("audit.log")).is_ok());
+        assert!(check_writable_dir(Path::new("audit.log")).is_ok());
+        assert!(check_writable_dir(&dir.join("not-exist").join("audit.log")).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use axum::http::header;
+use reqwest::{Client, Url};
+
+use crate::{
+    apikey::{constant_time_eq, hex, hmac_sha1},
+    convert::Converted,
+    handler::ProxyConfig,
+    processor::Degradation,
+};
+
+/// 1ノードあたりのリング上の仮想ノード数
+const VIRTUAL_NODES: usize = 64;
+/// 転送の署名を受け付ける時刻のずれ
+const MAX_SKEW: Duration = Duration::from_secs(300);
+
+/// 転送されたリクエストであることを示すヘッダー。受け取ったノードは再度転送しない。
+/// 値は`<UNIXTIME>.<署名>`で、署名は時刻とパス、クエリに対するクラスタのシークレットを鍵にしたHMAC-SHA1の16進数
+pub(crate) const FORWARDED_HEADER: &str = "x-misskey-webp-proxy-forwarded";
+/// 転送されたリクエストへの応答で、取得元の画像のバイト数を伝えるヘッダー
+pub(crate) const INPUT_BYTES_HEADER: &str = "x-misskey-webp-proxy-input-bytes";
+
+/// コンシステントハッシュでキャッシュのキーを担当するノードを決め、変換をそのノードに転送する
+pub(crate) struct Cluster {
+    client: Client,
+    peers: Vec<Url>,
+    /// 自分自身の`peers`での位置
+    this: usize,
+    /// ハッシュ値で並べた仮想ノードと、それに対応する`peers`の位置
+    ring: Vec<(u64, usize)>,
+    /// 転送を署名するノード間で共有するシークレット
+    secret: String,
+}
+
+/// シークレットはログに出さない
+impl std::fmt::Debug for Cluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cluster")
+            .field("peers", &self.peers)
+            .field("this", &self.this)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Cluster {
+    /// `this`が`peers`に含まれていない場合は追加する
+    pub(crate) fn new(mut peers: Vec<Url>, this: Url, secret: String) -> Result<Self> {
+        anyhow::ensure!(!secret.is_empty(), "Cluster secret is empty");
+        let this = match peers.iter().position(|peer| peer == &this) {
+            Some(index) => index,
+            None => {
+                peers.push(this);
+                peers.len() - 1
+            }
+        };
+
+        let mut ring: Vec<(u64, usize)> = peers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, peer)| {
+                (0..VIRTUAL_NODES)
+                    .map(move |i| (fnv1a(format!("{}#{}", peer, i).as_bytes()), index))
+            })
+            .collect();
+        ring.sort_unstable();
+
+        // ピア同士の通信には上流向けのプロキシを使わない
+        let client = Client::builder().no_proxy().build()?;
+        Ok(Self {
+            client,
+            peers,
+            this,
+            ring,
+            secret,
+        })
+    }
+
+    /// `path_and_query`への転送に付ける`FORWARDED_HEADER`の値
+    fn sign(&self, path_and_query: &str, now: SystemTime) -> String {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let message = format!("{}\n{}", timestamp, path_and_query);
+        format!(
+            "{}.{}",
+            timestamp,
+            hex(&hmac_sha1(self.secret.as_bytes(), message.as_bytes()))
+        )
+    }
+
+    /// `FORWARDED_HEADER`の値が他のノードの署名で、時刻が`MAX_SKEW`以内か
+    pub(crate) fn verify(&self, value: &str, path_and_query: &str, now: SystemTime) -> bool {
+        let Some((timestamp, _)) = value.split_once('.') else {
+            return false;
+        };
+        let Ok(timestamp) = timestamp.parse::<u64>() else {
+            return false;
+        };
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now.abs_diff(timestamp) > MAX_SKEW.as_secs() {
+            return false;
+        }
+        let expected = self.sign(path_and_query, UNIX_EPOCH + Duration::from_secs(timestamp));
+        constant_time_eq(expected.as_bytes(), value.to_ascii_lowercase().as_bytes())
+    }
+
+    /// `key`を担当するノードを返す。自分自身が担当する場合は`None`
+    pub(crate) fn owner(&self, key: &str) -> Option<&Url> {
+        let hash = fnv1a(key.as_bytes());
+        let pos = self.ring.partition_point(|(h, _)| *h < hash);
+        let (_, index) = self.ring.get(pos).unwrap_or(&self.ring[0]);
+        if *index == self.this {
+            None
+        } else {
+            Some(&self.peers[*index])
+        }
+    }
+
+    /// 担当するノードに変換を依頼する
+    pub(crate) async fn forward(&self, peer: &Url, config: &ProxyConfig) -> Result<Converted> {
+        let mut request = self
+            .client
+            .get(peer.clone())
+            .query(&config.to_query())
+            .build()?;
+        let url = request.url();
+        let path_and_query = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let signature = self.sign(&path_and_query, SystemTime::now());
+        request
+            .headers_mut()
+            .insert(FORWARDED_HEADER, signature.parse()?);
+        let resp = self.client.execute(request).await?.error_for_status()?;
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(Converted::known_content_type)
+            .context("Peer returned unknown content type")?;
+        let degraded = resp
+            .headers()
+            .get(crate::DEGRADED_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(',')
+                    .filter_map(|d| Degradation::parse(d.trim()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let input_bytes = resp
+            .headers()
+            .get(INPUT_BYTES_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default();
+        let body = resp.bytes().await?;
+
+        Ok(Converted {
+            content_type,
+            body,
+            degraded,
+            input_bytes,
+        })
+    }
+}
+
+/// ノード間で同じ値になるように、実装に依存しないハッシュを使う
+pub(crate) fn fnv1a(buf: &[u8]) -> u64 {
+    buf.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    const SECRET: &str = "cluster-secret";
+
+    fn peers() -> Vec<Url> {
+        [
+            "http://10.0.0.1:12766/",
+            "http://10.0.0.2:12766/",
+            "http://10.0.0.3:12766/",
+        ]
+        .iter()
+        .map(|peer| Url::parse(peer).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn debug_omits_secret() {
+        let cluster = Cluster::new(peers(), peers()[0].clone(), SECRET.to_string()).unwrap();
+        assert!(!format!("{:?}", cluster).contains(SECRET));
+    }
+
+    #[test]
+    fn owner_agrees_between_nodes() {
+        let clusters: Vec<Cluster> = peers()
+            .into_iter()
+            .map(|this| Cluster::new(peers(), this, SECRET.to_string()).unwrap())
+            .collect();
+
+        for i in 0..100 {
+            let key = format!("https://example.com/{}.png|Emoji", i);
+            // 各ノードから見た担当ノード。自分が担当する場合は自分のURL
+            let owners: Vec<&Url> = clusters
+                .iter()
+                .map(|cluster| cluster.owner(&key).unwrap_or(&cluster.peers[cluster.this]))
+                .collect();
+            assert!(owners.iter().all(|owner| *owner == owners[0]));
+        }
+    }
+
+    #[test]
+    fn keys_are_spread() {
+        let cluster = Cluster::new(peers(), peers()[0].clone(), SECRET.to_string()).unwrap();
+        let local = (0..300)
+            .filter(|i| {
+                cluster
+                    .owner(&format!("https://example.com/{}.png", i))
+                    .is_none()
+            })
+            .count();
+        assert!((50..150).contains(&local), "local = {}", local);
+    }
+
+    #[test]
+    fn add_self_to_peers() {
+        let this = Url::parse("http://10.0.0.4:12766/").unwrap();
+        let cluster = Cluster::new(peers(), this.clone(), SECRET.to_string()).unwrap();
+        assert_eq!(cluster.peers.len(), 4);
+        assert_eq!(cluster.peers[cluster.this], this);
+    }
+
+    #[rstest]
+    #[case::valid(SECRET, "/?url=a", 0, true)]
+    #[case::other_secret("other-secret", "/?url=a", 0, false)]
+    #[case::other_path(SECRET, "/?url=b", 0, false)]
+    #[case::skewed(SECRET, "/?url=a", 301, false)]
+    #[case::within_skew(SECRET, "/?url=a", 299, true)]
+    fn verify_test(
+        #[case] secret: &str,
+        #[case] path_and_query: &str,
+        #[case] elapsed: u64,
+        #[case] expected: bool,
+    ) {
+        let signer = Cluster::new(peers(), peers()[0].clone(), secret.to_string()).unwrap();
+        let verifier = Cluster::new(peers(), peers()[1].clone(), SECRET.to_string()).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let value = signer.sign("/?url=a", now);
+        assert_eq!(
+            verifier.verify(&value, path_and_query, now + Duration::from_secs(elapsed)),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case("1")]
+    #[case("")]
+    #[case("1700000000")]
+    #[case("1700000000.00")]
+    fn verify_malformed_test(#[case] value: &str) {
+        let cluster = Cluster::new(peers(), peers()[0].clone(), SECRET.to_string()).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert!(!cluster.verify(value, "/", now));
+    }
+}
@@ -0,0 +1,317 @@
+use anyhow::Result;
+use axum::body::Bytes;
+use reqwest::Url;
+
+use crate::{
+    client::{decode_image, sniff, DecodeOptions, ImageExt, Limits},
+    handler::{transform, ConvertType, OutputFormat, ProxyConfig},
+    inspect::{inspect, ImageInfo},
+    processor::{DecodeResult, Degradation, PresetSizes, ResizeFilter},
+    webp::{embed_xmp, WebpOptions},
+};
+
+/// 変換済みの画像
+#[derive(Debug, Clone, PartialEq)]
+pub struct Converted {
+    pub content_type: &'static str,
+    pub body: Bytes,
+    /// 完全な品質で変換できなかった理由
+    pub degraded: Vec<Degradation>,
+    /// 取得元の画像のバイト数
+    pub input_bytes: usize,
+}
+
+impl Converted {
+    /// 出力しうる`Content-Type`であれば`'static`な文字列にする
+    pub fn known_content_type(content_type: &str) -> Option<&'static str> {
+        match content_type {
+            "image/webp" => Some("image/webp"),
+            "image/png" => Some("image/png"),
+            "image/gif" => Some("image/gif"),
+            #[cfg(feature = "avif")]
+            "image/avif" => Some("image/avif"),
+            _ => None,
+        }
+    }
+
+    /// 変換後の画像のヘッダーから大きさとフレーム数を読む
+    pub fn info(&self) -> Option<ImageInfo> {
+        let ext = match self.content_type {
+            "image/webp" => ImageExt::Webp,
+            "image/png" => ImageExt::Png,
+            "image/gif" => ImageExt::Gif,
+            _ => return None,
+        };
+        inspect(ext, &self.body)
+    }
+}
+
+/// HTTPを介さずに変換する際の設定
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    pub config: ProxyConfig,
+    pub webp: WebpOptions,
+    pub limits: Limits,
+}
+
+impl ConvertOptions {
+    /// Media Proxyのデフォルトと同じ設定で`convert_type`に変換する
+    pub fn new(convert_type: ConvertType) -> Self {
+        Self {
+            // 取得はしないのでURLは使われない
+            config: ProxyConfig::new(Url::parse("data:,").unwrap(), convert_type),
+            webp: WebpOptions::new(75.0),
+            limits: Limits::default(),
+        }
+    }
+
+    /// 最初のフレームのみの静止画にする
+    pub fn static_(mut self, is_static: bool) -> Self {
+        self.config.is_static = is_static;
+        self
+    }
+
+    /// アスペクト比を維持したまま`width`x`height`に収まるように縮小する
+    pub fn max_size(mut self, width: Option<u32>, height: Option<u32>) -> Self {
+        self.config.width = width;
+        self.config.height = height;
+        self
+    }
+
+    /// 変換の種類ごとの大きさに掛ける倍率
+    pub fn dpr(mut self, dpr: f32) -> Self {
+        self.config.dpr = dpr;
+        self
+    }
+
+    /// 変換の種類ごとの大きさを変える
+    pub fn preset_sizes(mut self, sizes: PresetSizes) -> Self {
+        self.config.sizes = sizes;
+        self
+    }
+
+    pub fn filter(mut self, filter: ResizeFilter) -> Self {
+        self.config.filter = filter;
+        self
+    }
+
+    /// WebPの圧縮率。1-100に収める
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.config.quality = Some(quality.clamp(1, 100));
+        self
+    }
+
+    /// 出力形式。指定しない場合は変換の種類に従う
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.config.format = Some(format);
+        self
+    }
+
+    /// デコードを許可する最大のピクセル数(幅x高さ)
+    pub fn max_pixels(mut self, max_pixels: u64) -> Self {
+        self.limits.max_pixels = max_pixels;
+        self
+    }
+
+    /// WebPの出力に`source`を取得元として変換の設定と一緒にXMPで埋め込む
+    pub fn provenance(mut self, source: Url) -> Self {
+        self.config.url = source;
+        self.webp.provenance = true;
+        self
+    }
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self::new(ConvertType::Original)
+    }
+}
+
+/// 画像のバイト列を、Media Proxyと同じように判定、デコード、変換、エンコードする
+pub async fn convert(bytes: &[u8], options: ConvertOptions) -> Result<Converted> {
+    let buf = bytes.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let ConvertOptions {
+            config,
+            webp,
+            limits,
+        } = options;
+        let input_bytes = buf.len();
+        let fetched = sniff(buf, ImageExt::Unknown, &limits.disabled_formats)?;
+        let decode_options = DecodeOptions {
+            first_frame_only: config.first_frame_only(),
+            min_scale: fetched
+                .info
+                .map_or(1.0, |info| config.decode_scale(info.width, info.height)),
+            svg_box: config.svg_box(),
+        };
+        let decoded = decode_image(fetched, &limits, decode_options)?;
+        let mut degraded = vec![];
+        let transformed = transform(decoded, &config, &limits, &mut degraded)?;
+        encode(transformed, &config, &webp, degraded, input_bytes)
+    })
+    .await?
+}
+
+/// 設定に従った形式でエンコードする。アニメーションのエンコードに失敗して静止画になった場合は`degraded`に追加する
+pub fn encode(
+    buf: DecodeResult,
+    config: &ProxyConfig,
+    webp: &WebpOptions,
+    degraded: Vec<Degradation>,
+    input_bytes: usize,
+) -> Result<Converted> {
+    let webp = WebpOptions {
+        quality: config.quality.map_or(webp.quality, |q| q as f32),
+        exact: config.exact,
+        ..*webp
+    };
+    let animated = buf.is_animated();
+    let (content_type, body) = match (config.format, config.convert_type) {
+        (Some(OutputFormat::Gif), _) => ("image/gif", buf.to_gif()?),
+        #[cfg(feature = "avif")]
+        (Some(OutputFormat::Avif), _) if animated => ("image/avif", buf.to_avif(webp.quality)?),
+        (Some(OutputFormat::Png), _) | (None, ConvertType::Badge) => ("image/png", buf.to_png()?),
+        _ => {
+            let mut body = buf.to_webp(&webp)?;
+            if webp.provenance {
+                body = embed_xmp(&body, provenance_xmp(config).as_bytes())?;
+            }
+            ("image/webp", body)
+        }
+    };
+
+    let mut converted = Converted {
+        content_type,
+        body: body.into(),
+        degraded,
+        input_bytes,
+    };
+    if animated && converted.info().is_some_and(|info| !info.animated) {
+        converted.degraded.push(Degradation::StaticFallback);
+    }
+    Ok(converted)
+}
+
+/// 出力に埋め込む、取得元のURLと変換の設定を記録したXMP
+pub fn provenance_xmp(config: &ProxyConfig) -> String {
+    let key = config.cache_key();
+    let params = &key[ProxyConfig::cache_key_prefix(&config.url).len()..];
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+            "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+            "<rdf:Description rdf:about=\"\"",
+            " xmlns:dc=\"http://purl.org/dc/elements/1.1/\"",
+            " xmlns:mwp=\"https://github.com/tunamaguro/misskey-webp-proxy/ns/1.0/\">",
+            "<dc:source>{}</dc:source>",
+            "<mwp:convertType>{}</mwp:convertType>",
+            "<mwp:params>{}</mwp:params>",
+            "</rdf:Description></rdf:RDF></x:xmpmeta>",
+            "<?xpacket end=\"r\"?>"
+        ),
+        escape_xml(config.url.as_str()),
+        config.convert_type.name(),
+        escape_xml(params),
+    )
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut buf = vec![];
+        image::RgbaImage::new(width, height)
+            .write_to(&mut std::io::Cursor::new(&mut buf), image::ImageFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn converted_info() {
+        let converted = Converted {
+            content_type: "image/png",
+            body: Bytes::from(png(30, 20)),
+            degraded: vec![],
+            input_bytes: 0,
+        };
+
+        let info = converted.info().unwrap();
+        assert_eq!((info.width, info.height, info.animated), (30, 20, false));
+    }
+
+    #[tokio::test]
+    async fn convert_test() {
+        let buf = png(400, 200);
+        let converted = convert(&buf, ConvertOptions::new(ConvertType::Emoji))
+            .await
+            .unwrap();
+        assert_eq!(converted.content_type, "image/webp");
+        assert_eq!(converted.input_bytes, buf.len());
+        let info = converted.info().unwrap();
+        assert_eq!((info.width, info.height), (256, 128));
+
+        let converted = convert(&buf, ConvertOptions::new(ConvertType::Badge))
+            .await
+            .unwrap();
+        assert_eq!(converted.content_type, "image/png");
+
+        assert!(convert(b"not an image", ConvertOptions::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn convert_options_test() {
+        let buf = png(400, 200);
+        let options = ConvertOptions::new(ConvertType::Emoji)
+            .preset_sizes(PresetSizes {
+                emoji_height: 64,
+                ..Default::default()
+            })
+            .filter(ResizeFilter::Lanczos3)
+            .format(OutputFormat::Png);
+        let converted = convert(&buf, options).await.unwrap();
+        assert_eq!(converted.content_type, "image/png");
+        let info = converted.info().unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+
+        let options = ConvertOptions::default().max_size(Some(100), None).dpr(2.0);
+        let info = convert(&buf, options).await.unwrap().info().unwrap();
+        assert_eq!((info.width, info.height), (100, 50));
+
+        let options = ConvertOptions::default().max_pixels(100);
+        assert!(convert(&buf, options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn provenance_test() {
+        let buf = png(400, 200);
+        let source = Url::parse("https://example.com/a.png?x=1&y=2").unwrap();
+        let options = ConvertOptions::new(ConvertType::Emoji).provenance(source);
+        let converted = convert(&buf, options).await.unwrap();
+        let info = converted.info().unwrap();
+        assert_eq!((info.width, info.height), (256, 128));
+
+        let body = String::from_utf8_lossy(&converted.body);
+        assert!(body.contains("XMP "));
+        assert!(body.contains("<dc:source>https://example.com/a.png?x=1&amp;y=2</dc:source>"));
+        assert!(body.contains("<mwp:convertType>emoji</mwp:convertType>"));
+
+        let plain = convert(&buf, ConvertOptions::new(ConvertType::Emoji))
+            .await
+            .unwrap();
+        assert!(!String::from_utf8_lossy(&plain.body).contains("XMP "));
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+/// 起動時に接続を開く際の待ち時間の上限
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 名前解決した時刻とアドレス
+type Resolved = (Instant, Vec<SocketAddr>);
+
+/// 設定したホストの名前解決の結果を保持するリゾルバ。それ以外のホストは毎回システムのリゾルバに問い合わせる
+#[derive(Debug, Clone)]
+pub struct WarmResolver {
+    hosts: Arc<HashSet<String>>,
+    /// 名前解決の結果を使い続ける時間
+    ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, Resolved>>>,
+}
+
+impl WarmResolver {
+    pub fn new(hosts: &[String], ttl: Duration) -> Self {
+        Self {
+            hosts: Arc::new(
+                hosts
+                    .iter()
+                    .map(|h| h.trim_end_matches('.').to_ascii_lowercase())
+                    .collect(),
+            ),
+            ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// すべてのホストを名前解決してキャッシュに入れ、解決できたホストの数を返す
+    pub async fn warm(&self) -> usize {
+        let mut tasks = tokio::task::JoinSet::new();
+        for host in self.hosts.iter() {
+            let resolver = self.clone();
+            let host = host.clone();
+            tasks.spawn(async move {
+                let result = resolver.lookup(&host, Instant::now()).await;
+                if let Err(e) = &result {
+                    tracing::warn!("failed to resolve {}: {}", host, e);
+                }
+                result.is_ok()
+            });
+        }
+        let mut resolved = 0;
+        while let Some(result) = tasks.join_next().await {
+            resolved += result.unwrap_or(false) as usize;
+        }
+        resolved
+    }
+
+    /// 期限内のキャッシュがあればそれを返す。問い合わせに失敗した場合は期限切れのキャッシュを返す
+    async fn lookup(&self, host: &str, now: Instant) -> std::io::Result<Vec<SocketAddr>> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if !self.hosts.contains(&host) {
+            return Ok(tokio::net::lookup_host((host.as_str(), 0)).await?.collect());
+        }
+        let cached = self.cache.lock().unwrap().get(&host).cloned();
+        if let Some((resolved_at, addrs)) = &cached {
+            if now.duration_since(*resolved_at) < self.ttl {
+                return Ok(addrs.clone());
+            }
+        }
+        let resolved = tokio::net::lookup_host((host.as_str(), 0)).await;
+        match resolved.map(|addrs| addrs.collect::<Vec<_>>()) {
+            Ok(addrs) => {
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(host, (now, addrs.clone()));
+                Ok(addrs)
+            }
+            Err(e) => match cached {
+                Some((_, addrs)) => {
+                    tracing::warn!("failed to resolve {}, using stale addresses: {}", host, e);
+                    Ok(addrs)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    pub fn hosts(&self) -> impl Iterator<Item = &str> {
+        self.hosts.iter().map(String::as_str)
+    }
+}
+
+impl Resolve for WarmResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.lookup(name.as_str(), Instant::now()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// `hosts`にHTTPSで接続し、TLSのハンドシェイクを済ませた接続をコネクションプールに残す。
+/// レスポンスのステータスは問わない
+pub async fn warm_connections<'a>(
+    client: &reqwest::Client,
+    hosts: impl Iterator<Item = &'a str>,
+) -> usize {
+    let mut tasks = tokio::task::JoinSet::new();
+    for host in hosts {
+        let request = client
+            .head(format!("https://{}/", host))
+            .timeout(CONNECT_TIMEOUT)
+            .send();
+        let host = host.to_string();
+        tasks.spawn(async move {
+            let result = request.await;
+            if let Err(e) = &result {
+                tracing::warn!("failed to connect to {}: {}", host, e);
+            }
+            result.is_ok()
+        });
+    }
+    let mut connected = 0;
+    while let Some(result) = tasks.join_next().await {
+        connected += result.unwrap_or(false) as usize;
+    }
+    connected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn warm_test() {
+        let resolver = WarmResolver::new(
+            &["localhost".to_string(), "unknown.invalid".to_string()],
+            Duration::from_secs(60),
+        );
+        assert_eq!(resolver.warm().await, 1);
+        let cache = resolver.cache.lock().unwrap().clone();
+        assert!(cache.contains_key("localhost"));
+        assert!(!cache.contains_key("unknown.invalid"));
+    }
+
+    #[tokio::test]
+    async fn lookup_test() {
+        let resolver = WarmResolver::new(&["LocalHost.".to_string()], Duration::from_secs(60));
+        let now = Instant::now();
+        let cached: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        resolver
+            .cache
+            .lock()
+            .unwrap()
+            .insert("localhost".to_string(), (now, vec![cached]));
+        // 期限内はキャッシュを使う
+        assert_eq!(
+            resolver.lookup("localhost", now).await.unwrap(),
+            vec![cached]
+        );
+        // 期限が切れたら問い合わせ直す
+        let later = now + Duration::from_secs(61);
+        let addrs = resolver.lookup("localhost", later).await.unwrap();
+        assert!(addrs.iter().all(|a| a.ip().is_loopback()));
+        assert_eq!(resolver.cache.lock().unwrap()["localhost"], (later, addrs));
+    }
+
+    #[tokio::test]
+    async fn stale_test() {
+        let resolver = WarmResolver::new(&["unknown.invalid".to_string()], Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(resolver.lookup("unknown.invalid", now).await.is_err());
+
+        let stale: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        resolver
+            .cache
+            .lock()
+            .unwrap()
+            .insert("unknown.invalid".to_string(), (now, vec![stale]));
+        let later = now + Duration::from_secs(61);
+        assert_eq!(
+            resolver.lookup("unknown.invalid", later).await.unwrap(),
+            vec![stale]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use reqwest::Url;
+
+use crate::{
+    args::Args,
+    client::{get_client, ClientConfig},
+};
+
+/// 疎通を確かめる際のタイムアウト
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// 表示するフォントのファミリーの数
+const MAX_FAMILIES: usize = 10;
+
+/// `doctor`サブコマンドの引数
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub(crate) struct DoctorArgs {
+    /// 外部への疎通を確かめるURL
+    #[arg(long, default_value = "https://example.com/")]
+    probe_url: Url,
+}
+
+/// 有効にしてビルドしたfeature
+const FEATURES: [(&str, bool); 6] = [
+    ("zune-jpeg", cfg!(feature = "zune-jpeg")),
+    ("fast-resize", cfg!(feature = "fast-resize")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("redis", cfg!(feature = "redis")),
+    ("runtime-metrics", cfg!(feature = "runtime-metrics")),
+    ("avif", cfg!(feature = "avif")),
+];
+
+/// 実行環境と実際に使われる設定を標準出力に書く。問い合わせの作成に貼り付けられるようにする
+pub(crate) async fn run(args: &Args, doctor: &DoctorArgs) {
+    section("build");
+    item("version", env!("CARGO_PKG_VERSION"));
+    item("features", features().join(", "));
+    item(
+        "os",
+        format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+    );
+
+    section("libwebp");
+    for (name, version, abi) in crate::webp::library_versions() {
+        item(name, format!("{} (ABI {})", version, abi));
+    }
+
+    section("fonts");
+    let fontdb = crate::processor::fontdb();
+    let families: BTreeSet<_> = fontdb
+        .faces()
+        .flat_map(|face| face.families.iter().map(|(name, _)| name.as_str()))
+        .collect();
+    item("faces", fontdb.len());
+    item("families", families.len());
+    if !families.is_empty() {
+        let mut shown: Vec<_> = families.iter().take(MAX_FAMILIES).copied().collect();
+        if families.len() > MAX_FAMILIES {
+            shown.push("...");
+        }
+        item("examples", shown.join(", "));
+    }
+
+    section("network");
+    let direct = reqwest::Client::builder()
+        .no_proxy()
+        .build()
+        .map_err(Into::into);
+    item("direct", probe(direct, &doctor.probe_url).await);
+    match &args.http_proxy {
+        Some(proxy) => {
+            let client = get_client(&ClientConfig {
+                proxy_url: Some(proxy.clone()),
+                ..Default::default()
+            });
+            item(
+                &format!("via {}", proxy),
+                probe(client, &doctor.probe_url).await,
+            );
+        }
+        None => item("proxy", "not configured"),
+    }
+
+    section("cache");
+    item(
+        "memory",
+        match args.cache_size {
+            0 => "disabled".to_string(),
+            size => format!("{} bytes", size),
+        },
+    );
+    item(
+        "ttl",
+        args.cache_ttl
+            .map_or_else(|| "no expiry".to_string(), |t| format!("{}s", t)),
+    );
+    item("stale-if-error", format!("{}s", args.stale_if_error));
+    #[cfg(feature = "redis")]
+    item(
+        "redis",
+        match &args.redis_url {
+            Some(url) => match crate::check::check_redis(url).await {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("NG: {:#}", e),
+            },
+            None => "not configured".to_string(),
+        },
+    );
+
+    section("limits");
+    for (name, value) in limits(args) {
+        item(name, value);
+    }
+}
+
+fn features() -> Vec<&'static str> {
+    let enabled: Vec<_> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    match enabled.is_empty() {
+        true => vec!["none"],
+        false => enabled,
+    }
+}
+
+/// `url`を取得してステータスと掛かった時間を返す
+async fn probe(client: Result<reqwest::Client>, url: &Url) -> String {
+    let started = Instant::now();
+    let result: Result<_> =
+        async { Ok(client?.get(url.clone()).timeout(TIMEOUT).send().await?) }.await;
+    match result {
+        Ok(res) => format!("{} {} in {:?}", url, res.status(), started.elapsed()),
+        Err(e) => format!("{} NG: {:#}", url, e),
+    }
+}
+
+/// 取得とデコード、出力の上限。既定値から変えていなくても書く
+fn limits(args: &Args) -> Vec<(&'static str, String)> {
+    let disabled: Vec<_> = args
+        .disable_format
+        .iter()
+        .map(|ext| format!("{:?}", ext).to_ascii_lowercase())
+        .collect();
+    vec![
+        (
+            "max-download-size",
+            format!("{} bytes", args.max_download_size),
+        ),
+        ("max-pixels", args.max_pixels.to_string()),
+        ("max-dimension", args.max_dimension.to_string()),
+        (
+            "max-output",
+            format!("{}x{}", args.max_width, args.max_height),
+        ),
+        (
+            "max-animated-output",
+            match (args.animated_max_width, args.animated_max_height) {
+                (None, None) => "unlimited".to_string(),
+                (width, height) => format!(
+                    "{}x{}",
+                    width.map_or("-".to_string(), |w| w.to_string()),
+                    height.map_or("-".to_string(), |h| h.to_string())
+                ),
+            },
+        ),
+        ("max-fps", optional(args.max_fps)),
+        (
+            "max-animation-duration",
+            optional(args.max_animation_duration.map(|d| format!("{}ms", d))),
+        ),
+        ("max-quality", args.max_quality.to_string()),
+        (
+            "origin-rate-limit",
+            optional(
+                args.origin_rate_limit
+                    .filter(|rate| *rate > 0.0)
+                    .map(|rate| format!("{}/s burst {}", rate, args.origin_rate_burst)),
+            ),
+        ),
+        (
+            "decoder-sandbox",
+            match args.decoder_sandbox {
+                true => format!(
+                    "{} MiB, {}s CPU, {} MiB output, {}s timeout",
+                    args.decoder_sandbox_memory,
+                    args.decoder_sandbox_cpu,
+                    args.decoder_sandbox_output,
+                    args.decoder_sandbox_timeout
+                ),
+                false => "disabled".to_string(),
+            },
+        ),
+        (
+            "disabled-formats",
+            match disabled.is_empty() {
+                true => "none".to_string(),
+                false => disabled.join(", "),
+            },
+        ),
+        ("max-uri-length", args.max_uri_length.to_string()),
+        ("max-query-params", args.max_query_params.to_string()),
+        ("shed-max-queued", optional(args.shed_max_queued)),
+    ]
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "unlimited".to_string(), |v| v.to_string())
+}
+
+fn section(name: &str) {
+    println!("[{}]", name);
+}
+
+fn item(name: &str, value: impl std::fmt::Display) {
+    println!("  {:<24}{}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn limits_test() {
+        let args = Args::parse_from([
+            "misskey-webp-proxy",
+            "--max-fps=30",
+            "--animated-max-width=256",
+            "--disable-format=svg,ico",
+        ]);
+        let limits = limits(&args);
+        let get = |name: &str| {
+            limits
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.as_str())
+                .unwrap()
+        };
+        assert_eq!(get("max-fps"), "30");
+        assert_eq!(get("max-animated-output"), "256x-");
+        assert_eq!(get("shed-max-queued"), "unlimited");
+        assert_eq!(get("disabled-formats"), "svg, ico");
+    }
+
+    #[test]
+    fn doctor_args_test() {
+        let args = Args::parse_from([
+            "misskey-webp-proxy",
+            "doctor",
+            "--probe-url=https://example.net/",
+        ]);
+        let Some(crate::args::Command::Doctor(doctor)) = args.command else {
+            panic!("not doctor: {:?}", args.command);
+        };
+        assert_eq!(doctor.probe_url.as_str(), "https://example.net/");
+    }
+}
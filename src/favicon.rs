@@ -0,0 +1,154 @@
+use anyhow::Result;
+use reqwest::{Client, Url};
+
+use crate::client::{download_text, Limits};
+
+/// `<link>`タグから見つかったアイコンの候補
+#[derive(Debug, PartialEq)]
+pub struct IconLink {
+    pub url: Url,
+    /// `sizes`属性のうち最大の辺の長さ。`any`の場合は`u32::MAX`、未指定の場合は0
+    pub size: u32,
+}
+
+/// ページを取得し、最も大きいアイコンのurlを返す。見つからなければ`/favicon.ico`を返す
+pub async fn find_favicon(client: &Client, page_url: &Url, limits: &Limits) -> Result<Url> {
+    let fallback = page_url.join("/favicon.ico")?;
+    let html = match download_text(client, page_url, limits).await {
+        Ok(html) => html,
+        Err(e) => {
+            tracing::debug!("cannot fetch page, fallback to /favicon.ico: {:#}", e);
+            return Ok(fallback);
+        }
+    };
+
+    let best = parse_icon_links(&html, page_url)
+        .into_iter()
+        .max_by_key(|link| link.size);
+    Ok(best.map(|link| link.url).unwrap_or(fallback))
+}
+
+/// htmlから`rel`に`icon`を含む`<link>`タグを探す
+/// ## Note
+/// 簡易的な実装のため、コメントや`<script>`内のタグも拾う
+pub fn parse_icon_links(html: &str, base: &Url) -> Vec<IconLink> {
+    let lower = html.to_ascii_lowercase();
+    let mut links = vec![];
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find("<link") {
+        let start = rest + start;
+        let Some(end) = lower[start..].find('>') else {
+            break;
+        };
+        let end = start + end;
+        rest = end;
+
+        let tag = &html[start + "<link".len()..end];
+        let attrs = parse_attributes(tag);
+        let attr = |name: &str| {
+            attrs
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+
+        let is_icon = attr("rel")
+            .map(|rel| {
+                rel.split_ascii_whitespace().any(|r| {
+                    r.eq_ignore_ascii_case("icon") || r.eq_ignore_ascii_case("apple-touch-icon")
+                })
+            })
+            .unwrap_or(false);
+        if !is_icon {
+            continue;
+        }
+        let Some(url) = attr("href").and_then(|href| base.join(href).ok()) else {
+            continue;
+        };
+        let size = attr("sizes").map(parse_sizes).unwrap_or(0);
+
+        links.push(IconLink { url, size });
+    }
+
+    links
+}
+
+/// `sizes`属性をパースし最大の辺の長さを返す
+fn parse_sizes(sizes: &str) -> u32 {
+    sizes
+        .split_ascii_whitespace()
+        .filter_map(|s| {
+            if s.eq_ignore_ascii_case("any") {
+                return Some(u32::MAX);
+            }
+            let (w, h) = s.split_once(['x', 'X'])?;
+            Some(w.parse::<u32>().ok()?.max(h.parse::<u32>().ok()?))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// タグ内の`key="value"`形式の属性を取り出す
+fn parse_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attrs = vec![];
+    let mut chars = tag.trim_end_matches('/').chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let key: String =
+            std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace() && *c != '=')).collect();
+        if key.is_empty() {
+            break;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next_if_eq(&'=').is_none() {
+            attrs.push((key, String::new()));
+            continue;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let value: String = match chars.next_if(|c| *c == '"' || *c == '\'') {
+            Some(quote) => {
+                let v = std::iter::from_fn(|| chars.next_if(|c| *c != quote)).collect();
+                chars.next();
+                v
+            }
+            None => std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect(),
+        };
+        attrs.push((key, value));
+    }
+    attrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        r#"<link rel="icon" href="/favicon.png">"#,
+        vec![("https://example.com/favicon.png", 0)]
+    )]
+    #[case(
+        r#"<LINK REL='shortcut icon' HREF=icon.ico /><link rel="stylesheet" href="/style.css">"#,
+        vec![("https://example.com/path/icon.ico", 0)]
+    )]
+    #[case(
+        r#"<link rel="apple-touch-icon" sizes="180x180" href="https://cdn.example.com/a.png"><link rel="icon" sizes="16x16 32x32" href="/b.png">"#,
+        vec![("https://cdn.example.com/a.png", 180), ("https://example.com/b.png", 32)]
+    )]
+    #[case(r#"<link rel="icon" sizes="any" href="/c.svg">"#, vec![("https://example.com/c.svg", u32::MAX)])]
+    #[case(r#"<html><head><title>no icon</title></head></html>"#, vec![])]
+    fn parse_icon_links_test(#[case] html: &str, #[case] expected: Vec<(&str, u32)>) {
+        let base = Url::parse("https://example.com/path/index.html").unwrap();
+        let expected: Vec<IconLink> = expected
+            .into_iter()
+            .map(|(url, size)| IconLink {
+                url: Url::parse(url).unwrap(),
+                size,
+            })
+            .collect();
+        assert_eq!(parse_icon_links(html, &base), expected);
+    }
+}
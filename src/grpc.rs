@@ -0,0 +1,235 @@
+use std::{sync::Arc, time::SystemTime};
+
+use tonic::{Request, Response, Status};
+
+use crate::{
+    apikey::Rejection,
+    client::{fetch_image, parse_source_url},
+    convert_cached,
+    handler::{ConvertType, ProxyConfig},
+    tenant::Tenant,
+    timing::ServerTiming,
+    AppError, AppState, API_KEY_HEADER,
+};
+
+mod proto {
+    tonic::include_proto!("misskey_webp_proxy");
+}
+
+use proto::{
+    media_proxy_server::{MediaProxy, MediaProxyServer},
+    ConvertRequest, ConvertResponse, InfoRequest, InfoResponse, PurgeRequest, PurgeResponse,
+};
+
+/// HTTP APIと同じ変換を提供するgRPCサービス
+struct MediaProxyService {
+    state: Arc<AppState>,
+}
+
+/// `Request`にはURIが残らないので、`:authority`を拡張に入れて`Host`ヘッダーの代わりに使う
+#[derive(Debug, Clone)]
+struct Authority(String);
+
+fn with_authority<B>(mut request: http::Request<B>) -> http::Request<B> {
+    let authority = request
+        .uri()
+        .authority()
+        .map(|a| a.to_string())
+        .or_else(|| {
+            request
+                .headers()
+                .get(http::header::HOST)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        });
+    if let Some(authority) = authority {
+        request.extensions_mut().insert(Authority(authority));
+    }
+    request
+}
+
+impl MediaProxyService {
+    /// HTTPと同じく`x-api-key`で認証し、その日の上限に達していれば拒否する。
+    /// 署名付きのクエリはないので、シークレットだけで認証する。認証したキーの名前を返す
+    fn authenticate<T>(&self, request: &Request<T>) -> Result<Option<String>, AppError> {
+        let Some(api_keys) = &self.state.api_keys else {
+            return Ok(None);
+        };
+        let secret = request
+            .metadata()
+            .get(API_KEY_HEADER.as_str())
+            .and_then(|v| v.to_str().ok());
+        let unauthorized = || {
+            AppError::new(
+                http::StatusCode::UNAUTHORIZED,
+                anyhow::anyhow!("Invalid API key"),
+            )
+        };
+        let name = secret
+            .and_then(|secret| api_keys.authenticate(Some(secret), "", None))
+            .ok_or_else(unauthorized)?;
+        match api_keys.acquire(name, SystemTime::now()) {
+            Ok(()) => Ok(Some(name.to_string())),
+            Err(Rejection::Unauthorized) => Err(unauthorized()),
+            Err(Rejection::QuotaExceeded(retry_after)) => Err(AppError::new(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                anyhow::anyhow!(
+                    "API key quota exceeded, retry after {}s",
+                    retry_after.as_secs()
+                ),
+            )),
+        }
+    }
+
+    /// `:authority`に対応する`Host`ごとの設定
+    fn tenant<T>(&self, request: &Request<T>) -> Option<&Tenant> {
+        let authority = request.extensions().get::<Authority>()?;
+        self.state.tenants.as_ref()?.get(&authority.0)
+    }
+
+    /// ブロックリストとwebhook、`Host`ごとの取得元の制限を確かめる
+    async fn authorize_fetch(
+        &self,
+        tenant: Option<&Tenant>,
+        url: &reqwest::Url,
+        kind: &str,
+    ) -> Result<(), Status> {
+        if tenant.is_some_and(|tenant| !tenant.is_allowed(url)) {
+            return Err(Status::permission_denied(format!(
+                "Not allowed for this host: {}",
+                url
+            )));
+        }
+        self.state.authorize_fetch(url, kind).await.map_err(status)
+    }
+}
+
+fn invalid_url(e: anyhow::Error) -> Status {
+    Status::invalid_argument(format!("invalid url: {:#}", e))
+}
+
+/// HTTPのステータスに対応するgRPCのステータスにする
+fn status(e: AppError) -> Status {
+    let message = format!("{:#}", e.error);
+    match e.status {
+        http::StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        http::StatusCode::FORBIDDEN => Status::permission_denied(message),
+        http::StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted(message),
+        http::StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+#[tonic::async_trait]
+impl MediaProxy for MediaProxyService {
+    async fn convert(
+        &self,
+        request: Request<ConvertRequest>,
+    ) -> Result<Response<ConvertResponse>, Status> {
+        let key = self.authenticate(&request).map_err(status)?;
+        let tenant = self.tenant(&request);
+        let request = request.into_inner();
+        let convert_type = match request.r#type() {
+            proto::ConvertType::Original => ConvertType::Original,
+            proto::ConvertType::Emoji => ConvertType::Emoji,
+            proto::ConvertType::Avatar => ConvertType::Avatar,
+            proto::ConvertType::Preview => ConvertType::Preview,
+            proto::ConvertType::Badge => ConvertType::Badge,
+        };
+        if request.width == Some(0) || request.height == Some(0) {
+            return Err(Status::invalid_argument(
+                "width and height must be greater than 0",
+            ));
+        }
+
+        let mut config = ProxyConfig {
+            is_static: request.r#static,
+            width: request.width,
+            height: request.height,
+            ..ProxyConfig::new(
+                parse_source_url(&request.url).map_err(invalid_url)?,
+                convert_type,
+            )
+        };
+        self.authorize_fetch(tenant, &config.url, convert_type.name())
+            .await?;
+        if let Some(tenant) = tenant {
+            config = tenant.apply(config);
+        }
+        let config = self.state.clamp(config);
+
+        let result = convert_cached(&self.state, &config, &mut ServerTiming::default(), None).await;
+        let response = match (result, tenant.and_then(Tenant::fallback)) {
+            (Ok(converted), _) => ConvertResponse {
+                content_type: converted.content_type.to_string(),
+                body: converted.body.to_vec(),
+                degraded: converted
+                    .degraded
+                    .iter()
+                    .map(|d| d.as_str().to_string())
+                    .collect(),
+            },
+            (Err(e), Some(fallback)) => {
+                tracing::info!("serve fallback image: {:#}", e);
+                ConvertResponse {
+                    content_type: fallback.content_type.to_string(),
+                    body: fallback.body.to_vec(),
+                    degraded: vec![],
+                }
+            }
+            (Err(e), None) => return Err(Status::internal(format!("{:#}", e))),
+        };
+        if let (Some(api_keys), Some(key)) = (&self.state.api_keys, key) {
+            api_keys.record_bytes(&key, response.body.len() as u64);
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn info(&self, request: Request<InfoRequest>) -> Result<Response<InfoResponse>, Status> {
+        self.authenticate(&request).map_err(status)?;
+        let tenant = self.tenant(&request);
+        let url = parse_source_url(&request.into_inner().url).map_err(invalid_url)?;
+        self.authorize_fetch(tenant, &url, "info").await?;
+        let fetched = fetch_image(&self.state.client, &url, &self.state.limits)
+            .await
+            .map_err(|e| Status::unavailable(format!("{:#}", e)))?;
+        let info = fetched
+            .info
+            .ok_or_else(|| Status::failed_precondition("cannot read image header"))?;
+
+        Ok(Response::new(InfoResponse {
+            format: format!("{:?}", fetched.ext).to_lowercase(),
+            width: info.width,
+            height: info.height,
+            animated: info.animated,
+            frame_count: info.frame_count,
+        }))
+    }
+
+    async fn purge(
+        &self,
+        request: Request<PurgeRequest>,
+    ) -> Result<Response<PurgeResponse>, Status> {
+        // HTTPの管理用APIと同じく`authorization: Bearer <token>`が必要
+        self.state
+            .authorize_admin(&request.metadata().clone().into_headers())
+            .map_err(status)?;
+        let url = parse_source_url(&request.into_inner().url).map_err(invalid_url)?;
+        let purged = self.state.cache.purge(&ProxyConfig::cache_key_prefix(&url));
+
+        Ok(Response::new(PurgeResponse {
+            purged: purged as u32,
+        }))
+    }
+}
+
+/// gRPCサービスを起動する
+pub(crate) async fn serve(state: Arc<AppState>, addr: std::net::SocketAddr) -> anyhow::Result<()> {
+    tracing::info!(%addr, "Waiting gRPC request at {} ...", addr);
+    tonic::transport::Server::builder()
+        .layer(tower::util::MapRequestLayer::new(with_authority))
+        .add_service(MediaProxyServer::new(MediaProxyService { state }))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
@@ -1,21 +1,285 @@
-use crate::{client::download_image, processor::DecodeResult};
+use crate::{
+    client::{decode_image, fetch_image, parse_source_url, DecodeOptions, Limits, Passthrough},
+    processor::{
+        self, DecodeResult, Degradation, FitMode, Flip, FrameSelector, PresetSizes, ResizeFilter,
+        Rotation,
+    },
+    timing::ServerTiming,
+};
 use anyhow::{Ok, Result};
 use reqwest::{Client, Url};
 use serde::Deserialize;
 
 /// メディアプロキシのクエリ。フォールバックには未対応
 #[derive(Debug, PartialEq, Deserialize)]
-pub(crate) struct ProxyQuery {
+pub struct ProxyQuery {
     url: String,
     emoji: Option<usize>,
     avatar: Option<usize>,
     r#static: Option<usize>,
     preview: Option<usize>,
     badge: Option<usize>,
+    w: Option<u32>,
+    h: Option<u32>,
+    #[serde(alias = "scale")]
+    dpr: Option<f32>,
+    format: Option<OutputFormat>,
+    trim: Option<usize>,
+    square: Option<usize>,
+    /// アニメーションから取り出すフレームの番号
+    frame: Option<u32>,
+    /// アニメーションから取り出すフレームの時刻(ミリ秒)
+    t: Option<u32>,
+    exact: Option<usize>,
+    quality: Option<u8>,
+    fit: Option<FitMode>,
+    /// 背景色。`RRGGBB`もしくは`RRGGBBAA`
+    bg: Option<String>,
+    /// 時計回りの回転角度
+    rotate: Option<u32>,
+    flip: Option<Flip>,
+    grayscale: Option<usize>,
+    /// ガウスぼかしの強さ
+    blur: Option<f32>,
+    /// カンマ区切りの変換の列。例: `trim,resize:256x256:contain,round,blur:4`
+    ops: Option<String>,
+    /// キャッシュを使わずに取得し直す。管理用のトークンか署名があるリクエストのみ有効
+    refresh: Option<usize>,
+    /// ブラウザにキャッシュさせる秒数。サーバーの設定の範囲に収める
+    ttl: Option<u32>,
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum ConvertType {
+impl ProxyQuery {
+    fn convert_type(&self) -> ConvertType {
+        if self.emoji.is_some() {
+            ConvertType::Emoji
+        } else if self.avatar.is_some() {
+            ConvertType::Avatar
+        } else if self.preview.is_some() {
+            ConvertType::Preview
+        } else if self.badge.is_some() {
+            ConvertType::Badge
+        } else {
+            ConvertType::Original
+        }
+    }
+
+    /// クエリで変換の種類や出力形式が指定されていない場合、`/proxy/emoji.webp`のようなパスのファイル名から決める
+    pub fn with_path_filename(mut self, path: &str) -> Self {
+        let filename = path.rsplit('/').next().unwrap_or_default();
+        let (stem, ext) = filename.rsplit_once('.').unwrap_or((filename, ""));
+
+        let specified = self.emoji.is_some()
+            || self.avatar.is_some()
+            || self.r#static.is_some()
+            || self.preview.is_some()
+            || self.badge.is_some();
+        if !specified {
+            match stem {
+                "emoji" => self.emoji = Some(1),
+                "avatar" => self.avatar = Some(1),
+                "static" => self.r#static = Some(1),
+                "preview" => self.preview = Some(1),
+                "badge" => self.badge = Some(1),
+                _ => {}
+            }
+        }
+
+        if self.format.is_none() {
+            self.format = match ext.to_ascii_lowercase().as_str() {
+                "webp" => Some(OutputFormat::Webp),
+                "png" => Some(OutputFormat::Png),
+                "gif" => Some(OutputFormat::Gif),
+                #[cfg(feature = "avif")]
+                "avif" => Some(OutputFormat::Avif),
+                _ => None,
+            };
+        }
+        self
+    }
+
+    /// Client Hintsで出力の大きさが変わるか
+    pub fn uses_client_hints(&self) -> bool {
+        matches!(
+            self.convert_type(),
+            ConvertType::Avatar | ConvertType::Preview
+        )
+    }
+
+    /// avatarとpreviewの場合、クエリで指定されていない`dpr`と`w`をClient Hintsで補う
+    pub fn with_client_hints(mut self, dpr: Option<f32>, width: Option<u32>) -> Self {
+        if self.uses_client_hints() {
+            self.dpr = self.dpr.or(dpr);
+            self.w = self.w.or(width);
+        }
+        self
+    }
+}
+
+/// `ops`で指定できる変換の数の上限
+const MAX_OPS: usize = 16;
+/// `blur`のsigmaの上限
+const MAX_BLUR: f32 = 50.0;
+
+/// `ops`で指定する変換の1ステップ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Trim,
+    Square,
+    /// 内接する楕円で切り抜く
+    Round,
+    Grayscale,
+    Resize {
+        width: u32,
+        height: u32,
+        mode: FitMode,
+    },
+    Rotate(Rotation),
+    Flip(Flip),
+    Blur(f32),
+    Background([u8; 4]),
+}
+
+impl Op {
+    /// 変換を適用する
+    fn apply(
+        &self,
+        decoded_buf: DecodeResult,
+        filter: ResizeFilter,
+        limits: &Limits,
+    ) -> Result<DecodeResult> {
+        match *self {
+            Op::Trim => decoded_buf.trim(),
+            Op::Square => decoded_buf.pad_square(limits),
+            Op::Round => decoded_buf.round(),
+            Op::Grayscale => decoded_buf.grayscale(),
+            Op::Resize {
+                width,
+                height,
+                mode,
+            } => decoded_buf.fit_box(width, height, mode, filter),
+            Op::Rotate(rotation) => decoded_buf.rotate(rotation),
+            Op::Flip(flip) => decoded_buf.flip(flip),
+            Op::Blur(sigma) => decoded_buf.blur(sigma),
+            Op::Background(color) => decoded_buf.flatten(color),
+        }
+    }
+}
+
+impl std::str::FromStr for Op {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let op = match (name, arg) {
+            ("trim", None) => Op::Trim,
+            ("square", None) => Op::Square,
+            ("round", None) => Op::Round,
+            ("grayscale", None) => Op::Grayscale,
+            ("resize", Some(arg)) => {
+                let (size, mode) = match arg.split_once(':') {
+                    Some((size, mode)) => (size, mode),
+                    None => (arg, "contain"),
+                };
+                let (width, height) = size
+                    .split_once('x')
+                    .ok_or_else(|| anyhow::anyhow!("resize must be WxH: {}", s))?;
+                let (width, height): (u32, u32) = (width.parse()?, height.parse()?);
+                if width == 0 || height == 0 {
+                    return Err(anyhow::anyhow!("resize must be greater than 0: {}", s));
+                }
+                let mode = match mode {
+                    "contain" => FitMode::Contain,
+                    "cover" => FitMode::Cover,
+                    "fill" => FitMode::Fill,
+                    _ => return Err(anyhow::anyhow!("unknown fit mode: {}", s)),
+                };
+                Op::Resize {
+                    width,
+                    height,
+                    mode,
+                }
+            }
+            ("rotate", Some(arg)) => match Rotation::from_degrees(arg.parse()?)? {
+                Some(rotation) => Op::Rotate(rotation),
+                None => return Err(anyhow::anyhow!("rotate must not be 0: {}", s)),
+            },
+            ("flip", Some("h")) => Op::Flip(Flip::H),
+            ("flip", Some("v")) => Op::Flip(Flip::V),
+            ("blur", Some(arg)) => {
+                let sigma: f32 = arg.parse()?;
+                if !sigma.is_finite() || sigma <= 0.0 {
+                    return Err(anyhow::anyhow!("blur must be a positive number: {}", s));
+                }
+                Op::Blur(sigma.min(MAX_BLUR))
+            }
+            ("bg", Some(arg)) => Op::Background(parse_color(arg)?),
+            _ => return Err(anyhow::anyhow!("unknown op: {}", s)),
+        };
+        Ok(op)
+    }
+}
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Trim => write!(f, "trim"),
+            Op::Square => write!(f, "square"),
+            Op::Round => write!(f, "round"),
+            Op::Grayscale => write!(f, "grayscale"),
+            Op::Resize {
+                width,
+                height,
+                mode,
+            } => {
+                let mode = match mode {
+                    FitMode::Contain => "contain",
+                    FitMode::Cover => "cover",
+                    FitMode::Fill => "fill",
+                };
+                write!(f, "resize:{}x{}:{}", width, height, mode)
+            }
+            Op::Rotate(rotation) => write!(f, "rotate:{}", rotation.degrees()),
+            Op::Flip(Flip::H) => write!(f, "flip:h"),
+            Op::Flip(Flip::V) => write!(f, "flip:v"),
+            Op::Blur(sigma) => write!(f, "blur:{}", sigma),
+            Op::Background([r, g, b, a]) => {
+                write!(f, "bg:{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+            }
+        }
+    }
+}
+
+/// カンマ区切りの変換の列をパースする
+fn parse_ops(s: &str) -> Result<Vec<Op>> {
+    let ops = s
+        .split(',')
+        .filter(|op| !op.is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<Op>>>()?;
+    if ops.len() > MAX_OPS {
+        return Err(anyhow::anyhow!("ops must be at most {} steps", MAX_OPS));
+    }
+    Ok(ops)
+}
+
+/// 出力する画像の形式
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Webp,
+    Png,
+    Gif,
+    /// アニメーションのみAVIFにし、静止画はWebPにする
+    #[cfg(feature = "avif")]
+    Avif,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConvertType {
     Emoji,
     Avatar,
     Preview,
@@ -23,62 +287,1065 @@ pub(crate) enum ConvertType {
     Original,
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) struct ProxyConfig {
-    pub(crate) url: Url,
-    pub(crate) convert_type: ConvertType,
-    pub(crate) is_static: bool,
+impl ConvertType {
+    pub const ALL: [ConvertType; 5] = [
+        ConvertType::Emoji,
+        ConvertType::Avatar,
+        ConvertType::Preview,
+        ConvertType::Badge,
+        ConvertType::Original,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConvertType::Emoji => "emoji",
+            ConvertType::Avatar => "avatar",
+            ConvertType::Preview => "preview",
+            ConvertType::Badge => "badge",
+            ConvertType::Original => "original",
+        }
+    }
+
+    /// 変換タイプごとの枠の大きさ。決まっていない辺は`None`
+    fn box_size(&self, sizes: &PresetSizes, dpr: f32) -> (Option<u32>, Option<u32>) {
+        use processor::scaled;
+
+        match self {
+            ConvertType::Emoji => (None, Some(scaled(sizes.emoji_height, dpr))),
+            ConvertType::Avatar => (None, Some(scaled(sizes.avatar_height, dpr))),
+            ConvertType::Preview => (
+                Some(scaled(sizes.preview_width, dpr)),
+                Some(scaled(sizes.preview_height, dpr)),
+            ),
+            ConvertType::Badge => (
+                Some(scaled(sizes.badge_width, dpr)),
+                Some(scaled(sizes.badge_height, dpr)),
+            ),
+            ConvertType::Original => (None, None),
+        }
+    }
+}
+
+impl std::str::FromStr for ConvertType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "emoji" => Ok(ConvertType::Emoji),
+            "avatar" => Ok(ConvertType::Avatar),
+            "preview" => Ok(ConvertType::Preview),
+            "badge" => Ok(ConvertType::Badge),
+            "original" => Ok(ConvertType::Original),
+            _ => Err(anyhow::anyhow!("unknown convert type: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    pub url: Url,
+    pub convert_type: ConvertType,
+    pub is_static: bool,
+    /// 幅の上限。アスペクト比を維持したまま収まるように縮小する
+    pub width: Option<u32>,
+    /// 高さの上限。アスペクト比を維持したまま収まるように縮小する
+    pub height: Option<u32>,
+    /// プリセットの大きさに掛ける倍率
+    pub dpr: f32,
+    /// 出力形式。`None`の場合は変換タイプに従う
+    pub format: Option<OutputFormat>,
+    /// 透明な余白を切り取るか
+    pub trim: bool,
+    /// 透明な余白を足して正方形にするか
+    pub square: bool,
+    /// アニメーションの場合に静止画として取り出すフレーム
+    pub poster: Option<FrameSelector>,
+    /// WebPで完全に透明なピクセルのRGBを保持するか
+    pub exact: bool,
+    /// WebPの圧縮率。`None`の場合はサーバーの設定に従う
+    pub quality: Option<u8>,
+    /// 枠に合わせる方法。`None`の場合は変換タイプごとの従来の方法になる
+    pub fit: Option<FitMode>,
+    /// 透明なピクセルと合成する背景色
+    pub background: Option<[u8; 4]>,
+    /// デコード後に回転する角度
+    pub rotate: Option<Rotation>,
+    /// 回転の後に反転する向き
+    pub flip: Option<Flip>,
+    /// 白黒にするか
+    pub grayscale: bool,
+    /// ガウスぼかしのsigma
+    pub blur: Option<f32>,
+    /// アニメーションをこのフレームレートに並べ直す。サーバーの設定から決まる
+    pub fps: Option<u32>,
+    /// アニメーションを先頭からこのフレーム数までにする
+    pub max_frames: Option<u32>,
+    /// アニメーションを先頭からこの時間(ミリ秒)までにする
+    pub max_duration: Option<u32>,
+    /// 長辺の上限。サーバーの設定から決まる
+    pub max_size: Option<u32>,
+    /// アニメーションの場合の幅と高さの上限。サーバーの設定から決まる
+    pub animated_max_size: Option<(u32, u32)>,
+    /// ほかの変換の後に順番に適用する変換
+    pub ops: Vec<Op>,
+    /// 変換の種類ごとの大きさ
+    pub sizes: PresetSizes,
+    /// 拡大縮小に使うフィルタ
+    pub filter: ResizeFilter,
+    /// キャッシュを使わずに取得し直すか。キャッシュのキーには含めない
+    pub refresh: bool,
+    /// ブラウザにキャッシュさせる秒数。`None`の場合は変わらない画像として1年間キャッシュさせる。
+    /// キャッシュのキーには含めない
+    pub ttl: Option<u32>,
+}
+
+impl ProxyConfig {
+    /// 追加の変換を行わない設定を作る
+    pub fn new(url: Url, convert_type: ConvertType) -> Self {
+        Self {
+            url,
+            convert_type,
+            is_static: false,
+            width: None,
+            height: None,
+            dpr: 1.0,
+            format: None,
+            trim: false,
+            square: false,
+            poster: None,
+            exact: false,
+            quality: None,
+            fit: None,
+            background: None,
+            rotate: None,
+            flip: None,
+            grayscale: false,
+            blur: None,
+            fps: None,
+            max_frames: None,
+            max_duration: None,
+            max_size: None,
+            animated_max_size: None,
+            ops: Vec::new(),
+            sizes: PresetSizes::default(),
+            filter: ResizeFilter::default(),
+            refresh: false,
+            ttl: None,
+        }
+    }
+
+    /// キャッシュのキー。変換結果に影響するすべての設定を含める
+    pub fn cache_key(&self) -> String {
+        format!(
+            "{}{:?}|static={}|w={:?}|h={:?}|dpr={}|format={:?}|trim={}|square={}|poster={:?}|exact={}|quality={:?}|fit={:?}|bg={:?}|rotate={:?}|flip={:?}|grayscale={}|blur={:?}|fps={:?}|max_frames={:?}|max_duration={:?}|max_size={:?}|animated_max_size={:?}|ops={:?}|filter={:?}",
+            Self::cache_key_prefix(&self.url),
+            self.convert_type,
+            self.is_static,
+            self.width,
+            self.height,
+            self.dpr,
+            self.format,
+            self.trim,
+            self.square,
+            self.poster,
+            self.exact,
+            self.quality,
+            self.fit,
+            self.background,
+            self.rotate,
+            self.flip,
+            self.grayscale,
+            self.blur,
+            self.fps,
+            self.max_frames,
+            self.max_duration,
+            self.max_size,
+            self.animated_max_size,
+            self.ops,
+            self.filter,
+        )
+    }
+
+    /// 出力に最初のフレームしか使わないか。アニメーションのデコードを省略できる
+    pub fn first_frame_only(&self) -> bool {
+        let static_output = matches!(
+            (self.format, self.convert_type),
+            (Some(OutputFormat::Png), _) | (None, ConvertType::Badge)
+        );
+        match self.poster {
+            Some(FrameSelector::Index(index)) => index == 0,
+            Some(FrameSelector::Time(_)) => false,
+            None => self.is_static || static_output,
+        }
+    }
+
+    /// `width`x`height`の画像を変換する際、出力の画質を落とさずに縮小できる倍率。縮小できない場合は1.0
+    pub fn decode_scale(&self, width: u32, height: u32) -> f64 {
+        use processor::scaled;
+
+        // 切り取る範囲や変換の順番によって必要な大きさが変わるので縮小しない
+        if self.trim || !self.ops.is_empty() || width == 0 || height == 0 {
+            return 1.0;
+        }
+        let (width, height) = match self.rotate {
+            Some(Rotation::Rotate90 | Rotation::Rotate270) => (height as f64, width as f64),
+            _ => (width as f64, height as f64),
+        };
+        // アスペクト比を維持して枠に収める場合の倍率
+        let contain = |w: Option<u32>, h: Option<u32>| {
+            let sw = w.map_or(f64::INFINITY, |w| w as f64 / width);
+            let sh = h.map_or(f64::INFINITY, |h| h as f64 / height);
+            sw.min(sh)
+        };
+        // 枠を覆う場合の倍率。これ以降の変換は枠の大きさにしか依存しない
+        let cover = |w: u32, h: u32| (w as f64 / width).max(h as f64 / height);
+
+        let mut scale: f64 = 1.0;
+        if self.is_static {
+            scale = scale.min(contain(
+                None,
+                Some(scaled(self.sizes.static_height, self.dpr)),
+            ));
+        }
+        match self.fit {
+            None => {
+                match self.convert_type {
+                    ConvertType::Emoji => {
+                        scale = scale.min(contain(
+                            None,
+                            Some(scaled(self.sizes.emoji_height, self.dpr)),
+                        ))
+                    }
+                    ConvertType::Avatar => {
+                        scale = scale.min(contain(
+                            None,
+                            Some(scaled(self.sizes.avatar_height, self.dpr)),
+                        ))
+                    }
+                    ConvertType::Preview | ConvertType::Badge => {
+                        if let (Some(w), Some(h)) =
+                            self.convert_type.box_size(&self.sizes, self.dpr)
+                        {
+                            return scale.min(cover(w, h));
+                        }
+                    }
+                    ConvertType::Original => {}
+                }
+                scale = scale.min(contain(self.max_size, self.max_size));
+                scale = scale.min(contain(self.width, self.height));
+            }
+            Some(mode) => {
+                scale = scale.min(contain(self.max_size, self.max_size));
+                let (box_w, box_h) = self.convert_type.box_size(&self.sizes, self.dpr);
+                match (self.width.or(box_w), self.height.or(box_h), mode) {
+                    (Some(w), Some(h), FitMode::Cover | FitMode::Fill) => {
+                        return scale.min(cover(w, h))
+                    }
+                    (w, h, _) => scale = scale.min(contain(w, h)),
+                }
+            }
+        }
+        scale
+    }
+
+    /// 子プロセスでsvgを描画する枠。出力はアスペクト比を維持してこの枠に収まる大きさ以下になる。
+    /// 枠を覆うように変換する場合などは枠を決められないので`(None, None)`を返す
+    pub fn svg_box(&self) -> (Option<u32>, Option<u32>) {
+        use processor::scaled;
+
+        // `decode_scale`と同じく、切り取る範囲や変換の順番によって必要な大きさが変わる
+        if self.trim || !self.ops.is_empty() {
+            return (None, None);
+        }
+        let min = |a: Option<u32>, b: Option<u32>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let mut bounds = (self.max_size, self.max_size);
+        let mut bound = |(w, h): (Option<u32>, Option<u32>)| {
+            bounds = (min(bounds.0, w), min(bounds.1, h));
+        };
+        if self.is_static {
+            bound((None, Some(scaled(self.sizes.static_height, self.dpr))));
+        }
+        let (box_w, box_h) = self.convert_type.box_size(&self.sizes, self.dpr);
+        match self.fit {
+            None if matches!(self.convert_type, ConvertType::Preview | ConvertType::Badge) => {
+                return (None, None)
+            }
+            None => {
+                bound((box_w, box_h));
+                bound((self.width, self.height));
+            }
+            Some(mode) => {
+                let (w, h) = (self.width.or(box_w), self.height.or(box_h));
+                if w.is_some() && h.is_some() && matches!(mode, FitMode::Cover | FitMode::Fill) {
+                    return (None, None);
+                }
+                bound((w, h));
+            }
+        }
+        match self.rotate {
+            Some(Rotation::Rotate90 | Rotation::Rotate270) => (bounds.1, bounds.0),
+            _ => bounds,
+        }
+    }
+
+    /// 同じurlのキャッシュすべてに共通するキーの接頭辞
+    pub fn cache_key_prefix(url: &Url) -> String {
+        format!("{}|", url)
+    }
+
+    /// 画像に手を加えない設定か。出力形式の指定がない場合はWebPになる
+    pub fn is_passthrough(&self) -> bool {
+        self.convert_type == ConvertType::Original
+            && !self.is_static
+            && self.width.is_none()
+            && self.height.is_none()
+            && matches!(self.format, None | Some(OutputFormat::Webp))
+            && !self.trim
+            && !self.square
+            && self.poster.is_none()
+            && self.quality.is_none()
+            && self.background.is_none()
+            && self.rotate.is_none()
+            && self.flip.is_none()
+            && !self.grayscale
+            && self.blur.is_none()
+            && self.fps.is_none()
+            && self.max_frames.is_none()
+            && self.max_duration.is_none()
+            && self.ops.is_empty()
+    }
+
+    /// 同じ設定になるメディアプロキシのクエリ
+    pub fn to_query(&self) -> Vec<(&'static str, String)> {
+        let mut query = vec![("url", self.url.to_string())];
+        match self.convert_type {
+            ConvertType::Emoji => query.push(("emoji", "1".to_string())),
+            ConvertType::Avatar => query.push(("avatar", "1".to_string())),
+            ConvertType::Preview => query.push(("preview", "1".to_string())),
+            ConvertType::Badge => query.push(("badge", "1".to_string())),
+            ConvertType::Original => {}
+        }
+        if self.is_static {
+            query.push(("static", "1".to_string()));
+        }
+        if let Some(w) = self.width {
+            query.push(("w", w.to_string()));
+        }
+        if let Some(h) = self.height {
+            query.push(("h", h.to_string()));
+        }
+        query.push(("dpr", self.dpr.to_string()));
+        match self.format {
+            Some(OutputFormat::Webp) => query.push(("format", "webp".to_string())),
+            Some(OutputFormat::Png) => query.push(("format", "png".to_string())),
+            Some(OutputFormat::Gif) => query.push(("format", "gif".to_string())),
+            #[cfg(feature = "avif")]
+            Some(OutputFormat::Avif) => query.push(("format", "avif".to_string())),
+            None => {}
+        }
+        if self.trim {
+            query.push(("trim", "1".to_string()));
+        }
+        if self.square {
+            query.push(("square", "1".to_string()));
+        }
+        match self.poster {
+            Some(FrameSelector::Index(index)) => query.push(("frame", index.to_string())),
+            Some(FrameSelector::Time(t)) => query.push(("t", t.to_string())),
+            None => {}
+        }
+        if self.exact {
+            query.push(("exact", "1".to_string()));
+        }
+        if let Some(quality) = self.quality {
+            query.push(("quality", quality.to_string()));
+        }
+        match self.fit {
+            Some(FitMode::Contain) => query.push(("fit", "contain".to_string())),
+            Some(FitMode::Cover) => query.push(("fit", "cover".to_string())),
+            Some(FitMode::Fill) => query.push(("fit", "fill".to_string())),
+            None => {}
+        }
+        if let Some([r, g, b, a]) = self.background {
+            query.push(("bg", format!("{:02x}{:02x}{:02x}{:02x}", r, g, b, a)));
+        }
+        if let Some(rotate) = self.rotate {
+            query.push(("rotate", rotate.degrees().to_string()));
+        }
+        match self.flip {
+            Some(Flip::H) => query.push(("flip", "h".to_string())),
+            Some(Flip::V) => query.push(("flip", "v".to_string())),
+            None => {}
+        }
+        if self.grayscale {
+            query.push(("grayscale", "1".to_string()));
+        }
+        if let Some(blur) = self.blur {
+            query.push(("blur", blur.to_string()));
+        }
+        if !self.ops.is_empty() {
+            let ops: Vec<String> = self.ops.iter().map(Op::to_string).collect();
+            query.push(("ops", ops.join(",")));
+        }
+        if self.refresh {
+            query.push(("refresh", "1".to_string()));
+        }
+        if let Some(ttl) = self.ttl {
+            query.push(("ttl", ttl.to_string()));
+        }
+        query
+    }
+
+    /// 変換した画像に付ける`Cache-Control`
+    pub fn cache_control(&self) -> String {
+        match self.ttl {
+            Some(ttl) => format!("max-age={}", ttl),
+            None => "max-age=31536000, immutable".to_string(),
+        }
+    }
+
+    /// `ttl`をサーバー側の範囲に収める
+    pub fn clamp_ttl(mut self, min_ttl: u32, max_ttl: u32) -> Self {
+        self.ttl = self.ttl.map(|ttl| ttl.min(max_ttl).max(min_ttl));
+        self
+    }
+
+    /// `w`と`h`、`ops`の`resize`をサーバー側の上限に収める
+    pub fn clamp_size(mut self, max_width: u32, max_height: u32) -> Self {
+        self.width = self.width.map(|w| w.min(max_width));
+        self.height = self.height.map(|h| h.min(max_height));
+        for op in self.ops.iter_mut() {
+            if let Op::Resize { width, height, .. } = op {
+                *width = (*width).min(max_width);
+                *height = (*height).min(max_height);
+            }
+        }
+        self
+    }
+
+    /// `quality`をサーバー側の上限に収める
+    pub fn clamp_quality(mut self, max_quality: u8) -> Self {
+        self.quality = self.quality.map(|q| q.min(max_quality));
+        self
+    }
+
+    /// サーバーでフレームレートの上限が設定されていれば、アニメーションをそのフレームレートに揃える
+    pub fn clamp_fps(mut self, max_fps: Option<u32>) -> Self {
+        self.fps = max_fps;
+        self
+    }
+
+    /// 静止画でないoriginalの場合、長辺が`max_size`以下になるように縮小する
+    pub fn limit_original(mut self, max_size: Option<u32>) -> Self {
+        if self.convert_type == ConvertType::Original && !self.is_static {
+            self.max_size = max_size;
+        }
+        self
+    }
+
+    /// アニメーションの場合、幅が`max_width`以下、高さが`max_height`以下になるように縮小する。
+    /// 静止画より厳しい上限にすることで、フレーム数に比例して増える変換の負荷を抑える
+    pub fn limit_animated(mut self, max_width: Option<u32>, max_height: Option<u32>) -> Self {
+        self.animated_max_size = match (max_width, max_height) {
+            (None, None) => None,
+            (w, h) => Some((w.unwrap_or(u32::MAX), h.unwrap_or(u32::MAX))),
+        };
+        self
+    }
+
+    /// アニメーションをサーバー側の上限である`max_duration`ミリ秒までにする。
+    /// より短い指定がすでにあればそちらを使う
+    pub fn clamp_duration(mut self, max_duration: Option<u32>) -> Self {
+        self.max_duration = match (self.max_duration, max_duration) {
+            (Some(current), Some(max)) => Some(current.min(max)),
+            (current, max) => current.or(max),
+        };
+        self
+    }
+
+    /// previewの場合、アニメーションを先頭から`max_frames`枚、`max_duration`ミリ秒までにする
+    pub fn limit_preview(mut self, max_frames: Option<u32>, max_duration: Option<u32>) -> Self {
+        if self.convert_type == ConvertType::Preview {
+            self.max_frames = max_frames;
+            self.max_duration = max_duration;
+        }
+        self
+    }
 }
 
 impl TryFrom<ProxyQuery> for ProxyConfig {
     type Error = anyhow::Error;
 
     fn try_from(value: ProxyQuery) -> Result<Self, Self::Error> {
-        let url = Url::parse(&value.url)?;
-        let convert_type: ConvertType = if value.emoji.is_some() {
-            ConvertType::Emoji
-        } else if value.avatar.is_some() {
-            ConvertType::Avatar
-        } else if value.preview.is_some() {
-            ConvertType::Preview
-        } else if value.badge.is_some() {
-            ConvertType::Badge
-        } else {
-            ConvertType::Original
-        };
+        let url = parse_source_url(&value.url)?;
+        let convert_type = value.convert_type();
 
         let is_static = value.r#static.is_some();
+        if value.w == Some(0) || value.h == Some(0) {
+            return Err(anyhow::anyhow!("w and h must be greater than 0"));
+        }
+
+        const MAX_DPR: f32 = 3.0;
+        let dpr = value.dpr.unwrap_or(1.0);
+        if !dpr.is_finite() || dpr <= 0.0 {
+            return Err(anyhow::anyhow!("dpr must be a positive number"));
+        }
+        let dpr = dpr.min(MAX_DPR);
+
+        if value.blur.is_some_and(|b| !b.is_finite() || b <= 0.0) {
+            return Err(anyhow::anyhow!("blur must be a positive number"));
+        }
+        let blur = value.blur.map(|b| b.min(MAX_BLUR));
+
+        if value.quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            return Err(anyhow::anyhow!("quality must be between 1 and 100"));
+        }
+
+        let poster = match (value.frame, value.t) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow::anyhow!("frame and t cannot be specified together"))
+            }
+            (Some(index), None) => Some(FrameSelector::Index(index)),
+            (None, Some(t)) => Some(FrameSelector::Time(t)),
+            (None, None) => None,
+        };
         Ok({
             ProxyConfig {
-                url,
-                convert_type,
                 is_static,
+                width: value.w,
+                height: value.h,
+                dpr,
+                format: value.format,
+                trim: value.trim.is_some(),
+                square: value.square.is_some(),
+                poster,
+                exact: value.exact.is_some(),
+                quality: value.quality,
+                fit: value.fit,
+                background: value.bg.as_deref().map(parse_color).transpose()?,
+                rotate: Rotation::from_degrees(value.rotate.unwrap_or(0))?,
+                flip: value.flip,
+                grayscale: value.grayscale.is_some(),
+                blur,
+                ops: value
+                    .ops
+                    .as_deref()
+                    .map(parse_ops)
+                    .transpose()?
+                    .unwrap_or_default(),
+                refresh: value.refresh.is_some(),
+                ttl: value.ttl,
+                ..ProxyConfig::new(url, convert_type)
             }
         })
     }
 }
 
-pub(crate) async fn media_proxy(
+/// `RRGGBB`もしくは`RRGGBBAA`形式の色をパースする。先頭の`#`は省略できる
+fn parse_color(s: &str) -> Result<[u8; 4]> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return Err(anyhow::anyhow!("bg must be RRGGBB or RRGGBBAA"));
+    }
+    let mut color = [255; 4];
+    for (i, c) in color.iter_mut().enumerate().take(hex.len() / 2) {
+        *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(color)
+}
+
+/// キャッシュを温めるために事前に変換する画像
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct PrefetchEntry {
+    url: String,
+    r#type: PrefetchType,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PrefetchType {
+    Emoji,
+    Avatar,
+    Preview,
+    Badge,
+    Static,
+    Original,
+}
+
+impl TryFrom<PrefetchEntry> for ProxyConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: PrefetchEntry) -> Result<Self, Self::Error> {
+        let url = parse_source_url(&value.url)?;
+        let config = match value.r#type {
+            PrefetchType::Emoji => ProxyConfig::new(url, ConvertType::Emoji),
+            PrefetchType::Avatar => ProxyConfig::new(url, ConvertType::Avatar),
+            PrefetchType::Preview => ProxyConfig::new(url, ConvertType::Preview),
+            PrefetchType::Badge => ProxyConfig::new(url, ConvertType::Badge),
+            PrefetchType::Static => ProxyConfig {
+                is_static: true,
+                ..ProxyConfig::new(url, ConvertType::Original)
+            },
+            PrefetchType::Original => ProxyConfig::new(url, ConvertType::Original),
+        };
+        Ok(config)
+    }
+}
+
+/// 取得して変換する。取得元の画像のバイト数も返す。
+/// `opened`があれば取得し直さずに、そのレスポンスの残りを読み込む
+pub async fn media_proxy(
     client: &Client,
     proxy_config: &ProxyConfig,
+    limits: &Limits,
+    opened: Option<Passthrough>,
+    timing: &mut ServerTiming,
+    degraded: &mut Vec<Degradation>,
+) -> Result<(DecodeResult, usize)> {
+    let fetched = timing
+        .measure_async("download", async {
+            match opened {
+                Some(opened) => opened.into_fetched(&proxy_config.url, limits).await,
+                None => fetch_image(client, &proxy_config.url, limits).await,
+            }
+        })
+        .await?;
+    let input_bytes = fetched.buf.len();
+    let options = DecodeOptions {
+        first_frame_only: proxy_config.first_frame_only(),
+        min_scale: fetched.info.map_or(1.0, |info| {
+            proxy_config.decode_scale(info.width, info.height)
+        }),
+        svg_box: proxy_config.svg_box(),
+    };
+    // 子プロセスでデコードする場合は終了まで待つので、非同期のワーカーを止めないように別スレッドで行う
+    let decode_limits = limits.clone();
+    let decoded_buf = timing
+        .measure_async(
+            "decode",
+            tokio::task::spawn_blocking(move || decode_image(fetched, &decode_limits, options)),
+        )
+        .await??;
+    let decoded_buf = timing.measure("resize", || {
+        transform(decoded_buf, proxy_config, limits, degraded)
+    })?;
+    Ok((decoded_buf, input_bytes))
+}
+
+/// 設定に従って変換する。品質を落とした場合は`degraded`に理由を追加する
+pub fn transform(
+    mut decoded_buf: DecodeResult,
+    proxy_config: &ProxyConfig,
+    limits: &Limits,
+    degraded: &mut Vec<Degradation>,
 ) -> Result<DecodeResult> {
-    let mut decoded_buf = download_image(client, &proxy_config.url).await?;
+    if let Some(poster) = proxy_config.poster {
+        decoded_buf = decoded_buf.frame(poster)?;
+    }
+    if let Some(rotation) = proxy_config.rotate {
+        decoded_buf = decoded_buf.rotate(rotation)?;
+    }
+    if let Some(flip) = proxy_config.flip {
+        decoded_buf = decoded_buf.flip(flip)?;
+    }
+    let (sizes, dpr, filter) = (&proxy_config.sizes, proxy_config.dpr, proxy_config.filter);
     match proxy_config.is_static {
-        true => decoded_buf = decoded_buf.static_()?,
+        true => decoded_buf = decoded_buf.static_(sizes, dpr, filter)?,
         false => {
             // do nothing
         }
     }
 
-    match proxy_config.convert_type {
-        ConvertType::Emoji => decoded_buf = decoded_buf.emoji()?,
-        ConvertType::Avatar => decoded_buf = decoded_buf.avatar()?,
-        ConvertType::Preview => decoded_buf = decoded_buf.preview()?,
-        ConvertType::Badge => decoded_buf = decoded_buf.badge()?,
-        ConvertType::Original => {
-            // do nothing
+    if proxy_config.max_frames.is_some() || proxy_config.max_duration.is_some() {
+        if decoded_buf.truncate_cuts(proxy_config.max_frames, proxy_config.max_duration) {
+            degraded.push(Degradation::Truncated);
+        }
+        decoded_buf = decoded_buf.truncate(proxy_config.max_frames, proxy_config.max_duration)?;
+    }
+
+    if proxy_config.trim {
+        decoded_buf = decoded_buf.trim()?;
+    }
+
+    match proxy_config.fit {
+        None => {
+            match proxy_config.convert_type {
+                ConvertType::Emoji => decoded_buf = decoded_buf.emoji(sizes, dpr, filter)?,
+                ConvertType::Avatar => decoded_buf = decoded_buf.avatar(sizes, dpr, filter)?,
+                ConvertType::Preview => decoded_buf = decoded_buf.preview(sizes, dpr, filter)?,
+                ConvertType::Badge => decoded_buf = decoded_buf.badge(sizes, dpr, filter)?,
+                ConvertType::Original => {
+                    // do nothing
+                }
+            }
+
+            if let Some(max_size) = proxy_config.max_size {
+                decoded_buf = decoded_buf.fit(Some(max_size), Some(max_size), filter)?;
+            }
+
+            if proxy_config.width.is_some() || proxy_config.height.is_some() {
+                decoded_buf = decoded_buf.fit(proxy_config.width, proxy_config.height, filter)?;
+            }
+        }
+        Some(mode) => {
+            if let Some(max_size) = proxy_config.max_size {
+                decoded_buf = decoded_buf.fit(Some(max_size), Some(max_size), filter)?;
+            }
+
+            // `w`と`h`が指定されていれば変換タイプの枠より優先する
+            let (box_w, box_h) = proxy_config
+                .convert_type
+                .box_size(&proxy_config.sizes, proxy_config.dpr);
+            let width = proxy_config.width.or(box_w);
+            let height = proxy_config.height.or(box_h);
+            decoded_buf = match (width, height) {
+                (Some(w), Some(h)) => decoded_buf.fit_box(w, h, mode, filter)?,
+                // 片方の辺しか決まらない場合はアスペクト比を維持する
+                (w, h) => decoded_buf.fit(w, h, filter)?,
+            };
         }
     }
 
+    if let Some((max_width, max_height)) = proxy_config.animated_max_size {
+        if decoded_buf.is_animated() {
+            decoded_buf = decoded_buf.fit(Some(max_width), Some(max_height), filter)?;
+        }
+    }
+
+    if proxy_config.square {
+        decoded_buf = decoded_buf.pad_square(limits)?;
+    }
+
+    if let Some(sigma) = proxy_config.blur {
+        decoded_buf = decoded_buf.blur(sigma)?;
+    }
+
+    if let Some(color) = proxy_config.background {
+        decoded_buf = decoded_buf.flatten(color)?;
+    }
+
+    if proxy_config.grayscale {
+        decoded_buf = decoded_buf.grayscale()?;
+    }
+
+    for op in &proxy_config.ops {
+        decoded_buf = op.apply(decoded_buf, proxy_config.filter, limits)?;
+    }
+
+    if let Some(fps) = proxy_config.fps {
+        if decoded_buf.resample_drops_frames(fps) {
+            degraded.push(Degradation::FramesDropped);
+        }
+        decoded_buf = decoded_buf.resample(fps)?;
+    }
+
     Ok(decoded_buf)
 }
+
+/// スプライトシートのクエリ
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct SheetQuery {
+    url: String,
+    cols: Option<u32>,
+    rows: Option<u32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SheetConfig {
+    pub url: Url,
+    pub cols: u32,
+    pub rows: u32,
+}
+
+impl TryFrom<SheetQuery> for SheetConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: SheetQuery) -> Result<Self, Self::Error> {
+        const DEFAULT_GRID: u32 = 4;
+        const MAX_GRID: u32 = 8;
+
+        let url = parse_source_url(&value.url)?;
+        let cols = value.cols.unwrap_or(DEFAULT_GRID);
+        let rows = value.rows.unwrap_or(DEFAULT_GRID);
+        if !(1..=MAX_GRID).contains(&cols) || !(1..=MAX_GRID).contains(&rows) {
+            return Err(anyhow::anyhow!(
+                "cols and rows must be between 1 and {}",
+                MAX_GRID
+            ));
+        }
+
+        Ok(SheetConfig { url, cols, rows })
+    }
+}
+
+pub async fn sprite_sheet(
+    client: &Client,
+    sheet_config: &SheetConfig,
+    limits: &Limits,
+) -> Result<DecodeResult> {
+    let fetched = fetch_image(client, &sheet_config.url, limits).await?;
+    let (cols, rows) = (sheet_config.cols, sheet_config.rows);
+    let limits = limits.clone();
+    // デコードと縮小は重いのでブロッキングスレッドで行う
+    tokio::task::spawn_blocking(move || {
+        decode_image(fetched, &limits, DecodeOptions::default())?.sheet(cols, rows)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+    use serde_json::json;
+
+    #[rstest]
+    #[case("ff8000", Some([255, 128, 0, 255]))]
+    #[case("#FF800080", Some([255, 128, 0, 128]))]
+    #[case("ff80", None)]
+    #[case("gg8000", None)]
+    #[case("ffあ00", None)]
+    fn parse_color_test(#[case] s: &str, #[case] expected: Option<[u8; 4]>) {
+        assert_eq!(parse_color(s).ok(), expected);
+    }
+
+    #[rstest]
+    #[case(
+        "trim,resize:256x128:cover,round,blur:4",
+        Some(vec![
+            Op::Trim,
+            Op::Resize { width: 256, height: 128, mode: FitMode::Cover },
+            Op::Round,
+            Op::Blur(4.0),
+        ])
+    )]
+    #[case(
+        "resize:64x64,rotate:90,flip:h,bg:ffffff",
+        Some(vec![
+            Op::Resize { width: 64, height: 64, mode: FitMode::Contain },
+            Op::Rotate(Rotation::Rotate90),
+            Op::Flip(Flip::H),
+            Op::Background([255, 255, 255, 255]),
+        ])
+    )]
+    #[case("blur:1000", Some(vec![Op::Blur(MAX_BLUR)]))]
+    #[case("resize:0x64", None)]
+    #[case("resize:64", None)]
+    #[case("resize:64x64:stretch", None)]
+    #[case("rotate:45", None)]
+    #[case("blur:-1", None)]
+    #[case("trim:1", None)]
+    #[case("sharpen", None)]
+    #[case(&["trim"; MAX_OPS + 1].join(","), None)]
+    fn parse_ops_test(#[case] s: &str, #[case] expected: Option<Vec<Op>>) {
+        assert_eq!(parse_ops(s).ok(), expected);
+    }
+
+    #[rstest]
+    #[case(json!({"url": "https://example.com/a.png", "avatar": 1}), Some(2.0), Some(300))]
+    #[case(json!({"url": "https://example.com/a.png", "preview": 1, "dpr": 1.5, "w": 100}), Some(1.5), Some(100))]
+    #[case(json!({"url": "https://example.com/a.png", "emoji": 1, "avatar": 1}), None, None)]
+    #[case(json!({"url": "https://example.com/a.png"}), None, None)]
+    fn with_client_hints_test(
+        #[case] query: serde_json::Value,
+        #[case] dpr: Option<f32>,
+        #[case] w: Option<u32>,
+    ) {
+        let query: ProxyQuery = serde_json::from_value(query).unwrap();
+        let query = query.with_client_hints(Some(2.0), Some(300));
+        assert_eq!((query.dpr, query.w), (dpr, w));
+    }
+
+    #[rstest]
+    #[case("proxy/emoji.webp", json!({}), ConvertType::Emoji, false)]
+    #[case("proxy/avatar.webp", json!({}), ConvertType::Avatar, false)]
+    #[case("proxy/preview.webp", json!({}), ConvertType::Preview, false)]
+    #[case("badge.png", json!({}), ConvertType::Badge, false)]
+    #[case("proxy/static.webp", json!({}), ConvertType::Original, true)]
+    #[case("proxy/emoji.webp", json!({"avatar": 1}), ConvertType::Avatar, false)]
+    #[case("proxy/image.webp", json!({}), ConvertType::Original, false)]
+    #[case("emoji/image.webp", json!({}), ConvertType::Original, false)]
+    fn with_path_filename_test(
+        #[case] path: &str,
+        #[case] mut query: serde_json::Value,
+        #[case] convert_type: ConvertType,
+        #[case] is_static: bool,
+    ) {
+        query["url"] = json!("https://example.com/a.png");
+        let query: ProxyQuery = serde_json::from_value(query).unwrap();
+        let config = ProxyConfig::try_from(query.with_path_filename(path)).unwrap();
+        assert_eq!(
+            (config.convert_type, config.is_static),
+            (convert_type, is_static)
+        );
+    }
+
+    #[rstest]
+    #[case("proxy/emoji.webp", json!({}), Some(OutputFormat::Webp))]
+    #[case("proxy/avatar.PNG", json!({}), Some(OutputFormat::Png))]
+    #[case("proxy/image.gif", json!({}), Some(OutputFormat::Gif))]
+    #[case("proxy/emoji.png", json!({"format": "webp"}), Some(OutputFormat::Webp))]
+    #[cfg_attr(
+        feature = "avif",
+        case("proxy/image.avif", json!({}), Some(OutputFormat::Avif))
+    )]
+    #[cfg_attr(not(feature = "avif"), case("proxy/image.avif", json!({}), None))]
+    #[case("proxy/image", json!({}), None)]
+    #[case("proxy.png/image", json!({}), None)]
+    fn with_path_filename_format_test(
+        #[case] path: &str,
+        #[case] mut query: serde_json::Value,
+        #[case] expected: Option<OutputFormat>,
+    ) {
+        query["url"] = json!("https://example.com/a.png");
+        let query: ProxyQuery = serde_json::from_value(query).unwrap();
+        let config = ProxyConfig::try_from(query.with_path_filename(path)).unwrap();
+        assert_eq!(config.format, expected);
+    }
+
+    #[rstest]
+    #[case(json!({"url": "https://example.com/a.webp", "static": 1}), true)]
+    #[case(json!({"url": "https://example.com/a.webp", "badge": 1}), true)]
+    #[case(json!({"url": "https://example.com/a.webp", "format": "png"}), true)]
+    #[case(json!({"url": "https://example.com/a.webp", "frame": 0}), true)]
+    #[case(json!({"url": "https://example.com/a.webp", "static": 1, "frame": 3}), false)]
+    #[case(json!({"url": "https://example.com/a.webp", "format": "png", "t": 500}), false)]
+    #[case(json!({"url": "https://example.com/a.webp", "badge": 1, "format": "webp"}), false)]
+    #[case(json!({"url": "https://example.com/a.webp", "preview": 1}), false)]
+    fn first_frame_only_test(#[case] query: serde_json::Value, #[case] expected: bool) {
+        let query: ProxyQuery = serde_json::from_value(query).unwrap();
+        let config = ProxyConfig::try_from(query).unwrap();
+        assert_eq!(config.first_frame_only(), expected);
+    }
+
+    #[rstest]
+    #[case(json!({"url": "https://example.com/a.jpg", "emoji": 1}), 0.1)]
+    #[case(json!({"url": "https://example.com/a.jpg", "emoji": 1, "dpr": 2}), 0.2)]
+    #[case(json!({"url": "https://example.com/a.jpg", "preview": 1}), 0.15625)]
+    #[case(json!({"url": "https://example.com/a.jpg", "w": 400}), 0.2)]
+    #[case(json!({"url": "https://example.com/a.jpg", "w": 400, "rotate": 90}), 0.3125)]
+    #[case(json!({"url": "https://example.com/a.jpg", "w": 400, "h": 400, "fit": "cover"}), 0.3125)]
+    #[case(json!({"url": "https://example.com/a.jpg", "w": 400, "h": 400, "fit": "contain"}), 0.2)]
+    #[case(json!({"url": "https://example.com/a.jpg", "w": 4000}), 1.0)]
+    #[case(json!({"url": "https://example.com/a.jpg", "emoji": 1, "trim": 1}), 1.0)]
+    #[case(json!({"url": "https://example.com/a.jpg", "emoji": 1, "ops": "grayscale"}), 1.0)]
+    fn decode_scale_test(#[case] query: serde_json::Value, #[case] expected: f64) {
+        let query: ProxyQuery = serde_json::from_value(query).unwrap();
+        let config = ProxyConfig::try_from(query).unwrap();
+        // 2000x1280の画像
+        assert!((config.decode_scale(2000, 1280) - expected).abs() < 1e-9);
+    }
+
+    #[rstest]
+    #[case(json!({"url": "https://example.com/a.svg", "emoji": 1}), (None, Some(128)))]
+    #[case(json!({"url": "https://example.com/a.svg", "emoji": 1, "static": 1}), (None, Some(128)))]
+    #[case(json!({"url": "https://example.com/a.svg", "w": 400, "rotate": 90}), (None, Some(400)))]
+    #[case(json!({"url": "https://example.com/a.svg", "w": 400, "h": 300, "fit": "contain"}), (Some(400), Some(300)))]
+    #[case(json!({"url": "https://example.com/a.svg", "w": 400, "h": 300, "fit": "cover"}), (None, None))]
+    #[case(json!({"url": "https://example.com/a.svg", "preview": 1}), (None, None))]
+    #[case(json!({"url": "https://example.com/a.svg", "emoji": 1, "trim": 1}), (None, None))]
+    fn svg_box_test(
+        #[case] query: serde_json::Value,
+        #[case] expected: (Option<u32>, Option<u32>),
+    ) {
+        let query: ProxyQuery = serde_json::from_value(query).unwrap();
+        let config = ProxyConfig::try_from(query).unwrap();
+        assert_eq!(config.svg_box(), expected);
+    }
+
+    #[test]
+    fn refresh_test() {
+        let query = |refresh: bool| {
+            let mut query = json!({"url": "https://example.com/a.png", "emoji": 1});
+            if refresh {
+                query["refresh"] = json!(1);
+            }
+            ProxyConfig::try_from(serde_json::from_value::<ProxyQuery>(query).unwrap()).unwrap()
+        };
+        let refresh = query(true);
+        assert!(refresh.refresh);
+        assert!(!query(false).refresh);
+        // 取得し直した結果で同じキャッシュを上書きする
+        assert_eq!(refresh.cache_key(), query(false).cache_key());
+        assert!(refresh.to_query().contains(&("refresh", "1".to_string())));
+    }
+
+    #[rstest]
+    #[case(None, "max-age=31536000, immutable")]
+    #[case(Some(3600), "max-age=3600")]
+    #[case(Some(1), "max-age=60")]
+    #[case(Some(100_000_000), "max-age=86400")]
+    fn ttl_test(#[case] ttl: Option<u32>, #[case] expected: &str) {
+        let mut query = json!({"url": "https://example.com/a.png", "emoji": 1});
+        if let Some(ttl) = ttl {
+            query["ttl"] = json!(ttl);
+        }
+        let config = ProxyConfig::try_from(serde_json::from_value::<ProxyQuery>(query).unwrap())
+            .unwrap()
+            .clamp_ttl(60, 86400);
+        assert_eq!(config.cache_control(), expected);
+        // 同じ画像なのでキャッシュは共有する
+        assert_eq!(
+            config.cache_key(),
+            ProxyConfig::new(config.url.clone(), ConvertType::Emoji).cache_key()
+        );
+    }
+
+    #[rstest]
+    #[case(None, None, None)]
+    #[case(None, Some(10_000), Some(10_000))]
+    #[case(Some(3_000), Some(10_000), Some(3_000))]
+    #[case(Some(30_000), Some(10_000), Some(10_000))]
+    fn clamp_duration_test(
+        #[case] preview_duration: Option<u32>,
+        #[case] max_duration: Option<u32>,
+        #[case] expected: Option<u32>,
+    ) {
+        let query = json!({"url": "https://example.com/a.gif", "preview": 1});
+        let config = ProxyConfig::try_from(serde_json::from_value::<ProxyQuery>(query).unwrap())
+            .unwrap()
+            .limit_preview(None, preview_duration)
+            .clamp_duration(max_duration);
+        assert_eq!(config.max_duration, expected);
+    }
+
+    #[rstest]
+    #[case::animated(2, (96, 96))]
+    #[case::still(1, (128, 128))]
+    fn limit_animated_test(#[case] frame_count: u8, #[case] expected: (u32, u32)) {
+        let query = json!({"url": "https://example.com/a.gif", "emoji": 1});
+        let config = ProxyConfig::try_from(serde_json::from_value::<ProxyQuery>(query).unwrap())
+            .unwrap()
+            .limit_animated(None, Some(96));
+        let frames = (0..frame_count)
+            .map(|i| {
+                let img = image::RgbaImage::from_pixel(256, 256, image::Rgba([i, 0, 0, 255]));
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            })
+            .collect();
+        let decoded = match frame_count {
+            1 => DecodeResult::Image(image::RgbaImage::new(256, 256)),
+            _ => DecodeResult::Movie(frames),
+        };
+        let transformed = transform(decoded, &config, &Limits::default(), &mut vec![]).unwrap();
+        let size = match transformed {
+            DecodeResult::Image(img) => img.dimensions(),
+            DecodeResult::Movie(frames) => frames[0].buffer().dimensions(),
+            _ => panic!("transform must keep decoded frames"),
+        };
+        assert_eq!(size, expected);
+    }
+
+    #[test]
+    fn ops_roundtrip() {
+        let s = "trim,resize:256x256:contain,round,rotate:270,flip:v,grayscale,blur:2.5,bg:ff800080,square";
+        let ops = parse_ops(s).unwrap();
+        let joined: Vec<String> = ops.iter().map(Op::to_string).collect();
+        assert_eq!(joined.join(","), s);
+    }
+}
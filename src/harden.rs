@@ -0,0 +1,420 @@
+//! `--harden`で有効にするLandlockとseccompによる制限
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{args::Args, tenant::Tenants};
+
+/// 読み込みを許可するシステムのパス。フォント、証明書、名前解決の設定と共有ライブラリ
+const SYSTEM_READ_PATHS: &[&str] = &[
+    "/usr/share/fonts",
+    "/usr/local/share/fonts",
+    "/etc/fonts",
+    "/etc/ssl",
+    "/etc/pki",
+    "/etc/ca-certificates",
+    "/usr/share/ca-certificates",
+    "/usr/lib/ssl",
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+    "/etc/localtime",
+    "/usr/share/zoneinfo",
+    "/proc/self",
+];
+
+/// 共有ライブラリと動的リンカのパス
+const LIBRARY_PATHS: &[&str] = &["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+
+/// 制限の内容。`Args`から組み立てる
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Policy {
+    /// 読み込みを許可するファイルとディレクトリ
+    pub(crate) read: Vec<PathBuf>,
+    /// 書き込みを許可するファイル
+    pub(crate) write: Vec<PathBuf>,
+    /// 実行を許可するファイル。空の場合は`execve`も禁止する
+    pub(crate) execute: Vec<PathBuf>,
+}
+
+impl Policy {
+    /// 起動時に読み込む設定ファイルと、リクエストの処理中に読み込むファイルを許可する
+    pub(crate) fn from_args(args: &Args) -> Result<Self> {
+        let mut policy = Policy {
+            read: (SYSTEM_READ_PATHS.iter().chain(LIBRARY_PATHS))
+                .map(PathBuf::from)
+                .collect(),
+            ..Default::default()
+        };
+        if let Some(home) = std::env::var_os("HOME") {
+            let home = PathBuf::from(home);
+            policy.read.push(home.join(".fonts"));
+            policy.read.push(home.join(".local/share/fonts"));
+        }
+        policy.read.extend(args.file_root.iter().cloned());
+        if let Some(path) = &args.blocklist {
+            // 置き換えて更新されても読み込めるようにディレクトリごと許可する
+            policy
+                .read
+                .push(path.parent().unwrap_or(Path::new(".")).to_path_buf());
+        }
+        policy.read.extend(args.api_keys.iter().cloned());
+        policy.read.extend(args.origin_credentials.iter().cloned());
+        if let Some(path) = &args.tenants {
+            policy.read.push(path.clone());
+            policy.read.extend(Tenants::fallback_paths(path)?);
+        }
+        if let Some(path) = &args.audit_log {
+            // 作成するとディレクトリへの書き込みが必要になるので先に作っておく
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            policy.write.push(path.clone());
+        }
+        if args.decoder_sandbox {
+            // 動的リンカも実行の権限で開かれる
+            policy.execute.push(std::env::current_exe()?);
+            policy
+                .execute
+                .extend(LIBRARY_PATHS.iter().map(PathBuf::from));
+        }
+        Ok(policy)
+    }
+}
+
+/// Landlockとseccompを適用する。すでにあるスレッドには適用されないので、ランタイムを作る前に呼ぶ
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) fn apply(policy: &Policy) -> Result<()> {
+    // SAFETY: 引数を取らないprctlの呼び出し
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to set no_new_privs");
+    }
+    landlock::restrict(policy).context("failed to apply landlock")?;
+    seccomp::install(!policy.execute.is_empty()).context("failed to apply seccomp")?;
+    Ok(())
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub(crate) fn apply(_policy: &Policy) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "--harden is only supported on Linux x86_64 and aarch64"
+    ))
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod landlock {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt, path::Path};
+
+    use anyhow::{Context, Result};
+
+    use super::Policy;
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_READ_DIR: u64 = 1 << 3;
+    /// ABI v1で扱えるすべての権限
+    const ACCESS_V1: u64 = (1 << 13) - 1;
+    /// ABI v3で追加された`truncate`
+    const ACCESS_TRUNCATE: u64 = 1 << 14;
+    /// ファイルに対して指定できる権限
+    const ACCESS_FILE: u64 =
+        ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_TRUNCATE;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    struct Fd(libc::c_int);
+
+    impl Drop for Fd {
+        fn drop(&mut self) {
+            // SAFETY: 自分で開いたファイルディスクリプタを一度だけ閉じる
+            unsafe { libc::close(self.0) };
+        }
+    }
+
+    fn check(ret: libc::c_long) -> std::io::Result<libc::c_long> {
+        match ret {
+            -1 => Err(std::io::Error::last_os_error()),
+            ret => Ok(ret),
+        }
+    }
+
+    pub(super) fn restrict(policy: &Policy) -> Result<()> {
+        // SAFETY: バージョンの問い合わせではattrにnullを渡す
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        let abi = check(abi).context("landlock is not supported by this kernel")?;
+        let handled = match abi {
+            ..=2 => ACCESS_V1,
+            _ => ACCESS_V1 | ACCESS_TRUNCATE,
+        };
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: 有効な`RulesetAttr`とその大きさを渡している
+        let ruleset = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        })?;
+        let ruleset = Fd(ruleset as libc::c_int);
+
+        let read = ACCESS_READ_FILE | ACCESS_READ_DIR;
+        let rules = (policy.read.iter().map(|p| (p, read)))
+            .chain(
+                policy
+                    .write
+                    .iter()
+                    .map(|p| (p, ACCESS_WRITE_FILE | ACCESS_TRUNCATE)),
+            )
+            .chain(
+                policy
+                    .execute
+                    .iter()
+                    .map(|p| (p, ACCESS_EXECUTE | ACCESS_READ_FILE)),
+            );
+        for (path, access) in rules {
+            add_rule(&ruleset, path, access & handled)
+                .with_context(|| format!("{}", path.display()))?;
+        }
+
+        // SAFETY: 作成したルールセットのファイルディスクリプタを渡している
+        check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.0, 0) })?;
+        Ok(())
+    }
+
+    /// `path`以下に`access`を許可する。存在しないパスは無視する
+    fn add_rule(ruleset: &Fd, path: &Path, access: u64) -> Result<()> {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return Ok(());
+        };
+        let access = match metadata.is_dir() {
+            true => access,
+            false => access & ACCESS_FILE,
+        };
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: nul終端したパスを渡している
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        let fd = Fd(check(fd.into())? as libc::c_int);
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: fd.0,
+        };
+        // SAFETY: 有効な`PathBeneathAttr`を渡している
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.0,
+                RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    use anyhow::Result;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// x32 ABIのシステムコール番号に立つビット
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// 画像の変換には不要で、乗っ取られた場合に悪用されやすいシステムコール
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_personality,
+        libc::SYS_acct,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_name_to_handle_at,
+    ];
+
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// 禁止したシステムコールは`EPERM`で失敗させ、異なるアーキテクチャの呼び出しはプロセスを終了する
+    pub(super) fn program(allow_execve: bool) -> Vec<libc::sock_filter> {
+        let deny = stmt(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        );
+        let mut program = vec![
+            // seccomp_data.arch
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 4),
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                AUDIT_ARCH,
+                1,
+                0,
+            ),
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            // seccomp_data.nr
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        {
+            program.push(jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ));
+            program.push(deny);
+        }
+        let execve = (!allow_execve).then_some(libc::SYS_execve);
+        for nr in DENIED.iter().copied().chain(execve) {
+            program.push(jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                nr as u32,
+                0,
+                1,
+            ));
+            program.push(deny);
+        }
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+        program
+    }
+
+    pub(super) fn install(allow_execve: bool) -> Result<()> {
+        let mut program = program(allow_execve);
+        let prog = libc::sock_fprog {
+            len: program.len().try_into()?,
+            filter: program.as_mut_ptr(),
+        };
+        // SAFETY: `program`は呼び出しの間有効
+        let ret =
+            unsafe { libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, 0, &prog) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn policy_from_args_test() {
+        let dir = std::env::temp_dir().join(format!("harden-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let audit_log = dir.join("audit.log");
+        let args = Args::parse_from([
+            "misskey-webp-proxy",
+            "--file-root=/srv/files",
+            "--blocklist=/etc/proxy/blocklist.txt",
+            &format!("--audit-log={}", audit_log.display()),
+        ]);
+
+        let policy = Policy::from_args(&args).unwrap();
+        assert!(policy.read.contains(&PathBuf::from("/srv/files")));
+        assert!(policy.read.contains(&PathBuf::from("/etc/proxy")));
+        assert_eq!(policy.write, vec![audit_log.clone()]);
+        assert!(policy.execute.is_empty());
+        assert!(audit_log.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn seccomp_program_test() {
+        let denied = seccomp::program(false);
+        let allowed = seccomp::program(true);
+        // 禁止するシステムコールごとに比較と`EPERM`の2命令
+        assert_eq!(denied.len(), allowed.len() + 2);
+        assert_eq!(
+            denied.last().unwrap().k,
+            libc::SECCOMP_RET_ALLOW,
+            "最後はすべて許可する"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::{net::SocketAddr, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::{Body, HttpBody as _},
+    http::{header, HeaderValue, Request, Response},
+    Router,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use h3::server::RequestStream;
+use tower::ServiceExt as _;
+
+/// 受け取るリクエストボディの上限。変換のリクエストはGETなので管理用APIの分だけあればよい
+const MAX_REQUEST_BODY: usize = 2 * 1024 * 1024;
+
+/// PEMファイルの証明書と秘密鍵からQUICのサーバー設定を作る
+pub(crate) fn server_config(cert: &Path, key: &Path) -> Result<quinn::ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(
+        std::fs::File::open(cert).with_context(|| format!("cannot open {}", cert.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("cannot read certificates from {}", cert.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificate in {}", cert.display());
+    let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(
+        std::fs::File::open(key).with_context(|| format!("cannot open {}", key.display()))?,
+    ))
+    .with_context(|| format!("cannot read private key from {}", key.display()))?
+    .with_context(|| format!("no private key in {}", key.display()))?;
+
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// TCPのレスポンスに付けてHTTP/3で待ち受けていることを知らせる`Alt-Svc`の値
+pub(crate) fn alt_svc(port: u16) -> HeaderValue {
+    HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)).unwrap()
+}
+
+/// HTTP/3で待ち受け、受け取ったリクエストを`app`で処理する
+pub(crate) async fn serve(
+    app: Router,
+    addr: SocketAddr,
+    config: quinn::ServerConfig,
+) -> Result<()> {
+    let endpoint = quinn::Endpoint::server(config, addr)?;
+    tracing::info!(%addr, "Waiting HTTP/3 request at {} ...", addr);
+
+    while let Some(incoming) = endpoint.accept().await {
+        let app = app.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(app, incoming).await {
+                tracing::debug!("HTTP/3 connection closed: {:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_connection(app: Router, incoming: quinn::Incoming) -> Result<()> {
+    let conn = incoming.await?;
+    let mut conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+
+    while let Some(resolver) = conn.accept().await? {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let (request, stream) = match resolver.resolve_request().await {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    tracing::debug!("cannot read HTTP/3 request: {:#}", e);
+                    return;
+                }
+            };
+            if let Err(e) = serve_request(app, request, stream).await {
+                tracing::debug!("HTTP/3 stream closed: {:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_request<S>(
+    app: Router,
+    request: Request<()>,
+    mut stream: RequestStream<S, Bytes>,
+) -> Result<()>
+where
+    S: h3::quic::BidiStream<Bytes>,
+{
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        anyhow::ensure!(
+            body.len() + chunk.remaining() <= MAX_REQUEST_BODY,
+            "request body is too large"
+        );
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            body.extend_from_slice(bytes);
+            let len = bytes.len();
+            chunk.advance(len);
+        }
+    }
+
+    let (mut parts, ()) = request.into_parts();
+    // HTTP/3には`Host`ヘッダーがないので、テナントの判定などのために`:authority`から補う
+    if !parts.headers.contains_key(header::HOST) {
+        if let Some(authority) = parts.uri.authority() {
+            if let Ok(value) = HeaderValue::from_str(authority.as_str()) {
+                parts.headers.insert(header::HOST, value);
+            }
+        }
+    }
+    let request = Request::from_parts(parts, Body::from(body.freeze()));
+
+    let response: Response<Body> = app.oneshot(request).await?;
+    let (parts, body) = response.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+
+    let mut body = std::pin::pin!(body);
+    while let Some(frame) = std::future::poll_fn(|cx| body.as_mut().poll_frame(cx)).await {
+        // トレーラーは返さないので、データ以外のフレームは捨てる
+        if let Ok(data) = frame?.into_data() {
+            stream.send_data(data).await?;
+        }
+    }
+    stream.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,374 @@
+use crate::client::ImageExt;
+
+/// ヘッダーのみから読み取った画像の情報
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub animated: bool,
+    /// フレーム数。数えられない形式の場合は`None`
+    pub frame_count: Option<u32>,
+}
+
+impl ImageInfo {
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+
+/// ピクセルをデコードせずにコンテナのヘッダーを読み、大きさやアニメーションの有無を返す
+/// ## Note
+/// 対応していない形式もしくはヘッダーが壊れている場合は`None`を返す
+pub fn inspect(ext: ImageExt, buf: &[u8]) -> Option<ImageInfo> {
+    match ext {
+        ImageExt::Png => inspect_png(buf),
+        ImageExt::Jpeg => inspect_jpeg(buf),
+        ImageExt::Gif => inspect_gif(buf),
+        ImageExt::Webp => inspect_webp(buf),
+        ImageExt::Svg | ImageExt::Ico | ImageExt::Unknown => None,
+    }
+}
+
+fn u16_be(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u16_le(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(buf.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u24_le(buf: &[u8], at: usize) -> Option<u32> {
+    let b = buf.get(at..at + 3)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], 0]))
+}
+
+fn u32_be(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u32_le(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// IHDRから大きさを、acTLからフレーム数を読む
+fn inspect_png(buf: &[u8]) -> Option<ImageInfo> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !buf.starts_with(SIGNATURE) || buf.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32_be(buf, 16)?;
+    let height = u32_be(buf, 20)?;
+
+    // acTLはIDATより前にある
+    let mut frame_count = 1;
+    let mut pos = SIGNATURE.len();
+    while let (Some(len), Some(kind)) = (u32_be(buf, pos), buf.get(pos + 4..pos + 8)) {
+        match kind {
+            b"acTL" => {
+                frame_count = u32_be(buf, pos + 8)?;
+                break;
+            }
+            b"IDAT" | b"IEND" => break,
+            _ => pos = pos.checked_add(12 + len as usize)?,
+        }
+    }
+
+    Some(ImageInfo {
+        width,
+        height,
+        animated: frame_count > 1,
+        frame_count: Some(frame_count),
+    })
+}
+
+/// SOFマーカーから大きさを読む
+fn inspect_jpeg(buf: &[u8]) -> Option<ImageInfo> {
+    if !buf.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut pos = 2;
+    loop {
+        if *buf.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *buf.get(pos + 1)?;
+        match marker {
+            // 埋め草
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // SOF0-SOF15。DHT, JPG, DACは除く
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let height = u16_be(buf, pos + 5)? as u32;
+                let width = u16_be(buf, pos + 7)? as u32;
+                return Some(ImageInfo {
+                    width,
+                    height,
+                    animated: false,
+                    frame_count: Some(1),
+                });
+            }
+            // SOS以降は画像データなのでSOFは見つからない
+            0xD9 | 0xDA => return None,
+            _ => pos += 2 + u16_be(buf, pos + 2)? as usize,
+        }
+    }
+}
+
+/// Logical Screen Descriptorから大きさを読み、Image Descriptorを数える
+fn inspect_gif(buf: &[u8]) -> Option<ImageInfo> {
+    let width = u16_le(buf, 6)? as u32;
+    let height = u16_le(buf, 8)? as u32;
+    let frame_count = gif_delays(buf)?.len() as u32;
+
+    Some(ImageInfo {
+        width,
+        height,
+        animated: frame_count > 1,
+        frame_count: Some(frame_count),
+    })
+}
+
+/// gifの各フレームの表示時間(ミリ秒)をGraphic Control Extensionから読む。ピクセルはデコードしない
+pub fn gif_delays(buf: &[u8]) -> Option<Vec<u32>> {
+    if !(buf.starts_with(b"GIF87a") || buf.starts_with(b"GIF89a")) {
+        return None;
+    }
+    let color_table_size = |packed: u8| {
+        if packed & 0x80 != 0 {
+            3 * (1 << ((packed & 0x07) + 1))
+        } else {
+            0
+        }
+    };
+
+    // データサブブロックを読み飛ばす
+    let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let size = *buf.get(pos)? as usize;
+            pos += 1 + size;
+            if size == 0 {
+                return Some(pos);
+            }
+        }
+    };
+
+    let mut pos = 13 + color_table_size(*buf.get(10)?);
+    let mut delays = vec![];
+    // 直前のGraphic Control Extensionで指定された表示時間
+    let mut delay = 0;
+    loop {
+        match buf.get(pos) {
+            // Graphic Control Extension。表示時間は1/100秒単位
+            Some(0x21) if buf.get(pos + 1) == Some(&0xF9) => {
+                delay = u16_le(buf, pos + 4)? as u32 * 10;
+                pos = skip_sub_blocks(pos + 2)?;
+            }
+            // Extension
+            Some(0x21) => pos = skip_sub_blocks(pos + 2)?,
+            // Image Descriptor
+            Some(0x2C) => {
+                delays.push(std::mem::take(&mut delay));
+                let packed = *buf.get(pos + 9)?;
+                // LZWの最小コードサイズの1byteも飛ばす
+                pos = skip_sub_blocks(pos + 10 + color_table_size(packed) + 1)?;
+            }
+            // Trailerもしくは途中で切れている
+            _ => break,
+        }
+    }
+    Some(delays)
+}
+
+/// VP8/VP8L/VP8Xチャンクから大きさを読み、ANMFチャンクを数える
+fn inspect_webp(buf: &[u8]) -> Option<ImageInfo> {
+    let info = webp_header(buf)?;
+    let frame_count = if info.animated {
+        webp_delays(buf)?.len() as u32
+    } else {
+        1
+    };
+    Some(ImageInfo {
+        frame_count: Some(frame_count),
+        ..info
+    })
+}
+
+/// 最初のVP8/VP8L/VP8Xチャンクのみから大きさとアニメーションの有無を読む。
+/// 先頭だけを取得した場合にも使えるように、アニメーションのフレームは数えない
+pub fn webp_header(buf: &[u8]) -> Option<ImageInfo> {
+    if buf.get(0..4)? != b"RIFF" || buf.get(8..12)? != b"WEBP" {
+        return None;
+    }
+
+    let chunk = buf.get(12..16)?;
+    let data = 20;
+    let (width, height, animated) = match chunk {
+        b"VP8 " => {
+            if buf.get(data + 3..data + 6)? != [0x9D, 0x01, 0x2A] {
+                return None;
+            }
+            let width = (u16_le(buf, data + 6)? & 0x3FFF) as u32;
+            let height = (u16_le(buf, data + 8)? & 0x3FFF) as u32;
+            (width, height, false)
+        }
+        b"VP8L" => {
+            if *buf.get(data)? != 0x2F {
+                return None;
+            }
+            let bits = u32_le(buf, data + 1)?;
+            let width = (bits & 0x3FFF) + 1;
+            let height = ((bits >> 14) & 0x3FFF) + 1;
+            (width, height, false)
+        }
+        b"VP8X" => {
+            let flags = *buf.get(data)?;
+            let width = u24_le(buf, data + 4)? + 1;
+            let height = u24_le(buf, data + 7)? + 1;
+            (width, height, flags & 0x02 != 0)
+        }
+        _ => return None,
+    };
+
+    Some(ImageInfo {
+        width,
+        height,
+        animated,
+        frame_count: (!animated).then_some(1),
+    })
+}
+
+/// アニメーションwebpの各フレームの表示時間(ミリ秒)をANMFチャンクから読む。ピクセルはデコードしない
+pub fn webp_delays(buf: &[u8]) -> Option<Vec<u32>> {
+    if buf.get(0..4)? != b"RIFF" || buf.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut delays = vec![];
+    let mut pos = 12;
+    while let (Some(kind), Some(size)) = (buf.get(pos..pos + 4), u32_le(buf, pos + 4)) {
+        if kind == b"ANMF" {
+            // 位置と大きさの後に表示時間が続く
+            delays.push(u24_le(buf, pos + 8 + 12)?);
+        }
+        // チャンクは偶数バイトに揃えられている
+        pos = pos.checked_add(8 + size as usize + (size as usize & 1))?;
+    }
+    Some(delays)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    use image::{codecs::gif::GifEncoder, Frame, ImageFormat, RgbaImage};
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let img = image::DynamicImage::ImageRgb8(image::RgbImage::new(30, 20));
+        let mut buf = vec![];
+        img.write_to(&mut Cursor::new(&mut buf), format).unwrap();
+        buf
+    }
+
+    #[rstest]
+    #[case(ImageExt::Png, ImageFormat::Png)]
+    #[case(ImageExt::Jpeg, ImageFormat::Jpeg)]
+    #[case(ImageExt::Gif, ImageFormat::Gif)]
+    #[case(ImageExt::Webp, ImageFormat::WebP)]
+    fn inspect_still_test(#[case] ext: ImageExt, #[case] format: ImageFormat) {
+        let info = inspect(ext, &encode(format)).unwrap();
+        assert_eq!(
+            info,
+            ImageInfo {
+                width: 30,
+                height: 20,
+                animated: false,
+                frame_count: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn inspect_gif_anim_test() {
+        let mut buf = vec![];
+        {
+            let mut encoder = GifEncoder::new(&mut buf);
+            let frames = (0..4).map(|_| Frame::new(RgbaImage::new(16, 8)));
+            encoder.encode_frames(frames).unwrap();
+        }
+        let info = inspect(ImageExt::Gif, &buf).unwrap();
+        assert_eq!(info.frame_count, Some(4));
+        assert!(info.animated);
+        assert_eq!((info.width, info.height), (16, 8));
+    }
+
+    #[test]
+    fn gif_delays_test() {
+        let mut buf = vec![];
+        {
+            let mut encoder = GifEncoder::new(&mut buf);
+            let frames = [30, 70, 0].map(|ms| {
+                let delay = image::Delay::from_numer_denom_ms(ms, 1);
+                Frame::from_parts(RgbaImage::new(16, 8), 0, 0, delay)
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        assert_eq!(gif_delays(&buf), Some(vec![30, 70, 0]));
+        assert_eq!(gif_delays(b"\x89PNG"), None);
+    }
+
+    #[test]
+    fn webp_delays_test() {
+        let frames = [30, 70, 100]
+            .into_iter()
+            .enumerate()
+            .map(|(i, ms)| {
+                let img = RgbaImage::from_pixel(16, 8, image::Rgba([i as u8 * 100, 0, 0, 255]));
+                Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(ms, 1))
+            })
+            .collect();
+        let options = crate::webp::WebpOptions::new(75.0);
+        let buf = crate::webp::encode_webp_anim(frames, &options).unwrap();
+        assert_eq!(webp_delays(&buf), Some(vec![30, 70, 100]));
+        assert_eq!(webp_delays(b"\x89PNG"), None);
+    }
+
+    #[test]
+    fn webp_header_test() {
+        let frames = (0..3)
+            .map(|i| {
+                Frame::new(RgbaImage::from_pixel(
+                    16,
+                    8,
+                    image::Rgba([i * 100, 0, 0, 255]),
+                ))
+            })
+            .collect();
+        let options = crate::webp::WebpOptions::new(75.0);
+        let buf = crate::webp::encode_webp_anim(frames, &options).unwrap();
+        // 先頭のチャンクだけでも大きさとアニメーションの有無が分かる
+        let expected = ImageInfo {
+            width: 16,
+            height: 8,
+            animated: true,
+            frame_count: None,
+        };
+        assert_eq!(webp_header(&buf[..30]), Some(expected));
+
+        let still = webp_header(&encode(ImageFormat::WebP)).unwrap();
+        assert_eq!(still.frame_count, Some(1));
+        assert_eq!(webp_header(b"\x89PNG"), None);
+    }
+
+    #[test]
+    fn inspect_broken_test() {
+        assert_eq!(inspect(ImageExt::Png, b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(inspect(ImageExt::Jpeg, &[0xFF, 0xD8, 0xFF]), None);
+        assert_eq!(inspect(ImageExt::Webp, b"RIFF\0\0\0\0WEBP"), None);
+    }
+}
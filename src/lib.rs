@@ -1,4 +1,18 @@
-mod client;
-mod handler;
-mod processor;
-mod webp;
+#[cfg(feature = "avif")]
+pub mod avif;
+pub mod client;
+pub mod convert;
+pub mod dns;
+pub mod favicon;
+pub mod handler;
+pub mod inspect;
+pub mod processor;
+pub mod ratelimit;
+pub mod sandbox;
+pub mod sigv4;
+pub mod timing;
+pub mod webp;
+
+pub use convert::{convert, ConvertOptions, Converted};
+pub use handler::{ConvertType, OutputFormat};
+pub use processor::{Degradation, PresetSizes, ResizeFilter};
@@ -1,68 +1,1331 @@
+mod apikey;
 mod args;
-mod client;
-mod handler;
-mod processor;
-mod webp;
+mod blocklist;
+mod cache;
+mod check;
+mod cluster;
+mod doctor;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod harden;
+#[cfg(feature = "http3")]
+mod http3;
+mod openapi;
+#[cfg(feature = "redis")]
+mod singleflight;
+mod stats;
+mod tenant;
+mod warmup;
+mod webhook;
 
-use std::sync::Arc;
+use misskey_webp_proxy::{
+    client,
+    convert::{self},
+    dns, favicon, handler, inspect, processor, ratelimit, sandbox, sigv4, timing, webp,
+};
+
+use std::{sync::Arc, time::Duration};
 
+use apikey::ApiKeys;
 use args::Args;
 use axum::{
+    body::HttpBody as _,
     extract,
     http::{header, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
     routing, Router,
 };
+use blocklist::Blocklist;
+use cache::MemoryCache;
 use clap::Parser;
-use client::get_client;
-use handler::{media_proxy, ProxyConfig, ProxyQuery};
+use client::{
+    get_client, get_image_ext, guess_format, open_passthrough, probe_webp, ClientConfig, ImageExt,
+    Limits, Passthrough, UrlFilter,
+};
+use cluster::Cluster;
+use convert::Converted;
+use favicon::find_favicon;
+use futures_util::TryStreamExt as _;
+use handler::{
+    media_proxy, sprite_sheet, PrefetchEntry, ProxyConfig, ProxyQuery, SheetConfig, SheetQuery,
+};
+use inspect::ImageInfo;
+use ratelimit::OriginRateLimiter;
 use reqwest::Client;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt as _};
+use sandbox::DecoderSandbox;
+use sigv4::OriginCredentials;
+use stats::Stats;
+use tenant::Tenants;
+use timing::ServerTiming;
+use tracing_subscriber::{
+    filter::Targets, layer::SubscriberExt, util::SubscriberInitExt as _, Layer as _,
+};
+use webhook::PrefetchWebhook;
+use webp::WebpOptions;
+
+const IMAGE_WIDTH_HEADER: header::HeaderName = header::HeaderName::from_static("x-image-width");
+const IMAGE_HEIGHT_HEADER: header::HeaderName = header::HeaderName::from_static("x-image-height");
+const IMAGE_FRAME_COUNT_HEADER: header::HeaderName =
+    header::HeaderName::from_static("x-image-frame-count");
+/// 完全な品質で変換できなかった場合に理由をカンマ区切りで返す
+const DEGRADED_HEADER: header::HeaderName = header::HeaderName::from_static("x-proxy-degraded");
+/// リクエストごとに振るID。受け取ったリクエストに付いている場合はそれを使う
+const REQUEST_ID_HEADER: header::HeaderName = header::HeaderName::from_static("x-request-id");
+/// 期限切れのキャッシュを返す場合の`max-age`(秒)
+const STALE_MAX_AGE: u64 = 60;
+/// 取得元へリダイレクトする場合の`max-age`(秒)。取得元の画像は差し替えられることがあるので長くしない
+const REDIRECT_MAX_AGE: u64 = 300;
+/// APIキーのシークレットを指定するヘッダー
+const API_KEY_HEADER: header::HeaderName = header::HeaderName::from_static("x-api-key");
+const SEC_CH_DPR: header::HeaderName = header::HeaderName::from_static("sec-ch-dpr");
+const SEC_CH_WIDTH: header::HeaderName = header::HeaderName::from_static("sec-ch-width");
+/// `Accept-CH`と`Vary`に列挙するClient Hints
+const CLIENT_HINTS: &str = "Sec-CH-DPR, Sec-CH-Width";
+
+/// 各ハンドラで共有する状態
+struct AppState {
+    client: Client,
+    /// WebPのエンコード設定
+    webp: WebpOptions,
+    /// 変換の種類ごとに上書きするWebPのエンコード設定
+    webp_by_type: Vec<(handler::ConvertType, WebpOptions)>,
+    max_width: u32,
+    max_height: u32,
+    /// 拡大縮小に使うフィルタ
+    resize_filter: processor::ResizeFilter,
+    /// `quality`パラメータで指定できる圧縮率の上限
+    max_quality: u8,
+    /// アニメーションを揃えるフレームレート
+    max_fps: Option<u32>,
+    /// originalの長辺の上限
+    original_max_size: Option<u32>,
+    /// previewで残すアニメーションのフレーム数
+    preview_max_frames: Option<u32>,
+    /// previewで残すアニメーションの時間(ミリ秒)
+    preview_max_duration: Option<u32>,
+    /// すべてのアニメーションで残す時間(ミリ秒)
+    max_animation_duration: Option<u32>,
+    /// `ttl`で指定できるブラウザのキャッシュの秒数の下限
+    min_ttl: u32,
+    /// `ttl`で指定できるブラウザのキャッシュの秒数の上限
+    max_ttl: u32,
+    /// アニメーションの出力の幅の上限
+    animated_max_width: Option<u32>,
+    /// アニメーションの出力の高さの上限
+    animated_max_height: Option<u32>,
+    limits: Limits,
+    cache: MemoryCache,
+    /// 変換に失敗した場合に期限切れのキャッシュを返す期間。`None`の場合は返さない
+    stale_if_error: Option<Duration>,
+    stats: Arc<Stats>,
+    /// 管理用APIのトークン。`None`の場合は管理用APIを無効にする
+    admin_token: Option<String>,
+    /// 変換が不要なWebPは取得元へリダイレクトする
+    redirect_origin_webp: bool,
+    stream_passthrough: bool,
+    /// avatarとpreviewでClient Hintsに従う
+    client_hints: bool,
+    /// `Accept`で受け取れるクライアントにはアニメーションをAVIFで返す
+    #[cfg(feature = "avif")]
+    animated_avif: bool,
+    /// 起動時に確かめた項目。失敗した場合はエラーの内容
+    startup_checks: Vec<(&'static str, Result<(), String>)>,
+    /// エンコード待ちがこの数を超えると準備ができていないとみなす
+    ready_max_queued: usize,
+    /// 変換のリクエストのパスとクエリの合計の長さの上限
+    max_uri_length: usize,
+    /// 変換のリクエストのクエリパラメータの数の上限
+    max_query_params: usize,
+    /// エンコード待ちがこの数を超えると変換のリクエストを断る
+    shed_max_queued: Option<usize>,
+    /// 断る際に返す`Retry-After`の秒数
+    shed_retry_after: u64,
+    /// 取得を拒否するURL
+    blocklist: Option<Arc<Blocklist>>,
+    /// `Host`ヘッダーごとの設定
+    tenants: Option<Tenants>,
+    /// 変換の後にバックグラウンドで作る同じ画像の別の変換
+    eager_variants: Vec<args::EagerVariant>,
+    /// 変換に必要なAPIキー。`None`の場合は認証しない
+    api_keys: Option<ApiKeys>,
+    /// 取得前に問い合わせる外部のサービス
+    prefetch_webhook: Option<PrefetchWebhook>,
+    /// クラスタモードの場合、変換を担当するノードを決める
+    cluster: Option<Cluster>,
+    /// レプリカ間で変換をまとめる。`None`の場合はまとめない
+    #[cfg(feature = "redis")]
+    singleflight: Option<singleflight::RedisSingleflight>,
+}
+
+/// ログに出るので、トークンなどの秘密とキャッシュの中身は出力しない
+impl std::fmt::Debug for AppState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppState")
+            .field("webp", &self.webp)
+            .field("max_width", &self.max_width)
+            .field("max_height", &self.max_height)
+            .field("resize_filter", &self.resize_filter)
+            .field("limits", &self.limits)
+            .field("cache_enabled", &self.cache.is_enabled())
+            .field(
+                "admin_token",
+                &self.admin_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("api_keys", &self.api_keys.is_some())
+            .field("cluster", &self.cluster.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AppState {
+    /// 変換の種類に対応するWebPのエンコード設定
+    fn webp_options(&self, convert_type: handler::ConvertType) -> WebpOptions {
+        self.webp_by_type
+            .iter()
+            .find(|(t, _)| *t == convert_type)
+            .map_or(self.webp, |(_, options)| *options)
+    }
+
+    /// サーバーの設定による制限を反映する
+    fn clamp(&self, mut config: ProxyConfig) -> ProxyConfig {
+        config.filter = self.resize_filter;
+        // 無効な場合は出力形式の指定がないものとして扱う
+        #[cfg(feature = "avif")]
+        if !self.animated_avif && config.format == Some(handler::OutputFormat::Avif) {
+            config.format = None;
+        }
+        config
+            .clamp_size(self.max_width, self.max_height)
+            .clamp_quality(self.max_quality)
+            .clamp_fps(self.max_fps)
+            .limit_preview(self.preview_max_frames, self.preview_max_duration)
+            .clamp_duration(self.max_animation_duration)
+            .limit_original(self.original_max_size)
+            .limit_animated(self.animated_max_width, self.animated_max_height)
+            .clamp_ttl(self.min_ttl, self.max_ttl)
+    }
+
+    /// `Accept`によって出力形式を選ぶか。静止画にしかならない変換では選ばない
+    #[cfg(feature = "avif")]
+    fn negotiates_avif(&self, config: &ProxyConfig) -> bool {
+        self.animated_avif
+            && config.format.is_none()
+            && config.convert_type != handler::ConvertType::Badge
+            && !config.first_frame_only()
+    }
+
+    /// `Host`ヘッダーに対応する設定
+    fn tenant(&self, headers: &header::HeaderMap) -> Option<&tenant::Tenant> {
+        let host = headers.get(header::HOST)?.to_str().ok()?;
+        self.tenants.as_ref()?.get(host)
+    }
+
+    /// 管理用APIのトークンを検証する
+    fn authorize_admin(&self, headers: &header::HeaderMap) -> Result<(), AppError> {
+        let Some(token) = &self.admin_token else {
+            return Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                anyhow::anyhow!("Admin API is disabled"),
+            ));
+        };
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if bearer != Some(token.as_str()) {
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                anyhow::anyhow!("Invalid admin token"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// キャッシュを使わずに取得し直してよいか。管理用のトークンか署名が必要で、
+    /// 署名を検証できたクラスタ内の転送(`forwarded`)は転送元で確認済み
+    fn may_refresh(&self, headers: &header::HeaderMap, signed: bool, forwarded: bool) -> bool {
+        forwarded || signed || self.authorize_admin(headers).is_ok()
+    }
+
+    /// 設定で無効なスキーム、ブロックリストに一致するURLもしくはwebhookに拒否されたURLを拒否する。
+    /// `kind`は変換の種類で、webhookにそのまま渡す
+    async fn authorize_fetch(&self, url: &reqwest::Url, kind: &str) -> Result<(), AppError> {
+        client::check_source_scheme(url, &self.limits)?;
+        if let Some(blocklist) = &self.blocklist {
+            if blocklist.is_blocked(url) {
+                return Err(AppError::new(
+                    StatusCode::FORBIDDEN,
+                    anyhow::anyhow!("Blocked url: {}", url),
+                ));
+            }
+        }
+        if let Some(webhook) = &self.prefetch_webhook {
+            // webhookに問い合わせられない場合は取得しない
+            let allowed = webhook
+                .authorize(url, kind)
+                .await
+                .map_err(|e| AppError::new(StatusCode::SERVICE_UNAVAILABLE, e))?;
+            if !allowed {
+                return Err(AppError::new(
+                    StatusCode::FORBIDDEN,
+                    anyhow::anyhow!("Rejected by webhook: {}", url),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state, headers, signed, forwarded), fields(
+    convert_type = tracing::field::Empty,
+    cache = tracing::field::Empty,
+    input_format = tracing::field::Empty,
+    input_width = tracing::field::Empty,
+    input_height = tracing::field::Empty,
+    input_frames = tracing::field::Empty,
+    input_bytes = tracing::field::Empty,
+    output_width = tracing::field::Empty,
+    output_height = tracing::field::Empty,
+    output_frames = tracing::field::Empty,
+    output_bytes = tracing::field::Empty,
+))]
 async fn proxy_handler(
-    extract::State(state): extract::State<Arc<(Client, f32)>>,
+    extract::State(state): extract::State<Arc<AppState>>,
+    headers: header::HeaderMap,
+    signed: Option<extract::Extension<SignedRequest>>,
+    forwarded: Option<extract::Extension<ForwardedRequest>>,
     extract::Query(query): extract::Query<ProxyQuery>,
-) -> Result<impl IntoResponse, AppError> {
-    let config: ProxyConfig = query.try_into()?;
-    let client = &state.0;
-    let quality_factor = state.1;
+) -> Result<Response, AppError> {
+    // 署名を検証できた転送だけを信用する
+    let forwarded = forwarded.is_some();
+    // 転送されたリクエストは転送元で`Host`ごとの設定を反映済み
+    let tenant = match forwarded {
+        false => state.tenant(&headers),
+        true => None,
+    };
+    let vary = state.client_hints && query.uses_client_hints();
+    let mut config: ProxyConfig = match state.client_hints {
+        true => {
+            let (dpr, width) = client_hints(&headers);
+            query.with_client_hints(dpr, width).try_into()?
+        }
+        false => query.try_into()?,
+    };
+    config.refresh = (config.refresh || requests_no_cache(&headers))
+        && state.may_refresh(&headers, signed.is_some(), forwarded);
+    // 転送されたリクエストは転送元で選んだ形式をクエリに持つ
+    #[cfg(feature = "avif")]
+    let vary_accept = !forwarded && state.negotiates_avif(&config);
+    #[cfg(feature = "avif")]
+    if vary_accept && accepts_avif(&headers) {
+        config.format = Some(handler::OutputFormat::Avif);
+    }
+    let (url, convert_type, format) = (config.url.clone(), config.convert_type, config.format);
+    let mut response = tenant_response(&state, tenant, config, forwarded).await?;
+    // 転送されたリクエストの別の変換は転送元で作る
+    if response.status().is_success() && !forwarded {
+        let host = headers.get(header::HOST).and_then(|v| v.to_str().ok());
+        spawn_eager_variants(&state, url, convert_type, format, host);
+    }
+    #[cfg(feature = "avif")]
+    if vary_accept {
+        response
+            .headers_mut()
+            .append(header::VARY, header::HeaderValue::from_static("Accept"));
+    }
+    if !state.client_hints {
+        return Ok(response);
+    }
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::HeaderName::from_static("accept-ch"),
+        header::HeaderValue::from_static(CLIENT_HINTS),
+    );
+    if vary {
+        response_headers.append(header::VARY, header::HeaderValue::from_static(CLIENT_HINTS));
+    }
+    Ok(response)
+}
+
+/// `convert_type`の変換の後に設定された別の変換をバックグラウンドで作り、キャッシュに追加する。
+/// 出力形式はリクエストされた変換に合わせる。
+/// すでにキャッシュにあるものと、クラスタモードで他のノードが担当するものは作らない
+fn spawn_eager_variants(
+    state: &Arc<AppState>,
+    url: reqwest::Url,
+    convert_type: handler::ConvertType,
+    format: Option<handler::OutputFormat>,
+    host: Option<&str>,
+) {
+    let variants: Vec<_> = state
+        .eager_variants
+        .iter()
+        .filter(|v| v.from == convert_type)
+        .map(|v| ProxyConfig {
+            is_static: v.is_static,
+            format,
+            ..ProxyConfig::new(url.clone(), v.to)
+        })
+        .collect();
+    // 変換が混んでいる場合はリクエストされた変換を優先する
+    if variants.is_empty()
+        || !state.cache.is_enabled()
+        || state.stats.queued() > state.ready_max_queued
+    {
+        return;
+    }
+
+    let state = state.clone();
+    let host = host.map(str::to_string);
+    tokio::spawn(async move {
+        let tenant = match (&state.tenants, &host) {
+            (Some(tenants), Some(host)) => tenants.get(host),
+            _ => None,
+        };
+        for config in variants {
+            let config = state.clamp(match tenant {
+                Some(tenant) => tenant.apply(config),
+                None => config,
+            });
+            let key = config.cache_key();
+            let owned_by_peer = state
+                .cluster
+                .as_ref()
+                .is_some_and(|cluster| cluster.owner(&key).is_some());
+            if owned_by_peer || state.cache.contains(&key) {
+                continue;
+            }
+            if let Err(e) = convert_local(&state, &config, &mut ServerTiming::default(), None).await
+            {
+                tracing::debug!(url = %config.url, "eager variant failed: {:#}", e);
+            }
+        }
+    });
+}
+
+/// `Host`ごとの設定を反映して変換する。変換に失敗した場合は設定された画像を返す
+async fn tenant_response(
+    state: &AppState,
+    tenant: Option<&tenant::Tenant>,
+    config: ProxyConfig,
+    forwarded: bool,
+) -> Result<Response, AppError> {
+    let Some(tenant) = tenant else {
+        return proxy_response(state, config, forwarded).await;
+    };
+    authorize_tenant(tenant, &config.url)?;
+    let result = proxy_response(state, tenant.apply(config), forwarded).await;
+    match (result, tenant.fallback()) {
+        (Err(e), Some(fallback)) => {
+            tracing::info!("serve fallback image: {:#}", e.error);
+            Ok((
+                [
+                    (header::CACHE_CONTROL, "max-age=300"),
+                    (header::CONTENT_TYPE, fallback.content_type),
+                ],
+                fallback.body.clone(),
+            )
+                .into_response())
+        }
+        (result, _) => result,
+    }
+}
+
+/// `Host`ごとに許可された取得元か
+fn authorize_tenant(tenant: &tenant::Tenant, url: &reqwest::Url) -> Result<(), AppError> {
+    if tenant.is_allowed(url) {
+        return Ok(());
+    }
+    Err(AppError::new(
+        StatusCode::FORBIDDEN,
+        anyhow::anyhow!("Not allowed for this host: {}", url),
+    ))
+}
+
+/// 統計情報で取得元を区別する名前。ホスト名がない場合はスキーム
+fn origin_host(url: &reqwest::Url) -> &str {
+    url.host_str().unwrap_or(url.scheme())
+}
+
+/// `Cache-Control: no-cache`でキャッシュを使わないように求められているか
+fn requests_no_cache(headers: &header::HeaderMap) -> bool {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
+}
 
-    let buf = media_proxy(client, &config).await?;
+/// `Accept`で`image/avif`を受け取れるか。`q=0`は受け取れないとみなす
+#[cfg(feature = "avif")]
+fn accepts_avif(headers: &header::HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let is_avif = params
+                .next()
+                .is_some_and(|t| t.eq_ignore_ascii_case("image/avif"));
+            let refused = params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            is_avif && !refused
+        })
+}
+
+/// `Sec-CH-DPR`と`Sec-CH-Width`を読む。不正な値は無視する
+fn client_hints(headers: &header::HeaderMap) -> (Option<f32>, Option<u32>) {
+    let dpr = headers
+        .get(SEC_CH_DPR)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<f32>().ok())
+        .filter(|dpr| dpr.is_finite() && *dpr > 0.0);
+    let width = headers
+        .get(SEC_CH_WIDTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .filter(|w| *w > 0);
+    (dpr, width)
+}
+
+#[tracing::instrument(skip(state, headers), fields(
+    convert_type = tracing::field::Empty,
+    cache = tracing::field::Empty,
+    input_format = tracing::field::Empty,
+    input_width = tracing::field::Empty,
+    input_height = tracing::field::Empty,
+    input_frames = tracing::field::Empty,
+    input_bytes = tracing::field::Empty,
+    output_width = tracing::field::Empty,
+    output_height = tracing::field::Empty,
+    output_frames = tracing::field::Empty,
+    output_bytes = tracing::field::Empty,
+))]
+async fn favicon_handler(
+    extract::State(state): extract::State<Arc<AppState>>,
+    headers: header::HeaderMap,
+    extract::Query(query): extract::Query<ProxyQuery>,
+) -> Result<Response, AppError> {
+    let tenant = state.tenant(&headers);
+    let mut config: ProxyConfig = query.try_into()?;
+    // ページと見つかったアイコンの両方が許可された取得元でなければならない
+    if let Some(tenant) = tenant {
+        authorize_tenant(tenant, &config.url)?;
+    }
+    state.authorize_fetch(&config.url, "favicon").await?;
+    config.url = find_favicon(&state.client, &config.url, &state.limits).await?;
+    tenant_response(&state, tenant, config, false).await
+}
+
+/// `forwarded`が`true`の場合は他のノードから転送されたリクエストなので、自分で変換する
+async fn proxy_response(
+    state: &AppState,
+    config: ProxyConfig,
+    forwarded: bool,
+) -> Result<Response, AppError> {
+    state
+        .authorize_fetch(&config.url, config.convert_type.name())
+        .await?;
+    let config = state.clamp(config);
+    if state.redirect_origin_webp && !forwarded && is_acceptable_webp(state, &config).await {
+        return Ok((
+            StatusCode::FOUND,
+            [
+                (header::LOCATION, config.url.to_string()),
+                (
+                    header::CACHE_CONTROL,
+                    format!("max-age={}", REDIRECT_MAX_AGE),
+                ),
+            ],
+        )
+            .into_response());
+    }
+    // 取得元へのリクエストが済んでいれば、変換でそのレスポンスを使い取得し直さない
+    let mut opened = Ok(None);
+    if state.stream_passthrough && !forwarded {
+        match stream_passthrough(state, &config).await {
+            Streamed::Response(resp) => return Ok(resp),
+            Streamed::Opened(passthrough) => opened = Ok(Some(passthrough)),
+            Streamed::Failed(e) => opened = Err(e),
+            Streamed::Skipped => {}
+        }
+    }
+
+    let mut timing = ServerTiming::default();
+    let converted = match opened {
+        Ok(opened) if forwarded => convert_local(state, &config, &mut timing, opened).await,
+        Ok(opened) => convert_cached(state, &config, &mut timing, opened).await,
+        Err(e) => Err(e),
+    };
+    state
+        .stats
+        .record_origin_request(origin_host(&config.url), converted.is_err());
+    let (converted, stale_age) = match converted {
+        Ok(converted) => (converted, None),
+        Err(e) => {
+            let stale = state
+                .stale_if_error
+                .and_then(|max_stale| state.cache.get_stale(&config.cache_key(), max_stale));
+            let Some((converted, age)) = stale else {
+                return Err(e.into());
+            };
+            tracing::warn!("serving stale cache: {:#}", e);
+            tracing::Span::current().record("cache", "stale");
+            (converted, Some(age))
+        }
+    };
 
-    let cache_header = (header::CACHE_CONTROL, "max-age=31536000, immutable");
+    state.stats.record_format(converted.content_type);
+    if converted.input_bytes > 0 {
+        state.stats.record_bandwidth(
+            config.convert_type.name(),
+            converted.input_bytes,
+            converted.body.len(),
+        );
+    }
+    let info = converted.info();
+    let span = tracing::Span::current();
+    span.record("convert_type", config.convert_type.name());
+    span.record("output_bytes", converted.body.len());
+    if let Some(info) = info {
+        span.record("output_width", info.width);
+        span.record("output_height", info.height);
+        span.record("output_frames", info.frame_count);
+    }
+
+    let surrogate_keys = cache::surrogate_keys(&config.url);
+    let dimension_headers = dimension_headers(info);
+
+    // 期限切れのキャッシュは取得元が復旧したらすぐに置き換わるようにする
+    let cache_control = match stale_age {
+        Some(_) => format!("max-age={}", STALE_MAX_AGE),
+        None => config.cache_control(),
+    };
 
     // TODO:`Content-Security-Policy`および`Content-Disposition`に対応する
-    match config.convert_type {
-        handler::ConvertType::Badge => Ok((
-            [cache_header, (header::CONTENT_TYPE, "image/png")],
-            buf.to_png()?,
-        )),
-        _ => Ok((
-            [cache_header, (header::CONTENT_TYPE, "image/webp")],
-            buf.to_webp(quality_factor)?,
-        )),
+    Ok((
+        [
+            (header::CACHE_CONTROL, cache_control),
+            (header::CONTENT_TYPE, converted.content_type.to_string()),
+            (
+                header::HeaderName::from_static("surrogate-key"),
+                surrogate_keys.join(" "),
+            ),
+            (
+                header::HeaderName::from_static("cache-tag"),
+                surrogate_keys.join(","),
+            ),
+        ],
+        dimension_headers,
+        degraded_header(&converted.degraded),
+        stale_headers(stale_age),
+        input_bytes_header(forwarded, converted.input_bytes),
+        timing_header(&timing),
+        converted.body,
+    )
+        .into_response())
+}
+
+fn timing_header(timing: &ServerTiming) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    if let Some(value) = timing.header_value().and_then(|v| v.try_into().ok()) {
+        headers.insert(header::HeaderName::from_static("server-timing"), value);
+    }
+    headers
+}
+
+/// 期限切れのキャッシュを返す場合の`Age`と`Warning`
+fn stale_headers(age: Option<Duration>) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    if let Some(age) = age {
+        headers.insert(header::AGE, age.as_secs().into());
+        headers.insert(
+            header::WARNING,
+            header::HeaderValue::from_static("111 - \"Revalidation Failed\""),
+        );
+    }
+    headers
+}
+
+/// 転送元のノードが転送量を記録できるように、取得元のバイト数を返す
+fn input_bytes_header(forwarded: bool, input_bytes: usize) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    if forwarded {
+        headers.insert(cluster::INPUT_BYTES_HEADER, input_bytes.into());
     }
+    headers
 }
 
-#[tracing::instrument]
+fn degraded_header(degraded: &[processor::Degradation]) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    if !degraded.is_empty() {
+        let reasons: Vec<_> = degraded.iter().map(|d| d.as_str()).collect();
+        headers.insert(
+            DEGRADED_HEADER,
+            header::HeaderValue::from_str(&reasons.join(", ")).unwrap(),
+        );
+    }
+    headers
+}
+
+/// 画像をデコードせずにレイアウトできるように、大きさとアニメーションのフレーム数を返す
+fn dimension_headers(info: Option<ImageInfo>) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    let Some(info) = info else {
+        return headers;
+    };
+    headers.insert(IMAGE_WIDTH_HEADER, info.width.into());
+    headers.insert(IMAGE_HEIGHT_HEADER, info.height.into());
+    if let (true, Some(frame_count)) = (info.animated, info.frame_count) {
+        headers.insert(IMAGE_FRAME_COUNT_HEADER, frame_count.into());
+    }
+    headers
+}
+
+/// 変換せずにそのまま返せるWebPか
+async fn is_acceptable_webp(state: &AppState, config: &ProxyConfig) -> bool {
+    // 拡張子から明らかに異なる形式の場合はダウンロードしない。
+    // 署名が必要な取得元や`ipfs://`などはクライアントから取得できない
+    if !config.is_passthrough()
+        || !matches!(config.url.scheme(), "http" | "https")
+        || state.limits.credential(&config.url).is_some()
+        || !matches!(
+            get_image_ext(&config.url),
+            ImageExt::Webp | ImageExt::Unknown
+        )
+    {
+        return false;
+    }
+
+    // 先頭だけを取得してヘッダーから判断する
+    match probe_webp(&state.client, &config.url, &state.limits).await {
+        Ok(info) => info.is_some_and(|info| fits_passthrough(state, config, &info)),
+        Err(_) => false,
+    }
+}
+
+/// WebPの大きさが上限以内で、変換せずに返せるか
+fn fits_passthrough(state: &AppState, config: &ProxyConfig, info: &ImageInfo) -> bool {
+    info.pixels() <= state.limits.max_pixels
+        && info.width <= state.max_width
+        && info.height <= state.max_height
+        && config
+            .max_size
+            .is_none_or(|max| info.width <= max && info.height <= max)
+        && (!info.animated
+            || config
+                .animated_max_size
+                .is_none_or(|(max_w, max_h)| info.width <= max_w && info.height <= max_h))
+}
+
+/// 動画や音声は画像として扱えないので、取得元の`Content-Type`のまま転送する
+fn is_streamable_media(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    mime.starts_with("video/") || mime.starts_with("audio/")
+}
+
+/// `stream_passthrough`の結果
+enum Streamed {
+    /// 取得元の本文をそのまま転送する
+    Response(Response),
+    /// 転送できないが取得元へのリクエストは済んでいる。変換ではこのレスポンスの残りを読み込む
+    Opened(Passthrough),
+    /// 取得元へのリクエストに失敗した
+    Failed(anyhow::Error),
+    /// 取得元へリクエストしていない
+    Skipped,
+}
+
+/// 変換せずに返せる場合は、取得元の本文を読み込まずにそのまま転送する。
+/// 返せない場合は開いたレスポンスを返して、通常の変換に任せる
+async fn stream_passthrough(state: &AppState, config: &ProxyConfig) -> Streamed {
+    if !config.is_passthrough()
+        || !matches!(config.url.scheme(), "http" | "https" | "ipfs")
+        || !matches!(
+            get_image_ext(&config.url),
+            ImageExt::Webp | ImageExt::Unknown
+        )
+        || state.cache.contains(&config.cache_key())
+    {
+        return Streamed::Skipped;
+    }
+
+    let passthrough = match open_passthrough(&state.client, &config.url, &state.limits).await {
+        Ok(passthrough) => passthrough,
+        Err(e) => return Streamed::Failed(e),
+    };
+    let (content_type, info) = match guess_format(&passthrough.head) {
+        ImageExt::Webp => {
+            let Some(info) = inspect::inspect(ImageExt::Webp, &passthrough.head) else {
+                return Streamed::Opened(passthrough);
+            };
+            if !fits_passthrough(state, config, &info) {
+                return Streamed::Opened(passthrough);
+            }
+            // 先頭だけではフレーム数が分からない
+            let info = ImageInfo {
+                frame_count: None,
+                ..info
+            };
+            ("image/webp".to_string(), Some(info))
+        }
+        // 判定できないものはSVGとみなされる。`nosniff`を付けるので取得元の`Content-Type`以外では解釈されない
+        ImageExt::Svg | ImageExt::Unknown => match passthrough.content_type.clone() {
+            Some(content_type) if is_streamable_media(&content_type) => (content_type, None),
+            _ => return Streamed::Opened(passthrough),
+        },
+        _ => return Streamed::Opened(passthrough),
+    };
+
+    state
+        .stats
+        .record_origin_request(origin_host(&config.url), false);
+    tracing::Span::current().record("cache", "stream");
+    let surrogate_keys = cache::surrogate_keys(&config.url);
+    let mut headers = dimension_headers(info);
+    if let Some(len) = passthrough.content_length {
+        headers.insert(header::CONTENT_LENGTH, len.into());
+    }
+    Streamed::Response(
+        (
+            [
+                (header::CACHE_CONTROL, config.cache_control()),
+                (header::CONTENT_TYPE, content_type),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+                (
+                    header::HeaderName::from_static("surrogate-key"),
+                    surrogate_keys.join(" "),
+                ),
+                (
+                    header::HeaderName::from_static("cache-tag"),
+                    surrogate_keys.join(","),
+                ),
+            ],
+            headers,
+            axum::body::Body::from_stream(passthrough.into_stream()),
+        )
+            .into_response(),
+    )
+}
+
+/// クラスタモードで他のノードが担当する場合は転送し、それ以外は自分で変換する。
+/// `opened`があれば取得元へのリクエストは済んでいるので、転送せずにそのレスポンスを変換する
+async fn convert_cached(
+    state: &AppState,
+    config: &ProxyConfig,
+    timing: &mut ServerTiming,
+    opened: Option<Passthrough>,
+) -> anyhow::Result<Converted> {
+    if let (Some(cluster), None) = (&state.cluster, &opened) {
+        if let Some(peer) = cluster.owner(&config.cache_key()) {
+            match timing
+                .measure_async("forward", cluster.forward(peer, config))
+                .await
+            {
+                Ok(converted) => {
+                    tracing::Span::current().record("cache", "forwarded");
+                    return Ok(converted);
+                }
+                Err(e) => tracing::warn!(%peer, "failed to forward to peer: {:#}", e),
+            }
+        }
+    }
+    convert_local(state, config, timing, opened).await
+}
+
+/// キャッシュがあればそれを返し、なければ変換してキャッシュに追加する
+async fn convert_local(
+    state: &AppState,
+    config: &ProxyConfig,
+    timing: &mut ServerTiming,
+    opened: Option<Passthrough>,
+) -> anyhow::Result<Converted> {
+    let key = config.cache_key();
+    if config.refresh {
+        tracing::Span::current().record("cache", "refresh");
+    } else if let Some(converted) = timing.measure("cache", || state.cache.get(&key)) {
+        tracing::Span::current().record("cache", "hit");
+        return Ok(converted);
+    } else {
+        tracing::Span::current().record("cache", "miss");
+    }
+
+    #[cfg(feature = "redis")]
+    let converted = match &state.singleflight {
+        Some(singleflight) if config.refresh => {
+            singleflight
+                .refresh(&key, || convert(state, config, timing, opened))
+                .await?
+        }
+        Some(singleflight) => {
+            singleflight
+                .run(&key, || convert(state, config, timing, opened))
+                .await?
+        }
+        None => convert(state, config, timing, opened).await?,
+    };
+    #[cfg(not(feature = "redis"))]
+    let converted = convert(state, config, timing, opened).await?;
+    if state.cache.is_enabled() {
+        state.cache.insert(key, converted.clone());
+    }
+    Ok(converted)
+}
+
+async fn convert(
+    state: &AppState,
+    config: &ProxyConfig,
+    timing: &mut ServerTiming,
+    opened: Option<Passthrough>,
+) -> anyhow::Result<Converted> {
+    let _in_flight = state.stats.in_flight();
+    let webp_options = state.webp_options(config.convert_type);
+    let mut degraded = vec![];
+    let (buf, input_bytes) = media_proxy(
+        &state.client,
+        config,
+        &state.limits,
+        opened,
+        timing,
+        &mut degraded,
+    )
+    .await?;
+    state
+        .stats
+        .record_origin_fetch(origin_host(&config.url), input_bytes);
+
+    // エンコードは重いのでブロッキングスレッドで行う
+    let config = config.clone();
+    let stats = state.stats.clone();
+    stats.enqueue();
+    let encode = tokio::task::spawn_blocking(move || {
+        stats.dequeue();
+        convert::encode(buf, &config, &webp_options, degraded, input_bytes)
+    });
+    timing.measure_async("encode", encode).await?
+}
+
+/// 指定された画像をバックグラウンドで変換し、キャッシュに追加する
+#[tracing::instrument(skip(state, headers))]
+async fn prefetch_handler(
+    extract::State(state): extract::State<Arc<AppState>>,
+    headers: header::HeaderMap,
+    extract::Json(entries): extract::Json<Vec<PrefetchEntry>>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize_admin(&headers)?;
+    if !state.cache.is_enabled() {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("Cache is disabled"),
+        ));
+    }
+
+    let configs = entries
+        .into_iter()
+        // 通常のリクエストと同じキャッシュキーになるように同じ制限をかける
+        .map(|entry| ProxyConfig::try_from(entry).map(|config| state.clamp(config)))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e))?;
+
+    let count = configs.len();
+
+    tokio::spawn(async move {
+        for config in configs {
+            if let Err(e) = state
+                .authorize_fetch(&config.url, config.convert_type.name())
+                .await
+            {
+                tracing::warn!(url = %config.url, "prefetch rejected: {:#}", e.error);
+                continue;
+            }
+            if let Err(e) =
+                convert_cached(&state, &config, &mut ServerTiming::default(), None).await
+            {
+                tracing::warn!(url = %config.url, "prefetch failed: {:#}", e);
+            }
+        }
+        tracing::info!(count, "prefetch finished");
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        axum::Json(serde_json::json!({ "accepted": count })),
+    ))
+}
+
+/// 実行中の統計情報を返す
+#[tracing::instrument(skip(state, headers))]
+async fn stats_handler(
+    extract::State(state): extract::State<Arc<AppState>>,
+    headers: header::HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize_admin(&headers)?;
+
+    let mut stats = serde_json::to_value(state.stats.snapshot())?;
+    stats["cache"] = serde_json::to_value(state.cache.stats())?;
+    Ok(axum::Json(stats))
+}
+
+/// 長すぎるURIやクエリパラメータが多すぎるリクエストを、取得やデコードの前に拒否する
+async fn limit_request_uri(
+    extract::State(state): extract::State<Arc<AppState>>,
+    request: extract::Request,
+    next: middleware::Next,
+) -> Result<Response, AppError> {
+    let uri = request.uri();
+    let length = uri.path_and_query().map_or(0, |pq| pq.as_str().len());
+    if length > state.max_uri_length {
+        return Err(AppError::new(
+            StatusCode::URI_TOO_LONG,
+            anyhow::anyhow!("URI is {} bytes long", length),
+        ));
+    }
+    let params = uri
+        .query()
+        .map_or(0, |q| q.split('&').filter(|p| !p.is_empty()).count());
+    if params > state.max_query_params {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("Too many query parameters: {}", params),
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+/// エンコード待ちが多すぎる場合は変換せずに503を返し、通したリクエストの待ち時間を抑える
+async fn shed_load(
+    extract::State(state): extract::State<Arc<AppState>>,
+    request: extract::Request,
+    next: middleware::Next,
+) -> Response {
+    let queued = state.stats.queued();
+    match state.shed_max_queued {
+        Some(max) if queued > max => {
+            tracing::info!("shed request: {} conversions are queued", queued);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [
+                    (header::RETRY_AFTER, state.shed_retry_after.to_string()),
+                    (header::CACHE_CONTROL, "no-store".to_string()),
+                ],
+                "Too many conversions are queued",
+            )
+                .into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+/// 他のノードから転送されたことを署名で確かめたリクエストに付ける
+#[derive(Debug, Clone, Copy)]
+struct ForwardedRequest;
+
+/// 転送を示すヘッダーの署名を検証する。検証できないヘッダーは取り除き、通常のリクエストとして扱う
+async fn verify_forwarded(
+    extract::State(state): extract::State<Arc<AppState>>,
+    mut request: extract::Request,
+    next: middleware::Next,
+) -> Response {
+    let Some(value) = request.headers().get(cluster::FORWARDED_HEADER) else {
+        return next.run(request).await;
+    };
+    let path_and_query = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    let verified = state.cluster.as_ref().is_some_and(|cluster| {
+        value
+            .to_str()
+            .is_ok_and(|value| cluster.verify(value, path_and_query, std::time::SystemTime::now()))
+    });
+    if verified {
+        request.extensions_mut().insert(ForwardedRequest);
+    } else {
+        tracing::warn!("ignore unverified forwarded header");
+        request.headers_mut().remove(cluster::FORWARDED_HEADER);
+    }
+    next.run(request).await
+}
+
+/// `key`と`sig`の署名で認証されたリクエストに付ける
+#[derive(Debug, Clone, Copy)]
+struct SignedRequest;
+
+/// APIキーで認証し、その日の上限に達していれば429を返す
+async fn require_api_key(
+    extract::State(state): extract::State<Arc<AppState>>,
+    mut request: extract::Request,
+    next: middleware::Next,
+) -> Response {
+    let Some(api_keys) = &state.api_keys else {
+        return next.run(request).await;
+    };
+    // 署名を検証できたクラスタ内の転送は転送元で認証済み
+    if request.extensions().get::<ForwardedRequest>().is_some() {
+        return next.run(request).await;
+    }
+
+    let header = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let signed = header.is_none();
+    let name = api_keys.authenticate(header, request.uri().path(), request.uri().query());
+    let rejection = match name {
+        Some(name) => api_keys.acquire(name, std::time::SystemTime::now()).err(),
+        None => Some(apikey::Rejection::Unauthorized),
+    };
+    match rejection {
+        Some(apikey::Rejection::Unauthorized) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::CACHE_CONTROL, "no-store".to_string())],
+                "Invalid API key",
+            )
+                .into_response();
+        }
+        Some(apikey::Rejection::QuotaExceeded(retry_after)) => {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [
+                    (header::RETRY_AFTER, retry_after.as_secs().to_string()),
+                    (header::CACHE_CONTROL, "no-store".to_string()),
+                ],
+                "API key quota exceeded",
+            )
+                .into_response();
+        }
+        None => {}
+    }
+
+    let name = name.map(str::to_string).unwrap_or_default();
+    if signed {
+        request.extensions_mut().insert(SignedRequest);
+    }
+    let response = next.run(request).await;
+    if let Some(bytes) = response.body().size_hint().exact() {
+        api_keys.record_bytes(&name, bytes);
+        return response;
+    }
+    // そのまま転送する本文は長さが分からないので、送った分を数える
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().inspect_ok(move |chunk| {
+        if let Some(api_keys) = &state.api_keys {
+            api_keys.record_bytes(&name, chunk.len() as u64);
+        }
+    });
+    Response::from_parts(parts, axum::body::Body::from_stream(body))
+}
+
+/// 変換を受け付けられるか。できない場合は503と失敗した項目を返す
+#[tracing::instrument(skip(state))]
+async fn readyz_handler(extract::State(state): extract::State<Arc<AppState>>) -> Response {
+    let mut checks = state.startup_checks.clone();
+    #[cfg(feature = "redis")]
+    if let Some(singleflight) = &state.singleflight {
+        checks.push((
+            "redis",
+            singleflight.ping().await.map_err(|e| format!("{:#}", e)),
+        ));
+    }
+    let queued = state.stats.queued();
+    checks.push((
+        "load",
+        match queued > state.ready_max_queued {
+            true => Err(format!("{} conversions are queued", queued)),
+            false => Ok(()),
+        },
+    ));
+
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let checks: serde_json::Map<_, _> = checks
+        .into_iter()
+        .map(|(name, result)| {
+            (
+                name.to_string(),
+                result.err().unwrap_or("ok".to_string()).into(),
+            )
+        })
+        .collect();
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        status,
+        axum::Json(serde_json::json!({ "ready": ready, "checks": checks })),
+    )
+        .into_response()
+}
+
+/// Prometheus向けの統計情報を返す
+#[tracing::instrument(skip(state, headers))]
+async fn metrics_handler(
+    extract::State(state): extract::State<Arc<AppState>>,
+    headers: header::HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize_admin(&headers)?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.stats.prometheus(),
+    ))
+}
+
+#[tracing::instrument(skip(state, headers, signed, forwarded))]
 async fn proxy_handler_with_param(
-    extract::Path(_image_param): extract::Path<String>,
-    state: extract::State<Arc<(Client, f32)>>,
-    query: extract::Query<ProxyQuery>,
+    extract::Path(image_param): extract::Path<String>,
+    state: extract::State<Arc<AppState>>,
+    headers: header::HeaderMap,
+    signed: Option<extract::Extension<SignedRequest>>,
+    forwarded: Option<extract::Extension<ForwardedRequest>>,
+    extract::Query(query): extract::Query<ProxyQuery>,
+) -> Result<Response, AppError> {
+    let query = query.with_path_filename(&image_param);
+    proxy_handler(state, headers, signed, forwarded, extract::Query(query)).await
+}
+
+#[tracing::instrument(skip(state, headers))]
+async fn sheet_handler(
+    extract::State(state): extract::State<Arc<AppState>>,
+    headers: header::HeaderMap,
+    extract::Query(query): extract::Query<SheetQuery>,
 ) -> Result<impl IntoResponse, AppError> {
-    proxy_handler(state, query).await
+    let tenant = state.tenant(&headers);
+    let config: SheetConfig = query.try_into()?;
+    if let Some(tenant) = tenant {
+        authorize_tenant(tenant, &config.url)?;
+    }
+    state.authorize_fetch(&config.url, "sheet").await?;
+    let client = &state.client;
+    let mut webp_options = state.webp;
+    if let Some(quality) = tenant.and_then(|tenant| tenant.quality()) {
+        webp_options.quality = quality as f32;
+    }
+
+    let buf = sprite_sheet(client, &config, &state.limits).await?;
+
+    let body = tokio::task::spawn_blocking(move || buf.to_webp(&webp_options)).await??;
+
+    Ok((
+        [
+            (header::CACHE_CONTROL, "max-age=31536000, immutable"),
+            (header::CONTENT_TYPE, "image/webp"),
+        ],
+        body,
+    ))
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    if let Some(args::Command::DecodeWorker(worker)) = &args.command {
+        return sandbox::run_worker(worker);
+    }
+    if args.harden && args.command.is_none() {
+        harden::apply(&harden::Policy::from_args(&args)?)?;
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = args.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    if let Some(max_blocking_threads) = args.max_blocking_threads {
+        runtime.max_blocking_threads(max_blocking_threads);
+    }
+    let runtime = runtime.build()?;
+    match args.command {
+        Some(args::Command::Check) => {
+            if !runtime.block_on(check::run(&args)) {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Some(args::Command::Doctor(ref doctor)) => {
+            runtime.block_on(doctor::run(&args, doctor));
+            Ok(())
+        }
+        Some(args::Command::DecodeWorker(_)) => unreachable!(),
+        None => runtime.block_on(serve(args)),
+    }
+}
+
+/// 全体のWebPのエンコード設定と、変換の種類ごとに上書きした設定
+fn webp_options(args: &Args) -> (WebpOptions, Vec<(handler::ConvertType, WebpOptions)>) {
+    let webp = WebpOptions {
+        alpha_quality: args.alpha_quality,
+        alpha_filtering: args.alpha_filtering,
+        alpha_compression: args.alpha_compression,
+        preset: args.webp_preset,
+        adaptive: args
+            .anim_quality_threshold
+            .map(|threshold_pixels| webp::AdaptiveQuality {
+                threshold_pixels,
+                min_quality: args.anim_min_quality as f32,
+            }),
+        provenance: args.embed_provenance,
+        ..WebpOptions::new(args.quality_factor as f32)
+    };
+    let webp_by_type = handler::ConvertType::ALL
+        .into_iter()
+        .map(|convert_type| {
+            let mut options = webp;
+            if let Some(profile) = args
+                .alpha_profile
+                .iter()
+                .find(|p| p.convert_type == convert_type)
+            {
+                options.alpha_quality = profile.quality;
+                options.alpha_filtering = profile.filtering;
+                options.alpha_compression = profile.compression;
+            }
+            if let Some(profile) = args
+                .preset_profile
+                .iter()
+                .find(|p| p.convert_type == convert_type)
+            {
+                options.preset = profile.preset;
+            }
+            (convert_type, options)
+        })
+        .collect();
+    (webp, webp_by_type)
+}
+
+async fn serve(args: Args) -> anyhow::Result<()> {
+    // 監査ログはRUST_LOGの設定に関係なく記録する
+    let audit_layer = match &args.audit_log {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            let layer = tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(std::sync::Mutex::new(file))
+                .with_filter(Targets::new().with_target("audit", tracing::Level::INFO));
+            Some(layer)
+        }
+        None => None,
+    };
+    // tokio-consoleに送るタスクの情報は`tokio_unstable`でビルドした場合のみ得られる
+    #[cfg(all(feature = "runtime-metrics", tokio_unstable))]
+    let console_layer = Some(console_subscriber::spawn());
+    #[cfg(not(all(feature = "runtime-metrics", tokio_unstable)))]
+    let console_layer = None::<tracing_subscriber::layer::Identity>;
     tracing_subscriber::registry()
         .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "debug".into()),
+            tracing_subscriber::fmt::layer().with_filter(
+                tracing_subscriber::EnvFilter::try_from_default_env()
+                    .unwrap_or_else(|_| "debug".into()),
+            ),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(audit_layer)
+        .with(console_layer)
         .init();
     tracing::info!(
         host = args.host,
@@ -71,25 +1334,287 @@ async fn main() -> anyhow::Result<()> {
         args.host,
         args.port,
     );
-    let shared_state = Arc::new((
-        get_client(args.http_proxy.as_deref())?,
-        args.quality_factor as f32,
-    ));
+    let (webp, webp_by_type) = webp_options(&args);
+    let resolver = (!args.warm_hosts.is_empty())
+        .then(|| dns::WarmResolver::new(&args.warm_hosts, Duration::from_secs(args.warm_dns_ttl)));
+    let blocklist = match args.blocklist {
+        Some(path) => {
+            let blocklist = Arc::new(Blocklist::load(path)?);
+            blocklist
+                .clone()
+                .watch(Duration::from_secs(args.blocklist_reload_interval));
+            Some(blocklist)
+        }
+        None => None,
+    };
+    let shared_state = Arc::new(AppState {
+        client: get_client(&ClientConfig {
+            proxy_url: args.http_proxy,
+            pool_idle_per_host: args.upstream_pool_idle_per_host,
+            pool_idle_timeout: args.upstream_pool_idle_timeout.map(Duration::from_secs),
+            tcp_nodelay: args.upstream_tcp_nodelay,
+            tcp_keepalive: args.upstream_tcp_keepalive.map(Duration::from_secs),
+            resolver: resolver.clone(),
+            redirect_filter: blocklist
+                .clone()
+                .map(|blocklist| blocklist as Arc<dyn UrlFilter>),
+        })?,
+        webp,
+        webp_by_type,
+        max_width: args.max_width,
+        max_height: args.max_height,
+        resize_filter: args.resize_filter,
+        max_quality: args.max_quality,
+        max_fps: args.max_fps.filter(|fps| *fps > 0),
+        original_max_size: args.original_max_size.filter(|size| *size > 0),
+        preview_max_frames: args.preview_max_frames.filter(|n| *n > 0),
+        preview_max_duration: args.preview_max_duration.filter(|ms| *ms > 0),
+        max_animation_duration: args
+            .max_animation_duration
+            .filter(|secs| *secs > 0)
+            .map(|secs| secs.saturating_mul(1000)),
+        min_ttl: args.min_ttl,
+        max_ttl: args.max_ttl,
+        animated_max_width: args.animated_max_width.filter(|w| *w > 0),
+        animated_max_height: args.animated_max_height.filter(|h| *h > 0),
+        limits: Limits {
+            max_pixels: args.max_pixels,
+            max_dimension: args.max_dimension,
+            max_download_size: args.max_download_size,
+            rate_limiter: args
+                .origin_rate_limit
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| Arc::new(OriginRateLimiter::new(rate, args.origin_rate_burst))),
+            file_roots: args
+                .file_root
+                .iter()
+                .map(std::fs::canonicalize)
+                .collect::<Result<_, _>>()?,
+            disabled_formats: args.disable_format,
+            origin_credentials: args
+                .origin_credentials
+                .map(OriginCredentials::load)
+                .transpose()?
+                .map(Arc::new),
+            ipfs_gateway: args.ipfs_gateway,
+            preflight: args.preflight_head,
+            sandbox: match args.decoder_sandbox {
+                true => Some(DecoderSandbox {
+                    program: std::env::current_exe()?,
+                    max_memory: args.decoder_sandbox_memory.saturating_mul(1024 * 1024),
+                    max_cpu_secs: args.decoder_sandbox_cpu,
+                    max_output: args.decoder_sandbox_output.saturating_mul(1024 * 1024),
+                    timeout: Duration::from_secs(args.decoder_sandbox_timeout),
+                }),
+                false => None,
+            },
+        },
+        cache: MemoryCache::new(args.cache_size).with_ttl(args.cache_ttl.map(Duration::from_secs)),
+        stale_if_error: (args.stale_if_error > 0).then(|| Duration::from_secs(args.stale_if_error)),
+        stats: Arc::new(Stats::default()),
+        admin_token: args.admin_token,
+        redirect_origin_webp: args.redirect_origin_webp,
+        stream_passthrough: args.stream_passthrough,
+        client_hints: args.client_hints,
+        #[cfg(feature = "avif")]
+        animated_avif: args.animated_avif,
+        startup_checks: vec![
+            (
+                "fonts",
+                check::check_fonts().map_err(|e| format!("{:#}", e)),
+            ),
+            ("encoder", webp::self_test().map_err(|e| format!("{:#}", e))),
+        ],
+        ready_max_queued: args.ready_max_queued,
+        max_uri_length: args.max_uri_length,
+        max_query_params: args.max_query_params,
+        shed_max_queued: args.shed_max_queued,
+        shed_retry_after: args.shed_retry_after,
+        tenants: args.tenants.map(Tenants::load).transpose()?,
+        eager_variants: args.eager_variant,
+        api_keys: args.api_keys.map(ApiKeys::load).transpose()?,
+        blocklist,
+        prefetch_webhook: match args.prefetch_webhook {
+            Some(endpoint) => Some(PrefetchWebhook::new(
+                endpoint,
+                Duration::from_millis(args.prefetch_webhook_timeout),
+            )?),
+            None => None,
+        },
+        cluster: match args.cluster_self {
+            Some(this) => Some(Cluster::new(
+                args.cluster_peers,
+                this,
+                args.cluster_secret.ok_or_else(|| {
+                    anyhow::anyhow!("--cluster-secret is required in cluster mode")
+                })?,
+            )?),
+            None => None,
+        },
+        #[cfg(feature = "redis")]
+        singleflight: match &args.redis_url {
+            Some(url) => Some(
+                singleflight::RedisSingleflight::connect(
+                    url,
+                    Duration::from_secs(args.redis_lease),
+                )
+                .await?,
+            ),
+            None => None,
+        },
+    });
+
+    if args.warmup {
+        let started = std::time::Instant::now();
+        let options = shared_state.webp_by_type.clone();
+        tokio::task::spawn_blocking(move || warmup::run(&options))
+            .await?
+            .map_err(|e| e.context("warm-up failed"))?;
+        tracing::info!("warm-up finished in {:?}", started.elapsed());
+    }
 
-    let mut cors_layer = tower_http::cors::CorsLayer::new().allow_methods([http::Method::GET]);
+    if let Some(resolver) = &resolver {
+        let started = std::time::Instant::now();
+        let resolved = resolver.warm().await;
+        tracing::info!(
+            "resolved {} of {} hosts in {:?}",
+            resolved,
+            args.warm_hosts.len(),
+            started.elapsed()
+        );
+        if args.warm_connections {
+            let started = std::time::Instant::now();
+            let connected = dns::warm_connections(&shared_state.client, resolver.hosts()).await;
+            tracing::info!(
+                "connected to {} of {} hosts in {:?}",
+                connected,
+                args.warm_hosts.len(),
+                started.elapsed()
+            );
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_port) = args.grpc_port {
+        // IPv6アドレスやホスト名も受け付けるため、文字列を組み立てずに解決する
+        let addr = tokio::net::lookup_host((args.host.as_str(), grpc_port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("cannot resolve {}", args.host))?;
+        let state = shared_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(state, addr).await {
+                tracing::error!("gRPC server stopped: {:#}", e);
+            }
+        });
+    }
+
+    let mut cors_layer = tower_http::cors::CorsLayer::new()
+        .allow_methods([http::Method::GET])
+        .expose_headers([
+            IMAGE_WIDTH_HEADER,
+            IMAGE_HEIGHT_HEADER,
+            IMAGE_FRAME_COUNT_HEADER,
+            DEGRADED_HEADER,
+            REQUEST_ID_HEADER,
+        ]);
     if args.allow_origin.is_empty() {
         cors_layer = cors_layer.allow_origin(tower_http::cors::Any)
     } else {
         cors_layer = cors_layer.allow_origin(args.allow_origin)
     }
 
-    let app = Router::new()
-        .route("/health", routing::get(|| async { "Hello world" }))
+    // 負荷による制限は変換を伴うルートだけにかける
+    let convert_routes = Router::new()
         .route("/", routing::get(proxy_handler))
+        .route("/sheet", routing::get(sheet_handler))
+        .route("/favicon", routing::get(favicon_handler))
         .route("/*param", routing::get(proxy_handler_with_param))
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            shed_load,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            require_api_key,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            verify_forwarded,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            limit_request_uri,
+        ));
+    let app = Router::new()
+        .route("/health", routing::get(|| async { "Hello world" }))
+        .route("/livez", routing::get(|| async { "ok" }))
+        .route("/readyz", routing::get(readyz_handler))
+        .route("/admin/prefetch", routing::post(prefetch_handler))
+        .route("/stats", routing::get(stats_handler))
+        .route("/metrics", routing::get(metrics_handler))
+        .route(
+            "/openapi.json",
+            routing::get(|| async { axum::Json(openapi::document()) }),
+        )
+        .merge(convert_routes)
         .with_state(shared_state)
-        .layer(tower_http::trace::TraceLayer::new_for_http())
-        .layer(cors_layer);
+        .layer(tower_http::catch_panic::CatchPanicLayer::custom(
+            panic_response,
+        ))
+        .layer(
+            tower_http::trace::TraceLayer::new_for_http().make_span_with(
+                |request: &http::Request<axum::body::Body>| {
+                    let request_id = request
+                        .headers()
+                        .get(REQUEST_ID_HEADER)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default();
+                    tracing::info_span!(
+                        "request",
+                        method = %request.method(),
+                        uri = %request.uri(),
+                        request_id,
+                    )
+                },
+            ),
+        )
+        .layer(tower_http::request_id::PropagateRequestIdLayer::new(
+            REQUEST_ID_HEADER,
+        ))
+        .layer(cors_layer)
+        .layer(tower_http::request_id::SetRequestIdLayer::new(
+            REQUEST_ID_HEADER,
+            tower_http::request_id::MakeRequestUuid,
+        ));
+    #[cfg(feature = "http3")]
+    let app = if let Some(http3_port) = args.http3_port {
+        let (Some(cert), Some(key)) = (&args.http3_cert, &args.http3_key) else {
+            anyhow::bail!("--http3-cert and --http3-key are required for HTTP/3");
+        };
+        let config = http3::server_config(cert, key)?;
+        let addr = tokio::net::lookup_host((args.host.as_str(), http3_port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("cannot resolve {}", args.host))?;
+        let h3_app = app.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http3::serve(h3_app, addr, config).await {
+                tracing::error!("HTTP/3 server stopped: {:#}", e);
+            }
+        });
+        // TCPで受けたクライアントにHTTP/3で待ち受けていることを知らせる
+        let alt_svc = http3::alt_svc(http3_port);
+        app.layer(middleware::map_response(move |mut response: Response| {
+            let alt_svc = alt_svc.clone();
+            async move {
+                response.headers_mut().insert(header::ALT_SVC, alt_svc);
+                response
+            }
+        }))
+    } else {
+        app
+    };
     let listener = tokio::net::TcpListener::bind(format!("{}:{}", args.host, args.port))
         .await
         .unwrap();
@@ -98,17 +1623,47 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// ハンドラーがpanicした場合も接続を切らずに500を返す。
+/// リクエストのspanの中で呼ばれるのでリクエストIDも記録される
+fn panic_response(err: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    let message = err
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| err.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    tracing::error!("handler panicked: {}", message);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        [(header::CACHE_CONTROL, "no-store")],
+        "Something went wrong: internal error",
+    )
+        .into_response()
+}
+
 // Make our own error that wraps `anyhow::Error`.
-struct AppError(anyhow::Error);
+struct AppError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl AppError {
+    fn new(status: StatusCode, error: anyhow::Error) -> Self {
+        Self { status, error }
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        tracing::error!("stack trace: {:#}", self.0);
+        if self.status.is_server_error() {
+            tracing::error!("stack trace: {:#}", self.error);
+        } else {
+            tracing::info!("rejected request: {:#}", self.error);
+        }
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            self.status,
             [(header::CACHE_CONTROL, "max-age=300")],
-            format!("Something went wrong: {}", self.0),
+            format!("Something went wrong: {}", self.error),
         )
             .into_response()
     }
@@ -121,6 +1676,24 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let err = err.into();
+        // 入力が壊れている場合はサーバーの問題ではない
+        let status = if err.downcast_ref::<processor::InvalidImage>().is_some() {
+            StatusCode::UNPROCESSABLE_ENTITY
+        } else if err.downcast_ref::<client::InvalidUrl>().is_some() {
+            StatusCode::BAD_REQUEST
+        } else if err.downcast_ref::<client::FileNotAllowed>().is_some() {
+            StatusCode::FORBIDDEN
+        } else if err.downcast_ref::<client::DisabledFormat>().is_some() {
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        } else if let Some(rejected) = err.downcast_ref::<client::PreflightRejected>() {
+            match rejected {
+                client::PreflightRejected::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                client::PreflightRejected::NotImage(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            }
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        Self::new(status, err)
     }
 }
@@ -0,0 +1,457 @@
+use serde_json::{json, Value};
+
+/// `/openapi.json`で返すHTTP APIの説明。クエリの仕様は`handler::ProxyQuery`に合わせる
+pub(crate) fn document() -> Value {
+    let filename = json!({
+        "name": "filename",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" },
+        "example": "emoji.webp",
+    });
+    let filename_parameters: Vec<_> = std::iter::once(filename)
+        .chain(proxy_parameters())
+        .collect();
+    let grid = |name: &str, description: &str| {
+        let schema = json!({ "type": "integer", "minimum": 1, "maximum": 8, "default": 4 });
+        query(name, false, schema, description)
+    };
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "misskey-webp-proxy",
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/": proxy_operation("Convert an image."),
+            "/{filename}": {
+                "get": {
+                    "summary": "Convert an image. The filename selects the conversion and the output format when the query does not.",
+                    "description": "`emoji.webp`, `avatar.webp`, `preview.webp`, `badge.png` and `static.webp` select the conversion.",
+                    "parameters": filename_parameters,
+                    "responses": image_responses(),
+                },
+            },
+            "/favicon": proxy_operation("Convert the favicon of the page at `url`."),
+            "/sheet": {
+                "get": {
+                    "summary": "Lay out the frames of an animation in a grid.",
+                    "parameters": [
+                        query("url", true, json!({ "type": "string", "format": "uri" }), "Source image URL."),
+                        grid("cols", "Columns of the grid."),
+                        grid("rows", "Rows of the grid."),
+                    ],
+                    "responses": image_responses(),
+                },
+            },
+            "/admin/prefetch": {
+                "post": {
+                    "summary": "Convert images in the background and store them in the cache.",
+                    "security": [{ "adminToken": [] }],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "array",
+                                    "items": { "$ref": "#/components/schemas/PrefetchEntry" },
+                                },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "202": {
+                            "description": "Accepted entries.",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "properties": { "accepted": { "type": "integer" } },
+                                    },
+                                },
+                            },
+                        },
+                        "400": error_response("An entry is invalid."),
+                        "401": error_response("The admin token is wrong."),
+                        "403": error_response("The admin API is disabled."),
+                        "409": error_response("The cache is disabled."),
+                    },
+                },
+            },
+            "/stats": admin_operation("Runtime statistics.", "application/json"),
+            "/metrics": admin_operation("Statistics in the Prometheus text format.", "text/plain"),
+            "/readyz": {
+                "get": {
+                    "summary": "Whether the startup checks passed and the conversion queue is not full.",
+                    "responses": {
+                        "200": { "description": "Ready." },
+                        "503": { "description": "Not ready." },
+                    },
+                },
+            },
+            "/livez": plain_operation("Whether the process is running."),
+            "/health": plain_operation("Whether the process is running."),
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document.",
+                    "responses": { "200": { "description": "OpenAPI document." } },
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "Error": {
+                    "type": "string",
+                    "description": "Errors are returned as plain text starting with `Something went wrong: `.",
+                    "example": "Something went wrong: Image has zero width or height",
+                },
+                "PrefetchEntry": {
+                    "type": "object",
+                    "required": ["url", "type"],
+                    "properties": {
+                        "url": { "type": "string", "format": "uri" },
+                        "type": {
+                            "type": "string",
+                            "enum": ["emoji", "avatar", "preview", "badge", "static", "original"],
+                        },
+                    },
+                },
+            },
+            "securitySchemes": {
+                "adminToken": { "type": "http", "scheme": "bearer" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "x-api-key" },
+            },
+        },
+    })
+}
+
+fn query(name: &str, required: bool, schema: Value, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": required,
+        "schema": schema,
+        "description": description,
+    })
+}
+
+/// 値を問わず、指定するだけで有効になるフラグ
+fn flag(name: &str, description: &str) -> Value {
+    query(
+        name,
+        false,
+        json!({ "type": "integer", "example": 1 }),
+        description,
+    )
+}
+
+/// 変換のクエリ
+fn proxy_parameters() -> Vec<Value> {
+    let mut formats = vec!["webp", "png", "gif"];
+    if cfg!(feature = "avif") {
+        formats.push("avif");
+    }
+    vec![
+        query(
+            "url",
+            true,
+            json!({ "type": "string", "format": "uri" }),
+            "Source image URL. `file` and `ipfs` depend on the server settings.",
+        ),
+        flag("emoji", "Convert for an emoji."),
+        flag("avatar", "Convert for an avatar."),
+        flag("static", "Use the first frame of an animation."),
+        flag("preview", "Convert for a preview."),
+        flag("badge", "Convert for a badge. The output is PNG."),
+        query(
+            "w",
+            false,
+            json!({ "type": "integer", "minimum": 1 }),
+            "Maximum width.",
+        ),
+        query(
+            "h",
+            false,
+            json!({ "type": "integer", "minimum": 1 }),
+            "Maximum height.",
+        ),
+        query(
+            "dpr",
+            false,
+            json!({ "type": "number", "minimum": 1, "maximum": 4, "example": 2 }),
+            "Scale of the preset sizes. `scale` is an alias.",
+        ),
+        query(
+            "format",
+            false,
+            json!({ "type": "string", "enum": formats }),
+            "Output format. `avif` applies only to animations.",
+        ),
+        flag("trim", "Crop transparent margins."),
+        flag("square", "Pad with transparency to a square."),
+        query(
+            "frame",
+            false,
+            json!({ "type": "integer", "minimum": 0 }),
+            "Index of the animation frame to output as a still image.",
+        ),
+        query(
+            "t",
+            false,
+            json!({ "type": "integer", "minimum": 0 }),
+            "Time in milliseconds of the animation frame to output as a still image.",
+        ),
+        flag(
+            "exact",
+            "Keep the RGB values of fully transparent pixels in WebP.",
+        ),
+        query(
+            "quality",
+            false,
+            json!({ "type": "integer", "minimum": 1, "maximum": 100, "example": 80 }),
+            "WebP quality.",
+        ),
+        query(
+            "fit",
+            false,
+            json!({ "type": "string", "enum": ["contain", "cover", "fill"] }),
+            "How to fit the image into `w` and `h`.",
+        ),
+        query(
+            "bg",
+            false,
+            json!({
+                "type": "string",
+                "pattern": "^#?([0-9a-fA-F]{6}|[0-9a-fA-F]{8})$",
+                "example": "ffffff",
+            }),
+            "Background color as `RRGGBB` or `RRGGBBAA`.",
+        ),
+        query(
+            "rotate",
+            false,
+            json!({ "type": "integer", "enum": [0, 90, 180, 270] }),
+            "Clockwise rotation in degrees.",
+        ),
+        query(
+            "flip",
+            false,
+            json!({ "type": "string", "enum": ["h", "v"] }),
+            "Flip after rotating.",
+        ),
+        flag("grayscale", "Convert to grayscale."),
+        query(
+            "blur",
+            false,
+            json!({ "type": "number", "minimum": 0, "example": 4 }),
+            "Sigma of the Gaussian blur.",
+        ),
+        query(
+            "ops",
+            false,
+            json!({ "type": "string", "example": "trim,resize:256x256:contain,round,blur:4" }),
+            "Comma separated operations applied after the others.",
+        ),
+        flag(
+            "refresh",
+            "Fetch again without the cache. Needs the admin token or a signature.",
+        ),
+        query(
+            "ttl",
+            false,
+            json!({ "type": "integer", "minimum": 0, "example": 3600 }),
+            "Browser cache lifetime in seconds, clamped by the server.",
+        ),
+        query(
+            "key",
+            false,
+            json!({ "type": "string" }),
+            "API key name for signed URLs.",
+        ),
+        query(
+            "kid",
+            false,
+            json!({ "type": "string" }),
+            "ID of the secret used for `sig` while rotating secrets.",
+        ),
+        query(
+            "sig",
+            false,
+            json!({ "type": "string" }),
+            "HMAC-SHA1 of the path and query before `sig` for signed URLs.",
+        ),
+    ]
+}
+
+fn proxy_operation(summary: &str) -> Value {
+    json!({
+        "get": {
+            "summary": summary,
+            "security": [{}, { "apiKey": [] }],
+            "parameters": proxy_parameters(),
+            "responses": image_responses(),
+        },
+    })
+}
+
+fn image_responses() -> Value {
+    let image = json!({ "schema": { "type": "string", "format": "binary" } });
+    json!({
+        "200": {
+            "description": "Converted image.",
+            "headers": {
+                "x-image-width": { "schema": { "type": "integer" } },
+                "x-image-height": { "schema": { "type": "integer" } },
+                "x-image-frame-count": { "schema": { "type": "integer" } },
+                "x-proxy-degraded": {
+                    "description": "Reasons the image was not converted at full quality.",
+                    "schema": { "type": "string" },
+                },
+            },
+            "content": {
+                "image/webp": image,
+                "image/png": image,
+                "image/gif": image,
+            },
+        },
+        "302": { "description": "Redirect to the source when it is already acceptable." },
+        "400": error_response("The query or the URL is invalid."),
+        "401": error_response("The API key is missing or wrong."),
+        "413": error_response("The source is too large."),
+        "415": error_response("The source format is disabled or not an image."),
+        "422": error_response("The source image is broken."),
+        "429": error_response("The API key exceeded its rate limit."),
+        "500": error_response("Conversion failed."),
+        "503": error_response("The server is overloaded."),
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "text/plain": { "schema": { "$ref": "#/components/schemas/Error" } },
+        },
+    })
+}
+
+fn admin_operation(summary: &str, content_type: &str) -> Value {
+    json!({
+        "get": {
+            "summary": summary,
+            "security": [{ "adminToken": [] }],
+            "responses": {
+                "200": { "description": summary, "content": { content_type: {} } },
+                "401": error_response("The admin token is wrong."),
+                "403": error_response("The admin API is disabled."),
+            },
+        },
+    })
+}
+
+fn plain_operation(summary: &str) -> Value {
+    json!({
+        "get": {
+            "summary": summary,
+            "responses": { "200": { "description": "OK", "content": { "text/plain": {} } } },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::extract::Query;
+
+    use crate::handler::{ProxyConfig, ProxyQuery};
+
+    #[test]
+    fn document_test() {
+        let doc = document();
+        assert_eq!(doc["info"]["version"], env!("CARGO_PKG_VERSION"));
+        for path in ["/", "/{filename}", "/favicon", "/sheet", "/admin/prefetch"] {
+            assert!(doc["paths"][path].is_object(), "{} is not documented", path);
+        }
+    }
+
+    /// 説明の例が実際のクエリとして受け付けられる
+    #[test]
+    fn proxy_parameters_test() {
+        let params: Vec<String> = proxy_parameters()
+            .iter()
+            .filter(|p| !matches!(p["name"].as_str(), Some("key" | "kid" | "sig" | "t")))
+            .map(|p| {
+                let name = p["name"].as_str().unwrap();
+                let schema = &p["schema"];
+                let value = match (name, schema["example"].as_str()) {
+                    ("url", _) => "https%3A%2F%2Fexample.com%2Fa.png".to_string(),
+                    (_, Some(example)) => example.to_string(),
+                    _ => schema["example"]
+                        .as_f64()
+                        .or(schema["enum"][1].as_f64())
+                        .map(|v| v.to_string())
+                        .or(schema["enum"][0].as_str().map(|v| v.to_string()))
+                        .unwrap_or_else(|| "1".to_string()),
+                };
+                format!("{}={}", name, value)
+            })
+            .collect();
+        let uri = format!("/?{}", params.join("&")).parse().unwrap();
+        let Query(query) = Query::<ProxyQuery>::try_from_uri(&uri).unwrap();
+        ProxyConfig::try_from(query).unwrap();
+    }
+    /// `deserialize_struct`に渡されるフィールド名を集めるだけのデシリアライザ
+    #[derive(Default)]
+    struct FieldNames(Vec<&'static str>);
+
+    impl<'de> serde::Deserializer<'de> for &mut FieldNames {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(
+            self,
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: serde::de::Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0.extend(fields);
+            Err(serde::de::Error::custom("only field names are collected"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    /// `ProxyQuery`にフィールドを追加したら説明にも追加する
+    #[test]
+    fn proxy_parameters_cover_query_test() {
+        let mut fields = FieldNames::default();
+        let _ = <ProxyQuery as serde::Deserialize>::deserialize(&mut fields);
+        assert!(fields.0.contains(&"url"));
+
+        let params = proxy_parameters();
+        // 別名はもとのパラメータの説明に書く
+        let documented = |field: &str| {
+            params.iter().any(|p| {
+                p["name"] == field
+                    || p["description"]
+                        .as_str()
+                        .is_some_and(|d| d.contains(&format!("`{}` is an alias", field)))
+            })
+        };
+        for field in fields.0 {
+            assert!(documented(field), "{} is not documented", field);
+        }
+    }
+}
@@ -1,138 +1,1430 @@
 use anyhow::{Context, Ok, Result};
-use image::{imageops, Frame, RgbaImage};
+use serde::Deserialize;
+use std::io::Cursor;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-use crate::webp::{encode_webp_anim, encode_webp_image};
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        webp::WebPDecoder,
+    },
+    imageops, AnimationDecoder, Delay, Frame, ImageDecoder, RgbaImage,
+};
 
-pub(crate) enum DecodeResult {
+#[cfg(feature = "avif")]
+use crate::avif::encode_avif_anim;
+use crate::client::{decode_with_fallback, ImageExt, Limits};
+use crate::inspect::{gif_delays, webp_delays};
+use crate::webp::{
+    decode_webp_anim, encode_webp_anim, encode_webp_anim_stream, encode_webp_image, WebpOptions,
+};
+
+pub enum DecodeResult {
     Image(RgbaImage),
     Movie(Vec<Frame>),
     TextFmt(String),
+    /// デコード前のgifもしくはアニメーションwebp。リサイズ後の大きさのみを保持し、
+    /// エンコード時に1フレームずつデコードする
+    AnimStream {
+        buf: Vec<u8>,
+        size: Option<(u32, u32)>,
+        /// エンコード時に適用する再生時間の変換
+        playback: Playback,
+    },
+}
+
+/// `AnimStream`のフレームに対して1フレームずつ適用する変換
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Playback {
+    /// 先頭からこのフレーム数までにする
+    max_frames: Option<u32>,
+    /// 先頭からこの時間(ミリ秒)までにする
+    max_duration: Option<u32>,
+    /// このフレームレートに並べ直す
+    fps: Option<u32>,
+    /// フレームを`size`に変換する際のフィルタ
+    filter: ResizeFilter,
+    /// `size`に変換した後に順に適用する変換
+    stages: Vec<Stage>,
+    /// 連続する同じ内容のフレームをまとめずに、元のフレーム数のまま返す
+    keep_duplicates: bool,
+}
+
+impl Playback {
+    fn apply<'a>(self, frames: impl Iterator<Item = Result<Frame>> + 'a) -> AnimFrames<'a> {
+        let stages = self.stages;
+        // 切り詰めた後に変換し、捨てるフレームには適用しない
+        let frames = truncate(frames, self.max_frames, self.max_duration).map(move |f| {
+            let f = f?;
+            let delay = f.delay();
+            let img = stages
+                .iter()
+                .try_fold(f.into_buffer(), |img, stage| stage.apply(&img))?;
+            Ok(Frame::from_parts(img, 0, 0, delay))
+        });
+        // 並べ直した後に続けて同じ内容になったフレームもまとめる
+        match (self.fps, self.keep_duplicates) {
+            (Some(fps), false) => Box::new(MergeDuplicates::new(Resample::new(frames, fps))),
+            (Some(fps), true) => Box::new(Resample::new(frames, fps)),
+            (None, false) => Box::new(MergeDuplicates::new(frames)),
+            (None, true) => Box::new(frames),
+        }
+    }
+
+    /// `size`のフレームに`stages`を適用した後の大きさ
+    fn output_size(&self, size: (u32, u32)) -> (u32, u32) {
+        self.stages
+            .iter()
+            .fold(size, |size, stage| stage.output_size(size))
+    }
+}
+
+/// `AnimStream`の各フレームに適用する変換。すべてのフレームをデコードせずに済むように、
+/// エンコード時に1フレームずつ適用する
+#[derive(Debug, Clone, PartialEq)]
+enum Stage {
+    /// `width`x`height`に拡大縮小する
+    Resize {
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    },
+    /// `(x, y)`から`width`x`height`を切り取る
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// `width`x`height`の透明なキャンバスの`(x, y)`に配置する。はみ出た部分は切り取る
+    Place {
+        width: u32,
+        height: u32,
+        x: i64,
+        y: i64,
+    },
+    /// 時計回りに回転する
+    Rotate(Rotation),
+    /// 左右もしくは上下に反転する
+    Flip(Flip),
+    /// ガウスぼかしをかける
+    Blur(f32),
+    /// 透明度を保ったまま白黒にする
+    Grayscale,
+    /// 透明なピクセルを背景色と合成する
+    Flatten([u8; 4]),
+    /// 内接する楕円の外側を透明にする
+    Round,
+}
+
+impl Stage {
+    fn apply(&self, img: &RgbaImage) -> Result<RgbaImage> {
+        match *self {
+            Stage::Resize {
+                width,
+                height,
+                filter,
+            } => resize_rgba(img, width, height, filter),
+            Stage::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => Ok(imageops::crop_imm(img, x, y, width, height).to_image()),
+            Stage::Place {
+                width,
+                height,
+                x,
+                y,
+            } => {
+                let mut canvas = RgbaImage::new(width, height);
+                imageops::overlay(&mut canvas, img, x, y);
+                Ok(canvas)
+            }
+            Stage::Rotate(rotation) => Ok(match rotation {
+                Rotation::Rotate90 => imageops::rotate90(img),
+                Rotation::Rotate180 => imageops::rotate180(img),
+                Rotation::Rotate270 => imageops::rotate270(img),
+            }),
+            Stage::Flip(flip) => Ok(match flip {
+                Flip::H => imageops::flip_horizontal(img),
+                Flip::V => imageops::flip_vertical(img),
+            }),
+            Stage::Blur(sigma) => Ok(imageops::blur(img, sigma)),
+            Stage::Grayscale => {
+                Ok(image::DynamicImage::ImageLumaA8(imageops::grayscale_alpha(img)).into_rgba8())
+            }
+            Stage::Flatten(color) => {
+                let mut canvas =
+                    RgbaImage::from_pixel(img.width(), img.height(), image::Rgba(color));
+                imageops::overlay(&mut canvas, img, 0, 0);
+                Ok(canvas)
+            }
+            Stage::Round => {
+                let mut img = img.clone();
+                let (rx, ry) = (img.width() as f32 / 2.0, img.height() as f32 / 2.0);
+                for (x, y, pixel) in img.enumerate_pixels_mut() {
+                    let dx = (x as f32 + 0.5 - rx) / rx;
+                    let dy = (y as f32 + 0.5 - ry) / ry;
+                    if dx * dx + dy * dy > 1.0 {
+                        pixel[3] = 0;
+                    }
+                }
+                Ok(img)
+            }
+        }
+    }
+
+    /// `(width, height)`の画像に適用した後の大きさ
+    fn output_size(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        match *self {
+            Stage::Resize { width, height, .. }
+            | Stage::Crop { width, height, .. }
+            | Stage::Place { width, height, .. } => (width, height),
+            Stage::Rotate(Rotation::Rotate90 | Rotation::Rotate270) => (height, width),
+            Stage::Rotate(Rotation::Rotate180)
+            | Stage::Flip(_)
+            | Stage::Blur(_)
+            | Stage::Grayscale
+            | Stage::Flatten(_)
+            | Stage::Round => (width, height),
+        }
+    }
+}
+
+/// 変換できない画像
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidImage {
+    /// 取得元のレスポンスが空
+    EmptyBody,
+    /// 幅もしくは高さが0
+    ZeroSize,
+    /// アニメーションにフレームが1枚もない
+    NoFrames,
+    /// アニメーションのフレームの大きさが揃っていない
+    FrameSizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// 大きさが上限を超えている
+    TooLarge { width: u32, height: u32 },
+}
+
+impl std::fmt::Display for InvalidImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidImage::EmptyBody => write!(f, "Source returned an empty body"),
+            InvalidImage::ZeroSize => write!(f, "Image has zero width or height"),
+            InvalidImage::NoFrames => write!(f, "Animation has no frames"),
+            InvalidImage::FrameSizeMismatch { expected, actual } => write!(
+                f,
+                "Animation frame is {}x{} but expected {}x{}",
+                actual.0, actual.1, expected.0, expected.1
+            ),
+            InvalidImage::TooLarge { width, height } => {
+                write!(f, "Image too large: {}x{}", width, height)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidImage {}
+
+/// 完全な品質で変換できなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Degradation {
+    /// アニメーションのエンコードに失敗し、最初のフレームのみにした
+    StaticFallback,
+    /// フレームレートを揃えるためにフレームを捨てた
+    FramesDropped,
+    /// アニメーションを途中で切り詰めた
+    Truncated,
+}
+
+impl Degradation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Degradation::StaticFallback => "static-fallback",
+            Degradation::FramesDropped => "frames-dropped",
+            Degradation::Truncated => "truncated",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "static-fallback" => Some(Degradation::StaticFallback),
+            "frames-dropped" => Some(Degradation::FramesDropped),
+            "truncated" => Some(Degradation::Truncated),
+            _ => None,
+        }
+    }
+}
+
+/// アニメーションから取り出すフレーム
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameSelector {
+    /// 0から始まるフレームの番号
+    Index(u32),
+    /// 再生開始からの経過時間(ミリ秒)
+    Time(u32),
+}
+
+/// `selector`に一致するフレームを返す。範囲外の場合は最後のフレームを返す
+fn select_frame(
+    frames: impl Iterator<Item = Result<Frame>>,
+    selector: FrameSelector,
+) -> Result<Frame> {
+    let mut elapsed = 0u64;
+    let mut last = None;
+    for (i, f) in frames.enumerate() {
+        let f = f?;
+        let (numer, denom) = f.delay().numer_denom_ms();
+        let delay = numer as u64 / denom.max(1) as u64;
+        let hit = match selector {
+            FrameSelector::Index(index) => i as u64 == index as u64,
+            FrameSelector::Time(t) => (t as u64) < elapsed + delay,
+        };
+        if hit {
+            return Ok(f);
+        }
+        elapsed += delay;
+        last = Some(f);
+    }
+    last.ok_or(InvalidImage::NoFrames.into())
+}
+
+/// 変換の種類ごとの大きさ。デフォルトは仕様書の値
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PresetSizes {
+    pub emoji_height: u32,
+    pub avatar_height: u32,
+    pub preview_width: u32,
+    pub preview_height: u32,
+    pub badge_width: u32,
+    pub badge_height: u32,
+    /// `static`を指定した場合の高さ
+    pub static_height: u32,
+}
+
+impl Default for PresetSizes {
+    fn default() -> Self {
+        Self {
+            emoji_height: 128,
+            avatar_height: 320,
+            preview_width: 200,
+            preview_height: 200,
+            badge_width: 96,
+            badge_height: 96,
+            static_height: 422,
+        }
+    }
+}
+
+/// 拡大縮小に使うフィルタ
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    #[default]
+    Triangle,
+    CatmullRom,
+    Lanczos3,
+}
+
+impl std::str::FromStr for ResizeFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "triangle" => Ok(ResizeFilter::Triangle),
+            "catmullrom" => Ok(ResizeFilter::CatmullRom),
+            "lanczos3" => Ok(ResizeFilter::Lanczos3),
+            _ => Err(anyhow::anyhow!(
+                "filter must be nearest, triangle, catmullrom or lanczos3"
+            )),
+        }
+    }
+}
+
+/// 指定された大きさの枠に合わせる方法
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    /// 枠に収まるように縮小し、余白を透明で埋める
+    Contain,
+    /// 枠を覆うように拡大縮小し、はみ出た部分を中央で切り取る
+    Cover,
+    /// アスペクト比を無視して枠に合わせる
+    Fill,
+}
+
+/// 時計回りの回転角度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    /// 角度から作る。0度の場合は`None`
+    pub fn from_degrees(degrees: u32) -> Result<Option<Self>> {
+        match degrees {
+            0 => Ok(None),
+            90 => Ok(Some(Rotation::Rotate90)),
+            180 => Ok(Some(Rotation::Rotate180)),
+            270 => Ok(Some(Rotation::Rotate270)),
+            _ => Err(anyhow::anyhow!("rotate must be 0, 90, 180 or 270")),
+        }
+    }
+
+    pub fn degrees(&self) -> u32 {
+        match self {
+            Rotation::Rotate90 => 90,
+            Rotation::Rotate180 => 180,
+            Rotation::Rotate270 => 270,
+        }
+    }
+}
+
+/// 反転する向き
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flip {
+    /// 左右
+    H,
+    /// 上下
+    V,
+}
+
+/// プリセットの大きさに倍率を掛ける
+pub fn scaled(size: u32, dpr: f32) -> u32 {
+    ((size as f32 * dpr).round() as u32).max(1)
+}
+
+/// 不透明なピクセルを含む範囲を`(left, top, right, bottom)`で返す。すべて透明なら`None`
+fn opaque_bounds(img: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+    img.enumerate_pixels()
+        .filter(|(_, _, p)| p[3] != 0)
+        .fold(None, |acc, (x, y, _)| match acc {
+            None => Some((x, y, x, y)),
+            Some((l, t, r, b)) => Some((l.min(x), t.min(y), r.max(x), b.max(y))),
+        })
+}
+
+/// 2つの`opaque_bounds`の範囲を両方含む範囲
+fn union_bounds(a: (u32, u32, u32, u32), b: (u32, u32, u32, u32)) -> (u32, u32, u32, u32) {
+    (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3))
+}
+
+/// RGBA画像を指定された大きさに変換する
+#[cfg(not(feature = "fast-resize"))]
+fn resize_rgba(
+    img: &RgbaImage,
+    width: u32,
+    height: u32,
+    filter: ResizeFilter,
+) -> Result<RgbaImage> {
+    let filter = match filter {
+        ResizeFilter::Nearest => imageops::FilterType::Nearest,
+        ResizeFilter::Triangle => imageops::FilterType::Triangle,
+        ResizeFilter::CatmullRom => imageops::FilterType::CatmullRom,
+        ResizeFilter::Lanczos3 => imageops::FilterType::Lanczos3,
+    };
+    Ok(imageops::resize(img, width, height, filter))
+}
+
+/// RGBA画像をfast_image_resizeで指定された大きさに変換する。SIMDを使うため`imageops::resize`より高速
+#[cfg(feature = "fast-resize")]
+fn resize_rgba(
+    img: &RgbaImage,
+    width: u32,
+    height: u32,
+    filter: ResizeFilter,
+) -> Result<RgbaImage> {
+    use fast_image_resize::{
+        images::{Image, ImageRef},
+        FilterType, PixelType, ResizeAlg, ResizeOptions, Resizer,
+    };
+
+    let src = ImageRef::new(img.width(), img.height(), img.as_raw(), PixelType::U8x4)?;
+    let mut dst = Image::new(width, height, PixelType::U8x4);
+    let alg = match filter {
+        ResizeFilter::Nearest => ResizeAlg::Nearest,
+        ResizeFilter::Triangle => ResizeAlg::Convolution(FilterType::Bilinear),
+        ResizeFilter::CatmullRom => ResizeAlg::Convolution(FilterType::CatmullRom),
+        ResizeFilter::Lanczos3 => ResizeAlg::Convolution(FilterType::Lanczos3),
+    };
+    let options = ResizeOptions::new().resize_alg(alg);
+    Resizer::new().resize(&src, &mut dst, &options)?;
+
+    RgbaImage::from_raw(width, height, dst.into_vec()).context("resize rgba image failed")
+}
+
+/// フレームを`width`x`height`の透明なキャンバスの`(left, top)`に配置する。
+/// 既にキャンバスと同じ大きさで原点にある場合は何もしない
+pub fn place_on_canvas(frame: Frame, width: u32, height: u32) -> Frame {
+    if frame.buffer().dimensions() == (width, height) && (frame.left(), frame.top()) == (0, 0) {
+        return frame;
+    }
+    let mut canvas = RgbaImage::new(width, height);
+    imageops::overlay(
+        &mut canvas,
+        frame.buffer(),
+        frame.left() as i64,
+        frame.top() as i64,
+    );
+    Frame::from_parts(canvas, 0, 0, frame.delay())
+}
+
+/// `frames`枚のフレームから`cells`マスのスプライトシートに使うフレームの番号を等間隔に選ぶ
+fn sheet_indices(frames: usize, cells: usize) -> Vec<usize> {
+    let step = (frames as f64 / cells as f64).max(1.0);
+    (0..cells.min(frames))
+        .map(|i| (i as f64 * step) as usize)
+        .collect()
+}
+
+/// フレームの表示時間(ミリ秒)
+fn delay_ms(frame: &Frame) -> f64 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    numer as f64 / denom.max(1) as f64
+}
+
+/// 先頭から`max_frames`枚、`max_duration`ミリ秒までのフレームを返す。
+/// 最後のフレームの表示時間は`max_duration`に収まるように短くする
+fn truncate(
+    frames: impl Iterator<Item = Result<Frame>>,
+    max_frames: Option<u32>,
+    max_duration: Option<u32>,
+) -> impl Iterator<Item = Result<Frame>> {
+    let max_duration = max_duration.map_or(f64::INFINITY, |d| d as f64);
+    let mut elapsed = 0.0;
+    frames
+        .take(max_frames.map_or(usize::MAX, |n| n.max(1) as usize))
+        .map_while(move |f| {
+            let Result::Ok(f) = f else {
+                return Some(f);
+            };
+            // 最初のフレームは必ず残す
+            if elapsed > 0.0 && elapsed >= max_duration {
+                return None;
+            }
+            let delay = delay_ms(&f);
+            let start = elapsed;
+            elapsed += delay;
+            if elapsed <= max_duration {
+                return Some(Ok(f));
+            }
+            let remaining = ((max_duration - start).round() as u32).max(1);
+            let delay = image::Delay::from_numer_denom_ms(remaining, 1);
+            Some(Ok(Frame::from_parts(f.into_buffer(), 0, 0, delay)))
+        })
+}
+
+/// `truncate`で切り詰められるフレームがあるか
+fn is_truncated(delays: &[f64], max_frames: Option<u32>, max_duration: Option<u32>) -> bool {
+    if delays.len() <= 1 {
+        return false;
+    }
+    max_frames.is_some_and(|n| delays.len() > n.max(1) as usize)
+        || max_duration.is_some_and(|d| delays.iter().sum::<f64>() > d as f64)
+}
+
+/// `Resample`で一度も表示されずに捨てられるフレームがあるか
+fn drops_frames(delays: &[f64], fps: u32) -> bool {
+    let interval = 1000.0 / fps.max(1) as f64;
+    let mut start = 0.0;
+    delays.len() > 1
+        && delays.iter().any(|delay| {
+            let end = start + if *delay > 0.0 { *delay } else { interval };
+            // このフレームの表示中に最初に来る出力フレームの時刻
+            let tick = (start / interval).ceil() * interval;
+            start = end;
+            tick >= end
+        })
+}
+
+/// 同じとみなすピクセルの各チャンネルの差の上限。減色によるわずかな揺らぎを無視する
+const DUPLICATE_TOLERANCE: u8 = 2;
+
+/// 位置と大きさが同じで、各ピクセルの差が`DUPLICATE_TOLERANCE`以下のフレームか
+fn is_duplicate(a: &Frame, b: &Frame) -> bool {
+    (a.left(), a.top()) == (b.left(), b.top())
+        && a.buffer().dimensions() == b.buffer().dimensions()
+        && a.buffer()
+            .as_raw()
+            .iter()
+            .zip(b.buffer().as_raw())
+            .all(|(x, y)| x.abs_diff(*y) <= DUPLICATE_TOLERANCE)
+}
+
+/// 連続する同じ内容のフレームを1枚にまとめ、表示時間を合計するイテレータ。
+/// 表示時間を延ばすために同じフレームを繰り返すgifを小さく、速くエンコードできる
+struct MergeDuplicates<I> {
+    frames: I,
+    /// 次のフレームとまとめられるか確かめるまで保持するフレーム
+    pending: Option<Frame>,
+}
+
+impl<I> MergeDuplicates<I> {
+    fn new(frames: I) -> Self {
+        Self {
+            frames,
+            pending: None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Frame>>> Iterator for MergeDuplicates<I> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = match self.frames.next() {
+                Some(Result::Ok(f)) => f,
+                Some(Err(e)) => return Some(Err(e)),
+                None => return self.pending.take().map(Ok),
+            };
+            match self.pending.take() {
+                Some(pending) if is_duplicate(&pending, &frame) => {
+                    let delay = Duration::from(pending.delay()) + Duration::from(frame.delay());
+                    let (left, top) = (pending.left(), pending.top());
+                    self.pending = Some(Frame::from_parts(
+                        pending.into_buffer(),
+                        left,
+                        top,
+                        Delay::from_saturating_duration(delay),
+                    ));
+                }
+                Some(pending) => {
+                    self.pending = Some(frame);
+                    return Some(Ok(pending));
+                }
+                None => self.pending = Some(frame),
+            }
+        }
+    }
+}
+
+/// フレームの切り替わりを`1000 / fps`ミリ秒ごとの時刻に揃えるイテレータ。
+/// 表示中に一度も切り替わりの時刻が来ないフレームは捨て、それ以外は表示時間を間隔の倍数にする。
+/// フレームを複製しないので、長く表示するフレームがあっても出力は入力より多くならない
+struct Resample<I> {
+    frames: I,
+    fps: u32,
+    /// これまでのフレームの表示時間の合計
+    elapsed: f64,
+}
+
+impl<I> Resample<I> {
+    fn new(frames: I, fps: u32) -> Self {
+        Self {
+            frames,
+            fps: fps.max(1),
+            elapsed: 0.0,
+        }
+    }
+
+    /// `time`ミリ秒までに来る切り替わりの時刻の数
+    fn ticks(&self, time: f64) -> f64 {
+        (time * self.fps as f64 / 1000.0).ceil()
+    }
+}
+
+impl<I: Iterator<Item = Result<Frame>>> Iterator for Resample<I> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = match self.frames.next()? {
+                Result::Ok(f) => f,
+                Err(e) => return Some(Err(e)),
+            };
+            // 表示時間が0のフレームは1フレーム分表示する
+            let delay = match delay_ms(&frame) {
+                d if d > 0.0 => d,
+                _ => 1000.0 / self.fps as f64,
+            };
+            let start = self.elapsed;
+            self.elapsed += delay;
+            let ticks = self.ticks(self.elapsed) - self.ticks(start);
+            if ticks < 1.0 {
+                continue;
+            }
+            return Some(Ok(Frame::from_parts(
+                frame.into_buffer(),
+                0,
+                0,
+                Delay::from_numer_denom_ms((ticks as u32).saturating_mul(1000), self.fps),
+            )));
+        }
+    }
+}
+
+/// svgを`size`の大きさで描画する。`None`であればsvg本来の大きさで描画する
+fn rasterize_svg(txt: &str, size: Option<(u32, u32)>) -> Result<RgbaImage> {
+    let opt = usvg::Options::default();
+    let tree = usvg::Tree::from_str(txt, &opt, fontdb())?;
+
+    let intrinsic = tree.size();
+    let (width, height) = match size {
+        Some(size) => size,
+        None => {
+            let size = intrinsic.to_int_size();
+            (size.width(), size.height())
+        }
+    };
+    let transform = tiny_skia::Transform::from_scale(
+        width as f32 / intrinsic.width(),
+        height as f32 / intrinsic.height(),
+    );
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).context("init pixmap fail")?;
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    RgbaImage::from_raw(width, height, pixmap.take()).context("render svg error")
+}
+
+/// システムのフォントを読み込んだデータベース。読み込みに時間がかかるので最初に使う時に一度だけ読み込む
+pub fn fontdb() -> &'static usvg::fontdb::Database {
+    static FONTDB: OnceLock<usvg::fontdb::Database> = OnceLock::new();
+    FONTDB.get_or_init(|| {
+        let mut fontdb = usvg::fontdb::Database::new();
+        fontdb.load_system_fonts();
+        fontdb
+    })
+}
+
+type AnimFrames<'a> = Box<dyn Iterator<Item = Result<Frame>> + 'a>;
+
+/// gifもしくはアニメーションwebpを1フレームずつデコードするイテレータと、変換後の大きさを返す。
+/// `size`が指定されていれば各フレームをその大きさに変換し、
+/// `playback`を適用する。
+/// 合成済みのキャンバスと変換後のフレームのみを保持するため、フレーム数が多くてもメモリ使用量は増えない
+fn anim_frames(
+    buf: &[u8],
+    size: Option<(u32, u32)>,
+    playback: Playback,
+) -> Result<(u32, u32, AnimFrames<'_>)> {
+    let (screen_width, screen_height, frames): (_, _, AnimFrames) = if gif_delays(buf).is_some() {
+        let decoder = GifDecoder::new(Cursor::new(buf))?;
+        // 最初のフレームではなくLogical Screenの大きさをキャンバスにする
+        let (screen_width, screen_height) = decoder.dimensions();
+        let frames = decoder.into_frames().map(|f| Ok(f?));
+        (screen_width, screen_height, Box::new(frames))
+    } else {
+        // 途中のフレームで失敗した場合は試し直さない
+        decode_with_fallback(
+            ImageExt::Webp,
+            ("libwebp", || {
+                let frames = decode_webp_anim(buf)?;
+                let (screen_width, screen_height) = frames.dimensions();
+                Ok((screen_width, screen_height, Box::new(frames) as AnimFrames))
+            }),
+            ("image", || {
+                let decoder = WebPDecoder::new(Cursor::new(buf))?;
+                let (screen_width, screen_height) = decoder.dimensions();
+                let frames = decoder.into_frames().map(|f| Ok(f?));
+                Ok((screen_width, screen_height, Box::new(frames) as AnimFrames))
+            }),
+        )?
+    };
+    let (width, height) = size.unwrap_or((screen_width, screen_height));
+    let frames = frames.map(move |f| {
+        let f = place_on_canvas(f?, screen_width, screen_height);
+        if size.is_none() {
+            return Ok(f);
+        }
+        let resized = resize_rgba(f.buffer(), width, height, playback.filter)?;
+        Ok(Frame::from_parts(resized, 0, 0, f.delay()))
+    });
+    let (width, height) = playback.output_size((width, height));
+    Ok((width, height, playback.apply(frames)))
 }
 
 /// 画像の変換処理を実装する
 /// 仕様書: https://github.com/misskey-dev/media-proxy/blob/master/SPECIFICATION.md
 impl DecodeResult {
     /// emojiを指定された際の大きさに変換する
-    pub(crate) fn emoji(self) -> Result<DecodeResult> {
-        const EMOJI_HEIGHT: u32 = 128;
-
-        self.resize_by_height(EMOJI_HEIGHT)
+    pub fn emoji(
+        self,
+        sizes: &PresetSizes,
+        dpr: f32,
+        filter: ResizeFilter,
+    ) -> Result<DecodeResult> {
+        self.resize_by_height(scaled(sizes.emoji_height, dpr), filter)
     }
 
     /// avaterを指定された際の大きさに変換する
-    pub(crate) fn avatar(self) -> Result<DecodeResult> {
-        const AVATER_HEIGHT: u32 = 320;
-
-        self.resize_by_height(AVATER_HEIGHT)
+    pub fn avatar(
+        self,
+        sizes: &PresetSizes,
+        dpr: f32,
+        filter: ResizeFilter,
+    ) -> Result<DecodeResult> {
+        self.resize_by_height(scaled(sizes.avatar_height, dpr), filter)
     }
 
     /// previewを指定された際の大きさに変換する
-    pub(crate) fn preview(self) -> Result<DecodeResult> {
-        const PREVIEW_HEIGHT: u32 = 200;
-        const PREVIEW_WIDTH: u32 = 200;
-
-        self.resize(PREVIEW_HEIGHT, PREVIEW_WIDTH)
+    pub fn preview(
+        self,
+        sizes: &PresetSizes,
+        dpr: f32,
+        filter: ResizeFilter,
+    ) -> Result<DecodeResult> {
+        self.resize(
+            scaled(sizes.preview_height, dpr),
+            scaled(sizes.preview_width, dpr),
+            filter,
+        )
     }
 
     /// badgeに対応した際の大きさに変換する
-    pub(crate) fn badge(self) -> Result<DecodeResult> {
-        const BADGE_HEIGHT: u32 = 96;
-        const BADGE_WIDTH: u32 = 96;
-
-        self.resize(BADGE_HEIGHT, BADGE_WIDTH)
+    pub fn badge(
+        self,
+        sizes: &PresetSizes,
+        dpr: f32,
+        filter: ResizeFilter,
+    ) -> Result<DecodeResult> {
+        self.resize(
+            scaled(sizes.badge_height, dpr),
+            scaled(sizes.badge_width, dpr),
+            filter,
+        )
     }
 
     /// アニメーション画像であれば最初のフレームのみにする。ついでに大きさも変換する
-    pub(crate) fn static_(self) -> Result<DecodeResult> {
-        const STATIC_HEIGHT: u32 = 422;
+    pub fn static_(
+        self,
+        sizes: &PresetSizes,
+        dpr: f32,
+        filter: ResizeFilter,
+    ) -> Result<DecodeResult> {
+        self.first()?
+            .resize_by_height(scaled(sizes.static_height, dpr), filter)
+    }
+
+    /// 幅や高さが0の画像、フレームのないアニメーション、大きさの揃っていないフレームを拒否する
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            DecodeResult::Image(img) => {
+                if img.width() == 0 || img.height() == 0 {
+                    return Err(InvalidImage::ZeroSize.into());
+                }
+            }
+            DecodeResult::Movie(frames) => {
+                let first = frames.first().ok_or(InvalidImage::NoFrames)?;
+                let expected = first.buffer().dimensions();
+                if expected.0 == 0 || expected.1 == 0 {
+                    return Err(InvalidImage::ZeroSize.into());
+                }
+                if let Some(f) = frames.iter().find(|f| f.buffer().dimensions() != expected) {
+                    return Err(InvalidImage::FrameSizeMismatch {
+                        expected,
+                        actual: f.buffer().dimensions(),
+                    }
+                    .into());
+                }
+            }
+            DecodeResult::TextFmt(_) | DecodeResult::AnimStream { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// アニメーション画像であれば指定されたフレームのみにする
+    pub fn frame(self, selector: FrameSelector) -> Result<DecodeResult> {
+        match self {
+            DecodeResult::Image(_) | DecodeResult::TextFmt(_) => Ok(self),
+            DecodeResult::Movie(frames) => Ok(DecodeResult::Image(
+                select_frame(frames.into_iter().map(Ok), selector)?.into_buffer(),
+            )),
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => {
+                let (_, _, frames) = anim_frames(&buf, size, playback)?;
+                Ok(DecodeResult::Image(
+                    select_frame(frames, selector)?.into_buffer(),
+                ))
+            }
+        }
+    }
+
+    /// 複数のフレームを持つアニメーションか
+    pub fn is_animated(&self) -> bool {
+        self.delays().len() > 1
+    }
+
+    /// 各フレームの表示時間(ミリ秒)。静止画の場合は空
+    fn delays(&self) -> Vec<f64> {
+        match self {
+            DecodeResult::Movie(frames) => frames.iter().map(delay_ms).collect(),
+            DecodeResult::AnimStream { buf, .. } => gif_delays(buf)
+                .or_else(|| webp_delays(buf))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|d| d as f64)
+                .collect(),
+            DecodeResult::Image(_) | DecodeResult::TextFmt(_) => vec![],
+        }
+    }
+
+    /// `resample`でフレームが捨てられるか
+    pub fn resample_drops_frames(&self, fps: u32) -> bool {
+        drops_frames(&self.delays(), fps)
+    }
+
+    /// `truncate`でフレームが切り詰められるか
+    pub fn truncate_cuts(&self, max_frames: Option<u32>, max_duration: Option<u32>) -> bool {
+        is_truncated(&self.delays(), max_frames, max_duration)
+    }
+
+    /// アニメーションのフレームを`fps`の等間隔に並べ直す。フレームの間隔が不揃いな画像を整え、
+    /// フレーム数の上限にもなる
+    pub fn resample(self, fps: u32) -> Result<DecodeResult> {
+        match self {
+            DecodeResult::Movie(frames) if frames.len() > 1 => Ok(DecodeResult::Movie(
+                Resample::new(frames.into_iter().map(Ok), fps).collect::<Result<_>>()?,
+            )),
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => Ok(DecodeResult::AnimStream {
+                buf,
+                size,
+                playback: Playback {
+                    fps: Some(fps),
+                    ..playback
+                },
+            }),
+            _ => Ok(self),
+        }
+    }
+
+    /// アニメーションを先頭から`max_frames`枚、`max_duration`ミリ秒までに切り詰める
+    pub fn truncate(
+        self,
+        max_frames: Option<u32>,
+        max_duration: Option<u32>,
+    ) -> Result<DecodeResult> {
+        match self {
+            DecodeResult::Movie(frames) => Ok(DecodeResult::Movie(
+                truncate(frames.into_iter().map(Ok), max_frames, max_duration)
+                    .collect::<Result<_>>()?,
+            )),
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => Ok(DecodeResult::AnimStream {
+                buf,
+                size,
+                playback: Playback {
+                    max_frames,
+                    max_duration,
+                    ..playback
+                },
+            }),
+            _ => Ok(self),
+        }
+    }
+
+    /// 完全に透明な余白を切り取る。アニメーションの場合はすべてのフレームを含む範囲で切り取る
+    /// ## Note
+    /// すべてのピクセルが透明な場合は何も行わない
+    pub fn trim(self) -> Result<Self> {
+        const TRIM_PADDING: u32 = 2;
+
+        let bounds = match &self {
+            DecodeResult::Image(img) => opaque_bounds(img),
+            DecodeResult::Movie(frames) => frames
+                .iter()
+                .filter_map(|f| opaque_bounds(f.buffer()))
+                .reduce(union_bounds),
+            DecodeResult::TextFmt(_) => return self.render_svg()?.trim(),
+            // 範囲を求めるために1フレームずつデコードし、切り取りはエンコード時に行う
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => {
+                let (_, _, frames) = anim_frames(buf, *size, playback.clone())?;
+                let mut bounds = None;
+                for f in frames {
+                    if let Some(b) = opaque_bounds(f?.buffer()) {
+                        bounds = Some(bounds.map_or(b, |a| union_bounds(a, b)));
+                    }
+                }
+                bounds
+            }
+        };
+        let Some((left, top, right, bottom)) = bounds else {
+            return Ok(self);
+        };
+
+        let width = self.width()?;
+        let height = self.height()?;
+        let x = left.saturating_sub(TRIM_PADDING);
+        let y = top.saturating_sub(TRIM_PADDING);
+        let w = (right + TRIM_PADDING + 1).min(width) - x;
+        let h = (bottom + TRIM_PADDING + 1).min(height) - y;
+        if (w, h) == (width, height) {
+            return Ok(self);
+        }
+
+        self.map_stage(Stage::Crop {
+            x,
+            y,
+            width: w,
+            height: h,
+        })
+    }
+
+    /// 透明な余白を上下もしくは左右に足して正方形にする。元の画像は中央に配置される
+    /// ## Note
+    /// 極端な縦横比の画像は正方形にすると巨大になるので、`limits`の画素数を超える場合は拒否する
+    pub fn pad_square(self, limits: &Limits) -> Result<Self> {
+        let width = self.width()?;
+        let height = self.height()?;
+        if width == height {
+            return Ok(self);
+        }
+
+        let side = width.max(height);
+        if side as u64 * side as u64 > limits.max_pixels {
+            return Err(InvalidImage::TooLarge {
+                width: side,
+                height: side,
+            }
+            .into());
+        }
+        self.map_stage(Stage::Place {
+            width: side,
+            height: side,
+            x: ((side - width) / 2) as i64,
+            y: ((side - height) / 2) as i64,
+        })
+    }
+
+    /// 透明なピクセルを`color`の背景と合成する
+    pub fn flatten(self, color: [u8; 4]) -> Result<Self> {
+        self.map_stage(Stage::Flatten(color))
+    }
+
+    /// 時計回りに回転する
+    pub fn rotate(self, rotation: Rotation) -> Result<Self> {
+        self.map_stage(Stage::Rotate(rotation))
+    }
+
+    /// 左右もしくは上下に反転する
+    pub fn flip(self, flip: Flip) -> Result<Self> {
+        self.map_stage(Stage::Flip(flip))
+    }
+
+    /// ガウスぼかしをかける。`sigma`は出力の大きさに対するピクセル数
+    pub fn blur(self, sigma: f32) -> Result<Self> {
+        self.map_stage(Stage::Blur(sigma))
+    }
+
+    /// 透明度を保ったまま白黒にする
+    pub fn grayscale(self) -> Result<Self> {
+        self.map_stage(Stage::Grayscale)
+    }
+
+    /// 内接する楕円の外側を透明にする
+    pub fn round(self) -> Result<Self> {
+        self.map_stage(Stage::Round)
+    }
+
+    /// 幅が`max_width`以下、高さが`max_height`以下になるように変換を行う。その際アスペクト比は維持される
+    /// ## Note
+    /// もともとの画像もしくは動画が収まっている場合何も行わない
+    pub fn fit(
+        self,
+        max_width: Option<u32>,
+        max_height: Option<u32>,
+        filter: ResizeFilter,
+    ) -> Result<Self> {
+        let width = self.width()?;
+        let height = self.height()?;
+        let max_width = max_width.unwrap_or(width);
+        let max_height = max_height.unwrap_or(height);
+        if width <= max_width && height <= max_height {
+            return Ok(self);
+        }
+
+        let (w, h) = if width as u64 * max_height as u64 > height as u64 * max_width as u64 {
+            (
+                max_width,
+                (height as u64 * max_width as u64 / width as u64) as u32,
+            )
+        } else {
+            (
+                (width as u64 * max_height as u64 / height as u64) as u32,
+                max_height,
+            )
+        };
+        self.resize(h.max(1), w.max(1), filter)
+    }
+
+    /// `width`x`height`の枠に`mode`の方法で合わせる。出力は常に枠と同じ大きさになる
+    pub fn fit_box(
+        self,
+        width: u32,
+        height: u32,
+        mode: FitMode,
+        filter: ResizeFilter,
+    ) -> Result<Self> {
+        let (src_w, src_h) = (self.width()? as f64, self.height()? as f64);
+        let (w, h) = (width as f64, height as f64);
+        let scale = match mode {
+            FitMode::Fill => return self.resize(height, width, filter),
+            FitMode::Contain => (w / src_w).min(h / src_h),
+            FitMode::Cover => {
+                // 全体を拡大してから切り取ると極端な縦横比で途中の画像が巨大になるので、
+                // 先に枠と同じ縦横比の範囲を切り取ってから枠の大きさにする
+                let crop_w = (src_h * w / h).min(src_w).round().max(1.0) as u32;
+                let crop_h = (src_w * h / w).min(src_h).round().max(1.0) as u32;
+                let cropped = self.map_stage(Stage::Crop {
+                    x: (src_w as u32 - crop_w) / 2,
+                    y: (src_h as u32 - crop_h) / 2,
+                    width: crop_w,
+                    height: crop_h,
+                })?;
+                return cropped.resize(height, width, filter);
+            }
+        };
+        let resized_w = ((src_w * scale).round() as u32).max(1);
+        let resized_h = ((src_h * scale).round() as u32).max(1);
+        let resized = self.resize(resized_h, resized_w, filter)?;
+
+        // 枠との差分を中央に寄せて埋めるか切り取る
+        resized.map_stage(Stage::Place {
+            width,
+            height,
+            x: (width as i64 - resized_w as i64) / 2,
+            y: (height as i64 - resized_h as i64) / 2,
+        })
+    }
+
+    /// 静止画もしくはアニメーションのすべてのフレームに`stage`を適用する。
+    /// `AnimStream`はデコードせずに、エンコード時に1フレームずつ適用する
+    fn map_stage(self, stage: Stage) -> Result<Self> {
+        let res = match self {
+            DecodeResult::Image(img) => DecodeResult::Image(stage.apply(&img)?),
+            DecodeResult::Movie(frames) => DecodeResult::Movie(
+                frames
+                    .into_iter()
+                    .map(|f| Ok(Frame::from_parts(stage.apply(f.buffer())?, 0, 0, f.delay())))
+                    .collect::<Result<_>>()?,
+            ),
+            DecodeResult::TextFmt(_) => return self.render_svg()?.map_stage(stage),
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                mut playback,
+            } => {
+                playback.stages.push(stage);
+                DecodeResult::AnimStream {
+                    buf,
+                    size,
+                    playback,
+                }
+            }
+        };
+        Ok(res)
+    }
+
+    /// アニメーションからフレームを等間隔に抜き出し、`cols`x`rows`のグリッド画像にする
+    /// ## Note
+    /// フレーム数がマスの数より少ない場合、残りのマスは透明のままになる
+    pub fn sheet(self, cols: u32, rows: u32) -> Result<DecodeResult> {
+        const SHEET_CELL_HEIGHT: u32 = 128;
+        // 横長の画像でシートが巨大にならないようにマスの幅も抑える
+        const SHEET_CELL_WIDTH: u32 = SHEET_CELL_HEIGHT * 4;
+
+        if let DecodeResult::TextFmt(_) = self {
+            return self.render_svg()?.sheet(cols, rows);
+        }
+        let (width, height) = (self.width()?, self.height()?);
+        let scale = (SHEET_CELL_WIDTH as f64 / width as f64)
+            .min(SHEET_CELL_HEIGHT as f64 / height as f64)
+            .min(1.0);
+        let cell_w = ((width as f64 * scale).round() as u32).max(1);
+        let cell_h = ((height as f64 * scale).round() as u32).max(1);
+
+        let cells = (cols * rows) as usize;
+        let mut canvas = RgbaImage::new(cell_w * cols, cell_h * rows);
+        let mut put = |i: usize, img: &RgbaImage| -> Result<()> {
+            let i = i as u32;
+            let resized = resize_rgba(img, cell_w, cell_h, ResizeFilter::default())?;
+            imageops::overlay(
+                &mut canvas,
+                &resized,
+                ((i % cols) * cell_w) as i64,
+                ((i / cols) * cell_h) as i64,
+            );
+            Ok(())
+        };
+        match self {
+            DecodeResult::Image(img) => put(0, &img)?,
+            DecodeResult::Movie(frames) => {
+                for (i, index) in sheet_indices(frames.len(), cells).into_iter().enumerate() {
+                    put(i, frames[index].buffer())?;
+                }
+            }
+            // マスに使うフレームだけを1フレームずつデコードして配置する
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => {
+                // 再生時間を変えていなければ、デコードせずにヘッダーからフレーム数がわかる
+                let count = match (playback.max_frames, playback.max_duration, playback.fps) {
+                    (None, None, None) => gif_delays(&buf).or_else(|| webp_delays(&buf)),
+                    _ => None,
+                };
+                let playback = Playback {
+                    keep_duplicates: true,
+                    ..playback
+                };
+                let count = match count {
+                    Some(delays) => delays.len(),
+                    None => {
+                        let (_, _, mut frames) = anim_frames(&buf, size, playback.clone())?;
+                        frames.try_fold(0, |n, f| f.map(|_| n + 1))?
+                    }
+                };
+                let mut indices = sheet_indices(count, cells)
+                    .into_iter()
+                    .enumerate()
+                    .peekable();
+                let (_, _, frames) = anim_frames(&buf, size, playback)?;
+                for (index, f) in frames.enumerate() {
+                    let Some((i, _)) = indices.next_if(|(_, next)| *next == index) else {
+                        continue;
+                    };
+                    put(i, f?.buffer())?;
+                    if indices.peek().is_none() {
+                        break;
+                    }
+                }
+            }
+            DecodeResult::TextFmt(_) => unreachable!("svg is rendered before"),
+        }
 
-        self.first()?.resize_by_height(STATIC_HEIGHT)
+        Ok(DecodeResult::Image(canvas))
     }
 
     /// webpにエンコードする
-    pub(crate) fn to_webp(self, quality_factor: f32) -> Result<Vec<u8>> {
+    pub fn to_webp(self, options: &WebpOptions) -> Result<Vec<u8>> {
+        match self {
+            DecodeResult::Image(img) => encode_webp_image(img, options),
+            DecodeResult::Movie(frames) => {
+                let frames = MergeDuplicates::new(frames.into_iter().map(Ok));
+                encode_webp_anim(frames.collect::<Result<_>>()?, options)
+            }
+            DecodeResult::TextFmt(_) => self.render_svg()?.to_webp(options),
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => {
+                let (width, height, frames) = anim_frames(&buf, size, playback)?;
+                encode_webp_anim_stream(width, height, frames, options)
+            }
+        }
+    }
+
+    /// アニメーションAVIFにエンコードする
+    #[cfg(feature = "avif")]
+    pub fn to_avif(self, quality: f32) -> Result<Vec<u8>> {
         match self {
-            DecodeResult::Image(img) => encode_webp_image(img, quality_factor),
-            DecodeResult::Movie(frames) => encode_webp_anim(frames, quality_factor),
-            DecodeResult::TextFmt(_) => self.render_svg()?.to_webp(quality_factor),
+            DecodeResult::Movie(frames) => {
+                let (width, height) = frames.iter().fold((0, 0), |(w, h), f| {
+                    (
+                        w.max(f.left() + f.buffer().width()),
+                        h.max(f.top() + f.buffer().height()),
+                    )
+                });
+                let frames = MergeDuplicates::new(frames.into_iter().map(Ok));
+                encode_avif_anim(width, height, frames, quality)
+            }
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => {
+                let (width, height, frames) = anim_frames(&buf, size, playback)?;
+                encode_avif_anim(width, height, frames, quality)
+            }
+            DecodeResult::Image(_) | DecodeResult::TextFmt(_) => {
+                Err(anyhow::anyhow!("AVIF output supports only animations"))
+            }
         }
     }
 
     /// pngにエンコードする
-    pub(crate) fn to_png(self) -> Result<Vec<u8>> {
+    pub fn to_png(self) -> Result<Vec<u8>> {
         match self {
             DecodeResult::Image(img) => {
                 let mut buf: Vec<u8> = vec![];
                 img.write_to(&mut std::io::Cursor::new(&mut buf), image::ImageFormat::Png)?;
                 Ok(buf)
             }
-            DecodeResult::Movie(_) => self.first()?.to_png(),
+            DecodeResult::Movie(_) | DecodeResult::AnimStream { .. } => self.first()?.to_png(),
             DecodeResult::TextFmt(_) => self.render_svg()?.to_png(),
         }
     }
 
+    /// gifにエンコードする。パレットは各フレームごとに減色して作られる
+    pub fn to_gif(self) -> Result<Vec<u8>> {
+        // 1が最も高品質だが遅い。imageのデフォルトと同じ値
+        const GIF_ENCODE_SPEED: i32 = 10;
+
+        let encode = |frames: &mut dyn Iterator<Item = Result<Frame>>| -> Result<Vec<u8>> {
+            let mut buf: Vec<u8> = vec![];
+            {
+                let mut encoder = GifEncoder::new_with_speed(&mut buf, GIF_ENCODE_SPEED);
+                encoder.set_repeat(Repeat::Infinite)?;
+                for frame in frames {
+                    encoder.encode_frame(frame?)?;
+                }
+            }
+            Ok(buf)
+        };
+
+        match self {
+            DecodeResult::Image(img) => encode(&mut std::iter::once(Ok(Frame::new(img)))),
+            DecodeResult::Movie(frames) => {
+                encode(&mut MergeDuplicates::new(frames.into_iter().map(Ok)))
+            }
+            DecodeResult::TextFmt(_) => self.render_svg()?.to_gif(),
+            // 1フレームずつデコードしながらエンコードする
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => {
+                let (_, _, mut frames) = anim_frames(&buf, size, playback)?;
+                encode(&mut frames)
+            }
+        }
+    }
+
     /// 大きさを変換する
-    fn resize(self, h: u32, w: u32) -> Result<DecodeResult> {
+    fn resize(self, h: u32, w: u32, filter: ResizeFilter) -> Result<DecodeResult> {
         match self {
             DecodeResult::Image(img) => {
-                let resized = imageops::resize(&img, w, h, imageops::FilterType::Triangle);
+                let resized = resize_rgba(&img, w, h, filter)?;
                 Ok(DecodeResult::Image(resized))
             }
             DecodeResult::Movie(frames) => {
                 let mut tmp = Vec::new();
 
                 for f in frames {
-                    let resized =
-                        imageops::resize(f.buffer(), w, h, imageops::FilterType::Triangle);
+                    let resized = resize_rgba(f.buffer(), w, h, filter)?;
                     let new_frame = Frame::from_parts(resized, 0, 0, f.delay());
                     tmp.push(new_frame);
                 }
 
                 Ok(DecodeResult::Movie(tmp))
             }
-            DecodeResult::TextFmt(_) => self.render_svg()?.resize(h, w),
+            // 本来の大きさで描画してから縮小するとぼやけるため、直接出力の大きさで描画する
+            DecodeResult::TextFmt(txt) => {
+                Ok(DecodeResult::Image(rasterize_svg(&txt, Some((w, h)))?))
+            }
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                mut playback,
+            } => {
+                // 他の変換の後であれば、それらを適用したフレームを拡大縮小する
+                if playback.stages.is_empty() {
+                    return Ok(DecodeResult::AnimStream {
+                        buf,
+                        size: Some((w, h)),
+                        playback: Playback { filter, ..playback },
+                    });
+                }
+                playback.stages.push(Stage::Resize {
+                    width: w,
+                    height: h,
+                    filter,
+                });
+                Ok(DecodeResult::AnimStream {
+                    buf,
+                    size,
+                    playback,
+                })
+            }
         }
     }
 
     /// 仕様書にあるように高さが`height`以下になるように変換を行う。その際アスペクト比は維持される
     /// ## Note
     /// もともとの画像もしくは動画の高さが`height`以下の場合何も行わない
-    fn resize_by_height(self, height: u32) -> Result<Self> {
+    fn resize_by_height(self, height: u32, filter: ResizeFilter) -> Result<Self> {
         let current_height = self.height()?;
         if current_height <= height {
             return Ok(self);
         }
 
         let width = self.width()? * height / current_height;
-        self.resize(height, width)
+        self.resize(height, width, filter)
     }
 
     /// svgを画像に変換する
-    fn render_svg(self) -> Result<DecodeResult> {
+    pub fn render_svg(self) -> Result<DecodeResult> {
         let res = match self {
             DecodeResult::Image(_) => self,
             DecodeResult::Movie(_) => self,
-            DecodeResult::TextFmt(txt) => {
-                let opt = usvg::Options::default();
-                // opt.default_size = usvg::Size::from_wh(w as f32, h as f32).context("")?;
-                let mut fontdb = usvg::fontdb::Database::new();
-                fontdb.load_system_fonts();
-
-                let tree = usvg::Tree::from_str(&txt, &opt, &fontdb)?;
-
-                let pixmap_size: resvg::tiny_skia::IntSize = tree.size().to_int_size();
-                let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height())
-                    .context("init pixmap fail")?;
-                resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+            DecodeResult::AnimStream { .. } => self,
+            DecodeResult::TextFmt(txt) => DecodeResult::Image(rasterize_svg(&txt, None)?),
+        };
+        Ok(res)
+    }
 
-                let img =
-                    RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixmap.data().to_vec())
-                        .context("render svg error")?;
+    /// svgを`max_width`x`max_height`に収まるように縮小して描画する。本来の大きさより大きくは描画しない
+    pub fn render_svg_within(
+        self,
+        (max_width, max_height): (Option<u32>, Option<u32>),
+    ) -> Result<DecodeResult> {
+        let DecodeResult::TextFmt(txt) = &self else {
+            return Ok(self);
+        };
+        let (width, height) = (self.width()?, self.height()?);
+        let scale = [
+            max_width.map(|w| w as f64 / width as f64),
+            max_height.map(|h| h as f64 / height as f64),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(1.0, f64::min);
+        if scale >= 1.0 {
+            return self.render_svg();
+        }
+        let scaled = |n: u32| ((n as f64 * scale).round() as u32).max(1);
+        let size = (scaled(width), scaled(height));
+        Ok(DecodeResult::Image(rasterize_svg(txt, Some(size))?))
+    }
 
-                DecodeResult::Image(img)
-            }
+    /// svg本来の大きさが上限以内か確かめる。本来の大きさで描画されることがあるので、描画する前に確かめる
+    pub fn check_svg_size(&self, limits: &Limits) -> Result<()> {
+        let DecodeResult::TextFmt(_) = self else {
+            return Ok(());
         };
-        Ok(res)
+        let (width, height) = (self.width()?, self.height()?);
+        if width as u64 * height as u64 > limits.max_pixels
+            || width > limits.max_dimension
+            || height > limits.max_dimension
+        {
+            return Err(InvalidImage::TooLarge { width, height }.into());
+        }
+        Ok(())
     }
 
     /// 一枚の画像に変換する。もとから単一の画像であれば何もしない
@@ -141,13 +1433,40 @@ impl DecodeResult {
             DecodeResult::Image(_) => Ok(self),
             DecodeResult::TextFmt(_) => Ok(self),
             DecodeResult::Movie(frames) => {
-                let first = frames
-                    .into_iter()
-                    .next()
-                    .context("cannot find first frame")?;
+                let first = frames.into_iter().next().ok_or(InvalidImage::NoFrames)?;
 
                 Ok(DecodeResult::Image(first.into_buffer()))
             }
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => {
+                let playback = Playback {
+                    filter: playback.filter,
+                    stages: playback.stages,
+                    ..Default::default()
+                };
+                let (_, _, mut frames) = anim_frames(&buf, size, playback)?;
+                let first = frames.next().ok_or(InvalidImage::NoFrames)??;
+
+                Ok(DecodeResult::Image(first.into_buffer()))
+            }
+        }
+    }
+
+    /// `AnimStream`のすべてのフレームをデコードする。それ以外は何もしない
+    pub fn collect(self) -> Result<DecodeResult> {
+        match self {
+            DecodeResult::AnimStream {
+                buf,
+                size,
+                playback,
+            } => {
+                let (_, _, frames) = anim_frames(&buf, size, playback)?;
+                Ok(DecodeResult::Movie(frames.collect::<Result<_>>()?))
+            }
+            _ => Ok(self),
         }
     }
 
@@ -156,12 +1475,13 @@ impl DecodeResult {
         match self {
             DecodeResult::Image(img) => Ok(img.height()),
             DecodeResult::Movie(frames) => {
-                let first = frames.first().context("cannot find first frame")?;
+                let first = frames.first().ok_or(InvalidImage::NoFrames)?;
                 Ok(first.buffer().height())
             }
             DecodeResult::TextFmt(txt) => {
                 Ok(Self::create_svg_tree(txt)?.size().to_int_size().height())
             }
+            DecodeResult::AnimStream { .. } => Ok(self.anim_size()?.1),
         }
     }
 
@@ -170,22 +1490,40 @@ impl DecodeResult {
         match self {
             DecodeResult::Image(img) => Ok(img.width()),
             DecodeResult::Movie(frames) => {
-                let first = frames.first().context("cannot find first frame")?;
+                let first = frames.first().ok_or(InvalidImage::NoFrames)?;
                 Ok(first.buffer().width())
             }
             DecodeResult::TextFmt(txt) => {
                 Ok(Self::create_svg_tree(txt)?.size().to_int_size().width())
             }
+            DecodeResult::AnimStream { .. } => Ok(self.anim_size()?.0),
         }
     }
 
+    /// `AnimStream`の変換後の大きさ。`size`がなければヘッダーから読む
+    fn anim_size(&self) -> Result<(u32, u32)> {
+        let DecodeResult::AnimStream {
+            buf,
+            size,
+            playback,
+        } = self
+        else {
+            unreachable!("only animation streams have a deferred size")
+        };
+        let size = match size {
+            Some(size) => *size,
+            None => {
+                let (width, height, _) = anim_frames(buf, None, Playback::default())?;
+                (width, height)
+            }
+        };
+        Ok(playback.output_size(size))
+    }
+
     fn create_svg_tree(txt: &str) -> Result<usvg::Tree> {
         let opt = usvg::Options::default();
         // opt.default_size = usvg::Size::from_wh(w as f32, h as f32).context("")?;
-        let mut fontdb = usvg::fontdb::Database::new();
-        fontdb.load_system_fonts();
-
-        let tree = usvg::Tree::from_str(txt, &opt, &fontdb)?;
+        let tree = usvg::Tree::from_str(txt, &opt, fontdb())?;
         Ok(tree)
     }
 }
@@ -196,21 +1534,27 @@ mod tests {
 
     use crate::{client::*};
 
+    use super::{
+        DecodeResult, FitMode, Flip, FrameSelector, InvalidImage, PresetSizes, ResizeFilter,
+        Rotation,
+    };
+    use crate::webp::{decode_webp_anim, WebpOptions};
+
     use anyhow::Ok;
     use reqwest::Url;
     use rstest::*;
 
     #[fixture]
     fn client() -> reqwest::Client {
-        get_client(None).unwrap()
+        get_client(&ClientConfig::default()).unwrap()
     }
 
     #[rstest]
     #[tokio::test]
     async fn webp_image_encode_test(client: reqwest::Client) -> anyhow::Result<()> {
         let url = Url::parse("https://github.com/tunamaguro.png")?;
-        let res = download_image(&client, &url).await?;
-        let webp = res.to_webp(75.0)?;
+        let res = download_image(&client, &url, &Limits::default()).await?;
+        let webp = res.to_webp(&WebpOptions::new(75.0))?;
         let mut file = tokio::fs::File::create("./tests/out/avater.webp").await?;
 
         let mut contents = Cursor::new(webp);
@@ -226,9 +1570,9 @@ mod tests {
         let url = Url::parse(
             "https://media1.giphy.com/media/v1.Y2lkPTc5MGI3NjExMG9laDA4MGFvb3FmaG1wZ3BjaGswYTNtM3hoc29jYmozbXl5d3d5MiZlcD12MV9pbnRlcm5hbF9naWZfYnlfaWQmY3Q9Zw/BfbUe877N4xsUhpcPc/giphy.gif",
         )?;
-        let res = download_image(&client, &url).await?;
+        let res = download_image(&client, &url, &Limits::default()).await?;
 
-        let webp = res.to_webp(75.0)?;
+        let webp = res.to_webp(&WebpOptions::new(75.0))?;
         let mut file = tokio::fs::File::create("./tests/out/anim.webp").await?;
 
         let mut contents = Cursor::new(webp);
@@ -236,4 +1580,659 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn trim_test() -> anyhow::Result<()> {
+        let mut img = image::RgbaImage::new(100, 100);
+        for x in 40..50 {
+            for y in 10..30 {
+                img.put_pixel(x, y, image::Rgba([255, 0, 0, 255]));
+            }
+        }
+        match DecodeResult::Image(img).trim()? {
+            // 2pxの余白を含む
+            DecodeResult::Image(img) => assert_eq!(img.dimensions(), (14, 24)),
+            _ => panic!("trim must keep a single image"),
+        }
+
+        let empty = DecodeResult::Image(image::RgbaImage::new(100, 100)).trim()?;
+        assert_eq!(empty.width()?, 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pad_square_test() -> anyhow::Result<()> {
+        let img = image::RgbaImage::from_pixel(40, 20, image::Rgba([0, 0, 255, 255]));
+        match DecodeResult::Image(img).pad_square(&Limits::default())? {
+            DecodeResult::Image(img) => {
+                assert_eq!(img.dimensions(), (40, 40));
+                assert_eq!(img.get_pixel(0, 0)[3], 0);
+                assert_eq!(img.get_pixel(0, 10)[3], 255);
+            }
+            _ => panic!("pad_square must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn pad_square_too_large_test() {
+        // 縮小しない場合に65535x65535のキャンバスになる
+        let img = image::RgbaImage::new(1, 65535);
+        let Err(err) = DecodeResult::Image(img).pad_square(&Limits::default()) else {
+            panic!("pad_square must reject a huge canvas");
+        };
+        assert_eq!(
+            err.downcast_ref::<InvalidImage>(),
+            Some(&InvalidImage::TooLarge {
+                width: 65535,
+                height: 65535
+            })
+        );
+    }
+
+    #[rstest]
+    #[case(Some(50), None, (50, 25))]
+    #[case(None, Some(10), (20, 10))]
+    #[case(Some(80), Some(20), (40, 20))]
+    #[case(Some(400), Some(400), (100, 50))]
+    fn fit_size_test(
+        #[case] max_width: Option<u32>,
+        #[case] max_height: Option<u32>,
+        #[case] expected: (u32, u32),
+    ) -> anyhow::Result<()> {
+        let res = DecodeResult::Image(image::RgbaImage::new(100, 50)).fit(
+            max_width,
+            max_height,
+            ResizeFilter::default(),
+        )?;
+        match res {
+            DecodeResult::Image(img) => assert_eq!(img.dimensions(), expected),
+            _ => panic!("fit must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn gif_stream_encode_test() -> anyhow::Result<()> {
+        let mut gif = vec![];
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            let frames = (0..5).map(|i| {
+                let img = image::RgbaImage::from_pixel(64, 32, image::Rgba([i * 50, 0, 0, 255]));
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            });
+            encoder.encode_frames(frames)?;
+        }
+
+        let res = DecodeResult::AnimStream {
+            buf: gif,
+            size: None,
+            playback: Default::default(),
+        };
+        let res = res.resize_by_height(16, ResizeFilter::default())?;
+        assert_eq!((res.width()?, res.height()?), (32, 16));
+
+        let webp = res.to_webp(&WebpOptions::new(75.0))?;
+        let frames = decode_webp_anim(&webp)?.collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[0].buffer().dimensions(), (32, 16));
+
+        Ok(())
+    }
+
+    #[test]
+    fn stream_stays_lazy_test() -> anyhow::Result<()> {
+        // 不透明な部分の周りに8pxの透明な余白があるgif
+        let mut gif = vec![];
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            let frames = (0..5).map(|i| {
+                let mut img = image::RgbaImage::new(64, 32);
+                for y in 8..24 {
+                    for x in 8..56 {
+                        img.put_pixel(x, y, image::Rgba([i * 50, 0, 0, 255]));
+                    }
+                }
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            });
+            encoder.encode_frames(frames)?;
+        }
+        let stream = || DecodeResult::AnimStream {
+            buf: gif.clone(),
+            size: None,
+            playback: Default::default(),
+        };
+
+        let res = stream().trim()?.pad_square(&Limits::default())?;
+        assert!(matches!(res, DecodeResult::AnimStream { .. }));
+        assert_eq!((res.width()?, res.height()?), (52, 52));
+        let res = res.fit_box(26, 13, FitMode::Contain, ResizeFilter::default())?;
+        assert!(matches!(res, DecodeResult::AnimStream { .. }));
+        assert_eq!((res.width()?, res.height()?), (26, 13));
+
+        let webp = res.to_webp(&WebpOptions::new(75.0))?;
+        let frames = decode_webp_anim(&webp)?.collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[0].buffer().dimensions(), (26, 13));
+
+        let gif = stream().trim()?.to_gif()?;
+        let frames = image::AnimationDecoder::into_frames(image::codecs::gif::GifDecoder::new(
+            Cursor::new(gif),
+        )?)
+        .collect_frames()?;
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[0].buffer().dimensions(), (52, 20));
+
+        match stream().trim()?.sheet(2, 2)? {
+            DecodeResult::Image(img) => assert_eq!(img.dimensions(), (104, 40)),
+            _ => panic!("sheet must be a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn webp_stream_encode_test() -> anyhow::Result<()> {
+        let frames = (0..4)
+            .map(|i| {
+                let img = image::RgbaImage::from_pixel(64, 32, image::Rgba([i * 60, 0, 0, 255]));
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            })
+            .collect();
+        let webp = DecodeResult::Movie(frames).to_webp(&WebpOptions::new(75.0))?;
+
+        let res = DecodeResult::AnimStream {
+            buf: webp,
+            size: None,
+            playback: Default::default(),
+        };
+        assert!(res.is_animated());
+        assert!(res.truncate_cuts(Some(2), None));
+        let res = res.truncate(Some(2), None)?;
+        let res = res.resize_by_height(16, ResizeFilter::default())?;
+        assert_eq!((res.width()?, res.height()?), (32, 16));
+
+        let webp = res.to_webp(&WebpOptions::new(75.0))?;
+        let frames = decode_webp_anim(&webp)?.collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].buffer().dimensions(), (32, 16));
+
+        Ok(())
+    }
+
+    #[test]
+    fn gif_anim_encode_test() -> anyhow::Result<()> {
+        let frames = (0..3)
+            .map(|i| {
+                let img = image::RgbaImage::from_pixel(8, 8, image::Rgba([i * 100, 0, 0, 255]));
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(50, 1))
+            })
+            .collect();
+        let gif = DecodeResult::Movie(frames).to_gif()?;
+
+        let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(gif))?;
+        let frames = image::AnimationDecoder::into_frames(decoder).collect_frames()?;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1].delay().numer_denom_ms(), (50, 1));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(64, 64)]
+    #[case(64, 32)]
+    fn svg_resize_test(#[case] width: u32, #[case] height: u32) -> anyhow::Result<()> {
+        // 左半分だけ塗った4x4のsvg
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="4" viewBox="0 0 4 4"><rect width="2" height="4" fill="#ff0000"/></svg>"##;
+        let img = match DecodeResult::TextFmt(svg.to_string()).resize(
+            height,
+            width,
+            ResizeFilter::default(),
+        )? {
+            DecodeResult::Image(img) => img,
+            _ => panic!("svg must be rendered to an image"),
+        };
+        assert_eq!(img.dimensions(), (width, height));
+        // 拡大してぼやけることなく、境界がはっきりしている
+        let y = height / 2;
+        assert_eq!(img.get_pixel(width / 2 - 1, y).0, [255, 0, 0, 255]);
+        assert_eq!(img.get_pixel(width / 2, y).0, [0, 0, 0, 0]);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case((None, None), (400, 200))]
+    #[case((None, Some(100)), (200, 100))]
+    #[case((Some(100), Some(100)), (100, 50))]
+    #[case((Some(1000), None), (400, 200))]
+    fn svg_render_within_test(
+        #[case] bounds: (Option<u32>, Option<u32>),
+        #[case] expected: (u32, u32),
+    ) -> anyhow::Result<()> {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="400" height="200"/>"#;
+        match DecodeResult::TextFmt(svg.to_string()).render_svg_within(bounds)? {
+            DecodeResult::Image(img) => assert_eq!(img.dimensions(), expected),
+            _ => panic!("svg must be rendered to an image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn svg_too_large_test() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="100000" height="10"/>"#;
+        let decoded = DecodeResult::TextFmt(svg.to_string());
+        let err = decoded.check_svg_size(&Limits::default()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InvalidImage>(),
+            Some(&InvalidImage::TooLarge {
+                width: 100000,
+                height: 10
+            })
+        );
+        let limits = Limits {
+            max_pixels: 100,
+            ..Default::default()
+        };
+        let small = r#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20"/>"#;
+        assert!(DecodeResult::TextFmt(small.to_string())
+            .check_svg_size(&limits)
+            .is_err());
+        assert!(DecodeResult::TextFmt(small.to_string())
+            .check_svg_size(&Limits::default())
+            .is_ok());
+    }
+
+    #[rstest]
+    #[case(
+        DecodeResult::Image(image::RgbaImage::new(0, 10)),
+        InvalidImage::ZeroSize
+    )]
+    #[case(DecodeResult::Movie(vec![]), InvalidImage::NoFrames)]
+    #[case(
+        DecodeResult::Movie(vec![
+            image::Frame::new(image::RgbaImage::new(8, 8)),
+            image::Frame::new(image::RgbaImage::new(4, 8)),
+        ]),
+        InvalidImage::FrameSizeMismatch { expected: (8, 8), actual: (4, 8) }
+    )]
+    fn validate_test(#[case] res: DecodeResult, #[case] expected: InvalidImage) {
+        let err = res.validate().unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidImage>(), Some(&expected));
+    }
+
+    #[rstest]
+    #[case(FrameSelector::Index(1), 1)]
+    #[case(FrameSelector::Index(99), 3)]
+    #[case(FrameSelector::Time(0), 0)]
+    #[case(FrameSelector::Time(250), 2)]
+    #[case(FrameSelector::Time(10000), 3)]
+    fn frame_select_test(
+        #[case] selector: FrameSelector,
+        #[case] expected: u8,
+    ) -> anyhow::Result<()> {
+        let frames = (0..4)
+            .map(|i| {
+                let img = image::RgbaImage::from_pixel(8, 8, image::Rgba([i, 0, 0, 255]));
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            })
+            .collect();
+        match DecodeResult::Movie(frames).frame(selector)? {
+            DecodeResult::Image(img) => assert_eq!(img.get_pixel(0, 0)[0], expected),
+            _ => panic!("frame must be a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    // 間隔より短いフレームは捨て、長いフレームは間隔の倍数にする
+    #[case(vec![10, 10, 10, 200], 20, vec![(0, 50), (3, 200)])]
+    #[case(vec![100, 100], 20, vec![(0, 100), (1, 100)])]
+    #[case(vec![30, 30, 30], 20, vec![(0, 50), (1, 50)])]
+    // 表示時間が0のフレームは1フレーム分になる
+    #[case(vec![0, 0, 0], 10, vec![(0, 100), (1, 100), (2, 100)])]
+    // 長いフレームも複製せず、入力より多くならない
+    #[case(vec![60_000, 60_000], 60, vec![(0, 60_000), (1, 60_000)])]
+    fn resample_test(
+        #[case] delays: Vec<u32>,
+        #[case] fps: u32,
+        #[case] expected: Vec<(u8, u32)>,
+    ) -> anyhow::Result<()> {
+        let frames = delays
+            .iter()
+            .enumerate()
+            .map(|(i, delay)| {
+                let img = image::RgbaImage::from_pixel(8, 8, image::Rgba([i as u8, 0, 0, 255]));
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(*delay, 1))
+            })
+            .collect();
+        match DecodeResult::Movie(frames).resample(fps)? {
+            DecodeResult::Movie(frames) => {
+                let frames: Vec<(u8, u32)> = frames
+                    .iter()
+                    .map(|f| {
+                        (
+                            f.buffer().get_pixel(0, 0)[0],
+                            super::delay_ms(f).round() as u32,
+                        )
+                    })
+                    .collect();
+                assert_eq!(frames, expected);
+            }
+            _ => panic!("resample must keep an animation"),
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::repeated(vec![0, 0, 0, 50], vec![(0, 300), (50, 100)])]
+    #[case::alternating(vec![0, 50, 0], vec![(0, 100), (50, 100), (0, 100)])]
+    // わずかな差は同じとみなす
+    #[case::near_identical(vec![10, 12, 15], vec![(10, 200), (15, 100)])]
+    fn merge_duplicates_test(#[case] values: Vec<u8>, #[case] expected: Vec<(u8, u32)>) {
+        let frames = values.into_iter().map(|v| {
+            let img = image::RgbaImage::from_pixel(8, 8, image::Rgba([v, 0, 0, 255]));
+            Ok(image::Frame::from_parts(
+                img,
+                0,
+                0,
+                image::Delay::from_numer_denom_ms(100, 1),
+            ))
+        });
+        let merged: Vec<(u8, u32)> = super::MergeDuplicates::new(frames)
+            .map(|f| {
+                let f = f.unwrap();
+                (f.buffer().get_pixel(0, 0)[0], super::delay_ms(&f) as u32)
+            })
+            .collect();
+        assert_eq!(merged, expected);
+    }
+
+    #[rstest]
+    #[case(vec![10, 10, 10, 200], 20, true)]
+    #[case(vec![100, 100], 20, false)]
+    #[case(vec![50, 50, 50], 20, false)]
+    #[case(vec![0, 0, 0], 10, false)]
+    #[case(vec![500], 1, false)]
+    fn drops_frames_test(#[case] delays: Vec<u32>, #[case] fps: u32, #[case] expected: bool) {
+        let delays: Vec<f64> = delays.into_iter().map(|d| d as f64).collect();
+        assert_eq!(super::drops_frames(&delays, fps), expected);
+    }
+
+    #[rstest]
+    #[case(Some(2), None, true)]
+    #[case(Some(5), None, false)]
+    #[case(None, Some(250), true)]
+    #[case(None, Some(500), false)]
+    fn is_truncated_test(
+        #[case] max_frames: Option<u32>,
+        #[case] max_duration: Option<u32>,
+        #[case] expected: bool,
+    ) {
+        let delays = [100.0; 5];
+        assert_eq!(
+            super::is_truncated(&delays, max_frames, max_duration),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case(Some(2), None, vec![100, 100])]
+    #[case(None, Some(250), vec![100, 100, 50])]
+    #[case(Some(2), Some(150), vec![100, 50])]
+    // 最初のフレームは必ず残す
+    #[case(None, Some(0), vec![1])]
+    fn truncate_test(
+        #[case] max_frames: Option<u32>,
+        #[case] max_duration: Option<u32>,
+        #[case] expected: Vec<u32>,
+    ) -> anyhow::Result<()> {
+        let frames = (0..5)
+            .map(|_| {
+                let img = image::RgbaImage::new(8, 8);
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            })
+            .collect();
+        match DecodeResult::Movie(frames).truncate(max_frames, max_duration)? {
+            DecodeResult::Movie(frames) => {
+                let delays: Vec<u32> = frames.iter().map(|f| super::delay_ms(f) as u32).collect();
+                assert_eq!(delays, expected);
+            }
+            _ => panic!("truncate must keep an animation"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn flatten_test() -> anyhow::Result<()> {
+        let mut img = image::RgbaImage::new(2, 1);
+        img.put_pixel(1, 0, image::Rgba([255, 0, 0, 255]));
+        match DecodeResult::Image(img).flatten([0, 0, 255, 255])? {
+            DecodeResult::Image(img) => {
+                assert_eq!(img.get_pixel(0, 0).0, [0, 0, 255, 255]);
+                assert_eq!(img.get_pixel(1, 0).0, [255, 0, 0, 255]);
+            }
+            _ => panic!("flatten must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn blur_test() -> anyhow::Result<()> {
+        let mut img = image::RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 0, 255]));
+        img.put_pixel(8, 8, image::Rgba([255, 255, 255, 255]));
+        match DecodeResult::Image(img).blur(2.0)? {
+            DecodeResult::Image(img) => {
+                // 周りに広がる
+                assert!(img.get_pixel(8, 8)[0] < 255);
+                assert!(img.get_pixel(9, 8)[0] > 0);
+            }
+            _ => panic!("blur must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_test() -> anyhow::Result<()> {
+        let img = image::RgbaImage::from_pixel(16, 8, image::Rgba([255, 0, 0, 255]));
+        match DecodeResult::Image(img).round()? {
+            DecodeResult::Image(img) => {
+                assert_eq!(img.get_pixel(0, 0)[3], 0);
+                assert_eq!(img.get_pixel(15, 7)[3], 0);
+                assert_eq!(img.get_pixel(8, 4)[3], 255);
+                assert_eq!(img.get_pixel(0, 4)[3], 255);
+            }
+            _ => panic!("round must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn grayscale_test() -> anyhow::Result<()> {
+        let frames = (0..2)
+            .map(|_| {
+                let img = image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 128]));
+                image::Frame::new(img)
+            })
+            .collect();
+        match DecodeResult::Movie(frames).grayscale()? {
+            DecodeResult::Movie(frames) => {
+                for f in frames {
+                    let [r, g, b, a] = f.buffer().get_pixel(0, 0).0;
+                    assert!(r == g && g == b);
+                    assert_eq!(a, 128);
+                }
+            }
+            _ => panic!("grayscale must keep an animation"),
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(Flip::H, (0, 0))]
+    #[case(Flip::V, (1, 1))]
+    fn flip_test(#[case] flip: Flip, #[case] red_at: (u32, u32)) -> anyhow::Result<()> {
+        let mut img = image::RgbaImage::new(2, 2);
+        img.put_pixel(1, 0, image::Rgba([255, 0, 0, 255]));
+        match DecodeResult::Image(img).flip(flip)? {
+            DecodeResult::Image(img) => assert_eq!(img.get_pixel(red_at.0, red_at.1)[0], 255),
+            _ => panic!("flip must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(Rotation::Rotate90, (1, 2), (0, 1))]
+    #[case(Rotation::Rotate180, (2, 1), (0, 0))]
+    #[case(Rotation::Rotate270, (1, 2), (0, 0))]
+    fn rotate_test(
+        #[case] rotation: Rotation,
+        #[case] size: (u32, u32),
+        #[case] red_at: (u32, u32),
+    ) -> anyhow::Result<()> {
+        // 右端だけ赤い2x1の画像
+        let mut img = image::RgbaImage::new(2, 1);
+        img.put_pixel(1, 0, image::Rgba([255, 0, 0, 255]));
+        match DecodeResult::Image(img).rotate(rotation)? {
+            DecodeResult::Image(img) => {
+                assert_eq!(img.dimensions(), size);
+                assert_eq!(img.get_pixel(red_at.0, red_at.1)[0], 255);
+            }
+            _ => panic!("rotate must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn stream_rotate_test() -> anyhow::Result<()> {
+        let mut gif = vec![];
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            let frames = (0..3).map(|i| {
+                let img = image::RgbaImage::from_pixel(64, 32, image::Rgba([i * 80, 0, 0, 255]));
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            });
+            encoder.encode_frames(frames)?;
+        }
+        let res = DecodeResult::AnimStream {
+            buf: gif,
+            size: None,
+            playback: Default::default(),
+        }
+        .rotate(Rotation::Rotate90)?
+        .flip(Flip::H)?;
+        // すべてのフレームをデコードせずに回転後の大きさがわかる
+        assert!(matches!(res, DecodeResult::AnimStream { .. }));
+        assert_eq!((res.width()?, res.height()?), (32, 64));
+
+        match res.static_(&PresetSizes::default(), 1.0, ResizeFilter::default())? {
+            DecodeResult::Image(img) => assert_eq!(img.dimensions(), (32, 64)),
+            _ => panic!("static must be a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    // 横長の画像を正方形の枠に合わせる
+    #[case(FitMode::Contain, [0, 0, 0, 0], [255, 0, 0, 255])]
+    #[case(FitMode::Cover, [255, 0, 0, 255], [255, 0, 0, 255])]
+    #[case(FitMode::Fill, [255, 0, 0, 255], [255, 0, 0, 255])]
+    fn fit_box_test(
+        #[case] mode: FitMode,
+        #[case] corner: [u8; 4],
+        #[case] center: [u8; 4],
+    ) -> anyhow::Result<()> {
+        let img = image::RgbaImage::from_pixel(100, 50, image::Rgba([255, 0, 0, 255]));
+        match DecodeResult::Image(img).fit_box(40, 40, mode, ResizeFilter::default())? {
+            DecodeResult::Image(img) => {
+                assert_eq!(img.dimensions(), (40, 40));
+                assert_eq!(img.get_pixel(0, 0).0, corner);
+                assert_eq!(img.get_pixel(20, 20).0, center);
+            }
+            _ => panic!("fit_box must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fit_box_cover_extreme_aspect_test() -> anyhow::Result<()> {
+        // 全体を枠を覆う大きさにすると200x13107000になる
+        let img = image::RgbaImage::from_fn(1, 65535, |_, y| match y {
+            32700..=32834 => image::Rgba([255, 0, 0, 255]),
+            _ => image::Rgba([0, 0, 255, 255]),
+        });
+        let res =
+            DecodeResult::Image(img).fit_box(200, 200, FitMode::Cover, ResizeFilter::default())?;
+        match res {
+            DecodeResult::Image(img) => {
+                assert_eq!(img.dimensions(), (200, 200));
+                // 中央の1x1を切り取って拡大している
+                assert_eq!(img.get_pixel(100, 100).0, [255, 0, 0, 255]);
+            }
+            _ => panic!("fit_box must keep a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(2, 2, 64, 64)]
+    #[case(4, 1, 128, 32)]
+    fn sheet_size_test(
+        #[case] cols: u32,
+        #[case] rows: u32,
+        #[case] width: u32,
+        #[case] height: u32,
+    ) -> anyhow::Result<()> {
+        let frames = (0..10)
+            .map(|_| image::Frame::new(image::RgbaImage::new(32, 32)))
+            .collect();
+        let res = DecodeResult::Movie(frames).sheet(cols, rows)?;
+        match res {
+            DecodeResult::Image(img) => {
+                assert_eq!(img.dimensions(), (width, height));
+            }
+            _ => panic!("sheet must be a single image"),
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(65535, 1, 512, 1)]
+    #[case(1, 65535, 1, 128)]
+    #[case(1000, 500, 256, 128)]
+    fn sheet_cell_bounded_test(
+        #[case] src_w: u32,
+        #[case] src_h: u32,
+        #[case] cell_w: u32,
+        #[case] cell_h: u32,
+    ) -> anyhow::Result<()> {
+        // 極端な縦横比でもマスは512x128に収まる
+        let img = image::RgbaImage::new(src_w, src_h);
+        let res = DecodeResult::Image(img).sheet(8, 8)?;
+        match res {
+            DecodeResult::Image(img) => {
+                assert_eq!(img.dimensions(), (cell_w * 8, cell_h * 8));
+            }
+            _ => panic!("sheet must be a single image"),
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,113 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+/// これ以上待つ必要がある場合は取得せずにエラーにする
+const MAX_WAIT: Duration = Duration::from_secs(10);
+/// 保持するバケットの数がこれを超えたら、満タンになったものを削除する
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// 取得元のホストごとのトークンバケット
+#[derive(Debug)]
+pub struct OriginRateLimiter {
+    /// 1秒あたりに補充するトークン数
+    rate: f64,
+    /// バケットの容量
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl OriginRateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// トークンを1つ予約し、使えるようになるまでの待ち時間を返す。
+    /// 待ち時間が`MAX_WAIT`を超える場合は予約せずに`None`を返す
+    fn reserve(&self, host: &str, now: Instant) -> Option<Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets
+                .retain(|_, b| b.tokens + now.duration_since(b.last).as_secs_f64() * rate < burst);
+        }
+
+        let bucket = buckets.entry(host.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            last: now,
+        });
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        let tokens = (bucket.tokens + elapsed * self.rate).min(self.burst) - 1.0;
+        let wait = Duration::from_secs_f64((-tokens).max(0.0) / self.rate);
+        if wait > MAX_WAIT {
+            return None;
+        }
+        bucket.tokens = tokens;
+        bucket.last = now;
+        Some(wait)
+    }
+
+    /// `host`への取得が許可されるまで待つ
+    pub async fn acquire(&self, host: &str) -> Result<()> {
+        match self.reserve(host, Instant::now()) {
+            Some(wait) if wait.is_zero() => Ok(()),
+            Some(wait) => {
+                tracing::debug!(host, ?wait, "outbound rate limited");
+                tokio::time::sleep(wait).await;
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Too many requests to {}", host)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn reserve_test() {
+        let limiter = OriginRateLimiter::new(1.0, 2);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve("a.example", now), Some(Duration::ZERO));
+        assert_eq!(limiter.reserve("a.example", now), Some(Duration::ZERO));
+        assert_eq!(
+            limiter.reserve("a.example", now),
+            Some(Duration::from_secs(1))
+        );
+        // 別のホストには影響しない
+        assert_eq!(limiter.reserve("b.example", now), Some(Duration::ZERO));
+
+        // 時間が経てば補充される
+        let later = now + Duration::from_secs(3);
+        assert_eq!(limiter.reserve("a.example", later), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn reject_long_wait() {
+        let limiter = OriginRateLimiter::new(0.1, 1);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve("a.example", now), Some(Duration::ZERO));
+        assert_eq!(
+            limiter.reserve("a.example", now),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(limiter.reserve("a.example", now), None);
+    }
+}
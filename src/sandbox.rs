@@ -0,0 +1,442 @@
+use anyhow::{Context, Result};
+use image::{Delay, Frame, RgbaImage};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::client::{decode_by_ext, DecodeOptions, ImageExt, Limits};
+use crate::processor::DecodeResult;
+
+/// 子プロセスでデコードする際の設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecoderSandbox {
+    /// `decode-worker`サブコマンドを実行するプログラム
+    pub program: PathBuf,
+    /// 子プロセスのアドレス空間の上限(バイト)
+    pub max_memory: u64,
+    /// 子プロセスのCPU時間の上限(秒)
+    pub max_cpu_secs: u64,
+    /// 子プロセスから受け取るデコード結果の上限(バイト)。アニメーションは全フレームを
+    /// このプロセスのメモリに読み込むため、展開後の大きさがこれを超える画像は拒否する
+    pub max_output: u64,
+    /// 子プロセスの実行時間の上限。入出力で止まっている間はCPU時間に数えられないため別に設ける
+    pub timeout: Duration,
+}
+
+/// `decode-worker`サブコマンドの引数。親プロセスが渡す
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct WorkerArgs {
+    #[arg(long)]
+    ext: String,
+    #[arg(long)]
+    max_pixels: u64,
+    #[arg(long)]
+    max_dimension: u32,
+    #[arg(long)]
+    first_frame_only: bool,
+    #[arg(long)]
+    min_scale: f64,
+    #[arg(long)]
+    svg_max_width: Option<u32>,
+    #[arg(long)]
+    svg_max_height: Option<u32>,
+    #[arg(long)]
+    max_memory: u64,
+    #[arg(long)]
+    max_cpu_secs: u64,
+    #[arg(long)]
+    max_output: u64,
+}
+
+fn ext_name(ext: ImageExt) -> &'static str {
+    match ext {
+        ImageExt::Png => "png",
+        ImageExt::Jpeg => "jpeg",
+        ImageExt::Gif => "gif",
+        ImageExt::Svg => "svg",
+        ImageExt::Webp => "webp",
+        ImageExt::Ico => "ico",
+        ImageExt::Unknown => "unknown",
+    }
+}
+
+fn parse_ext(name: &str) -> ImageExt {
+    match name {
+        "png" => ImageExt::Png,
+        "jpeg" => ImageExt::Jpeg,
+        "gif" => ImageExt::Gif,
+        "svg" => ImageExt::Svg,
+        "webp" => ImageExt::Webp,
+        "ico" => ImageExt::Ico,
+        _ => ImageExt::Unknown,
+    }
+}
+
+/// 子プロセスで`buf`をデコードする。svgの描画とgifの展開も子プロセスで行い、RGBAのみを受け取る
+pub fn decode_in_child(
+    buf: Vec<u8>,
+    ext: ImageExt,
+    limits: &Limits,
+    options: DecodeOptions,
+    sandbox: &DecoderSandbox,
+) -> Result<DecodeResult> {
+    let mut child = Command::new(&sandbox.program)
+        .arg("decode-worker")
+        .args(["--ext", ext_name(ext)])
+        .arg(format!("--max-pixels={}", limits.max_pixels))
+        .arg(format!("--max-dimension={}", limits.max_dimension))
+        .arg(format!("--min-scale={}", options.min_scale))
+        .args(options.svg_box.0.map(|w| format!("--svg-max-width={}", w)))
+        .args(options.svg_box.1.map(|h| format!("--svg-max-height={}", h)))
+        .arg(format!("--max-memory={}", sandbox.max_memory))
+        .arg(format!("--max-cpu-secs={}", sandbox.max_cpu_secs))
+        .arg(format!("--max-output={}", sandbox.max_output))
+        .args(options.first_frame_only.then_some("--first-frame-only"))
+        // 秘密を含む環境変数を子プロセスに渡さない
+        .env_clear()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to spawn decoder process")?;
+
+    let mut stdin = child.stdin.take().context("decoder stdin is not piped")?;
+    let stdout = child.stdout.take().context("decoder stdout is not piped")?;
+    let stderr = child.stderr.take().context("decoder stderr is not piped")?;
+    // 子プロセスが入力を読み切らずに終了してもここで止まらないように別スレッドで書き込む
+    let writer = std::thread::spawn(move || stdin.write_all(&buf));
+    // 出力はすべてを溜めずにフレームごとに読み込む。上限を超えたら読むのをやめ、子プロセスの書き込みを失敗させる
+    let max_output = sandbox.max_output;
+    let reader = std::thread::spawn(move || read_result(stdout, max_output));
+    let errors = std::thread::spawn(move || {
+        let mut buf = vec![];
+        let _ = stderr.take(MAX_STDERR).read_to_end(&mut buf);
+        buf
+    });
+    let status = wait_timeout(&mut child, sandbox.timeout);
+    // 子プロセスが途中で終了した場合の書き込みエラーは終了状態で報告する
+    let _ = writer.join();
+    let decoded = reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("decoder output reader panicked"))?;
+    let stderr = errors.join().unwrap_or_default();
+    let status = status?;
+
+    if !status.success() {
+        // 上限で読むのをやめたために子プロセスが失敗した場合は、上限を超えたことを報告する
+        if let Err(err) = &decoded {
+            if err.is::<OutputTooLarge>() {
+                return decoded;
+            }
+        }
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(anyhow::anyhow!(
+            "decoder process failed ({}): {}",
+            status,
+            stderr.trim()
+        ));
+    }
+    decoded
+}
+
+/// 子プロセスの標準エラー出力から読み込む上限(バイト)
+const MAX_STDERR: u64 = 64 * 1024;
+
+/// `timeout`まで子プロセスの終了を待つ。過ぎた場合は子プロセスを終了させる
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow::anyhow!(
+                "decoder process timed out after {:?}",
+                timeout
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// 子プロセスのデコード結果が上限を超えた
+#[derive(Debug)]
+struct OutputTooLarge(u64);
+
+impl std::fmt::Display for OutputTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "decoder output exceeds {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for OutputTooLarge {}
+
+/// `decode-worker`サブコマンドの本体。標準入力の画像をデコードし、結果を標準出力へ書き込む
+pub fn run_worker(args: &WorkerArgs) -> Result<()> {
+    apply_rlimits(args.max_memory, args.max_cpu_secs)?;
+
+    let mut buf = vec![];
+    std::io::stdin().read_to_end(&mut buf)?;
+    let limits = Limits {
+        max_pixels: args.max_pixels,
+        max_dimension: args.max_dimension,
+        ..Default::default()
+    };
+    let options = DecodeOptions {
+        first_frame_only: args.first_frame_only,
+        min_scale: args.min_scale,
+        svg_box: (args.svg_max_width, args.svg_max_height),
+    };
+    // 本来の大きさで描画してから縮小しないように、出力に必要な大きさで描画する
+    let decoded = decode_by_ext(buf, parse_ext(&args.ext), &limits, options)?
+        .render_svg_within(options.svg_box)?
+        .collect()?;
+    // 親プロセスは上限を超えた出力を読まないので、書き込む前に拒否する
+    if output_len(&decoded) > args.max_output {
+        return Err(OutputTooLarge(args.max_output).into());
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    write_result(&decoded, &mut stdout)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// メモリとCPU時間の上限を設定する。超えた場合はカーネルが子プロセスを終了させる
+#[cfg(unix)]
+fn apply_rlimits(max_memory: u64, max_cpu_secs: u64) -> Result<()> {
+    let set = |resource, value: u64| {
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        // SAFETY: 有効な`rlimit`へのポインタを渡している
+        match unsafe { libc::setrlimit(resource, &limit) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+    set(libc::RLIMIT_AS, max_memory).context("failed to limit memory")?;
+    set(libc::RLIMIT_CPU, max_cpu_secs).context("failed to limit cpu time")?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_rlimits(_max_memory: u64, _max_cpu_secs: u64) -> Result<()> {
+    Err(anyhow::anyhow!("decoder sandbox is only supported on unix"))
+}
+
+/// デコード結果を書き込む。`Image`は`I`, 幅, 高さ, RGBAの順、`Movie`は`M`, フレーム数に続いて
+/// フレームごとに幅, 高さ, 左, 上, 表示時間の分子と分母, RGBAの順。数値はリトルエンディアンのu32
+fn write_result(decoded: &DecodeResult, out: &mut impl Write) -> Result<()> {
+    let put = |n: u32, out: &mut dyn Write| out.write_all(&n.to_le_bytes());
+    match decoded {
+        DecodeResult::Image(img) => {
+            out.write_all(b"I")?;
+            put(img.width(), out)?;
+            put(img.height(), out)?;
+            out.write_all(img.as_raw())?;
+        }
+        DecodeResult::Movie(frames) => {
+            out.write_all(b"M")?;
+            put(frames.len().try_into()?, out)?;
+            for frame in frames {
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                let img = frame.buffer();
+                for n in [
+                    img.width(),
+                    img.height(),
+                    frame.left(),
+                    frame.top(),
+                    numer,
+                    denom,
+                ] {
+                    put(n, out)?;
+                }
+                out.write_all(img.as_raw())?;
+            }
+        }
+        DecodeResult::TextFmt(_) | DecodeResult::AnimStream { .. } => {
+            return Err(anyhow::anyhow!("decode result is not rendered"));
+        }
+    }
+    Ok(())
+}
+
+/// `write_result`で書き込む大きさ(バイト)
+fn output_len(decoded: &DecodeResult) -> u64 {
+    let rgba = |img: &RgbaImage| (img.width() as u64 * img.height() as u64).saturating_mul(4);
+    match decoded {
+        DecodeResult::Image(img) => rgba(img).saturating_add(1 + 4 * 2),
+        DecodeResult::Movie(frames) => frames
+            .iter()
+            .map(|frame| 4 * 6 + rgba(frame.buffer()))
+            .sum::<u64>()
+            .saturating_add(1 + 4),
+        DecodeResult::TextFmt(_) | DecodeResult::AnimStream { .. } => 0,
+    }
+}
+
+/// `write_result`で書き込んだデコード結果を読み込む。`max_output`バイトを超える場合は読むのをやめて拒否する
+fn read_result(input: impl Read, max_output: u64) -> Result<DecodeResult> {
+    fn read_u32(input: &mut impl Read) -> Result<u32> {
+        let mut n = [0; 4];
+        input
+            .read_exact(&mut n)
+            .context("truncated decoder output")?;
+        Ok(u32::from_le_bytes(n))
+    }
+
+    let mut input = input.take(max_output);
+    // 確保する前に残りの上限と比べ、子プロセスが書いた大きさをそのまま信用しない
+    let rgba = |input: &mut std::io::Take<_>, width: u32, height: u32| -> Result<RgbaImage> {
+        let len = (width as u64 * height as u64).saturating_mul(4);
+        if len > input.limit() {
+            return Err(OutputTooLarge(max_output).into());
+        }
+        let mut data = vec![0; len as usize];
+        input
+            .read_exact(&mut data)
+            .context("truncated decoder output")?;
+        RgbaImage::from_raw(width, height, data).context("invalid decoder output")
+    };
+
+    let mut tag = [0];
+    input.read_exact(&mut tag).context("empty decoder output")?;
+    let decoded = match &tag {
+        b"I" => {
+            let (width, height) = (read_u32(&mut input)?, read_u32(&mut input)?);
+            DecodeResult::Image(rgba(&mut input, width, height)?)
+        }
+        b"M" => {
+            let count = read_u32(&mut input)?;
+            let mut frames = vec![];
+            for _ in 0..count {
+                let (width, height) = (read_u32(&mut input)?, read_u32(&mut input)?);
+                let (left, top) = (read_u32(&mut input)?, read_u32(&mut input)?);
+                let (numer, denom) = (read_u32(&mut input)?, read_u32(&mut input)?);
+                let img = rgba(&mut input, width, height)?;
+                let delay = Delay::from_numer_denom_ms(numer, denom.max(1));
+                frames.push(Frame::from_parts(img, left, top, delay));
+            }
+            DecodeResult::Movie(frames)
+        }
+        _ => return Err(anyhow::anyhow!("unknown decoder output")),
+    };
+    if input.limit() == 0 {
+        // 上限ちょうどで終わっているか、まだ続いているかを確かめる
+        let mut rest = [0];
+        if input.into_inner().read(&mut rest)? > 0 {
+            return Err(OutputTooLarge(max_output).into());
+        }
+    } else if input.read(&mut [0])? > 0 {
+        return Err(anyhow::anyhow!("trailing decoder output"));
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn image(width: u32, height: u32, value: u8) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, image::Rgba([value, 0, 0, 255]))
+    }
+
+    #[test]
+    fn roundtrip_image_test() {
+        let mut buf = vec![];
+        write_result(&DecodeResult::Image(image(3, 2, 7)), &mut buf).unwrap();
+        match read_result(&buf[..], u64::MAX).unwrap() {
+            DecodeResult::Image(img) => assert_eq!(img, image(3, 2, 7)),
+            _ => panic!("expected image"),
+        }
+    }
+
+    #[test]
+    fn roundtrip_movie_test() {
+        let frames = vec![
+            Frame::from_parts(image(4, 4, 1), 0, 0, Delay::from_numer_denom_ms(100, 1)),
+            Frame::from_parts(image(4, 4, 2), 1, 2, Delay::from_numer_denom_ms(50, 3)),
+        ];
+        let mut buf = vec![];
+        write_result(&DecodeResult::Movie(frames), &mut buf).unwrap();
+        let DecodeResult::Movie(frames) = read_result(&buf[..], u64::MAX).unwrap() else {
+            panic!("expected movie");
+        };
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].buffer(), &image(4, 4, 2));
+        assert_eq!((frames[1].left(), frames[1].top()), (1, 2));
+        assert_eq!(frames[1].delay().numer_denom_ms(), (50, 3));
+    }
+
+    #[rstest]
+    #[case::empty(vec![])]
+    #[case::unknown_tag(b"X".to_vec())]
+    #[case::truncated(vec![b'I', 2, 0, 0, 0, 2, 0, 0, 0, 1, 2, 3])]
+    #[case::trailing(vec![b'I', 0, 0, 0, 0, 0, 0, 0, 0, 9])]
+    fn read_result_reject_test(#[case] buf: Vec<u8>) {
+        assert!(read_result(&buf[..], u64::MAX).is_err());
+    }
+
+    #[test]
+    fn read_result_max_output_test() {
+        let frames = vec![
+            Frame::from_parts(image(4, 4, 1), 0, 0, Delay::from_numer_denom_ms(100, 1)),
+            Frame::from_parts(image(4, 4, 2), 0, 0, Delay::from_numer_denom_ms(100, 1)),
+        ];
+        let decoded = DecodeResult::Movie(frames);
+        let mut buf = vec![];
+        write_result(&decoded, &mut buf).unwrap();
+        assert_eq!(output_len(&decoded), buf.len() as u64);
+
+        assert!(read_result(&buf[..], buf.len() as u64).is_ok());
+        // 最後のフレームの途中で上限に達する
+        let Err(err) = read_result(&buf[..], buf.len() as u64 - 1) else {
+            panic!("output over the limit must be rejected");
+        };
+        assert!(err.is::<OutputTooLarge>());
+        // 書き込まれた大きさを信用せずに、確保する前に拒否する
+        let huge = [
+            b"I".as_slice(),
+            &u32::MAX.to_le_bytes(),
+            &u32::MAX.to_le_bytes(),
+        ]
+        .concat();
+        let Err(err) = read_result(&huge[..], 1024) else {
+            panic!("output over the limit must be rejected");
+        };
+        assert!(err.is::<OutputTooLarge>());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_timeout_test() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let started = Instant::now();
+        assert!(wait_timeout(&mut child, Duration::from_millis(100)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // 終了させた子プロセスは回収済み
+        assert!(child.try_wait().unwrap().is_some());
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let status = wait_timeout(&mut child, Duration::from_secs(5)).unwrap();
+        assert!(status.success());
+    }
+
+    #[rstest]
+    #[case(ImageExt::Png)]
+    #[case(ImageExt::Jpeg)]
+    #[case(ImageExt::Gif)]
+    #[case(ImageExt::Svg)]
+    #[case(ImageExt::Webp)]
+    #[case(ImageExt::Ico)]
+    #[case(ImageExt::Unknown)]
+    fn ext_name_test(#[case] ext: ImageExt) {
+        assert_eq!(parse_ext(ext_name(ext)), ext);
+    }
+}
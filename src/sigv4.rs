@@ -0,0 +1,354 @@
+use std::{collections::HashMap, path::PathBuf, time::SystemTime};
+
+use anyhow::{Context, Result};
+use openssl::{hash::MessageDigest, pkey::PKey, sha::sha256, sign::Signer};
+use reqwest::{header::HeaderMap, Url};
+use serde::Deserialize;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+/// 本文のないGETのペイロードのハッシュ
+const EMPTY_PAYLOAD_SHA256: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn default_service() -> String {
+    "s3".to_string()
+}
+
+/// 取得元に署名するための認証情報
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credential {
+    region: String,
+    #[serde(default = "default_service")]
+    service: String,
+    access_key_id: String,
+    secret_access_key: String,
+    /// 一時的な認証情報の場合のセッショントークン
+    session_token: Option<String>,
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // ログにシークレットを出さない
+        f.debug_struct("Credential")
+            .field("region", &self.region)
+            .field("service", &self.service)
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Credential {
+    /// `url`へのGETに付けるヘッダーを返す
+    pub fn sign(&self, url: &Url, now: SystemTime) -> Result<HeaderMap> {
+        let (amz_date, _) = amz_date(now);
+        let mut headers = vec![
+            ("host", host_header(url)?),
+            ("x-amz-content-sha256", EMPTY_PAYLOAD_SHA256.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = self.authorization(url, &headers, &amz_date)?;
+
+        let mut map = HeaderMap::new();
+        // `host`はreqwestがURLから付ける
+        for (name, value) in headers.into_iter().skip(1) {
+            map.insert(name, value.parse()?);
+        }
+        map.insert(reqwest::header::AUTHORIZATION, authorization.parse()?);
+        Ok(map)
+    }
+
+    /// 名前の順に並んだ`headers`に署名して`Authorization`ヘッダーの値を返す
+    fn authorization(
+        &self,
+        url: &Url,
+        headers: &[(&str, String)],
+        amz_date: &str,
+    ) -> Result<String> {
+        let date = &amz_date[..8];
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "GET\n{}\n{}\n{}\n{}\n{}",
+            canonical_uri(url),
+            canonical_query(url),
+            canonical_headers,
+            signed_headers,
+            EMPTY_PAYLOAD_SHA256
+        );
+
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            amz_date,
+            scope,
+            hex(&sha256(canonical_request.as_bytes()))
+        );
+        let signature = hex(&hmac_sha256(
+            &self.signing_key(date)?,
+            string_to_sign.as_bytes(),
+        )?);
+        Ok(format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, self.access_key_id, scope, signed_headers, signature
+        ))
+    }
+
+    fn signing_key(&self, date: &str) -> Result<Vec<u8>> {
+        let secret = format!("AWS4{}", self.secret_access_key);
+        let key = hmac_sha256(secret.as_bytes(), date.as_bytes())?;
+        let key = hmac_sha256(&key, self.region.as_bytes())?;
+        let key = hmac_sha256(&key, self.service.as_bytes())?;
+        hmac_sha256(&key, b"aws4_request")
+    }
+}
+
+/// ホスト名のパターンごとの認証情報
+#[derive(Debug, Default)]
+pub struct OriginCredentials(Vec<(String, Credential)>);
+
+impl OriginCredentials {
+    /// `{"<host>": {"region": ..., "access_key_id": ..., "secret_access_key": ...}}`の形のJSONファイルを読み込む
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let txt = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&txt)
+    }
+
+    fn parse(txt: &str) -> Result<Self> {
+        let configs: HashMap<String, Credential> = serde_json::from_str(txt)?;
+        let mut patterns: Vec<_> = configs
+            .into_iter()
+            .map(|(pattern, credential)| (pattern.to_lowercase(), credential))
+            .collect();
+        // 完全一致を優先し、ワイルドカードは長いものから試す
+        patterns.sort_by_key(|(pattern, _)| {
+            (pattern.starts_with("*."), std::cmp::Reverse(pattern.len()))
+        });
+        Ok(Self(patterns))
+    }
+
+    /// `host`に一致する認証情報を返す。パターンは完全一致か`*.example.com`の形
+    pub fn get(&self, host: &str) -> Option<&Credential> {
+        let host = host.to_lowercase();
+        self.0
+            .iter()
+            .find(|(pattern, _)| match pattern.strip_prefix("*.") {
+                Some(suffix) => host
+                    .strip_suffix(suffix)
+                    .is_some_and(|rest| rest.ends_with('.')),
+                None => *pattern == host,
+            })
+            .map(|(_, credential)| credential)
+    }
+}
+
+fn host_header(url: &Url) -> Result<String> {
+    let host = url.host_str().context("URL has no host")?;
+    Ok(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+/// `20150830T123600Z`と`20150830`の形のUTCの日時
+fn amz_date(now: SystemTime) -> (String, String) {
+    let secs = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    );
+    (time, date)
+}
+
+/// UNIX時間の日数をグレゴリオ暦の年月日にする
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// パスの各セグメントを一度だけエンコードする。S3と同じく正規化はしない
+fn canonical_uri(url: &Url) -> String {
+    let path = url.path();
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/')
+        .map(|segment| {
+            uri_encode(&percent_encoding::percent_decode_str(segment).collect::<Vec<_>>())
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn canonical_query(url: &Url) -> String {
+    let mut pairs: Vec<_> = url
+        .query_pairs()
+        .map(|(k, v)| (uri_encode(k.as_bytes()), uri_encode(v.as_bytes())))
+        .collect();
+    pairs.sort();
+    pairs
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// 英数字と`-_.~`以外をエンコードする
+fn uri_encode(buf: &[u8]) -> String {
+    buf.iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(message)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+    use std::time::Duration;
+
+    fn credential(region: &str, service: &str) -> Credential {
+        Credential {
+            region: region.to_string(),
+            service: service.to_string(),
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        }
+    }
+
+    #[test]
+    fn signing_key_test() {
+        // https://docs.aws.amazon.com/general/latest/gr/signature-v4-examples.html
+        let key = credential("us-east-1", "iam")
+            .signing_key("20120215")
+            .unwrap();
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn get_vanilla_test() {
+        // aws-sig-v4-test-suiteのget-vanilla
+        let url = Url::parse("https://example.amazonaws.com/").unwrap();
+        let headers = [
+            ("host", "example.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let authorization = credential("us-east-1", "service")
+            .authorization(&url, &headers, "20150830T123600Z")
+            .unwrap();
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn sign_test() {
+        let url = Url::parse("https://bucket.s3.amazonaws.com/a.png").unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_440_938_160);
+        let headers = credential("us-east-1", "s3").sign(&url, now).unwrap();
+        assert_eq!(headers["x-amz-date"], "20150830T123600Z");
+        assert_eq!(headers["x-amz-content-sha256"], EMPTY_PAYLOAD_SHA256);
+        assert!(headers["authorization"]
+            .to_str()
+            .unwrap()
+            .contains("SignedHeaders=host;x-amz-content-sha256;x-amz-date,"));
+    }
+
+    #[rstest]
+    #[case(0, "19700101T000000Z")]
+    #[case(951_782_400, "20000229T000000Z")]
+    #[case(1_440_938_160, "20150830T123600Z")]
+    #[case(4_102_444_799, "20991231T235959Z")]
+    fn amz_date_test(#[case] secs: u64, #[case] expected: &str) {
+        let (time, date) = amz_date(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(time, expected);
+        assert_eq!(date, &expected[..8]);
+    }
+
+    #[rstest]
+    #[case("https://example.com/", "/", "")]
+    #[case("https://example.com/a%20b/c(1).png", "/a%20b/c%281%29.png", "")]
+    #[case("https://example.com/a?b=2&a=1&c=x%2Fy", "/a", "a=1&b=2&c=x%2Fy")]
+    fn canonical_test(#[case] url: &str, #[case] uri: &str, #[case] query: &str) {
+        let url = Url::parse(url).unwrap();
+        assert_eq!(canonical_uri(&url), uri);
+        assert_eq!(canonical_query(&url), query);
+    }
+
+    #[rstest]
+    #[case("bucket.r2.example.com", Some("auto"))]
+    #[case("BUCKET.R2.EXAMPLE.COM", Some("auto"))]
+    #[case("special.r2.example.com", Some("eu"))]
+    #[case("r2.example.com", None)]
+    #[case("evilr2.example.com", None)]
+    #[case("private.example.net", Some("us-east-1"))]
+    fn origin_credentials_test(#[case] host: &str, #[case] region: Option<&str>) {
+        let credentials = OriginCredentials::parse(
+            r#"{
+                "*.r2.example.com": {"region": "auto", "access_key_id": "a", "secret_access_key": "b"},
+                "special.r2.example.com": {"region": "eu", "access_key_id": "a", "secret_access_key": "b"},
+                "private.example.net": {"region": "us-east-1", "service": "s3", "access_key_id": "a", "secret_access_key": "b"}
+            }"#,
+        )
+        .unwrap();
+        let found = credentials.get(host).map(|c| c.region.as_str());
+        assert_eq!(found, region);
+    }
+
+    #[test]
+    fn debug_hides_secret() {
+        let debug = format!("{:?}", credential("us-east-1", "s3"));
+        assert!(!debug.contains("EXAMPLEKEY"));
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::body::Bytes;
+use redis::{aio::ConnectionManager, AsyncCommands};
+
+use crate::{convert::Converted, processor::Degradation};
+
+/// 結果を待つ間の問い合わせ間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Redisのリースを使い、同じキーの変換を複数のレプリカで1回にまとめる
+/// ## Note
+/// Redisに接続できない場合はリースを取らずにそのまま変換する
+pub(crate) struct RedisSingleflight {
+    conn: ConnectionManager,
+    /// リースの有効期間。変換結果もこの期間だけRedisに残す
+    lease: Duration,
+    /// リースの所有者を区別するためのプレフィックス
+    owner: String,
+    counter: AtomicU64,
+}
+
+impl std::fmt::Debug for RedisSingleflight {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisSingleflight")
+            .field("lease", &self.lease)
+            .field("owner", &self.owner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisSingleflight {
+    pub(crate) async fn connect(url: &str, lease: Duration) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        Ok(Self {
+            conn,
+            lease,
+            owner: format!("{}-{}", std::process::id(), nanos),
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Redisに接続できるか確かめる
+    pub(crate) async fn ping(&self) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("PING").query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// `key`のリースを取れた場合は`f`で変換して結果をRedisに置く。
+    /// 取れなかった場合はリースを持つレプリカの結果を待つ
+    pub(crate) async fn run<F, Fut>(&self, key: &str, f: F) -> Result<Converted>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Converted>>,
+    {
+        match self.acquire_or_wait(key).await {
+            Ok(Flight::Done(converted)) => Ok(converted),
+            Ok(Flight::Leader(token)) => {
+                let converted = f().await;
+                if let Ok(converted) = &converted {
+                    if let Err(e) = self.store(key, converted).await {
+                        tracing::warn!("failed to store result to redis: {:#}", e);
+                    }
+                }
+                if let Err(e) = self.release(key, &token).await {
+                    tracing::warn!("failed to release redis lease: {:#}", e);
+                }
+                converted
+            }
+            Ok(Flight::Abandoned) => f().await,
+            Err(e) => {
+                tracing::warn!("redis singleflight is unavailable: {:#}", e);
+                f().await
+            }
+        }
+    }
+
+    /// リースを取らずに`f`で変換し直し、Redisの結果を上書きする
+    pub(crate) async fn refresh<F, Fut>(&self, key: &str, f: F) -> Result<Converted>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Converted>>,
+    {
+        let converted = f().await?;
+        if let Err(e) = self.store(key, &converted).await {
+            tracing::warn!("failed to store result to redis: {:#}", e);
+        }
+        Ok(converted)
+    }
+
+    async fn acquire_or_wait(&self, key: &str) -> Result<Flight> {
+        let mut conn = self.conn.clone();
+        let result_key = result_key(key);
+        let lock_key = lock_key(key);
+
+        if let Some(converted) = self.fetch(&mut conn, &result_key).await? {
+            return Ok(Flight::Done(converted));
+        }
+
+        let token = format!(
+            "{}-{}",
+            self.owner,
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        );
+        let acquired: bool = redis::cmd("SET")
+            .arg(&lock_key)
+            .arg(&token)
+            .arg("NX")
+            .arg("PX")
+            .arg(self.lease.as_millis() as u64)
+            .query_async::<Option<String>>(&mut conn)
+            .await?
+            .is_some();
+        if acquired {
+            return Ok(Flight::Leader(token));
+        }
+
+        let deadline = tokio::time::Instant::now() + self.lease;
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            if let Some(converted) = self.fetch(&mut conn, &result_key).await? {
+                return Ok(Flight::Done(converted));
+            }
+            // 結果を置かずにリースが解放された場合は変換に失敗している
+            let locked: bool = conn.exists(&lock_key).await?;
+            if !locked {
+                break;
+            }
+        }
+        Ok(Flight::Abandoned)
+    }
+
+    async fn fetch(&self, conn: &mut ConnectionManager, key: &str) -> Result<Option<Converted>> {
+        let buf: Option<Vec<u8>> = conn.get(key).await?;
+        Ok(buf.and_then(|buf| decode(&buf)))
+    }
+
+    async fn store(&self, key: &str, converted: &Converted) -> Result<()> {
+        let mut conn = self.conn.clone();
+        conn.pset_ex::<_, _, ()>(
+            result_key(key),
+            encode(converted),
+            self.lease.as_millis() as u64,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// 自分が持っているリースのみを解放する
+    async fn release(&self, key: &str, token: &str) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::Script::new(
+            r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("DEL", KEYS[1]) else return 0 end"#,
+        )
+        .key(lock_key(key))
+        .arg(token)
+        .invoke_async::<()>(&mut conn)
+        .await?;
+        Ok(())
+    }
+}
+
+enum Flight {
+    /// 他のレプリカが変換した結果
+    Done(Converted),
+    /// リースを取得したので自分で変換する
+    Leader(String),
+    /// 待っている間に結果が得られなかった
+    Abandoned,
+}
+
+fn lock_key(key: &str) -> String {
+    format!("misskey-webp-proxy:lock:{}", key)
+}
+
+fn result_key(key: &str) -> String {
+    format!("misskey-webp-proxy:result:{}", key)
+}
+
+/// `Content-Type`と本体を改行で区切って1つの値にする
+fn encode(converted: &Converted) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(converted.content_type.len() + 1 + converted.body.len());
+    buf.extend_from_slice(converted.content_type.as_bytes());
+    // 取得元のバイト数と品質を落とした理由はContent-Typeの後ろに空白区切りで続ける
+    buf.extend_from_slice(format!(" {}", converted.input_bytes).as_bytes());
+    for d in &converted.degraded {
+        buf.push(b' ');
+        buf.extend_from_slice(d.as_str().as_bytes());
+    }
+    buf.push(b'\n');
+    buf.extend_from_slice(&converted.body);
+    buf
+}
+
+fn decode(buf: &[u8]) -> Option<Converted> {
+    let sep = buf.iter().position(|b| *b == b'\n')?;
+    let mut header = std::str::from_utf8(&buf[..sep]).ok()?.split(' ');
+    let content_type = Converted::known_content_type(header.next()?)?;
+    let mut input_bytes = 0;
+    let mut degraded = vec![];
+    for field in header {
+        match field.parse() {
+            Ok(n) => input_bytes = n,
+            Err(_) => degraded.extend(Degradation::parse(field)),
+        }
+    }
+    Some(Converted {
+        content_type,
+        body: Bytes::copy_from_slice(&buf[sep + 1..]),
+        degraded,
+        input_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn encode_roundtrip() {
+        let converted = Converted {
+            content_type: "image/png",
+            body: Bytes::from_static(b"\x89PNG\n\x00"),
+            degraded: vec![],
+            input_bytes: 6,
+        };
+        assert_eq!(decode(&encode(&converted)), Some(converted));
+
+        let converted = Converted {
+            content_type: "image/webp",
+            body: Bytes::from_static(b"RIFF"),
+            degraded: vec![Degradation::Truncated, Degradation::FramesDropped],
+            input_bytes: 1024,
+        };
+        assert_eq!(decode(&encode(&converted)), Some(converted));
+    }
+
+    #[test]
+    fn decode_without_input_bytes() {
+        let converted = decode(b"image/webp truncated\nRIFF").unwrap();
+        assert_eq!(converted.input_bytes, 0);
+        assert_eq!(converted.degraded, vec![Degradation::Truncated]);
+    }
+
+    #[test]
+    fn decode_unknown_content_type() {
+        assert_eq!(decode(b"text/html\n<html>"), None);
+        assert_eq!(decode(b"image/webp"), None);
+    }
+}
@@ -0,0 +1,523 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+
+/// 個別に数える取得元のホスト数の上限。超えたら使用量の少ない半分を`other`にまとめる
+const MAX_ORIGINS: usize = 1000;
+/// Prometheusに個別に出力する取得元のホスト数。残りは`other`にまとめる
+const METRICS_TOP_ORIGINS: usize = 20;
+
+/// 起動してからの統計情報
+#[derive(Debug)]
+pub(crate) struct Stats {
+    started: Instant,
+    /// 変換中のリクエスト数
+    in_flight: AtomicUsize,
+    /// エンコードのためにブロッキングスレッドの空きを待っている数
+    queued: AtomicUsize,
+    /// 出力した`Content-Type`ごとのレスポンス数
+    formats: Mutex<BTreeMap<&'static str, u64>>,
+    /// 変換の種類ごとの転送量
+    bandwidth: Mutex<BTreeMap<&'static str, Bandwidth>>,
+    /// 取得元のホストごとの使用量
+    origins: Mutex<Origins>,
+}
+
+#[derive(Debug, Default)]
+struct Origins {
+    hosts: HashMap<String, OriginUsage>,
+    /// `hosts`から外したホストの合計
+    other: OriginUsage,
+}
+
+impl Origins {
+    fn entry(&mut self, host: &str) -> &mut OriginUsage {
+        if !self.hosts.contains_key(host) && self.hosts.len() >= MAX_ORIGINS {
+            let mut hosts: Vec<_> = self.hosts.drain().collect();
+            hosts.sort_unstable_by_key(|(_, usage)| std::cmp::Reverse(usage.requests));
+            for (_, usage) in hosts.split_off(MAX_ORIGINS / 2) {
+                self.other.add(&usage);
+            }
+            self.hosts.extend(hosts);
+        }
+        self.hosts.entry(host.to_string()).or_default()
+    }
+
+    /// リクエスト数の多い`n`件と、残りを合計した`other`
+    fn top(&self, n: usize) -> Vec<(String, OriginUsage)> {
+        let mut hosts: Vec<_> = self
+            .hosts
+            .iter()
+            .map(|(host, usage)| (host.clone(), *usage))
+            .collect();
+        hosts.sort_unstable_by(|a, b| b.1.requests.cmp(&a.1.requests).then(a.0.cmp(&b.0)));
+        let mut other = self.other;
+        for (_, usage) in hosts.split_off(n.min(hosts.len())) {
+            other.add(&usage);
+        }
+        hosts.push(("other".to_string(), other));
+        hosts
+    }
+}
+
+/// 取得元のホストごとのリクエスト数と取得したバイト数
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct OriginUsage {
+    requests: u64,
+    errors: u64,
+    fetched_bytes: u64,
+}
+
+impl OriginUsage {
+    fn add(&mut self, other: &OriginUsage) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.fetched_bytes += other.fetched_bytes;
+    }
+}
+
+/// 取得元の画像と出力した画像の合計バイト数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub(crate) struct Bandwidth {
+    responses: u64,
+    input_bytes: u64,
+    output_bytes: u64,
+    /// 出力の方が大きい場合は負になる
+    saved_bytes: i64,
+}
+
+/// 破棄されるまでカウンタを1増やしておく
+pub(crate) struct Counting<'a>(&'a AtomicUsize);
+
+impl Drop for Counting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct StatsSnapshot {
+    uptime_secs: u64,
+    /// プロセスの常駐メモリ。取得できない環境では`None`
+    rss_bytes: Option<u64>,
+    in_flight: usize,
+    queued: usize,
+    formats: BTreeMap<&'static str, u64>,
+    bandwidth: BTreeMap<&'static str, Bandwidth>,
+    /// ランタイムの外から呼ばれた場合は`None`
+    #[cfg(feature = "runtime-metrics")]
+    runtime: Option<RuntimeSnapshot>,
+}
+
+/// tokioのランタイムの状態
+#[cfg(feature = "runtime-metrics")]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct RuntimeSnapshot {
+    workers: usize,
+    alive_tasks: usize,
+    /// どのワーカーにも割り当てられていないタスク数
+    global_queue_depth: usize,
+    /// ワーカーがタスクを処理していた時間の合計
+    busy_secs: f64,
+    /// ブロッキングスレッドの状態。`tokio_unstable`でビルドした場合のみ
+    blocking: Option<BlockingSnapshot>,
+}
+
+#[cfg(feature = "runtime-metrics")]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct BlockingSnapshot {
+    threads: usize,
+    idle_threads: usize,
+    /// ブロッキングスレッドの空きを待っているタスク数
+    queue_depth: usize,
+}
+
+#[cfg(feature = "runtime-metrics")]
+impl RuntimeSnapshot {
+    fn current() -> Option<Self> {
+        let metrics = tokio::runtime::Handle::try_current().ok()?.metrics();
+        let busy = (0..metrics.num_workers())
+            .map(|worker| metrics.worker_total_busy_duration(worker))
+            .sum::<std::time::Duration>();
+        #[cfg(tokio_unstable)]
+        let blocking = Some(BlockingSnapshot {
+            threads: metrics.num_blocking_threads(),
+            idle_threads: metrics.num_idle_blocking_threads(),
+            queue_depth: metrics.blocking_queue_depth(),
+        });
+        #[cfg(not(tokio_unstable))]
+        let blocking = None;
+        Some(Self {
+            workers: metrics.num_workers(),
+            alive_tasks: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+            busy_secs: busy.as_secs_f64(),
+            blocking,
+        })
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            in_flight: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            formats: Mutex::new(BTreeMap::new()),
+            bandwidth: Mutex::new(BTreeMap::new()),
+            origins: Mutex::new(Origins::default()),
+        }
+    }
+}
+
+impl Stats {
+    pub(crate) fn in_flight(&self) -> Counting<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Counting(&self.in_flight)
+    }
+
+    /// エンコード待ちに追加する。エンコードを始めたら`dequeue`を呼ぶ
+    pub(crate) fn enqueue(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dequeue(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// エンコード待ちの数
+    pub(crate) fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_format(&self, content_type: &'static str) {
+        *self
+            .formats
+            .lock()
+            .unwrap()
+            .entry(content_type)
+            .or_default() += 1;
+    }
+
+    /// 取得元の代わりに変換後の画像を返したことを記録する
+    pub(crate) fn record_bandwidth(
+        &self,
+        convert_type: &'static str,
+        input_bytes: usize,
+        output_bytes: usize,
+    ) {
+        let mut bandwidth = self.bandwidth.lock().unwrap();
+        let entry = bandwidth.entry(convert_type).or_default();
+        entry.responses += 1;
+        entry.input_bytes += input_bytes as u64;
+        entry.output_bytes += output_bytes as u64;
+        entry.saved_bytes += input_bytes as i64 - output_bytes as i64;
+    }
+
+    /// 取得元のホストへのリクエストを記録する。変換に失敗した場合は`error`を`true`にする
+    pub(crate) fn record_origin_request(&self, host: &str, error: bool) {
+        let mut origins = self.origins.lock().unwrap();
+        let usage = origins.entry(host);
+        usage.requests += 1;
+        if error {
+            usage.errors += 1;
+        }
+    }
+
+    /// 取得元のホストから実際にダウンロードしたバイト数を記録する
+    pub(crate) fn record_origin_fetch(&self, host: &str, bytes: usize) {
+        self.origins.lock().unwrap().entry(host).fetched_bytes += bytes as u64;
+    }
+
+    /// Prometheusのテキスト形式で出力する
+    pub(crate) fn prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            out.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, help, name, kind
+            ));
+            for (labels, value) in samples {
+                out.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        };
+        metric(
+            "misskey_webp_proxy_uptime_seconds",
+            "gauge",
+            "Seconds since the process started.",
+            vec![(String::new(), snapshot.uptime_secs.to_string())],
+        );
+        metric(
+            "misskey_webp_proxy_in_flight",
+            "gauge",
+            "Requests currently being converted.",
+            vec![(String::new(), snapshot.in_flight.to_string())],
+        );
+        metric(
+            "misskey_webp_proxy_queued",
+            "gauge",
+            "Conversions waiting for a blocking thread.",
+            vec![(String::new(), snapshot.queued.to_string())],
+        );
+        metric(
+            "misskey_webp_proxy_responses_total",
+            "counter",
+            "Responses by output content type.",
+            snapshot
+                .formats
+                .iter()
+                .map(|(t, n)| (format!("{{content_type=\"{}\"}}", t), n.to_string()))
+                .collect(),
+        );
+        let by_type = |f: fn(&Bandwidth) -> String| {
+            snapshot
+                .bandwidth
+                .iter()
+                .map(|(t, b)| (format!("{{convert_type=\"{}\"}}", t), f(b)))
+                .collect()
+        };
+        metric(
+            "misskey_webp_proxy_input_bytes_total",
+            "counter",
+            "Bytes of source images for served responses.",
+            by_type(|b| b.input_bytes.to_string()),
+        );
+        metric(
+            "misskey_webp_proxy_output_bytes_total",
+            "counter",
+            "Bytes of converted images for served responses.",
+            by_type(|b| b.output_bytes.to_string()),
+        );
+        metric(
+            "misskey_webp_proxy_saved_bytes_total",
+            "gauge",
+            "Input bytes minus output bytes. Negative if outputs were larger.",
+            by_type(|b| b.saved_bytes.to_string()),
+        );
+        let origins = self.origins.lock().unwrap().top(METRICS_TOP_ORIGINS);
+        let by_origin = |f: fn(&OriginUsage) -> u64| {
+            origins
+                .iter()
+                .map(|(host, usage)| (format!("{{origin=\"{}\"}}", host), f(usage).to_string()))
+                .collect()
+        };
+        metric(
+            "misskey_webp_proxy_origin_requests_total",
+            "counter",
+            "Requests by source host. Hosts outside the top are summed into origin=\"other\".",
+            by_origin(|u| u.requests),
+        );
+        metric(
+            "misskey_webp_proxy_origin_errors_total",
+            "counter",
+            "Failed requests by source host.",
+            by_origin(|u| u.errors),
+        );
+        metric(
+            "misskey_webp_proxy_origin_fetched_bytes_total",
+            "counter",
+            "Bytes downloaded from each source host.",
+            by_origin(|u| u.fetched_bytes),
+        );
+        #[cfg(feature = "runtime-metrics")]
+        if let Some(runtime) = snapshot.runtime {
+            let gauge = |value: usize| vec![(String::new(), value.to_string())];
+            metric(
+                "misskey_webp_proxy_runtime_workers",
+                "gauge",
+                "Worker threads of the async runtime.",
+                gauge(runtime.workers),
+            );
+            metric(
+                "misskey_webp_proxy_runtime_alive_tasks",
+                "gauge",
+                "Tasks alive in the async runtime.",
+                gauge(runtime.alive_tasks),
+            );
+            metric(
+                "misskey_webp_proxy_runtime_global_queue_depth",
+                "gauge",
+                "Tasks waiting in the global queue of the async runtime.",
+                gauge(runtime.global_queue_depth),
+            );
+            metric(
+                "misskey_webp_proxy_runtime_busy_seconds_total",
+                "counter",
+                "Seconds the workers spent running tasks.",
+                vec![(String::new(), runtime.busy_secs.to_string())],
+            );
+            if let Some(blocking) = runtime.blocking {
+                metric(
+                    "misskey_webp_proxy_runtime_blocking_threads",
+                    "gauge",
+                    "Threads in the blocking pool.",
+                    gauge(blocking.threads),
+                );
+                metric(
+                    "misskey_webp_proxy_runtime_idle_blocking_threads",
+                    "gauge",
+                    "Idle threads in the blocking pool.",
+                    gauge(blocking.idle_threads),
+                );
+                metric(
+                    "misskey_webp_proxy_runtime_blocking_queue_depth",
+                    "gauge",
+                    "Tasks waiting for a thread in the blocking pool.",
+                    gauge(blocking.queue_depth),
+                );
+            }
+        }
+        out
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            uptime_secs: self.started.elapsed().as_secs(),
+            rss_bytes: rss_bytes(),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            formats: self.formats.lock().unwrap().clone(),
+            bandwidth: self.bandwidth.lock().unwrap().clone(),
+            #[cfg(feature = "runtime-metrics")]
+            runtime: RuntimeSnapshot::current(),
+        }
+    }
+}
+
+/// `/proc/self/status`の`VmRSS`を読む
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_vm_rss(&status)
+}
+
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_vm_rss_test() {
+        let status = "Name:\tmisskey-webp-pr\nVmPeak:\t  20000 kB\nVmRSS:\t   1234 kB\n";
+        assert_eq!(parse_vm_rss(status), Some(1234 * 1024));
+        assert_eq!(parse_vm_rss("Name:\tfoo\n"), None);
+    }
+
+    #[cfg(feature = "runtime-metrics")]
+    #[tokio::test]
+    async fn runtime_snapshot_test() {
+        let stats = Stats::default();
+        let runtime = stats.snapshot().runtime.unwrap();
+        assert_eq!(runtime.workers, 1);
+        assert!(stats
+            .prometheus()
+            .contains("misskey_webp_proxy_runtime_workers 1\n"));
+        assert!(std::thread::spawn(move || stats.snapshot().runtime)
+            .join()
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn in_flight_guard() {
+        let stats = Stats::default();
+        {
+            let _a = stats.in_flight();
+            let _b = stats.in_flight();
+            assert_eq!(stats.snapshot().in_flight, 2);
+        }
+        assert_eq!(stats.snapshot().in_flight, 0);
+    }
+
+    #[test]
+    fn record_bandwidth_test() {
+        let stats = Stats::default();
+        stats.record_bandwidth("emoji", 1000, 200);
+        stats.record_bandwidth("emoji", 100, 300);
+        stats.record_bandwidth("avatar", 500, 100);
+
+        let bandwidth = stats.snapshot().bandwidth;
+        assert_eq!(
+            bandwidth["emoji"],
+            Bandwidth {
+                responses: 2,
+                input_bytes: 1100,
+                output_bytes: 500,
+                saved_bytes: 600,
+            }
+        );
+        assert_eq!(bandwidth["avatar"].saved_bytes, 400);
+
+        let metrics = stats.prometheus();
+        assert!(
+            metrics.contains("misskey_webp_proxy_saved_bytes_total{convert_type=\"emoji\"} 600\n")
+        );
+        assert!(metrics.contains("# TYPE misskey_webp_proxy_input_bytes_total counter\n"));
+    }
+
+    #[test]
+    fn record_origin_test() {
+        let stats = Stats::default();
+        stats.record_origin_request("a.example", false);
+        stats.record_origin_request("a.example", true);
+        stats.record_origin_fetch("a.example", 100);
+        stats.record_origin_request("b.example", false);
+
+        let top = stats.origins.lock().unwrap().top(1);
+        assert_eq!(
+            top,
+            vec![
+                (
+                    "a.example".to_string(),
+                    OriginUsage {
+                        requests: 2,
+                        errors: 1,
+                        fetched_bytes: 100,
+                    }
+                ),
+                (
+                    "other".to_string(),
+                    OriginUsage {
+                        requests: 1,
+                        errors: 0,
+                        fetched_bytes: 0,
+                    }
+                ),
+            ]
+        );
+        assert!(stats
+            .prometheus()
+            .contains("misskey_webp_proxy_origin_errors_total{origin=\"a.example\"} 1\n"));
+    }
+
+    #[test]
+    fn origins_are_bounded() {
+        let stats = Stats::default();
+        for _ in 0..3 {
+            stats.record_origin_request("busy.example", false);
+        }
+        for i in 0..MAX_ORIGINS {
+            stats.record_origin_request(&format!("{}.example", i), false);
+        }
+
+        let origins = stats.origins.lock().unwrap();
+        assert!(origins.hosts.len() <= MAX_ORIGINS);
+        assert_eq!(origins.hosts["busy.example"].requests, 3);
+        let total: u64 = origins.hosts.values().map(|u| u.requests).sum();
+        assert_eq!(total + origins.other.requests, MAX_ORIGINS as u64 + 3);
+    }
+}
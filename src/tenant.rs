@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{blocklist::glob_match, convert::Converted, handler::ProxyConfig};
+
+/// 設定ファイルに書く`Host`ごとの設定
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantConfig {
+    /// `quality`の指定がない場合の圧縮率
+    quality: Option<u8>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    /// 取得を許可するホスト名のglob。空の場合はすべて許可する
+    #[serde(default)]
+    allow_hosts: Vec<String>,
+    /// 変換に失敗した場合に返す画像のパス
+    fallback: Option<PathBuf>,
+}
+
+/// `Host`ごとの設定
+#[derive(Debug)]
+pub(crate) struct Tenant {
+    quality: Option<u8>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    allow_hosts: Vec<String>,
+    fallback: Option<Fallback>,
+}
+
+/// 変換に失敗した場合に返す画像
+#[derive(Debug)]
+pub(crate) struct Fallback {
+    pub(crate) content_type: &'static str,
+    pub(crate) body: Bytes,
+}
+
+impl Tenant {
+    fn load(config: TenantConfig) -> Result<Self> {
+        if config.quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            return Err(anyhow::anyhow!("quality must be between 1 and 100"));
+        }
+        let fallback = match config.fallback {
+            Some(path) => {
+                let body = std::fs::read(&path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                let content_type = image::guess_format(&body)
+                    .ok()
+                    .and_then(|format| Converted::known_content_type(format.to_mime_type()))
+                    .with_context(|| format!("{} is not webp, png or gif", path.display()))?;
+                Some(Fallback {
+                    content_type,
+                    body: body.into(),
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            quality: config.quality,
+            max_width: config.max_width,
+            max_height: config.max_height,
+            allow_hosts: config
+                .allow_hosts
+                .into_iter()
+                .map(|host| host.to_lowercase())
+                .collect(),
+            fallback,
+        })
+    }
+
+    /// 取得元が許可されたホストか
+    pub(crate) fn is_allowed(&self, url: &Url) -> bool {
+        if self.allow_hosts.is_empty() {
+            return true;
+        }
+        url.host_str().is_some_and(|host| {
+            let host = host.to_lowercase();
+            self.allow_hosts
+                .iter()
+                .any(|pattern| glob_match(pattern, &host))
+        })
+    }
+
+    /// この`Host`の設定を変換の指定に反映する
+    pub(crate) fn apply(&self, mut config: ProxyConfig) -> ProxyConfig {
+        config.quality = config.quality.or(self.quality);
+        config.clamp_size(
+            self.max_width.unwrap_or(u32::MAX),
+            self.max_height.unwrap_or(u32::MAX),
+        )
+    }
+
+    /// `quality`の指定がない場合の圧縮率
+    pub(crate) fn quality(&self) -> Option<u8> {
+        self.quality
+    }
+
+    pub(crate) fn fallback(&self) -> Option<&Fallback> {
+        self.fallback.as_ref()
+    }
+}
+
+/// `Host`ヘッダーで選ぶ設定の一覧
+#[derive(Debug)]
+pub(crate) struct Tenants(HashMap<String, Tenant>);
+
+impl Tenants {
+    /// `{"<host>": {...}}`の形のJSONファイルを読み込む
+    pub(crate) fn load(path: PathBuf) -> Result<Self> {
+        let txt = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&txt)
+    }
+
+    /// 設定ファイルに書かれた`fallback`のパスを返す
+    pub(crate) fn fallback_paths(path: &Path) -> Result<Vec<PathBuf>> {
+        let txt = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let configs: HashMap<String, TenantConfig> = serde_json::from_str(&txt)?;
+        Ok(configs
+            .into_values()
+            .filter_map(|config| config.fallback)
+            .collect())
+    }
+
+    fn parse(txt: &str) -> Result<Self> {
+        let configs: HashMap<String, TenantConfig> = serde_json::from_str(txt)?;
+        configs
+            .into_iter()
+            .map(|(host, config)| {
+                let tenant = Tenant::load(config).with_context(|| format!("tenant {}", host))?;
+                Ok((host.to_lowercase(), tenant))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    /// `Host`ヘッダーの値に対応する設定。ポート番号は無視する
+    pub(crate) fn get(&self, host: &str) -> Option<&Tenant> {
+        let host = match host.rsplit_once(':') {
+            // IPv6アドレスの`:`はポート番号の区切りではない
+            Some((name, port)) if !port.contains(']') => name,
+            _ => host,
+        };
+        self.0.get(&host.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    fn tenants() -> Tenants {
+        Tenants::parse(
+            r#"{
+                "misskey.example": {"quality": 60, "max_width": 200, "allow_hosts": ["*.misskey.example"]},
+                "Other.example": {}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case("misskey.example", true)]
+    #[case("misskey.example:3000", true)]
+    #[case("MISSKEY.example", true)]
+    #[case("other.example", true)]
+    #[case("unknown.example", false)]
+    #[case("[::1]", false)]
+    fn get_test(#[case] host: &str, #[case] expected: bool) {
+        assert_eq!(tenants().get(host).is_some(), expected);
+    }
+
+    #[rstest]
+    #[case("https://media.misskey.example/a.png", true)]
+    #[case("https://example.com/a.png", false)]
+    fn is_allowed_test(#[case] url: &str, #[case] expected: bool) {
+        let tenants = tenants();
+        let url = Url::parse(url).unwrap();
+        assert_eq!(
+            tenants.get("misskey.example").unwrap().is_allowed(&url),
+            expected
+        );
+        assert!(tenants.get("other.example").unwrap().is_allowed(&url));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(Tenants::parse(r#"{"a.example": {"quality": 0}}"#).is_err());
+        assert!(Tenants::parse(r#"{"a.example": {"unknown": 1}}"#).is_err());
+        assert!(Tenants::parse(r#"{"a.example": {"fallback": "/not-exist.png"}}"#).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// 処理ごとの所要時間。`Server-Timing`ヘッダーとして返す
+#[derive(Debug, Default)]
+pub struct ServerTiming {
+    entries: Vec<(&'static str, Duration)>,
+}
+
+impl ServerTiming {
+    /// 所要時間を記録する。同じ名前で複数回記録した場合は合計する
+    pub fn record(&mut self, name: &'static str, duration: Duration) {
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, total)) => *total += duration,
+            None => self.entries.push((name, duration)),
+        }
+    }
+
+    /// `f`の実行にかかった時間を記録する
+    pub fn measure<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.record(name, start.elapsed());
+        res
+    }
+
+    /// `fut`の完了までにかかった時間を記録する
+    pub async fn measure_async<T>(
+        &mut self,
+        name: &'static str,
+        fut: impl Future<Output = T>,
+    ) -> T {
+        let start = Instant::now();
+        let res = fut.await;
+        self.record(name, start.elapsed());
+        res
+    }
+
+    /// `Server-Timing`ヘッダーの値。何も記録していない場合は`None`
+    pub fn header_value(&self) -> Option<String> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let value = self
+            .entries
+            .iter()
+            .map(|(name, d)| format!("{};dur={:.1}", name, d.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn header_value_test() {
+        let mut timing = ServerTiming::default();
+        assert_eq!(timing.header_value(), None);
+
+        timing.record("download", Duration::from_micros(12_340));
+        timing.record("resize", Duration::from_millis(1));
+        timing.record("resize", Duration::from_millis(2));
+        assert_eq!(
+            timing.header_value().unwrap(),
+            "download;dur=12.3, resize;dur=3.0"
+        );
+    }
+}
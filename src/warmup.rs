@@ -0,0 +1,97 @@
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use image::{codecs::gif::GifEncoder, Delay, Frame, ImageFormat, RgbaImage};
+use reqwest::Url;
+
+use crate::{
+    client::{decode_by_ext, DecodeOptions, ImageExt, Limits},
+    handler::{transform, ConvertType, ProxyConfig},
+    webp::WebpOptions,
+};
+
+/// 文字を含むSVG。フォントの読み込みも確かめる
+const SAMPLE_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="64" height="32"><rect width="64" height="32" fill="#86b300"/><text x="4" y="24" font-size="20">Aa</text></svg>"##;
+
+/// 起動時に小さな画像を一通り変換し、最初のリクエストで初期化の時間がかからないようにする。
+/// 設定の誤りもここで検出する
+pub(crate) fn run(options: &[(ConvertType, WebpOptions)]) -> Result<()> {
+    for (convert_type, options) in options {
+        options
+            .config()
+            .with_context(|| format!("invalid webp config for {}", convert_type.name()))?;
+    }
+    crate::processor::fontdb();
+
+    let samples = [
+        ("still", ImageExt::Png, still()?),
+        ("animated", ImageExt::Gif, animated()?),
+        ("svg", ImageExt::Svg, SAMPLE_SVG.as_bytes().to_vec()),
+    ];
+    let url = Url::parse("https://warmup.invalid/")?;
+    for (name, ext, buf) in samples {
+        for (convert_type, options) in options {
+            convert(
+                buf.clone(),
+                ext,
+                &ProxyConfig::new(url.clone(), *convert_type),
+                options,
+            )
+            .with_context(|| {
+                format!(
+                    "failed to convert {} sample as {}",
+                    name,
+                    convert_type.name()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn convert(buf: Vec<u8>, ext: ImageExt, config: &ProxyConfig, options: &WebpOptions) -> Result<()> {
+    let limits = Limits::default();
+    let decoded = decode_by_ext(buf, ext, &limits, DecodeOptions::default())?;
+    transform(decoded, config, &limits, &mut vec![])?.to_webp(options)?;
+    Ok(())
+}
+
+fn gradient(shift: u8) -> RgbaImage {
+    RgbaImage::from_fn(96, 96, |x, y| {
+        image::Rgba([(x * 2) as u8, (y * 2) as u8, shift, 255])
+    })
+}
+
+fn still() -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    gradient(0).write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
+    Ok(buf)
+}
+
+fn animated() -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    {
+        let mut encoder = GifEncoder::new(&mut buf);
+        let frames = (0..3)
+            .map(|i| Frame::from_parts(gradient(i * 80), 0, 0, Delay::from_numer_denom_ms(100, 1)));
+        encoder.encode_frames(frames)?;
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_test() {
+        let options = ConvertType::ALL.map(|t| (t, WebpOptions::new(75.0)));
+        run(&options).unwrap();
+    }
+
+    #[test]
+    fn invalid_config_test() {
+        let options = [(ConvertType::Emoji, WebpOptions::new(150.0))];
+        assert!(run(&options).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::{Client, Url};
+use serde::Serialize;
+
+/// 取得前に外部のサービスへ問い合わせ、取得してよいか確認する
+#[derive(Debug)]
+pub(crate) struct PrefetchWebhook {
+    client: Client,
+    endpoint: Url,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookRequest<'a> {
+    url: &'a str,
+    r#type: &'a str,
+}
+
+impl PrefetchWebhook {
+    pub(crate) fn new(endpoint: Url, timeout: Duration) -> Result<Self> {
+        let client = Client::builder().no_proxy().timeout(timeout).build()?;
+        Ok(Self { client, endpoint })
+    }
+
+    /// `url`と変換の種類をJSONでPOSTする。2xxなら許可、4xxなら拒否とし、それ以外はエラーにする
+    pub(crate) async fn authorize(&self, url: &Url, kind: &str) -> Result<bool> {
+        let status = self
+            .client
+            .post(self.endpoint.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&WebhookRequest {
+                url: url.as_str(),
+                r#type: kind,
+            })?)
+            .send()
+            .await?
+            .status();
+        if status.is_success() {
+            Ok(true)
+        } else if status.is_client_error() {
+            Ok(false)
+        } else {
+            Err(anyhow::anyhow!("Webhook returned {}", status))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{http::StatusCode, routing, Json, Router};
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn authorize_test() -> Result<()> {
+        let app = Router::new().route(
+            "/",
+            routing::post(|Json(req): Json<serde_json::Value>| async move {
+                match req["url"].as_str() {
+                    Some(url) if url.contains("bad") => StatusCode::FORBIDDEN,
+                    Some(url) if url.contains("broken") => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::NO_CONTENT,
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let webhook = PrefetchWebhook::new(
+            Url::parse(&format!("http://{}/", addr))?,
+            Duration::from_secs(5),
+        )?;
+        let ok = Url::parse("https://good.example/a.png")?;
+        let bad = Url::parse("https://bad.example/a.png")?;
+        let broken = Url::parse("https://broken.example/a.png")?;
+        assert_eq!(webhook.authorize(&ok, "emoji").await?, true);
+        assert_eq!(webhook.authorize(&bad, "emoji").await?, false);
+        assert!(webhook.authorize(&broken, "emoji").await.is_err());
+
+        Ok(())
+    }
+}
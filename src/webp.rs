@@ -2,14 +2,16 @@ use std::marker::PhantomData;
 
 use anyhow::{Context, Ok, Result};
 use image::{Frame, RgbaImage};
+
+use crate::processor::{place_on_canvas, InvalidImage};
 use libwebp_sys::{
     WebPAnimEncoder, WebPAnimEncoderAdd, WebPAnimEncoderAssemble, WebPAnimEncoderDelete,
     WebPAnimEncoderNewInternal, WebPAnimEncoderOptions, WebPAnimEncoderOptionsInitInternal,
     WebPConfig, WebPData, WebPDataClear, WebPEncode, WebPGetMuxABIVersion, WebPMemoryWrite,
     WebPMemoryWriter, WebPMemoryWriterClear, WebPMemoryWriterInit, WebPMux, WebPMuxAnimParams,
     WebPMuxAssemble, WebPMuxCreateInternal, WebPMuxDelete, WebPMuxError, WebPMuxSetAnimationParams,
-    WebPPicture, WebPPictureFree, WebPPictureImportRGBA, WebPPreset, WebPValidateConfig,
-    WEBP_CSP_MODE,
+    WebPMuxSetChunk, WebPPicture, WebPPictureAlloc, WebPPictureFree, WebPPictureImportRGBA,
+    WebPPreset, WebPValidateConfig, WEBP_CSP_MODE,
 };
 
 struct ManagedWebpMemoryWriter {
@@ -28,20 +30,131 @@ impl Drop for ManagedWebpMemoryWriter {
     }
 }
 
+/// libwebpのプリセット。画像の内容に合わせて圧縮のパラメータを調整する
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Preset {
+    Default,
+    /// 人物の写真など
+    #[default]
+    Picture,
+    /// 風景の写真など
+    Photo,
+    /// 輪郭のはっきりしたイラスト
+    Drawing,
+    /// 小さいカラフルな画像
+    Icon,
+    /// 文字を含む画像
+    Text,
+}
+
+impl Preset {
+    fn to_sys(self) -> WebPPreset {
+        match self {
+            Preset::Default => WebPPreset::WEBP_PRESET_DEFAULT,
+            Preset::Picture => WebPPreset::WEBP_PRESET_PICTURE,
+            Preset::Photo => WebPPreset::WEBP_PRESET_PHOTO,
+            Preset::Drawing => WebPPreset::WEBP_PRESET_DRAWING,
+            Preset::Icon => WebPPreset::WEBP_PRESET_ICON,
+            Preset::Text => WebPPreset::WEBP_PRESET_TEXT,
+        }
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Preset::Default),
+            "picture" => Ok(Preset::Picture),
+            "photo" => Ok(Preset::Photo),
+            "drawing" => Ok(Preset::Drawing),
+            "icon" => Ok(Preset::Icon),
+            "text" => Ok(Preset::Text),
+            _ => Err(anyhow::anyhow!("unknown webp preset: {}", s)),
+        }
+    }
+}
+
+/// 大きなアニメーションの画質を途中から下げる設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveQuality {
+    /// エンコード済みのピクセル数がこれを超えたフレームから画質を下げる
+    pub threshold_pixels: u64,
+    /// 下げる画質の下限。0-100
+    pub min_quality: f32,
+}
+
+impl AdaptiveQuality {
+    /// `encoded`ピクセルをエンコードした後のフレームの画質。閾値を超えた分に反比例して下げるため、
+    /// 出力の大きさはフレーム数に比例せずに緩やかに増える
+    fn quality(&self, quality: f32, encoded: u64) -> f32 {
+        if encoded <= self.threshold_pixels {
+            return quality;
+        }
+        let lowered = quality * self.threshold_pixels as f32 / encoded as f32;
+        lowered.max(self.min_quality.min(quality))
+    }
+}
+
+/// WebPのエンコード設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WebpOptions {
+    /// 0-100の圧縮率
+    pub quality: f32,
+    pub preset: Preset,
+    /// 透明度の圧縮率。0-100
+    pub alpha_quality: u8,
+    /// 透明度の予測フィルタ。0がなし、1が高速、2が最良
+    pub alpha_filtering: u8,
+    /// 透明度を可逆圧縮するか。無効にすると無圧縮で保存する
+    pub alpha_compression: bool,
+    /// 完全に透明なピクセルのRGBを保持するか。無効の場合は圧縮しやすい値に置き換えられる
+    pub exact: bool,
+    /// アニメーションの画質を途中から下げる。`None`の場合はすべてのフレームを同じ画質にする
+    pub adaptive: Option<AdaptiveQuality>,
+    /// 取得元と変換の設定をXMPで埋め込むか
+    pub provenance: bool,
+}
+
+impl WebpOptions {
+    /// 透明度はlibwebpのデフォルトと同じ設定にする
+    pub fn new(quality: f32) -> Self {
+        Self {
+            quality,
+            preset: Preset::default(),
+            alpha_quality: 100,
+            alpha_filtering: 1,
+            alpha_compression: true,
+            exact: false,
+            adaptive: None,
+            provenance: false,
+        }
+    }
+
+    /// libwebpのエンコード設定を作り、値が範囲内か検証する
+    pub fn config(&self) -> Result<WebPConfig> {
+        let mut config = WebPConfig::new_with_preset(self.preset.to_sys(), self.quality)
+            .map_err(|_| anyhow::anyhow!("WebPConfig init failed"))?;
+        config.alpha_quality = self.alpha_quality as i32;
+        config.alpha_filtering = self.alpha_filtering as i32;
+        config.alpha_compression = self.alpha_compression as i32;
+        config.exact = self.exact as i32;
+        if unsafe { WebPValidateConfig(&config) } == 0 {
+            return Err(anyhow::anyhow!("WebpConfig Validate error"));
+        }
+        Ok(config)
+    }
+}
+
 struct ManagedWebpPicture {
     config: WebPConfig,
     picture: WebPPicture,
 }
 
 impl ManagedWebpPicture {
-    fn from_rgba(rgba_img: &RgbaImage, quality_factor: f32) -> Result<Self> {
-        let mut config =
-            WebPConfig::new_with_preset(WebPPreset::WEBP_PRESET_PICTURE, quality_factor)
-                .map_err(|_| anyhow::anyhow!("WebPConfig init failed"))?;
-        config.alpha_compression = 0;
-        if unsafe { WebPValidateConfig(&config) } == 0 {
-            return Err(anyhow::anyhow!("WebpConfig Validate error"));
-        }
+    fn from_rgba(rgba_img: &RgbaImage, options: &WebpOptions) -> Result<Self> {
+        let config = options.config()?;
 
         let mut picture =
             WebPPicture::new().map_err(|_| anyhow::anyhow!("WebPPicture init failed"))?;
@@ -97,9 +210,67 @@ impl Drop for ManagedWebpPicture {
     }
 }
 
+/// アニメーションのフレームに使い回すピクチャ。キャンバスと同じ大きさで一度だけ確保する
+struct ManagedFramePicture {
+    picture: WebPPicture,
+}
+
+impl ManagedFramePicture {
+    fn new(width: u32, height: u32) -> Result<Self> {
+        let mut picture =
+            WebPPicture::new().map_err(|_| anyhow::anyhow!("WebPPicture init failed"))?;
+        picture.use_argb = 1;
+        picture.width = width as i32;
+        picture.height = height as i32;
+        if unsafe { WebPPictureAlloc(&mut picture) } == 0 {
+            return Err(anyhow::anyhow!("WebPPictureAlloc failed"));
+        }
+        Ok(Self { picture })
+    }
+
+    /// フレームのRGBAをARGBに変換して確保済みのバッファの位置に直接書き込む。
+    /// キャンバスより小さいフレームも一度キャンバスへ複製せずに済む
+    fn import(&mut self, frame: &Frame) -> Result<()> {
+        let (width, height) = (self.picture.width as u32, self.picture.height as u32);
+        let rgba_img = frame.buffer();
+        let (left, top) = (frame.left() as usize, frame.top() as usize);
+        let fits = frame.left().checked_add(rgba_img.width()) <= Some(width)
+            && frame.top().checked_add(rgba_img.height()) <= Some(height);
+        if !fits {
+            return Err(InvalidImage::FrameSizeMismatch {
+                expected: (width, height),
+                actual: rgba_img.dimensions(),
+            }
+            .into());
+        }
+        let stride = self.picture.argb_stride as usize;
+        let argb =
+            unsafe { std::slice::from_raw_parts_mut(self.picture.argb, stride * height as usize) };
+        if rgba_img.dimensions() != (width, height) {
+            // フレームの外側は透明にする
+            argb.fill(0);
+        }
+        let row_len = rgba_img.width() as usize;
+        let rows = rgba_img.as_raw().chunks_exact(row_len * 4);
+        for (dst, src) in argb.chunks_exact_mut(stride).skip(top).zip(rows) {
+            let dst = &mut dst[left..left + row_len];
+            for (pixel, rgba) in dst.iter_mut().zip(src.chunks_exact(4)) {
+                *pixel = u32::from_be_bytes([rgba[3], rgba[0], rgba[1], rgba[2]]);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ManagedFramePicture {
+    fn drop(&mut self) {
+        unsafe { WebPPictureFree(&mut self.picture) }
+    }
+}
+
 /// アニメーションを含まない画像をWebpにエンコードする
-pub(crate) fn encode_webp_image(rgba_img: RgbaImage, quality_factor: f32) -> Result<Vec<u8>> {
-    let wrt = ManagedWebpPicture::from_rgba(&rgba_img, quality_factor)?.encode()?;
+pub fn encode_webp_image(rgba_img: RgbaImage, options: &WebpOptions) -> Result<Vec<u8>> {
+    let wrt = ManagedWebpPicture::from_rgba(&rgba_img, options)?.encode()?;
     let buf = wrt.get();
     Ok(buf.into())
 }
@@ -148,37 +319,91 @@ struct ManagedWebpAnim {
     anim_option: WebPAnimEncoderOptions,
     anim_encoder: *mut WebPAnimEncoder,
     webp_muxabi_ver: i32,
-    frames: Vec<Frame>,
+    width: u32,
+    height: u32,
 }
 
 impl ManagedWebpAnim {
-    fn new(frames: Vec<Frame>) -> Result<Self> {
-        let first_frame = frames.first().context("cannot get first frame")?;
+    fn new(width: u32, height: u32) -> Result<Self> {
         let mux_abi_version = WebPGetMuxABIVersion();
         let mut anim_option = std::mem::MaybeUninit::<WebPAnimEncoderOptions>::uninit();
         unsafe { WebPAnimEncoderOptionsInitInternal(anim_option.as_mut_ptr(), mux_abi_version) };
         let anim_option = unsafe { anim_option.assume_init() };
         let encoder = unsafe {
-            WebPAnimEncoderNewInternal(
-                first_frame.buffer().width() as i32,
-                first_frame.buffer().height() as i32,
-                &anim_option,
-                mux_abi_version,
-            )
+            WebPAnimEncoderNewInternal(width as i32, height as i32, &anim_option, mux_abi_version)
         };
 
         Ok(Self {
             anim_option,
             webp_muxabi_ver: mux_abi_version,
             anim_encoder: encoder,
-            frames,
+            width,
+            height,
         })
     }
 
-    fn encode(self, quality_factor: f32) -> Result<Vec<u8>> {
-        let mut time_stamp_ms = 0;
-        for f in self.frames.iter() {
-            self.anim_encoder_add(f, &mut time_stamp_ms, quality_factor)?;
+    /// フレームを1枚ずつ受け取りながらエンコードする。
+    /// 途中で失敗した場合は、最初のフレームを静止画としてエンコードしたものを返す
+    fn encode<I>(self, frames: I, options: &WebpOptions) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = Result<Frame>>,
+    {
+        let mut first = None;
+        match self.encode_frames(frames, options, &mut first) {
+            Result::Ok(buf) => Ok(buf),
+            Err(e) => match first {
+                Some(first) => {
+                    tracing::warn!("animation encode failed, fallback to first frame: {:#}", e);
+                    let first = place_on_canvas(first, self.width, self.height);
+                    encode_webp_image(first.into_buffer(), options)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// `first`には最初にデコードできたフレームを入れる
+    fn encode_frames<I>(
+        &self,
+        frames: I,
+        options: &WebpOptions,
+        first: &mut Option<Frame>,
+    ) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = Result<Frame>>,
+    {
+        // 設定の検証とピクチャの確保はフレームごとに行わない
+        let config = options.config()?;
+        let mut picture = ManagedFramePicture::new(self.width, self.height)?;
+        // 端数のある表示時間を切り捨てると長いアニメーションでずれるので、
+        // 経過時間は小数で持ち、libwebpに渡すときのみ丸める
+        let mut time_stamp_ms = 0.0;
+        let mut encoded = 0;
+        for f in frames {
+            let f = f?;
+            let mut frame_config = config;
+            if let Some(adaptive) = options.adaptive {
+                frame_config.quality = adaptive.quality(config.quality, encoded);
+            }
+            encoded += self.width as u64 * self.height as u64;
+            let added = self.anim_encoder_add(&f, &mut time_stamp_ms, &frame_config, &mut picture);
+            // 最初のフレームは複製せずにエンコード後に引き取る
+            if first.is_none() {
+                *first = Some(f);
+            }
+            added?;
+        }
+        // 最後のフレームの表示時間を決めるために終了時刻を渡す
+        let status = unsafe {
+            WebPAnimEncoderAdd(
+                self.anim_encoder,
+                std::ptr::null_mut(),
+                time_stamp_ms.round() as i32,
+                std::ptr::null(),
+            )
+        };
+        if status == 0 {
+            return Err(anyhow::anyhow!("Webp Anim encode faild: {}", status));
         }
 
         let mut webp_data = std::mem::MaybeUninit::<WebPData>::uninit();
@@ -190,7 +415,7 @@ impl ManagedWebpAnim {
 
         // mux
         let mux = ManagedWebpMux::new(&webp_data.webp_data, self.webp_muxabi_ver);
-        Self::check_mux_error(unsafe {
+        check_mux_error(unsafe {
             WebPMuxSetAnimationParams(
                 mux.mux,
                 &WebPMuxAnimParams {
@@ -200,7 +425,7 @@ impl ManagedWebpAnim {
             )
         })?;
 
-        Self::check_mux_error(unsafe { WebPMuxAssemble(mux.mux, &mut webp_data.webp_data) })?;
+        check_mux_error(unsafe { WebPMuxAssemble(mux.mux, &mut webp_data.webp_data) })?;
         let buf = unsafe {
             std::slice::from_raw_parts(webp_data.webp_data.bytes, webp_data.webp_data.size)
         };
@@ -208,23 +433,25 @@ impl ManagedWebpAnim {
         Ok(buf)
     }
 
+    /// `time_stamp`にフレームを追加し、表示時間だけ進める
     fn anim_encoder_add(
         &self,
         frame: &Frame,
-        time_stamp: &mut u32,
-        quality_factor: f32,
+        time_stamp: &mut f64,
+        config: &WebPConfig,
+        picture: &mut ManagedFramePicture,
     ) -> Result<()> {
-        let duration = frame.delay().numer_denom_ms();
-        *time_stamp += duration.0 / duration.1;
-        let mut pic = ManagedWebpPicture::from_rgba(frame.buffer(), quality_factor)?;
+        picture.import(frame)?;
         let status = unsafe {
             WebPAnimEncoderAdd(
                 self.anim_encoder,
-                &mut pic.picture,
-                *time_stamp as i32,
-                &pic.config,
+                &mut picture.picture,
+                time_stamp.round() as i32,
+                config,
             )
         };
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        *time_stamp += numer as f64 / denom.max(1) as f64;
         if status == 0 {
             return Err(anyhow::anyhow!(format!(
                 "Webp Anim encode faild: {}",
@@ -234,13 +461,36 @@ impl ManagedWebpAnim {
 
         Ok(())
     }
+}
 
-    fn check_mux_error(e: WebPMuxError) -> Result<()> {
-        match e {
-            WebPMuxError::WEBP_MUX_OK => Ok(()),
-            _ => Err(anyhow::anyhow!("mux err")),
-        }
+fn check_mux_error(e: WebPMuxError) -> Result<()> {
+    match e {
+        WebPMuxError::WEBP_MUX_OK => Ok(()),
+        _ => Err(anyhow::anyhow!("mux err")),
+    }
+}
+
+/// WebPにXMPのメタデータを埋め込む。既にある場合は置き換える
+pub fn embed_xmp(webp: &[u8], xmp: &[u8]) -> Result<Vec<u8>> {
+    let src = WebPData {
+        bytes: webp.as_ptr(),
+        size: webp.len(),
+    };
+    let mux = ManagedWebpMux::new(&src, WebPGetMuxABIVersion());
+    if mux.mux.is_null() {
+        return Err(anyhow::anyhow!("mux create failed"));
     }
+    let chunk = WebPData {
+        bytes: xmp.as_ptr(),
+        size: xmp.len(),
+    };
+    check_mux_error(unsafe { WebPMuxSetChunk(mux.mux, c"XMP ".as_ptr(), &chunk, 1) })?;
+
+    let mut assembled = ManagedWebpData::new(std::mem::MaybeUninit::zeroed());
+    check_mux_error(unsafe { WebPMuxAssemble(mux.mux, &mut assembled.webp_data) })?;
+    let buf =
+        unsafe { std::slice::from_raw_parts(assembled.webp_data.bytes, assembled.webp_data.size) };
+    Ok(buf.to_vec())
 }
 
 impl Drop for ManagedWebpAnim {
@@ -251,10 +501,33 @@ impl Drop for ManagedWebpAnim {
     }
 }
 
-/// アニメーションをWebpにエンコードする
-pub(crate) fn encode_webp_anim(frames: Vec<Frame>, quality_factor: f32) -> Result<Vec<u8>> {
-    let encoder = ManagedWebpAnim::new(frames)?;
-    encoder.encode(quality_factor)
+/// アニメーションをWebpにエンコードする。キャンバスはすべてのフレームを含む大きさにする
+pub fn encode_webp_anim(frames: Vec<Frame>, options: &WebpOptions) -> Result<Vec<u8>> {
+    if frames.is_empty() {
+        return Err(InvalidImage::NoFrames.into());
+    }
+    let (width, height) = frames.iter().fold((0, 0), |(w, h), f| {
+        (
+            w.max(f.left() + f.buffer().width()),
+            h.max(f.top() + f.buffer().height()),
+        )
+    });
+    let encoder = ManagedWebpAnim::new(width, height)?;
+    encoder.encode(frames.into_iter().map(Ok), options)
+}
+
+/// フレームを1枚ずつ受け取りながらアニメーションをWebpにエンコードする。すべてのフレームを保持しないため省メモリ
+pub fn encode_webp_anim_stream<I>(
+    width: u32,
+    height: u32,
+    frames: I,
+    options: &WebpOptions,
+) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = Result<Frame>>,
+{
+    let encoder = ManagedWebpAnim::new(width, height)?;
+    encoder.encode(frames, options)
 }
 
 use libwebp_sys::{
@@ -271,7 +544,7 @@ struct ManagedWebpAnimDecoder<'a> {
 }
 
 impl<'a> ManagedWebpAnimDecoder<'a> {
-    pub(crate) fn new(src: &'a [u8]) -> Result<Self> {
+    pub fn new(src: &'a [u8]) -> Result<Self> {
         let mut dec_options = std::mem::MaybeUninit::<WebPAnimDecoderOptions>::uninit();
         let init_ok = unsafe { WebPAnimDecoderOptionsInit(dec_options.as_mut_ptr()) };
         if init_ok != 1 {
@@ -300,40 +573,22 @@ impl<'a> ManagedWebpAnimDecoder<'a> {
         })
     }
 
-    pub(crate) fn decode(&self) -> Result<Vec<Frame>> {
-        let decoded = unsafe { self.decode_innternal()? };
-        let mut frames = vec![];
-
-        let mut before_timestamp = 0;
-        for (buf, timestamp) in decoded {
-            let delay = image::Delay::from_numer_denom_ms((timestamp - before_timestamp) as u32, 1);
-            let f = Frame::from_parts(buf, 0, 0, delay);
-            frames.push(f);
-            before_timestamp = timestamp;
-        }
-        Ok(frames)
-    }
-
-    unsafe fn decode_innternal(&self) -> Result<Vec<(RgbaImage, i32)>> {
-        let anim_info = self.get_anim_info()?;
-        let width = anim_info.canvas_width;
-        let height = anim_info.canvas_height;
-        let outbuf_length = width * height * 4; // w * h * rgba
-        let mut frames = vec![];
-        while WebPAnimDecoderHasMoreFrames(self.decoder) > 0 {
-            let mut outbuf = std::ptr::null_mut();
-            let mut timestamp = 0;
-            let is_ok = WebPAnimDecoderGetNext(self.decoder, &mut outbuf, &mut timestamp);
-            if is_ok == 0 {
-                return Err(anyhow::anyhow!("webp anim decode failed"));
-            }
-            let buf = std::slice::from_raw_parts(outbuf, outbuf_length as usize);
-            let img = image::RgbaImage::from_raw(width, height, buf.to_vec())
-                .context(anyhow::anyhow!("read rgba image failed"))?;
-            frames.push((img, timestamp));
+    /// 次のフレームをデコードする。`timestamp`は直前のフレームの終了時刻で、このフレームの終了時刻に更新する。
+    /// デコーダの出力バッファは次の呼び出しで上書きされるので複製する
+    fn next_frame(&self, width: u32, height: u32, timestamp: &mut i32) -> Result<Frame> {
+        let mut outbuf = std::ptr::null_mut();
+        let mut end = 0;
+        if unsafe { WebPAnimDecoderGetNext(self.decoder, &mut outbuf, &mut end) } == 0 {
+            return Err(anyhow::anyhow!("webp anim decode failed"));
         }
-
-        Ok(frames)
+        let len = width as usize * height as usize * 4; // w * h * rgba
+        let buf = unsafe { std::slice::from_raw_parts(outbuf, len) };
+        let img =
+            RgbaImage::from_raw(width, height, buf.to_vec()).context("read rgba image failed")?;
+        let delay =
+            image::Delay::from_numer_denom_ms(end.saturating_sub(*timestamp).max(0) as u32, 1);
+        *timestamp = end;
+        Ok(Frame::from_parts(img, 0, 0, delay))
     }
 
     unsafe fn get_anim_info(&self) -> Result<WebPAnimInfo> {
@@ -346,7 +601,7 @@ impl<'a> ManagedWebpAnimDecoder<'a> {
         Ok(anim_info)
     }
 
-    pub(crate) fn count_frame(&self) -> Result<u32> {
+    pub fn count_frame(&self) -> Result<u32> {
         unsafe { self.get_anim_info().map(|x| x.frame_count) }
     }
 }
@@ -357,11 +612,406 @@ impl<'a> Drop for ManagedWebpAnimDecoder<'a> {
     }
 }
 
-pub(crate) fn decode_webp_anim(src: &[u8]) -> Result<Vec<Frame>> {
+/// アニメーションWebpを1フレームずつデコードするイテレータ。フレームはすべてキャンバスの大きさに合成済み
+pub struct WebpAnimFrames<'a> {
+    decoder: ManagedWebpAnimDecoder<'a>,
+    width: u32,
+    height: u32,
+    /// 直前のフレームの終了時刻(ミリ秒)
+    timestamp: i32,
+    /// デコードに失敗したら以降のフレームは返さない
+    failed: bool,
+}
+
+impl WebpAnimFrames<'_> {
+    /// キャンバスの大きさ
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+impl Iterator for WebpAnimFrames<'_> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || unsafe { WebPAnimDecoderHasMoreFrames(self.decoder.decoder) } == 0 {
+            return None;
+        }
+        let frame = self
+            .decoder
+            .next_frame(self.width, self.height, &mut self.timestamp);
+        self.failed = frame.is_err();
+        Some(frame)
+    }
+}
+
+/// アニメーションWebpのフレームを必要になった時点でデコードするイテレータを返す
+pub fn decode_webp_anim(src: &[u8]) -> Result<WebpAnimFrames<'_>> {
     let decoder = ManagedWebpAnimDecoder::new(src)?;
-    decoder.decode()
+    let anim_info = unsafe { decoder.get_anim_info()? };
+    Ok(WebpAnimFrames {
+        decoder,
+        width: anim_info.canvas_width,
+        height: anim_info.canvas_height,
+        timestamp: 0,
+        failed: false,
+    })
+}
+/// 小さな画像をエンコードしてデコードし直し、libwebpが使えるか確かめる
+pub fn self_test() -> Result<()> {
+    let img = RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 128]));
+    let webp = encode_webp_image(img, &WebpOptions::new(75.0))?;
+    let decoded = decode_webp_image(&webp)?.context("decoded as an animation")?;
+    if decoded.dimensions() != (4, 4) {
+        return Err(anyhow::anyhow!(
+            "decoded size mismatch: {:?}",
+            decoded.dimensions()
+        ));
+    }
+    Ok(())
 }
-pub(crate) fn count_webp_anim_frame(src: &[u8]) -> Result<u32> {
+
+/// リンクしたlibwebpの各ライブラリのバージョンと、ビルド時のヘッダーのABIのバージョン
+pub fn library_versions() -> [(&'static str, String, String); 4] {
+    use libwebp_sys::{
+        WebPGetDecoderVersion, WebPGetDemuxVersion, WebPGetEncoderVersion, WebPGetMuxVersion,
+        WEBP_DECODER_ABI_VERSION, WEBP_DEMUX_ABI_VERSION, WEBP_ENCODER_ABI_VERSION,
+    };
+
+    let abi = |v: u32| format!("{}.{}", v >> 8, v & 0xff);
+    unsafe {
+        [
+            (
+                "encoder",
+                version_string(WebPGetEncoderVersion()),
+                abi(WEBP_ENCODER_ABI_VERSION),
+            ),
+            (
+                "decoder",
+                version_string(WebPGetDecoderVersion()),
+                abi(WEBP_DECODER_ABI_VERSION),
+            ),
+            (
+                "mux",
+                version_string(WebPGetMuxVersion()),
+                abi(WebPGetMuxABIVersion() as u32),
+            ),
+            (
+                "demux",
+                version_string(WebPGetDemuxVersion()),
+                abi(WEBP_DEMUX_ABI_VERSION),
+            ),
+        ]
+    }
+}
+
+/// libwebpの`0xMMmmpp`の形のバージョンを`MM.mm.pp`にする
+fn version_string(version: i32) -> String {
+    format!(
+        "{}.{}.{}",
+        (version >> 16) & 0xff,
+        (version >> 8) & 0xff,
+        version & 0xff
+    )
+}
+
+/// アニメーションの最初のフレームのみをデコードする
+pub fn decode_webp_anim_first(src: &[u8]) -> Result<RgbaImage> {
+    let first = decode_webp_anim(src)?
+        .next()
+        .ok_or(InvalidImage::NoFrames)??;
+    Ok(first.into_buffer())
+}
+pub fn count_webp_anim_frame(src: &[u8]) -> Result<u32> {
     let decoder = ManagedWebpAnimDecoder::new(src)?;
     decoder.count_frame()
 }
+
+use libwebp_sys::{
+    VP8StatusCode, WebPBitstreamFeatures, WebPDecodeRGBA, WebPFree, WebPGetFeatures,
+};
+
+struct ManagedWebpDecodedBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ManagedWebpDecodedBuffer {
+    fn get(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for ManagedWebpDecodedBuffer {
+    fn drop(&mut self) {
+        unsafe { WebPFree(self.ptr as _) }
+    }
+}
+
+/// アニメーションを含まないWebpをlibwebpでデコードする。アニメーションの場合は`None`を返す
+pub fn decode_webp_image(src: &[u8]) -> Result<Option<RgbaImage>> {
+    let mut features = std::mem::MaybeUninit::<WebPBitstreamFeatures>::uninit();
+    let status = unsafe { WebPGetFeatures(src.as_ptr(), src.len(), features.as_mut_ptr()) };
+    if status != VP8StatusCode::VP8_STATUS_OK {
+        return Err(anyhow::anyhow!("webp get features failed: {:?}", status));
+    }
+    let features = unsafe { features.assume_init() };
+    if features.has_animation != 0 {
+        return Ok(None);
+    }
+
+    let mut width = 0;
+    let mut height = 0;
+    let ptr = unsafe { WebPDecodeRGBA(src.as_ptr(), src.len(), &mut width, &mut height) };
+    if ptr.is_null() {
+        return Err(anyhow::anyhow!("webp decode failed"));
+    }
+    let decoded = ManagedWebpDecodedBuffer {
+        ptr,
+        len: width as usize * height as usize * 4, // w * h * rgba
+    };
+
+    let img = RgbaImage::from_raw(width as u32, height as u32, decoded.get().to_vec())
+        .context("read rgba image failed")?;
+    Ok(Some(img))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn webp_image_roundtrip_test() -> anyhow::Result<()> {
+        let img = RgbaImage::from_pixel(30, 20, image::Rgba([0, 128, 255, 255]));
+        let webp = encode_webp_image(img, &WebpOptions::new(75.0))?;
+        let decoded = decode_webp_image(&webp)?.context("must be a still image")?;
+        assert_eq!(decoded.dimensions(), (30, 20));
+
+        Ok(())
+    }
+
+    #[test]
+    fn self_test_test() {
+        self_test().unwrap();
+    }
+
+    #[rstest]
+    #[case(0x010302, "1.3.2")]
+    #[case(0x000605, "0.6.5")]
+    fn version_string_test(#[case] version: i32, #[case] expected: &str) {
+        assert_eq!(version_string(version), expected);
+    }
+
+    #[test]
+    fn alpha_compression_test() -> anyhow::Result<()> {
+        // 透明な余白のある絵文字を想定する
+        let img = RgbaImage::from_fn(128, 128, |x, y| {
+            let alpha = if (32..96).contains(&x) && (32..96).contains(&y) {
+                255
+            } else {
+                0
+            };
+            image::Rgba([255, 0, 0, alpha])
+        });
+        let compressed = encode_webp_image(img.clone(), &WebpOptions::new(75.0))?;
+        let uncompressed = encode_webp_image(
+            img,
+            &WebpOptions {
+                alpha_compression: false,
+                ..WebpOptions::new(75.0)
+            },
+        )?;
+        assert!(compressed.len() < uncompressed.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn exact_test() -> anyhow::Result<()> {
+        // 右半分は透明だが、縦縞の色を持つ
+        let img = RgbaImage::from_fn(32, 32, |x, _| match x {
+            0..16 => image::Rgba([255, 0, 0, 255]),
+            _ if x % 4 < 2 => image::Rgba([0, 255, 0, 0]),
+            _ => image::Rgba([0, 0, 255, 0]),
+        });
+        let stripe = |options: &WebpOptions| -> anyhow::Result<bool> {
+            let webp = encode_webp_image(img.clone(), options)?;
+            let decoded = decode_webp_image(&webp)?.context("must be a still image")?;
+            Ok(decoded.get_pixel(24, 16)[1] as i32 - decoded.get_pixel(26, 16)[1] as i32 > 64)
+        };
+
+        let exact = WebpOptions {
+            exact: true,
+            ..WebpOptions::new(100.0)
+        };
+        assert!(stripe(&exact)?);
+        assert!(!stripe(&WebpOptions::new(100.0))?);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::below_threshold(0, 75.0)]
+    #[case::at_threshold(1000, 75.0)]
+    #[case::double(2000, 37.5)]
+    #[case::floor(10000, 20.0)]
+    fn adaptive_quality_test(#[case] encoded: u64, #[case] expected: f32) {
+        let adaptive = AdaptiveQuality {
+            threshold_pixels: 1000,
+            min_quality: 20.0,
+        };
+        assert_eq!(adaptive.quality(75.0, encoded), expected);
+        // もとの画質が下限より低い場合はそのまま
+        assert_eq!(adaptive.quality(10.0, encoded), 10.0);
+    }
+
+    #[test]
+    fn adaptive_quality_encode_test() -> anyhow::Result<()> {
+        let frames: Vec<_> = (0..20)
+            .map(|i| {
+                let img = RgbaImage::from_fn(64, 64, |x, y| {
+                    image::Rgba([(x * 4) as u8, (y * 4) as u8, i * 12, 255])
+                });
+                Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            })
+            .collect();
+        let options = WebpOptions::new(90.0);
+        let flat = encode_webp_anim(frames.clone(), &options)?;
+        let adaptive = WebpOptions {
+            adaptive: Some(AdaptiveQuality {
+                threshold_pixels: 64 * 64 * 2,
+                min_quality: 10.0,
+            }),
+            ..options
+        };
+        let lowered = encode_webp_anim(frames, &adaptive)?;
+        assert!(lowered.len() < flat.len());
+        // 画質を下げると似たフレームはまとめられるが、再生時間は変わらない
+        let total: u32 = decode_webp_anim(&lowered)?
+            .map(|f| f.map(|f| f.delay().numer_denom_ms().0))
+            .sum::<Result<_>>()?;
+        assert_eq!(total, 2000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn frame_picture_import_test() -> anyhow::Result<()> {
+        let mut picture = ManagedFramePicture::new(2, 1)?;
+        let mut img = RgbaImage::new(2, 1);
+        img.put_pixel(0, 0, image::Rgba([0x11, 0x22, 0x33, 0x44]));
+        img.put_pixel(1, 0, image::Rgba([0x55, 0x66, 0x77, 0x88]));
+        picture.import(&Frame::new(img))?;
+        let argb = unsafe { std::slice::from_raw_parts(picture.picture.argb, 2) };
+        assert_eq!(argb, [0x44112233, 0x88556677]);
+
+        // 小さいフレームは位置に合わせて書き込み、残りは透明にする
+        let img = RgbaImage::from_pixel(1, 1, image::Rgba([0x11, 0x22, 0x33, 0x44]));
+        let delay = image::Delay::from_numer_denom_ms(0, 1);
+        picture.import(&Frame::from_parts(img, 1, 0, delay))?;
+        let argb = unsafe { std::slice::from_raw_parts(picture.picture.argb, 2) };
+        assert_eq!(argb, [0, 0x44112233]);
+
+        assert!(picture.import(&Frame::new(RgbaImage::new(1, 2))).is_err());
+        let img = RgbaImage::new(2, 1);
+        assert!(picture
+            .import(&Frame::from_parts(img, 1, 0, delay))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn webp_anim_fallback_test() -> anyhow::Result<()> {
+        // キャンバスと大きさの異なるフレームはWebPAnimEncoderAddで失敗する
+        let encoder = ManagedWebpAnim::new(30, 20)?;
+        let frames = vec![
+            Frame::new(RgbaImage::from_pixel(30, 20, image::Rgba([255, 0, 0, 255]))),
+            Frame::new(RgbaImage::from_pixel(60, 40, image::Rgba([0, 255, 0, 255]))),
+        ];
+        let webp = encoder.encode(frames.into_iter().map(Ok), &WebpOptions::new(75.0))?;
+        let decoded = decode_webp_image(&webp)?.context("must fallback to a still image")?;
+        assert_eq!(decoded.dimensions(), (30, 20));
+
+        Ok(())
+    }
+
+    #[test]
+    fn webp_anim_timestamp_test() -> anyhow::Result<()> {
+        // 1フレームあたり33.33ms
+        let delay = image::Delay::from_numer_denom_ms(100, 3);
+        let frames = (0..60)
+            .map(|i| {
+                let img = RgbaImage::from_pixel(8, 8, image::Rgba([(i % 2) * 255, i * 4, 0, 255]));
+                Frame::from_parts(img, 0, 0, delay)
+            })
+            .collect();
+        let webp = encode_webp_anim(frames, &WebpOptions::new(75.0))?;
+        let frames = decode_webp_anim(&webp)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(frames.len(), 60);
+
+        let total: u32 = frames
+            .iter()
+            .map(|f| {
+                let (numer, denom) = f.delay().numer_denom_ms();
+                assert!((33..=34).contains(&(numer / denom)));
+                numer / denom
+            })
+            .sum();
+        assert_eq!(total, 2000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn webp_anim_canvas_test() -> anyhow::Result<()> {
+        // 最初のフレームがキャンバスより小さい
+        let delay = image::Delay::from_numer_denom_ms(100, 1);
+        let frames = vec![
+            Frame::from_parts(
+                RgbaImage::from_pixel(10, 10, image::Rgba([255, 0, 0, 255])),
+                5,
+                5,
+                delay,
+            ),
+            Frame::from_parts(
+                RgbaImage::from_pixel(40, 30, image::Rgba([0, 255, 0, 255])),
+                0,
+                0,
+                delay,
+            ),
+        ];
+        let webp = encode_webp_anim(frames, &WebpOptions::new(75.0))?;
+        let frames = decode_webp_anim(&webp)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].buffer().dimensions(), (40, 30));
+        assert_eq!(frames[0].buffer().get_pixel(0, 0)[3], 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn webp_anim_first_test() -> anyhow::Result<()> {
+        let delay = image::Delay::from_numer_denom_ms(100, 1);
+        let frames = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]]
+            .into_iter()
+            .map(|rgba| {
+                Frame::from_parts(RgbaImage::from_pixel(8, 8, image::Rgba(rgba)), 0, 0, delay)
+            })
+            .collect();
+        let webp = encode_webp_anim(frames, &WebpOptions::new(100.0))?;
+        let first = decode_webp_anim_first(&webp)?;
+        assert_eq!(first.dimensions(), (8, 8));
+        let [r, g, b, _] = first.get_pixel(4, 4).0;
+        assert!(r > 200 && g < 50 && b < 50);
+
+        // 残りのフレームはデコードしない
+        let mut frames = decode_webp_anim(&webp)?;
+        assert_eq!(frames.dimensions(), (8, 8));
+        assert!(frames.nth(1).is_some());
+        assert!(frames.next().is_some());
+        assert!(frames.next().is_none());
+
+        Ok(())
+    }
+}